@@ -0,0 +1,101 @@
+//! Benchmarks for writing the forwarded requests to the upstream servers.
+//!
+//! Run them with `cargo bench --bench forwarding`. The requests are written to a loopback TCP connection, so every
+//! write is a system call, and the number of writes per request is printed before the measurements.
+//!
+//! - `write_to_stream/coalesced`: the request serialized at once and written with a single write.
+//! - `write_to_stream/per_part`: the request line, every header and the CRLFs written one at a time, as the proxy
+//!   server used to, for comparison.
+
+use std::hint::black_box;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http::Request;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+use rust_loadbalancer::request::{format_request_line, write_to_stream};
+
+
+/// Stream counting the writes made to the stream it wraps.
+struct CountingStream {
+    stream: TcpStream,
+    writes: usize,
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        self.writes += 1;
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+
+/// Writes the request a part at a time, the way requests were written before they were coalesced.
+async fn write_per_part(request: &Request<Vec<u8>>, stream: &mut CountingStream) -> Result<(), std::io::Error> {
+    stream.write_all(format_request_line(request)?.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    for (header_name, header_value) in request.headers() {
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    stream.write_all(b"\r\n").await
+}
+
+
+/// Opens a loopback connection whose other end discards everything it receives.
+fn upstream_connection(runtime: &Runtime) -> CountingStream {
+    runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut upstream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move { tokio::io::copy(&mut upstream, &mut tokio::io::sink()).await });
+        CountingStream { stream, writes: 0 }
+    })
+}
+
+
+fn bench_write_to_stream(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+    let mut group = c.benchmark_group("write_to_stream");
+    group.throughput(Throughput::Elements(1));
+
+    for header_count in [4, 16] {
+        let mut request = Request::builder().method("GET").uri("/index.html?lang=en").header("Host", "localhost");
+        for i in 1..header_count {
+            request = request.header(format!("X-Header-{}", i), "value");
+        }
+        let request = request.body(Vec::new()).unwrap();
+
+        let mut coalesced = upstream_connection(&runtime);
+        let mut per_part = upstream_connection(&runtime);
+        runtime.block_on(write_to_stream(&request, &mut coalesced)).unwrap();
+        runtime.block_on(write_per_part(&request, &mut per_part)).unwrap();
+        println!("{} headers: {} write(s) coalesced, {} writes per part", header_count, coalesced.writes, per_part.writes);
+
+        group.bench_with_input(BenchmarkId::new("coalesced", header_count), &request, |b, request| {
+            b.iter(|| runtime.block_on(write_to_stream(black_box(request), &mut coalesced)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("per_part", header_count), &request, |b, request| {
+            b.iter(|| runtime.block_on(write_per_part(black_box(request), &mut per_part)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+
+criterion_group!(benches, bench_write_to_stream);
+criterion_main!(benches);
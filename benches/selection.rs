@@ -0,0 +1,129 @@
+//! Benchmarks for the per-request selection path.
+//!
+//! Run them with `cargo bench --bench selection`, or a single group with e.g.
+//! `cargo bench --bench selection -- select_upstream/`. Criterion stores the results in `target/criterion` and
+//! compares every run with the previous one, so a regression shows up as a change in the report.
+//!
+//! - `select_upstream`: selection among 10, 100 and 1000 healthy upstreams, for each strategy: `random`,
+//!   `least_bytes` (the fewest bytes relayed over the window) and `reported_load` (the lower reported load of two).
+//! - `select_upstream_excluded`: the same pools with half of the upstreams excluded for the attempt.
+//! - `client_request_builder`: rewriting the headers of a request before it is forwarded.
+
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http::Request;
+
+use rust_loadbalancer::byte_volume::ByteVolumes;
+use rust_loadbalancer::load_report::LoadReports;
+use rust_loadbalancer::request::{client_request_builder, ForwardedHeaderFormat};
+use rust_loadbalancer::selection::select_upstream;
+
+const POOL_SIZES: [usize; 3] = [10, 100, 1000];
+
+
+fn upstream_pool(size: usize) -> Vec<String> {
+    (0..size).map(|i| format!("10.0.{}.{}:8080", i / 256, i % 256)).collect()
+}
+
+
+/// Bytes relayed by every upstream of the pool, all different so the pick depends on every one of them.
+fn byte_volumes(upstreams: &[String], now: Instant) -> ByteVolumes {
+    let byte_volumes = ByteVolumes::new(Duration::from_secs(60));
+    for (i, upstream) in upstreams.iter().enumerate() {
+        byte_volumes.record(upstream, 1000 + i as u64, now);
+    }
+    byte_volumes
+}
+
+
+/// Load reported by every upstream of the pool.
+fn load_reports(upstreams: &[String]) -> LoadReports {
+    let load_reports = LoadReports::default();
+    for (i, upstream) in upstreams.iter().enumerate() {
+        load_reports.record(upstream, (i % 10) as f64 / 10.0);
+    }
+    load_reports
+}
+
+
+fn bench_select_upstream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("select_upstream");
+    group.throughput(Throughput::Elements(1));
+
+    for size in POOL_SIZES {
+        let upstreams = upstream_pool(size);
+        let excluded = HashSet::new();
+
+        group.bench_with_input(BenchmarkId::new("random", size), &upstreams, |b, upstreams| {
+            b.iter(|| select_upstream(black_box(upstreams), black_box(&excluded)))
+        });
+
+        let now = Instant::now();
+        let byte_volumes = byte_volumes(&upstreams, now);
+        group.bench_with_input(BenchmarkId::new("least_bytes", size), &upstreams, |b, upstreams| {
+            b.iter(|| byte_volumes.select(black_box(upstreams), black_box(&excluded), now))
+        });
+
+        let load_reports = load_reports(&upstreams);
+        group.bench_with_input(BenchmarkId::new("reported_load", size), &upstreams, |b, upstreams| {
+            b.iter(|| load_reports.select(black_box(upstreams), black_box(&excluded)))
+        });
+    }
+
+    group.finish();
+}
+
+
+fn bench_select_upstream_excluded(c: &mut Criterion) {
+    let mut group = c.benchmark_group("select_upstream_excluded");
+    group.throughput(Throughput::Elements(1));
+
+    for size in POOL_SIZES {
+        let upstreams = upstream_pool(size);
+        let excluded: HashSet<String> = upstreams.iter().step_by(2).cloned().collect();
+
+        group.bench_with_input(BenchmarkId::new("random", size), &upstreams, |b, upstreams| {
+            b.iter(|| select_upstream(black_box(upstreams), black_box(&excluded)))
+        });
+
+        let now = Instant::now();
+        let byte_volumes = byte_volumes(&upstreams, now);
+        group.bench_with_input(BenchmarkId::new("least_bytes", size), &upstreams, |b, upstreams| {
+            b.iter(|| byte_volumes.select(black_box(upstreams), black_box(&excluded), now))
+        });
+
+        let load_reports = load_reports(&upstreams);
+        group.bench_with_input(BenchmarkId::new("reported_load", size), &upstreams, |b, upstreams| {
+            b.iter(|| load_reports.select(black_box(upstreams), black_box(&excluded)))
+        });
+    }
+
+    group.finish();
+}
+
+
+fn bench_client_request_builder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client_request_builder");
+    group.throughput(Throughput::Elements(1));
+
+    for header_count in [2, 8, 16] {
+        let mut request = Request::builder().method("GET").uri("/index.html?lang=en").header("Host", "localhost");
+        for i in 1..header_count {
+            request = request.header(format!("X-Header-{}", i), "value");
+        }
+        let request = request.body(Vec::new()).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("headers", header_count), &request, |b, request| {
+            b.iter(|| client_request_builder(black_box(Some("192.168.1.1:54321")), ForwardedHeaderFormat::XForwardedFor, black_box(request), black_box(1), &[]))
+        });
+    }
+
+    group.finish();
+}
+
+
+criterion_group!(benches, bench_select_upstream, bench_select_upstream_excluded, bench_client_request_builder);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Feeds arbitrary bytes to `read_client_request`, which must answer every input with a request or a typed error.
+//!
+//! The first byte of the input is the size of the parts the rest is delivered in, 0 for a single read, so the fuzzer
+//! also explores the requests arriving in several parts.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use rust_loadbalancer::request::{read_client_request, RequestConfig};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, input)) = data.split_first() else {
+        return;
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        // deliver the input in parts of `split` bytes, each returned by a different read, or at once for 0
+        let part_length = match split {
+            0 => input.len().max(1),
+            split => usize::from(split),
+        };
+        let mut stream: Box<dyn AsyncRead + Unpin> = Box::new(Cursor::new(Vec::new()));
+        for part in input.chunks(part_length) {
+            stream = Box::new(stream.chain(Cursor::new(part.to_vec())));
+        }
+
+        let mut buffer = vec![0; 1024];
+        // any outcome but a panic is fine, the typed error is answered by the proxy
+        let _ = read_client_request(&mut stream, &mut buffer, &RequestConfig::default()).await;
+    });
+});
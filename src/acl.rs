@@ -0,0 +1,111 @@
+//! # ACL Module
+//!
+//! This module denies requests by method and path at the edge, before they are routed to an upstream server.
+//!
+//! The rules are given in order with `--acl-rule`, each as `deny` followed by `key=value` conditions:
+//!
+//! - `method=TRACE`: The request method, matched exactly.
+//! - `path=^/\.git/`: A regular expression searched in the request path.
+//! - `except_cidr=10.0.0.0/8`: A network whose clients the rule doesn't apply to, may be repeated.
+//! - `status=404`: The status the denied requests are answered with. Default is 403 Forbidden.
+//!
+//! A rule needs a `method` or a `path` condition, and matches the requests meeting all of its conditions. The rules
+//! are evaluated in order and the first matching one denies the request, so a more specific rule must come before a
+//! broader one. The regular expressions and networks are parsed with the options, so an invalid rule stops the proxy
+//! server at startup.
+//!
+//! ## Structures
+//!
+//! - `AclRule`: A deny rule and the status it answers with.
+//!
+//! ## Functions
+//!
+//! ### `evaluate`
+//!
+//! This function returns the index of the first rule denying a request, if any.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use http::{Method, Request, StatusCode};
+use ipnet::IpNet;
+use regex::Regex;
+
+/// A rule denying the requests that match its method and path, unless they come from one of its networks.
+#[derive(Debug, Clone)]
+pub struct AclRule {
+    /// Method of the denied requests, any method if `None`.
+    pub method: Option<Method>,
+
+    /// Expression searched in the path of the denied requests, any path if `None`.
+    pub path: Option<Regex>,
+
+    /// Networks of the clients the rule doesn't apply to.
+    pub except_cidr: Vec<IpNet>,
+
+    /// Status the denied requests are answered with.
+    pub status: StatusCode,
+}
+
+impl AclRule {
+    /// Tells whether the rule denies a request of `client_ip`.
+    pub fn matches(&self, request: &Request<Vec<u8>>, client_ip: IpAddr) -> bool {
+        self.method.as_ref().is_none_or(|method| request.method() == method)
+            && self.path.as_ref().is_none_or(|path| path.is_match(request.uri().path()))
+            && !self.except_cidr.iter().any(|network| network.contains(&client_ip))
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = String;
+
+    /// Parses a rule such as `deny method=DELETE path=^/api/ except_cidr=10.0.0.0/8 status=405`.
+    fn from_str(rule: &str) -> Result<AclRule, String> {
+        let mut words = rule.split_whitespace();
+        if words.next() != Some("deny") {
+            return Err(format!("expected a rule starting with deny, got {:?}", rule));
+        }
+
+        let mut acl_rule = AclRule { method: None, path: None, except_cidr: Vec::new(), status: StatusCode::FORBIDDEN };
+        for condition in words {
+            let (key, value) = condition.split_once('=').ok_or(format!("expected key=value, got {:?}", condition))?;
+            match key {
+                "method" => {
+                    let method = Method::from_bytes(value.as_bytes()).map_err(|_| format!("invalid method {:?}", value))?;
+                    acl_rule.method = Some(method);
+                }
+                "path" => {
+                    let path = Regex::new(value).map_err(|e| format!("invalid path expression {:?}: {}", value, e))?;
+                    acl_rule.path = Some(path);
+                }
+                "except_cidr" => {
+                    let network = value.parse().map_err(|e| format!("invalid network {:?}: {}", value, e))?;
+                    acl_rule.except_cidr.push(network);
+                }
+                "status" => {
+                    acl_rule.status = value.parse::<u16>().ok()
+                        .and_then(|status| StatusCode::from_u16(status).ok())
+                        .filter(|status| status.is_client_error() || status.is_server_error())
+                        .ok_or(format!("expected a 4xx or 5xx status, got {:?}", value))?;
+                }
+                _ => return Err(format!("unknown condition {:?}, expected method, path, except_cidr or status", key)),
+            }
+        }
+
+        if acl_rule.method.is_none() && acl_rule.path.is_none() {
+            return Err(format!("expected a method or path condition in {:?}", rule));
+        }
+        Ok(acl_rule)
+    }
+}
+
+/// Returns the index of the first rule denying a request of `client_ip`, or `None` if the request is allowed.
+///
+/// # Arguments
+///
+/// * `rules` - The rules, in the order they were given.
+/// * `request` - The parsed client request.
+/// * `client_ip` - The IP address of the client, as reported by a trusted proxy if any.
+pub fn evaluate(rules: &[AclRule], request: &Request<Vec<u8>>, client_ip: IpAddr) -> Option<usize> {
+    rules.iter().position(|rule| rule.matches(request, client_ip))
+}
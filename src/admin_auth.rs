@@ -0,0 +1,114 @@
+//! # Admin Auth Module
+//!
+//! This module authenticates the requests of the metrics listener, which can change the behavior of the proxy server,
+//! with a bearer token, for hosts where binding the listener to a private interface doesn't keep the other users out.
+//!
+//! With `--admin-token` or `--admin-token-file`, every request of the metrics listener must carry the token in an
+//! `Authorization: Bearer <token>` header. The presented token is compared in constant time, so the time taken to
+//! refuse a guess tells nothing about how much of it was right. A request without the header or with another token is
+//! answered with 401 Unauthorized. With `--admin-token-exempt-metrics`, `GET /metrics` is answered without a token,
+//! since the Prometheus scrapers are often configured without one.
+//!
+//! The token of `--admin-token-file` is read again on `SIGHUP`, so it can be rotated without closing the listener: the
+//! old token is refused as soon as the new one is read. A file that can't be read, or holds no token, keeps the
+//! current token in place.
+//!
+//! ## Structures
+//!
+//! - `AdminAuth`: The token the requests of the metrics listener must present, and where it is read from.
+//!
+//! ## Functions
+//!
+//! ### `constant_time_eq`
+//!
+//! This function compares two byte strings in a time that only depends on their lengths.
+
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use http::header::AUTHORIZATION;
+use http::{Method, Request};
+
+/// The token the requests of the metrics listener must present, and where it is read from.
+#[derive(Debug)]
+pub struct AdminAuth {
+    /// The token currently accepted.
+    token: RwLock<Vec<u8>>,
+
+    /// The file the token is read from, read again on `reload`, if it isn't given on the command line.
+    token_file: Option<PathBuf>,
+
+    /// Answer `GET /metrics` without a token.
+    exempt_metrics: bool,
+}
+
+impl AdminAuth {
+    /// Creates the authentication with a fixed token, which can't be empty.
+    pub fn new(token: &str, exempt_metrics: bool) -> Result<AdminAuth, String> {
+        let token = token.trim();
+        if token.is_empty() {
+            return Err("the admin token is empty".to_string());
+        }
+        Ok(AdminAuth { token: RwLock::new(token.as_bytes().to_vec()), token_file: None, exempt_metrics })
+    }
+
+    /// Creates the authentication with the token held in `token_file`, read again by `reload`.
+    pub fn from_file(token_file: PathBuf, exempt_metrics: bool) -> Result<AdminAuth, String> {
+        let token = read_token(&token_file)?;
+        Ok(AdminAuth { token: RwLock::new(token), token_file: Some(token_file), exempt_metrics })
+    }
+
+    /// Reads the token from the token file again, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the token was read, and replaced the current one.
+    /// * `Ok(false)` - If the token is fixed, it is kept.
+    /// * `Err(String)` - If the token file can't be read or holds no token, the current token is kept.
+    pub fn reload(&self) -> Result<bool, String> {
+        let Some(token_file) = &self.token_file else {
+            return Ok(false);
+        };
+        let token = read_token(token_file)?;
+        *self.token.write().unwrap() = token;
+        Ok(true)
+    }
+
+    /// Tells whether a request for `path` with `method` is answered without a token.
+    pub fn is_exempt(&self, method: &Method, path: &str) -> bool {
+        self.exempt_metrics && *method == Method::GET && path == "/metrics"
+    }
+
+    /// Tells whether `request` is answered: it is exempt, or it presents the current token as a bearer token.
+    pub fn authorize<B>(&self, request: &Request<B>) -> bool {
+        if self.is_exempt(request.method(), request.uri().path()) {
+            return true;
+        }
+        let presented = request.headers().get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim());
+        match presented {
+            Some(token) => constant_time_eq(token.as_bytes(), &self.token.read().unwrap()),
+            None => false,
+        }
+    }
+}
+
+/// Reads the token of a token file, the surrounding whitespace and line breaks removed.
+fn read_token(token_file: &Path) -> Result<Vec<u8>, String> {
+    let token = std::fs::read_to_string(token_file).map_err(|e| format!("could not read the admin token from {}: {}", token_file.display(), e))?;
+    match token.trim() {
+        "" => Err(format!("{} holds no admin token", token_file.display())),
+        token => Ok(token.as_bytes().to_vec()),
+    }
+}
+
+/// Compares two byte strings in a time that only depends on their lengths, not on where they first differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
@@ -0,0 +1,98 @@
+//! # Buffer Pool Module
+//!
+//! This module provides a bounded pool of byte buffers shared by all the client connections.
+//!
+//! Every connection checks a buffer out when it starts and returns it when it ends, so the buffers used to read
+//! requests and relay responses are reused instead of being allocated for each connection. The pool keeps at most
+//! `max_pooled` idle buffers: buffers returned to a full pool are dropped, which releases the memory under low load.
+//!
+//! ## Structures
+//!
+//! - `BufferPool`: The pool itself, with hit/miss counters describing how often a checkout was served from the pool.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A bounded pool of byte buffers of a fixed size.
+#[derive(Debug)]
+pub struct BufferPool {
+    /// Idle buffers ready to be checked out.
+    buffers: Mutex<Vec<Vec<u8>>>,
+
+    /// Size in bytes of every buffer handed out by the pool.
+    buffer_size: usize,
+
+    /// Maximum number of idle buffers kept in the pool.
+    max_pooled: usize,
+
+    /// Number of checkouts served with a pooled buffer.
+    hits: AtomicU64,
+
+    /// Number of checkouts that had to allocate a new buffer.
+    misses: AtomicU64,
+}
+
+impl BufferPool {
+    /// Creates an empty pool handing out buffers of `buffer_size` bytes and keeping at most `max_pooled` idle buffers.
+    pub fn new(buffer_size: usize, max_pooled: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            buffer_size,
+            max_pooled,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks a buffer out of the pool, allocating a new one if the pool is empty.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - A buffer of `buffer_size` bytes.
+    pub fn checkout(&self) -> Vec<u8> {
+        let pooled = self.buffers.lock().unwrap().pop();
+
+        match pooled {
+            Some(buffer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                vec![0; self.buffer_size]
+            }
+        }
+    }
+
+    /// Returns a buffer to the pool.
+    ///
+    /// The buffer is dropped instead if the pool is already full or if its size doesn't match the pool's buffer size.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer previously obtained with `checkout`.
+    pub fn checkin(&self, buffer: Vec<u8>) {
+        if buffer.len() != self.buffer_size {
+            return;
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_pooled {
+            buffers.push(buffer);
+        }
+    }
+
+    /// Returns the number of idle buffers currently in the pool.
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Returns the pool statistics.
+    ///
+    /// # Returns
+    ///
+    /// * `(u64, u64)` - The number of checkouts served from the pool (hits) and the number that allocated (misses).
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,137 @@
+//! # Byte Volume Module
+//!
+//! This module prefers the upstream servers that transferred the fewest bytes recently.
+//!
+//! When the responses vary wildly in size, such as file downloads, counting the requests or the connections of every
+//! upstream server says little about its load: a few huge transfers keep an upstream server busier than many tiny
+//! requests. With `--least-bytes`, the bytes of the requests sent to every upstream server and of the responses
+//! relayed from it are counted over a sliding window, and a request is sent to the candidate with the smallest
+//! volume, drawn at random among the ones tied.
+//!
+//! The window of an upstream server is a ring of one second buckets, so recording bytes and summing the window cost
+//! a few additions whatever the traffic. The clock is passed in by the caller, so the window can be driven in tests.
+//!
+//! ## Structures
+//!
+//! - `ByteVolumes`: The bytes transferred with every upstream server over the window.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// The bytes transferred with every upstream server over a sliding window.
+#[derive(Debug)]
+pub struct ByteVolumes {
+    /// The instant the seconds of the buckets are counted from.
+    started_at: Instant,
+
+    /// Number of one second buckets in the window.
+    window_seconds: usize,
+
+    /// The buckets of every upstream server: the second a bucket holds the bytes of, and the bytes.
+    buckets: Mutex<HashMap<String, Vec<(u64, u64)>>>,
+}
+
+impl ByteVolumes {
+    /// Creates the volumes counted over `window`, rounded up to whole seconds.
+    pub fn new(window: Duration) -> ByteVolumes {
+        let window_seconds = window.as_secs() + u64::from(window.subsec_nanos() > 0);
+        ByteVolumes { started_at: Instant::now(), window_seconds: window_seconds.max(1) as usize, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the length of the window.
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds as u64)
+    }
+
+    /// Returns the second of `now`, counted from the creation of the volumes.
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started_at).as_secs()
+    }
+
+    /// Records `bytes` transferred with an upstream server at `now`.
+    pub fn record(&self, upstream_address: &str, bytes: u64, now: Instant) {
+        let second = self.second(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        let ring = buckets.entry(upstream_address.to_string()).or_insert_with(|| vec![(0, 0); self.window_seconds]);
+
+        // the bucket of this second holds the bytes of an older second until it is reused
+        let bucket = &mut ring[second as usize % self.window_seconds];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 += bytes;
+    }
+
+    /// Returns the bytes transferred with an upstream server over the window ending at `now`.
+    pub fn volume(&self, upstream_address: &str, now: Instant) -> u64 {
+        let buckets = self.buckets.lock().unwrap();
+        buckets.get(upstream_address).map_or(0, |ring| self.sum(ring, self.second(now)))
+    }
+
+    /// Sums the buckets of a ring holding the bytes of the seconds within the window ending at `second`.
+    fn sum(&self, ring: &[(u64, u64)], second: u64) -> u64 {
+        let oldest = (second + 1).saturating_sub(self.window_seconds as u64);
+        ring.iter().filter(|(bucket_second, _)| (oldest..=second).contains(bucket_second)).map(|(_, bytes)| bytes).sum()
+    }
+
+    /// Selects the upstream server with the smallest volume over the window ending at `now`, skipping the excluded
+    /// ones. The ties are broken at random, so the upstream servers without any traffic share the requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address_list` - The addresses of the candidate upstream servers.
+    /// * `excluded` - The upstream addresses that must not be selected.
+    /// * `now` - The current instant.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The selected upstream address, or `None` if every candidate is excluded or the list is
+    ///   empty.
+    pub fn select(&self, upstream_address_list: &[String], excluded: &HashSet<String>, now: Instant) -> Option<String> {
+        let second = self.second(now);
+        let buckets = self.buckets.lock().unwrap();
+        let volume = |address: &String| buckets.get(address).map_or(0, |ring| self.sum(ring, second));
+
+        let candidates: Vec<(&String, u64)> = upstream_address_list.iter()
+            .filter(|address| !excluded.contains(*address))
+            .map(|address| (address, volume(address)))
+            .collect();
+        let least = candidates.iter().map(|(_, volume)| *volume).min()?;
+        let tied: Vec<&String> = candidates.into_iter().filter(|(_, volume)| *volume == least).map(|(address, _)| address).collect();
+        Some(tied[rand::thread_rng().gen_range(0..tied.len())].to_string())
+    }
+
+    /// Returns the chance of every candidate upstream server to be selected by `select` at `now`, in the order of the
+    /// list: the candidates with the smallest volume share it evenly.
+    pub fn odds(&self, upstream_address_list: &[String], excluded: &HashSet<String>, now: Instant) -> Vec<(String, f64)> {
+        let second = self.second(now);
+        let buckets = self.buckets.lock().unwrap();
+        let volume = |address: &String| buckets.get(address).map_or(0, |ring| self.sum(ring, second));
+
+        let volumes: Vec<Option<u64>> = upstream_address_list.iter().map(|address| (!excluded.contains(address)).then(|| volume(address))).collect();
+        let least = volumes.iter().flatten().min().copied();
+        let tied = volumes.iter().filter(|volume| volume.is_some() && **volume == least).count();
+        upstream_address_list.iter().zip(volumes)
+            .map(|(address, volume)| (address.clone(), if volume.is_some() && volume == least { 1.0 / tied as f64 } else { 0.0 }))
+            .collect()
+    }
+
+    /// Renders the volume of every upstream server over the window ending at `now` as the `lb_upstream_window_bytes`
+    /// gauge, in the Prometheus text format.
+    pub fn render_prometheus(&self, now: Instant) -> String {
+        let second = self.second(now);
+        let buckets = self.buckets.lock().unwrap();
+        let mut upstreams: Vec<_> = buckets.iter().collect();
+        upstreams.sort_by_key(|(upstream_address, _)| *upstream_address);
+
+        let mut rendered = String::from("# TYPE lb_upstream_window_bytes gauge\n");
+        for (upstream_address, ring) in upstreams {
+            let _ = writeln!(rendered, "lb_upstream_window_bytes{{upstream=\"{}\"}} {}", upstream_address, self.sum(ring, second));
+        }
+        rendered
+    }
+}
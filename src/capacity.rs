@@ -0,0 +1,258 @@
+//! # Capacity Module
+//!
+//! This module caps the number of requests every upstream server handles at the same time.
+//!
+//! An upstream server with a fixed number of workers answers slowly, or not at all, when it is sent more requests
+//! than it has workers. The `UpstreamLimiter` holds a semaphore for every upstream server, with as many permits as
+//! requests it may handle concurrently. Every request takes a slot of the upstream server it is sent to, and gives it
+//! back once its response has been relayed.
+//!
+//! When every candidate upstream server is at capacity, the request is queued: it waits for a slot to be given back
+//! for up to the queue timeout of the limiter, then fails with `Error::QueueTimeout`, answered with 503 Service
+//! Unavailable. A zero queue timeout fails the request right away.
+//!
+//! The upstream servers are picked at random, by their reported load with `with_load_reports`, or by the bytes
+//! transferred with them recently with `with_byte_volumes`.
+//!
+//! ## Structures
+//!
+//! - `UpstreamLimiter`: The concurrency limit of the upstream servers and the slots taken on every one of them.
+//! - `UpstreamSlot`: A request slot of an upstream server, given back when dropped.
+//!
+//! ## Enums
+//!
+//! - `Error`: The reasons a slot can't be taken.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::byte_volume::ByteVolumes;
+use crate::load_report::LoadReports;
+use crate::selection::{select_upstream, selection_odds};
+
+/// The reasons a request slot can't be taken.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Every candidate upstream server is excluded, or there is none.
+    NoUpstream,
+
+    /// Every candidate upstream server stayed at capacity for the queue timeout.
+    QueueTimeout,
+}
+
+/// The concurrency limit of the upstream servers, and the slots taken on every one of them.
+#[derive(Debug)]
+pub struct UpstreamLimiter {
+    /// Maximum number of requests an upstream server handles at the same time, unlimited if `None`.
+    max_per_upstream: Option<usize>,
+
+    /// Time a request waits for a slot when every candidate upstream server is at capacity.
+    queue_timeout: Duration,
+
+    /// The semaphore of every upstream server a slot was taken on.
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+
+    /// Wakes the queued requests when a slot is given back.
+    released: Notify,
+
+    /// The loads reported by the upstream servers, preferring the least loaded ones if set.
+    load_reports: Option<Arc<LoadReports>>,
+
+    /// The bytes transferred with the upstream servers recently, preferring the ones with the fewest if set.
+    byte_volumes: Option<Arc<ByteVolumes>>,
+}
+
+impl UpstreamLimiter {
+    /// Creates a limiter allowing `max_per_upstream` concurrent requests to every upstream server, queuing the
+    /// requests for up to `queue_timeout` when they are all at capacity.
+    pub fn new(max_per_upstream: Option<usize>, queue_timeout: Duration) -> UpstreamLimiter {
+        UpstreamLimiter { max_per_upstream, queue_timeout, semaphores: Mutex::new(HashMap::new()), released: Notify::new(), load_reports: None, byte_volumes: None }
+    }
+
+    /// Picks the upstream servers by the loads they report instead of at random, or at random with `None`.
+    pub fn with_load_reports(mut self, load_reports: Option<Arc<LoadReports>>) -> UpstreamLimiter {
+        self.load_reports = load_reports;
+        self
+    }
+
+    /// Returns the loads reported by the upstream servers, if they are used to pick them.
+    pub fn load_reports(&self) -> Option<&Arc<LoadReports>> {
+        self.load_reports.as_ref()
+    }
+
+    /// Picks the upstream servers with the fewest bytes transferred recently instead of at random, or at random with
+    /// `None`.
+    pub fn with_byte_volumes(mut self, byte_volumes: Option<Arc<ByteVolumes>>) -> UpstreamLimiter {
+        self.byte_volumes = byte_volumes;
+        self
+    }
+
+    /// Returns the bytes transferred with the upstream servers recently, if they are used to pick them.
+    pub fn byte_volumes(&self) -> Option<&Arc<ByteVolumes>> {
+        self.byte_volumes.as_ref()
+    }
+
+    /// Creates a limiter that never limits the concurrent requests.
+    pub fn unlimited() -> UpstreamLimiter {
+        UpstreamLimiter::new(None, Duration::ZERO)
+    }
+
+    /// Takes a slot of an upstream server selected among the candidates, waiting for one to be given back if they
+    /// are all at capacity.
+    ///
+    /// The upstream server is picked with `select_upstream`, or `LoadReports::select` or `ByteVolumes::select` if set,
+    /// among the candidates
+    /// with a free slot, so the excluded ones and the ones at capacity are never selected.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address_list` - The addresses of the candidate upstream servers.
+    /// * `excluded` - The upstream addresses that must not be selected.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(UpstreamSlot)` - The slot taken, telling the address of its upstream server and how long it was waited for.
+    /// * `Err(Error::NoUpstream)` - If every candidate is excluded or the list is empty.
+    /// * `Err(Error::QueueTimeout)` - If every candidate stayed at capacity for the queue timeout.
+    pub async fn acquire(self: &Arc<Self>, upstream_address_list: &[String], excluded: &HashSet<String>) -> Result<UpstreamSlot, Error> {
+        let started_at = tokio::time::Instant::now();
+        let deadline = started_at + self.queue_timeout;
+        let mut waited = false;
+
+        loop {
+            // register for the wake-up before looking for a slot, so a slot given back in between isn't missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let mut unavailable = excluded.clone();
+            let mut at_capacity = false;
+            while let Some(upstream_address) = self.select(upstream_address_list, &unavailable) {
+                if let Some(mut slot) = self.try_acquire(&upstream_address) {
+                    if waited {
+                        slot.queued = started_at.elapsed();
+                    }
+                    return Ok(slot);
+                }
+                unavailable.insert(upstream_address);
+                at_capacity = true;
+            }
+
+            if !at_capacity {
+                return Err(Error::NoUpstream);
+            }
+            log::debug!("Every upstream server is at capacity, queuing the request");
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(Error::QueueTimeout);
+            }
+            waited = true;
+        }
+    }
+
+    /// Selects one of the candidate upstream servers that aren't excluded.
+    fn select(&self, upstream_address_list: &[String], excluded: &HashSet<String>) -> Option<String> {
+        match (&self.load_reports, &self.byte_volumes) {
+            (Some(load_reports), _) => load_reports.select(upstream_address_list, excluded),
+            (None, Some(byte_volumes)) => byte_volumes.select(upstream_address_list, excluded, Instant::now()),
+            (None, None) => select_upstream(upstream_address_list, excluded),
+        }
+    }
+
+    /// Returns the name of the strategy the upstream servers are picked with: `reported_load`, `least_bytes` or
+    /// `random`.
+    pub fn strategy(&self) -> &'static str {
+        match (&self.load_reports, &self.byte_volumes) {
+            (Some(_), _) => "reported_load",
+            (None, Some(_)) => "least_bytes",
+            (None, None) => "random",
+        }
+    }
+
+    /// Returns the chance of every candidate upstream server to be picked by `acquire` right now, in the order of the
+    /// list, without taking a slot. The excluded candidates and the ones at capacity have none, so every chance is
+    /// zero when the request would be queued.
+    pub fn odds(&self, upstream_address_list: &[String], excluded: &HashSet<String>) -> Vec<(String, f64)> {
+        let mut unavailable = excluded.clone();
+        unavailable.extend(upstream_address_list.iter().filter(|address| self.is_at_capacity(address)).cloned());
+        match (&self.load_reports, &self.byte_volumes) {
+            (Some(load_reports), _) => load_reports.odds(upstream_address_list, &unavailable),
+            (None, Some(byte_volumes)) => byte_volumes.odds(upstream_address_list, &unavailable, Instant::now()),
+            (None, None) => selection_odds(upstream_address_list, &unavailable),
+        }
+    }
+
+    /// Returns the maximum number of requests an upstream server handles at the same time, if limited.
+    pub fn max_per_upstream(&self) -> Option<usize> {
+        self.max_per_upstream
+    }
+
+    /// Tells whether every slot of an upstream server is taken.
+    pub fn is_at_capacity(&self, upstream_address: &str) -> bool {
+        self.max_per_upstream.is_some_and(|max_per_upstream| self.in_flight(upstream_address) >= max_per_upstream)
+    }
+
+    /// Takes a slot of an upstream server if it isn't at capacity, without waiting.
+    pub fn try_acquire(self: &Arc<Self>, upstream_address: &str) -> Option<UpstreamSlot> {
+        let permit = match self.max_per_upstream {
+            Some(max_per_upstream) => {
+                let semaphore = self.semaphores.lock().unwrap()
+                    .entry(upstream_address.to_string())
+                    .or_insert_with(|| Arc::new(Semaphore::new(max_per_upstream)))
+                    .clone();
+                Some(semaphore.try_acquire_owned().ok()?)
+            }
+            None => None,
+        };
+
+        Some(UpstreamSlot { limiter: self.clone(), upstream_address: upstream_address.to_string(), permit, queued: Duration::ZERO })
+    }
+
+    /// Returns the number of slots taken on an upstream server, always 0 when unlimited.
+    pub fn in_flight(&self, upstream_address: &str) -> usize {
+        match (self.max_per_upstream, self.semaphores.lock().unwrap().get(upstream_address)) {
+            (Some(max_per_upstream), Some(semaphore)) => max_per_upstream - semaphore.available_permits(),
+            _ => 0,
+        }
+    }
+}
+
+/// A request slot of an upstream server, given back to the limiter when dropped.
+#[derive(Debug)]
+pub struct UpstreamSlot {
+    /// The limiter the slot was taken from.
+    limiter: Arc<UpstreamLimiter>,
+
+    /// The address of the upstream server the slot was taken on.
+    upstream_address: String,
+
+    /// The permit of the semaphore of the upstream server, `None` when the limiter is unlimited.
+    permit: Option<OwnedSemaphorePermit>,
+
+    /// Time the request waited in the queue for the slot.
+    queued: Duration,
+}
+
+impl UpstreamSlot {
+    /// Returns the address of the upstream server the slot was taken on.
+    pub fn upstream_address(&self) -> &str {
+        &self.upstream_address
+    }
+
+    /// Returns the time the request waited in the queue for the slot, zero if a slot was free right away.
+    pub fn queued(&self) -> Duration {
+        self.queued
+    }
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        // give the permit back before waking the queued requests, so they find it
+        if let Some(permit) = self.permit.take() {
+            drop(permit);
+            self.limiter.released.notify_waiters();
+        }
+    }
+}
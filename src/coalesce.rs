@@ -0,0 +1,225 @@
+//! # Coalesce Module
+//!
+//! This module lets identical requests arriving at the same time share a single upstream response (single-flight).
+//!
+//! When many clients ask for the same hot resource at once, every one of them would otherwise hit an upstream
+//! server. With the `Coalescer`, the first request for a method, host, target and accepted representation (`Accept`
+//! and `Accept-Encoding`) leads the flight: it is proxied as usual, and its response is recorded while it is relayed.
+//! The identical requests arriving while it is in flight follow it: they wait for the recorded response and are
+//! answered with a copy of it, without contacting an upstream server.
+//!
+//! Only `GET` and `HEAD` requests without a body are coalesced, and never the requests carrying credentials
+//! (`Authorization` or `Cookie`), whose response may be personal, nor the ranged (`Range`) and conditional (`If-*`)
+//! requests, whose partial or empty response only answers them. When the leader fails, or its response is larger
+//! than `MAX_SHARED_RESPONSE_SIZE`, the followers are proxied on their own.
+//!
+//! ## Structures
+//!
+//! - `RequestKey`: The identity of a request, shared by the requests that can be coalesced with it.
+//! - `SharedResponse`: The response of a leader, as relayed to its client.
+//! - `Coalescer`: The flights in progress, by request identity.
+//! - `Leader`: The request leading a flight, publishing its response to the followers when dropped.
+//! - `Follower`: A request waiting for the response of the leader of its flight.
+//! - `Recorder`: A writer recording a copy of what it writes, to capture the response of a leader.
+//!
+//! ## Enums
+//!
+//! - `Flight`: The role of a request in its flight.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use http::header::{HeaderName, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, COOKIE, HOST, RANGE};
+use http::{Method, Request};
+use tokio::io::AsyncWrite;
+use tokio::sync::watch;
+
+use crate::spool::SpooledBody;
+
+/// Maximum size of a response shared with the followers, the followers of a larger one are proxied on their own.
+pub const MAX_SHARED_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// The identity of a request: its method, host, target, and the representations it accepts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestKey {
+    method: Method,
+    host: Option<Vec<u8>>,
+    target: String,
+    accept: Vec<Vec<u8>>,
+    accept_encoding: Vec<Vec<u8>>,
+}
+
+impl RequestKey {
+    /// Returns the identity of a request, or `None` if the request must not be coalesced.
+    pub fn of(request: &Request<Vec<u8>>) -> Option<RequestKey> {
+        let coalescible = (request.method() == Method::GET || request.method() == Method::HEAD)
+            && request.body().is_empty()
+            && request.extensions().get::<SpooledBody>().is_none()
+            && !request.headers().contains_key(AUTHORIZATION)
+            && !request.headers().contains_key(COOKIE)
+            && !request.headers().contains_key(RANGE)
+            && !request.headers().keys().any(|name| name.as_str().starts_with("if-"));
+
+        coalescible.then(|| RequestKey {
+            method: request.method().clone(),
+            host: request.headers().get(HOST).map(|host| host.as_bytes().to_ascii_lowercase()),
+            target: request.uri().to_string(),
+            accept: header_values(request, &ACCEPT),
+            accept_encoding: header_values(request, &ACCEPT_ENCODING),
+        })
+    }
+}
+
+/// Returns the values of a header of a request, in order.
+fn header_values(request: &Request<Vec<u8>>, name: &HeaderName) -> Vec<Vec<u8>> {
+    request.headers().get_all(name).iter().map(|value| value.as_bytes().to_vec()).collect()
+}
+
+/// The response of a leader, as relayed to its client.
+#[derive(Debug, Clone)]
+pub struct SharedResponse {
+    /// The raw response, head and body.
+    pub bytes: Arc<Vec<u8>>,
+
+    /// Status code of the response.
+    pub status: u16,
+
+    /// The response ended with the upstream connection, the client connection must be closed after it.
+    pub close_delimited: bool,
+
+    /// The address of the upstream server that answered.
+    pub upstream_address: String,
+}
+
+/// The outcome of a flight: `None` while in flight, then the shared response, or `Some(None)` if there is none.
+type Outcome = Option<Option<SharedResponse>>;
+
+/// The flights in progress, by request identity.
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    /// The outcome of every flight in progress.
+    in_flight: Mutex<HashMap<RequestKey, watch::Receiver<Outcome>>>,
+
+    /// Number of requests answered with the response of a leader.
+    coalesced: AtomicU64,
+}
+
+impl Coalescer {
+    /// Joins the flight of an identical request in progress, or starts a new one led by this request.
+    pub fn join(&self, key: RequestKey) -> Flight<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(outcome) = in_flight.get(&key) {
+            return Flight::Follower(Follower { coalescer: self, outcome: outcome.clone() });
+        }
+
+        let (sender, outcome) = watch::channel(None);
+        in_flight.insert(key.clone(), outcome);
+        Flight::Leader(Leader { coalescer: self, key, sender, response: None })
+    }
+
+    /// Returns the number of requests answered with the response of a leader.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+/// The role of a request in its flight.
+#[derive(Debug)]
+pub enum Flight<'a> {
+    /// The request is proxied, and shares its response.
+    Leader(Leader<'a>),
+
+    /// The request waits for the response of the leader.
+    Follower(Follower<'a>),
+}
+
+/// The request leading a flight. Its response is published to the followers when it is dropped, or the lack of one
+/// if it wasn't shared.
+#[derive(Debug)]
+pub struct Leader<'a> {
+    coalescer: &'a Coalescer,
+    key: RequestKey,
+    sender: watch::Sender<Outcome>,
+    response: Option<SharedResponse>,
+}
+
+impl Leader<'_> {
+    /// Shares the response relayed to the client with the followers.
+    pub fn share(&mut self, response: SharedResponse) {
+        self.response = Some(response);
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        // end the flight before publishing its outcome, so the identical requests arriving now lead a new one
+        self.coalescer.in_flight.lock().unwrap().remove(&self.key);
+        self.sender.send_replace(Some(self.response.take()));
+    }
+}
+
+/// A request waiting for the response of the leader of its flight.
+#[derive(Debug)]
+pub struct Follower<'a> {
+    coalescer: &'a Coalescer,
+    outcome: watch::Receiver<Outcome>,
+}
+
+impl Follower<'_> {
+    /// Waits for the leader to be done, and returns its response if it was shared.
+    pub async fn response(mut self) -> Option<SharedResponse> {
+        let response = self.outcome.wait_for(Option::is_some).await.ok()?.clone().flatten();
+        if response.is_some() {
+            self.coalescer.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        response
+    }
+}
+
+/// A writer recording a copy of the bytes written to the inner writer, up to a limit.
+#[derive(Debug)]
+pub struct Recorder<'a, W> {
+    inner: &'a mut W,
+    recorded: Option<Vec<u8>>,
+    limit: usize,
+}
+
+impl<'a, W> Recorder<'a, W> {
+    /// Records the bytes written to `inner`, giving up once more than `limit` bytes were written.
+    pub fn new(inner: &'a mut W, limit: usize) -> Recorder<'a, W> {
+        Recorder { inner, recorded: Some(Vec::new()), limit }
+    }
+
+    /// Returns the bytes written, or `None` if there were more than the limit.
+    pub fn into_recorded(self) -> Option<Vec<u8>> {
+        self.recorded
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Recorder<'_, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = written {
+            if this.recorded.as_ref().is_some_and(|recorded| recorded.len() + written > this.limit) {
+                this.recorded = None;
+            }
+            if let Some(recorded) = this.recorded.as_mut() {
+                recorded.extend_from_slice(&buf[..written]);
+            }
+        }
+        written
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
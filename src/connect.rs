@@ -0,0 +1,336 @@
+//! # Connect Module
+//!
+//! This module opens the connections to the upstream servers, retrying the connection attempts that fail.
+//!
+//! A single connection attempt to a healthy upstream server occasionally fails, for example when a SYN is dropped.
+//! The `Connector` retries the same upstream server a configured number of times, waiting a short delay with some
+//! jitter between two attempts, before the caller moves on to another upstream server. Every attempt of a request,
+//! on every upstream server, counts against the connect budget of the request: once it is spent, the request fails.
+//! The upstream addresses are resolved with the resolver of the connector, a `CachingResolver` by default. With an
+//! upstream proxy, the connections are made to the egress proxy instead, and tunneled to the upstream servers.
+//!
+//! Every failed attempt is classified into a `FailureKind` and counted for its upstream server, so a 502 or a 503 can
+//! be told apart: a refused connection, a timeout, a reset, a DNS failure or a failure of the egress proxy. The
+//! failures met after the connection was made, while relaying a request, are counted with `record_failure`, such as a
+//! new connection closed before a byte of the response was sent, or a response head over the maximum size or too slow
+//! to arrive.
+//!
+//! ## Structures
+//!
+//! - `Connector`: The retry settings, the resolver and the egress proxy of the upstream connections, with counters
+//!   telling the connections made on the first attempt from the ones made after a retry, and the failures of every
+//!   upstream server by kind.
+//!
+//! ## Enums
+//!
+//! - `Error`: The reasons a connection to an upstream server can't be made.
+//! - `FailureKind`: The classes of upstream connection failures.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+use crate::resolver::{CachingResolver, Resolver, SystemResolver};
+use crate::upstream_proxy::UpstreamProxy;
+
+/// The reasons a connection to an upstream server can't be made.
+#[derive(Debug)]
+pub enum Error {
+    /// Every attempt to connect to the upstream server failed, with the error of the last attempt.
+    ConnectFailed(std::io::Error),
+
+    /// The address of the upstream server couldn't be resolved.
+    ResolveFailed(std::io::Error),
+
+    /// The egress proxy couldn't be reached, or failed to open the tunnel to the upstream server.
+    ProxyFailed(std::io::Error),
+
+    /// The connect budget of the request is spent.
+    BudgetExhausted,
+
+    /// No upstream server is left to connect to, with the kind of the last failure if an upstream server was tried.
+    NoUpstream(Option<FailureKind>),
+
+    /// Every upstream server left stayed at capacity for the queue timeout.
+    QueueTimeout,
+}
+
+impl Error {
+    /// Returns the class of the failure, `None` if no upstream server was tried.
+    pub fn kind(&self) -> Option<FailureKind> {
+        match self {
+            Error::ConnectFailed(e) => Some(FailureKind::from_io_kind(e.kind())),
+            Error::ResolveFailed(_) => Some(FailureKind::Dns),
+            Error::ProxyFailed(_) => Some(FailureKind::Proxy),
+            Error::BudgetExhausted => Some(FailureKind::TimedOut),
+            Error::NoUpstream(kind) => *kind,
+            Error::QueueTimeout => None,
+        }
+    }
+}
+
+/// The classes of upstream connection failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FailureKind {
+    /// The upstream server refused the connection, nothing listens on its port.
+    Refused,
+    /// The connection or the response took too long, the upstream server may be blackholed.
+    TimedOut,
+    /// The upstream server reset the connection.
+    Reset,
+    /// The upstream server closed a new connection without sending a byte of the response.
+    Empty,
+    /// The address of the upstream server couldn't be resolved.
+    Dns,
+    /// The egress proxy couldn't be reached, or failed to open the tunnel to the upstream server.
+    Proxy,
+    /// The upstream server sent a response head over the maximum head size.
+    OversizedHead,
+    /// The upstream server sent a response body over the maximum response size.
+    OversizedBody,
+    /// The upstream server didn't complete its response head within the head read timeout.
+    SlowHead,
+    /// Any other failure, such as an unreachable network.
+    Other,
+}
+
+impl FailureKind {
+    /// Classifies an I/O error kind. This is the single place mapping the I/O errors onto the failure classes.
+    pub fn from_io_kind(kind: std::io::ErrorKind) -> FailureKind {
+        match kind {
+            std::io::ErrorKind::ConnectionRefused => FailureKind::Refused,
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => FailureKind::TimedOut,
+            std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::BrokenPipe => FailureKind::Reset,
+            _ => FailureKind::Other,
+        }
+    }
+
+    /// Returns the label of the class, as used in the logs and the metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::Refused => "refused",
+            FailureKind::TimedOut => "timeout",
+            FailureKind::Reset => "reset",
+            FailureKind::Empty => "empty",
+            FailureKind::Dns => "dns",
+            FailureKind::Proxy => "proxy",
+            FailureKind::OversizedHead => "oversized_head",
+            FailureKind::OversizedBody => "oversized_body",
+            FailureKind::SlowHead => "slow_head",
+            FailureKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Opens the connections to the upstream servers, retrying the failed attempts.
+#[derive(Debug)]
+pub struct Connector {
+    /// Number of attempts made after the first one failed, on the same upstream server.
+    retries: u32,
+
+    /// Delay before a retry, increased by a random jitter of up to half of it.
+    retry_delay: Duration,
+
+    /// Time allowed to connect to an upstream server for a request, across every upstream server and attempt.
+    budget: Option<Duration>,
+
+    /// Resolver of the upstream addresses.
+    resolver: Arc<dyn Resolver>,
+
+    /// Egress proxy the connections are tunneled through, if the upstream servers can't be reached directly.
+    upstream_proxy: Option<UpstreamProxy>,
+
+    /// Number of connections made on the first attempt.
+    first_attempts: AtomicU64,
+
+    /// Number of connections made after one or more retries.
+    retried: AtomicU64,
+
+    /// Number of requests sent again on a new connection, after the upstream server closed a reused one.
+    pooled_retries: AtomicU64,
+
+    /// Number of failures of every upstream server, by kind.
+    failures: Mutex<BTreeMap<(String, FailureKind), u64>>,
+}
+
+impl Connector {
+    /// Creates a connector making `retries` more attempts after a failed one, `retry_delay` apart, within `budget`.
+    ///
+    /// The upstream addresses are resolved by the system resolver, whose answers are cached.
+    pub fn new(retries: u32, retry_delay: Duration, budget: Option<Duration>) -> Connector {
+        Connector {
+            retries,
+            retry_delay,
+            budget,
+            resolver: Arc::new(CachingResolver::new(Arc::new(SystemResolver), None)),
+            upstream_proxy: None,
+            first_attempts: AtomicU64::new(0),
+            retried: AtomicU64::new(0),
+            pooled_retries: AtomicU64::new(0),
+            failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Replaces the resolver of the upstream addresses.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Connector {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Tunnels the connections through an egress proxy, or connects to the upstream servers directly with `None`.
+    pub fn with_upstream_proxy(mut self, upstream_proxy: Option<UpstreamProxy>) -> Connector {
+        self.upstream_proxy = upstream_proxy;
+        self
+    }
+
+    /// Renders the retry settings and the egress proxy with its password redacted, as JSON.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "connect_retries": self.retries,
+            "connect_retry_delay_ms": self.retry_delay.as_millis() as u64,
+            "connect_budget_ms": self.budget.map(|budget| budget.as_millis() as u64),
+            "upstream_proxy": self.upstream_proxy.as_ref().map(UpstreamProxy::redacted_url),
+        })
+    }
+
+    /// Returns the instant the connect budget of a request starting now is spent, if the budget is limited.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.budget.map(|budget| Instant::now() + budget)
+    }
+
+    /// Connects to an upstream server, retrying the failed attempts.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address` - The address of the upstream server.
+    /// * `deadline` - The instant the connect budget of the request is spent, see `deadline`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(TcpStream)` - The connection to the upstream server.
+    /// * `Err(Error::ConnectFailed)` - If every attempt failed.
+    /// * `Err(Error::ResolveFailed)` - If the address of the upstream server couldn't be resolved.
+    /// * `Err(Error::ProxyFailed)` - If the egress proxy couldn't open the tunnel to the upstream server.
+    /// * `Err(Error::BudgetExhausted)` - If the budget was spent before a connection could be made.
+    pub async fn connect(&self, upstream_address: &str, deadline: Option<Instant>) -> Result<TcpStream, Error> {
+        let mut attempt = 0;
+
+        loop {
+            let connected = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match timeout(remaining, self.attempt(upstream_address)).await {
+                        Ok(connected) => connected,
+                        Err(_) => {
+                            self.record_failure(upstream_address, FailureKind::TimedOut);
+                            return Err(Error::BudgetExhausted);
+                        }
+                    }
+                }
+                None => self.attempt(upstream_address).await,
+            };
+
+            let e = match connected {
+                Ok(stream) => {
+                    let counter = if attempt == 0 { &self.first_attempts } else { &self.retried };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    return Ok(stream);
+                }
+                Err(e) => e,
+            };
+            self.record_failure(upstream_address, e.kind().unwrap_or(FailureKind::Other));
+
+            // a name that doesn't resolve won't resolve a few milliseconds later
+            let e = match e {
+                Error::ConnectFailed(e) => e,
+                e => return Err(e),
+            };
+            if attempt == self.retries {
+                return Err(Error::ConnectFailed(e));
+            }
+            attempt += 1;
+            log::debug!("Connection attempt {} to {} failed, retrying: {}", attempt, upstream_address, e);
+
+            let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.retry_delay / 2);
+            let delay = self.retry_delay + jitter;
+            if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                return Err(Error::BudgetExhausted);
+            }
+            sleep(delay).await;
+        }
+    }
+
+    /// Makes a single connection attempt, resolving the upstream address first.
+    ///
+    /// With an egress proxy, the address of the proxy is resolved and connected to instead, and the upstream address
+    /// is left for the proxy to resolve.
+    async fn attempt(&self, upstream_address: &str) -> Result<TcpStream, Error> {
+        if let Some(upstream_proxy) = &self.upstream_proxy {
+            let resolved = self.resolver.resolve(&upstream_proxy.address).await.map_err(Error::ProxyFailed)?;
+            let mut stream = TcpStream::connect(&resolved.addresses[..]).await.map_err(Error::ProxyFailed)?;
+            upstream_proxy.tunnel(&mut stream, upstream_address).await.map_err(Error::ProxyFailed)?;
+            return Ok(stream);
+        }
+
+        let resolved = self.resolver.resolve(upstream_address).await.map_err(Error::ResolveFailed)?;
+        TcpStream::connect(&resolved.addresses[..]).await.map_err(Error::ConnectFailed)
+    }
+
+    /// Counts a failure of an upstream server.
+    ///
+    /// The connection failures are counted by `connect`, the failures met while relaying a request over an open
+    /// connection are counted by the caller.
+    pub fn record_failure(&self, upstream_address: &str, kind: FailureKind) {
+        *self.failures.lock().unwrap().entry((upstream_address.to_string(), kind)).or_insert(0) += 1;
+    }
+
+    /// Counts a request sent again on a new connection, after the upstream server closed the reused connection it was
+    /// sent on before answering.
+    pub fn record_pooled_retry(&self) {
+        self.pooled_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of requests sent again after the upstream server closed a reused connection.
+    pub fn pooled_retries(&self) -> u64 {
+        self.pooled_retries.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of failures of an upstream server of the given kind.
+    pub fn failure_count(&self, upstream_address: &str, kind: FailureKind) -> u64 {
+        self.failures.lock().unwrap().get(&(upstream_address.to_string(), kind)).copied().unwrap_or(0)
+    }
+
+    /// Renders the failure counters in the Prometheus text format, as the `lb_upstream_errors_total` counter, along
+    /// with the `lb_pooled_connection_retries_total` counter.
+    pub fn render_failures(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_upstream_errors_total counter\n");
+        for ((upstream_address, kind), count) in self.failures.lock().unwrap().iter() {
+            rendered.push_str(&format!("lb_upstream_errors_total{{upstream=\"{}\",kind=\"{}\"}} {}\n", upstream_address, kind, count));
+        }
+        rendered.push_str("# TYPE lb_pooled_connection_retries_total counter\n");
+        rendered.push_str(&format!("lb_pooled_connection_retries_total {}\n", self.pooled_retries()));
+        rendered
+    }
+
+    /// Returns the connection statistics.
+    ///
+    /// # Returns
+    ///
+    /// * `(u64, u64)` - The number of connections made on the first attempt, and the number made after a retry.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.first_attempts.load(Ordering::Relaxed), self.retried.load(Ordering::Relaxed))
+    }
+}
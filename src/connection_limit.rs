@@ -0,0 +1,94 @@
+//! # Connection Limit Module
+//!
+//! This module caps the number of connections a single client IP address can hold open at the same time.
+//!
+//! Holding many connections open while sending requests slowly (slowloris) exhausts the resources of the proxy
+//! server without any request rate to limit. The `ConnectionLimiter` counts the open connections of every client IP
+//! address in a shared map, and refuses a connection that would exceed the limit. Every accepted connection holds a
+//! `ConnectionGuard`, which gives its slot back when the connection ends.
+//!
+//! ## Structures
+//!
+//! - `ConnectionLimiter`: The open connections of every client IP address, and the limit they are held to.
+//! - `ConnectionGuard`: The slot of an open connection, released when dropped.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Counts the open connections of every client IP address and caps them.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    /// Maximum number of connections a client IP address can hold open.
+    max_per_ip: usize,
+
+    /// Number of open connections of every client IP address holding at least one.
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    /// Creates a limiter allowing `max_per_ip` open connections to every client IP address.
+    pub fn new(max_per_ip: usize) -> ConnectionLimiter {
+        ConnectionLimiter { max_per_ip, open: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the maximum number of connections a client IP address can hold open.
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    /// Takes a connection slot of a client IP address.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the client opening a connection.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(ConnectionGuard)` - The slot of the connection, to keep until the connection is closed.
+    /// * `None` - If the client IP address already holds `max_per_ip` connections, the connection must be closed.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+
+        *count += 1;
+        Some(ConnectionGuard { limiter: self.clone(), ip })
+    }
+
+    /// Returns the number of connections a client IP address holds open.
+    pub fn open_connections(&self, ip: IpAddr) -> usize {
+        self.open.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of client IP addresses holding at least one open connection.
+    pub fn clients(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+}
+
+/// The slot of an open connection of a client IP address, given back to the limiter when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    /// The limiter the slot was taken from.
+    limiter: Arc<ConnectionLimiter>,
+
+    /// The IP address of the client holding the connection.
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+
+        // forget the client IP addresses without connections, so the map doesn't grow with every client ever seen
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! # Context Module
+//!
+//! This module groups the state the client connections of a pool share for as long as the proxy server runs: the
+//! settings applied to the requests and responses, the connector opening the upstream connections, the concurrency
+//! limit picking the upstream servers, and the state of the optional features spanning several requests.
+//!
+//! The `Connector` only connects to the upstream servers and retries the failed attempts. The features keeping state
+//! across requests (coalescing, ejection, load shedding, fault injection, request rates, idle and pre-warmed
+//! connections) live next to it in the `ProxyContext`, which is created once per pool and passed to every connection.
+//! A feature that isn't enabled is `None`. The histograms of the durations of the phases of the relayed responses are
+//! always kept.
+//!
+//! ## Structures
+//!
+//! - `ProxyContext`: The settings, connector, concurrency limit and feature state shared by the client connections of
+//!   a pool.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde_json::Value;
+
+use crate::capacity::UpstreamLimiter;
+use crate::coalesce::Coalescer;
+use crate::connect::Connector;
+use crate::dashboard::RequestRates;
+use crate::ejection::Ejector;
+use crate::fault::FaultInjector;
+use crate::idle_connections::IdleConnections;
+use crate::load_shedding::LoadShedder;
+use crate::prewarm::PrewarmPool;
+use crate::request::RequestConfig;
+use crate::response::ResponseConfig;
+use crate::spool::BodySpool;
+use crate::timing::PhaseDurations;
+
+/// The settings, connector, concurrency limit and feature state shared by the client connections of a pool.
+#[derive(Debug)]
+pub struct ProxyContext {
+    /// Settings applied to every client request before it is forwarded.
+    pub request_config: RequestConfig,
+
+    /// Settings applied to every upstream response before it is relayed.
+    pub response_config: ResponseConfig,
+
+    /// Connector opening the connections to the upstream servers.
+    pub connector: Connector,
+
+    /// Concurrency limit of the upstream servers, taken a slot of by every request, and their selection strategy.
+    pub limiter: Arc<UpstreamLimiter>,
+
+    /// Flights of identical requests sharing a single upstream response, if the requests are coalesced.
+    pub coalescer: Option<Coalescer>,
+
+    /// Passive health state of the upstream servers, if they are ejected on server errors.
+    pub ejector: Option<Ejector>,
+
+    /// Controller shedding a share of the requests while the proxy server is overloaded, if enabled.
+    pub load_shedder: Option<LoadShedder>,
+
+    /// Faults injected into the requests, if fault injection is enabled.
+    pub fault_injector: Option<FaultInjector>,
+
+    /// Requests and server errors of every upstream server over the recent buckets, if the status page is served.
+    pub request_rates: Option<RequestRates>,
+
+    /// Idle keep-alive connections of every upstream server, if their number is capped.
+    pub idle_connections: Option<Arc<IdleConnections>>,
+
+    /// Connections opened to the healthy upstream servers ahead of the first requests, if they are pre-warmed.
+    pub prewarm: Option<Arc<PrewarmPool>>,
+
+    /// Histograms of the durations of every phase of the relayed responses.
+    pub phase_durations: PhaseDurations,
+}
+
+impl ProxyContext {
+    /// Creates a context with the given settings and connector, the concurrent requests to the upstream servers
+    /// unlimited and every optional feature disabled.
+    pub fn new(request_config: RequestConfig, response_config: ResponseConfig, connector: Connector) -> ProxyContext {
+        ProxyContext {
+            request_config,
+            response_config,
+            connector,
+            limiter: Arc::new(UpstreamLimiter::unlimited()),
+            coalescer: None,
+            ejector: None,
+            load_shedder: None,
+            fault_injector: None,
+            request_rates: None,
+            idle_connections: None,
+            prewarm: None,
+            phase_durations: PhaseDurations::default(),
+        }
+    }
+
+    /// Renders the settings of the connector and the optional features enabled, as JSON.
+    pub fn to_json(&self) -> Value {
+        let mut rendered = self.connector.to_json();
+        rendered["coalesce"] = self.coalescer.is_some().into();
+        rendered["eject_on_5xx"] = self.ejector.is_some().into();
+        rendered["load_shedding"] = self.load_shedder.is_some().into();
+        rendered["fault_injection"] = self.fault_injector.is_some().into();
+        rendered["faults"] = self.fault_injector.as_ref().and_then(FaultInjector::spec).map(|spec| spec.to_json()).into();
+        rendered["max_idle_per_upstream"] = self.idle_connections.as_ref().map(|idle_connections| idle_connections.max_per_upstream()).into();
+        rendered["prewarm"] = self.prewarm.as_ref().map(|prewarm| prewarm.per_upstream()).into();
+        rendered
+    }
+
+    /// Renders the upstream failures, the request phase histograms and the metrics of the optional features enabled,
+    /// in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        [
+            self.connector.render_failures(),
+            self.phase_durations.render_prometheus(),
+            self.ejector.as_ref().map(Ejector::render_prometheus).unwrap_or_default(),
+            self.idle_connections.as_ref().map(|idle_connections| idle_connections.render_prometheus()).unwrap_or_default(),
+            self.prewarm.as_ref().map(|prewarm| prewarm.render_prometheus()).unwrap_or_default(),
+            self.limiter.byte_volumes().map(|byte_volumes| byte_volumes.render_prometheus(Instant::now())).unwrap_or_default(),
+            self.load_shedder.as_ref().map(LoadShedder::render_prometheus).unwrap_or_default(),
+            self.fault_injector.as_ref().map(FaultInjector::render_prometheus).unwrap_or_default(),
+            self.request_config.body_spool.as_deref().map(BodySpool::render_prometheus).unwrap_or_default(),
+        ].concat()
+    }
+}
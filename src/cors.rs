@@ -0,0 +1,129 @@
+//! # CORS Module
+//!
+//! This module answers the CORS preflight requests in place of the upstream servers, and tells which origin the
+//! responses to the other cross-origin requests allow.
+//!
+//! A browser sends an `OPTIONS` request with `Origin` and `Access-Control-Request-Method` headers before a
+//! cross-origin request it isn't allowed to send right away. With `--cors-origin`, the proxy server answers these
+//! preflight requests itself with 204 No Content, so the upstream servers don't have to implement CORS:
+//!
+//! - `--cors-origin`: An origin allowed to send requests, such as `https://app.example.com`, or `*` for any origin.
+//! - `--cors-methods`: The methods allowed, `GET,HEAD,POST` by default.
+//! - `--cors-headers`: The request headers allowed, besides the CORS-safelisted ones.
+//!
+//! A preflight from an allowed origin is answered with `Access-Control-Allow-Origin`, `Access-Control-Allow-Methods`
+//! and `Access-Control-Allow-Headers`. A preflight from another origin is answered without them, and the browser
+//! doesn't send the request. The other requests from an allowed origin are forwarded, and their response gets an
+//! `Access-Control-Allow-Origin` header replacing the one of the upstream server, if any.
+//!
+//! ## Structures
+//!
+//! - `CorsOrigin`: An origin allowed to send cross-origin requests.
+//! - `CorsPolicy`: The origins, methods and headers allowed.
+//!
+//! ## Functions
+//!
+//! ### `is_preflight`
+//!
+//! This function tells whether a request is a CORS preflight request.
+
+use std::str::FromStr;
+
+use http::header::{HeaderName, HeaderValue, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN};
+use http::{Method, Request, StatusCode};
+
+use crate::emit::ProxyResponse;
+
+/// Name of the upstream server reported in the access log for the preflight requests answered by the proxy server.
+pub const PREFLIGHT_UPSTREAM: &str = "preflight";
+
+/// An origin allowed to send cross-origin requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsOrigin {
+    /// Any origin, `*`.
+    Any,
+    /// A single origin, a scheme, a host and an optional port such as `https://app.example.com:8443`.
+    Exact(String),
+}
+
+impl CorsOrigin {
+    /// Returns the origin as given, `*` for any origin.
+    pub fn as_str(&self) -> &str {
+        match self {
+            CorsOrigin::Any => "*",
+            CorsOrigin::Exact(origin) => origin,
+        }
+    }
+}
+
+impl FromStr for CorsOrigin {
+    type Err = String;
+
+    /// Parses `*` or an origin such as `https://app.example.com`.
+    fn from_str(origin: &str) -> Result<CorsOrigin, String> {
+        if origin == "*" {
+            return Ok(CorsOrigin::Any);
+        }
+        let (scheme, host) = origin.split_once("://").ok_or(format!("expected * or an origin such as https://app.example.com, got {:?}", origin))?;
+        let valid_scheme = !scheme.is_empty() && scheme.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"+-.".contains(&byte));
+        let valid_host = !host.is_empty() && host.bytes().all(|byte| byte.is_ascii_graphic() && !b"/?#@".contains(&byte));
+        if !valid_scheme || !valid_host {
+            return Err(format!("expected an origin without a path, such as https://app.example.com, got {:?}", origin));
+        }
+        Ok(CorsOrigin::Exact(origin.to_ascii_lowercase()))
+    }
+}
+
+/// The origins, methods and headers allowed to the cross-origin requests.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// The origins allowed.
+    pub origins: Vec<CorsOrigin>,
+
+    /// The methods allowed, listed in the preflight responses.
+    pub methods: Vec<Method>,
+
+    /// The request headers allowed, listed in the preflight responses.
+    pub headers: Vec<HeaderName>,
+}
+
+impl CorsPolicy {
+    /// Returns the `Access-Control-Allow-Origin` value of the response to `request`, `None` if the request isn't a
+    /// cross-origin request or comes from an origin that isn't allowed.
+    ///
+    /// The origin of the request is echoed back, unless any origin is allowed.
+    pub fn allow_origin(&self, request: &Request<Vec<u8>>) -> Option<HeaderValue> {
+        let origin = request.headers().get(ORIGIN)?;
+        self.origins.iter().find_map(|allowed| match allowed {
+            CorsOrigin::Any => Some(HeaderValue::from_static("*")),
+            CorsOrigin::Exact(allowed) if allowed.as_bytes().eq_ignore_ascii_case(origin.as_bytes()) => Some(origin.clone()),
+            CorsOrigin::Exact(_) => None,
+        })
+    }
+
+    /// Returns the status and the raw response answering the preflight `request`.
+    pub fn preflight_response(&self, request: &Request<Vec<u8>>) -> (StatusCode, Vec<u8>) {
+        let mut response = ProxyResponse::new(StatusCode::NO_CONTENT);
+        if let Some(allow_origin) = self.allow_origin(request) {
+            let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+            response = response.header("Access-Control-Allow-Origin", allow_origin).header("Access-Control-Allow-Methods", methods.join(", "));
+            if !self.headers.is_empty() {
+                let headers: Vec<&str> = self.headers.iter().map(HeaderName::as_str).collect();
+                response = response.header("Access-Control-Allow-Headers", headers.join(", "));
+            }
+        }
+        // the answer depends on the origin, unless any origin is allowed
+        if !self.origins.contains(&CorsOrigin::Any) {
+            response = response.header("Vary", "Origin");
+        }
+        (response.status(), response.emit(request.method()))
+    }
+}
+
+/// Tells whether `request` is a CORS preflight request: an `OPTIONS` request with `Origin` and
+/// `Access-Control-Request-Method` headers.
+pub fn is_preflight(request: &Request<Vec<u8>>) -> bool {
+    request.method() == Method::OPTIONS
+        && request.headers().contains_key(ORIGIN)
+        && request.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
@@ -0,0 +1,254 @@
+//! # Dashboard Module
+//!
+//! This module renders the status page served on `GET /dashboard` by the metrics listener, for the humans following
+//! an incident from a browser, a phone included, rather than from the Prometheus metrics.
+//!
+//! The page is a single self-contained HTML document: the styles are inline and the request rates are drawn as inline
+//! SVG, so it loads nothing else and works offline. It refreshes itself every `DASHBOARD_REFRESH` with a meta refresh,
+//! without any script. Every upstream server of every pool is a row of a table with:
+//!
+//! - Its state: `healthy` while it passes its health checks, `ejected` while it is ejected on server errors, and
+//!   `unhealthy` otherwise.
+//! - Its requests in flight.
+//! - Its share of server errors over the recent requests, the 5xx responses and the requests left unanswered.
+//! - A sparkline of its request rate over the last `RATE_BUCKETS` buckets of `RATE_BUCKET`.
+//!
+//! The requests and errors of every upstream server are counted by `RequestRates`, in a ring of buckets like the
+//! byte volumes of `--least-bytes`. The clock is passed in by the caller, so the buckets can be driven in tests.
+//!
+//! ## Structures
+//!
+//! - `RequestRates`: The requests and server errors of every upstream server over the recent buckets.
+//! - `UpstreamState`: The state of an upstream server shown on the page.
+//! - `UpstreamStatus`: The row of an upstream server.
+//! - `PoolStatus`: The upstream servers of a pool.
+//!
+//! ## Functions
+//!
+//! ### `render_dashboard`
+//!
+//! This function renders the status page of the pools.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time counted by every bucket of the request rates.
+pub const RATE_BUCKET: Duration = Duration::from_secs(10);
+
+/// Number of buckets of the request rates, the recent requests are those of the last 5 minutes.
+pub const RATE_BUCKETS: usize = 30;
+
+/// Time between two refreshes of the status page by the browser.
+pub const DASHBOARD_REFRESH: Duration = Duration::from_secs(5);
+
+/// The requests and server errors of every upstream server over the last `RATE_BUCKETS` buckets.
+#[derive(Debug)]
+pub struct RequestRates {
+    /// The instant the buckets are counted from.
+    started_at: Instant,
+
+    /// The buckets of every upstream server.
+    buckets: Mutex<HashMap<String, Vec<RateBucket>>>,
+}
+
+/// The requests and server errors of an upstream server counted in a bucket.
+#[derive(Debug, Clone, Copy, Default)]
+struct RateBucket {
+    /// The index of the bucket, counted from the creation of the rates.
+    index: u64,
+
+    /// Number of requests.
+    requests: u64,
+
+    /// Number of server errors.
+    errors: u64,
+}
+
+impl Default for RequestRates {
+    fn default() -> RequestRates {
+        RequestRates { started_at: Instant::now(), buckets: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl RequestRates {
+    /// Returns the index of the bucket of `now`, counted from the creation of the rates.
+    fn bucket(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.started_at).as_secs_f64() / RATE_BUCKET.as_secs_f64()) as u64
+    }
+
+    /// Records a request answered by an upstream server with `status` at `now`, a server error from 500 on.
+    pub fn record(&self, upstream_address: &str, status: u16, now: Instant) {
+        let index = self.bucket(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        let ring = buckets.entry(upstream_address.to_string()).or_insert_with(|| vec![RateBucket::default(); RATE_BUCKETS]);
+
+        // the bucket holds the requests of an older period until it is reused
+        let bucket = &mut ring[index as usize % RATE_BUCKETS];
+        if bucket.index != index {
+            *bucket = RateBucket { index, ..RateBucket::default() };
+        }
+        bucket.requests += 1;
+        bucket.errors += u64::from(status >= 500);
+    }
+
+    /// Returns the requests and server errors of an upstream server in every bucket up to the one of `now`, oldest
+    /// first.
+    pub fn recent(&self, upstream_address: &str, now: Instant) -> Vec<(u64, u64)> {
+        let index = self.bucket(now);
+        let buckets = self.buckets.lock().unwrap();
+        let ring = buckets.get(upstream_address);
+        (0..RATE_BUCKETS as u64).rev()
+            .map(|age| match index.checked_sub(age) {
+                Some(bucket_index) => ring.map(|ring| ring[bucket_index as usize % RATE_BUCKETS])
+                    .filter(|bucket| bucket.index == bucket_index)
+                    .map_or((0, 0), |bucket| (bucket.requests, bucket.errors)),
+                None => (0, 0),
+            })
+            .collect()
+    }
+}
+
+/// The state of an upstream server shown on the status page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamState {
+    /// The upstream server passes its health checks and gets requests.
+    Healthy,
+    /// The upstream server fails its health checks.
+    Unhealthy,
+    /// The upstream server passes its health checks, but is ejected on server errors for a while.
+    Ejected,
+}
+
+impl UpstreamState {
+    /// Returns the name of the state, shown on its badge.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamState::Healthy => "healthy",
+            UpstreamState::Unhealthy => "unhealthy",
+            UpstreamState::Ejected => "ejected",
+        }
+    }
+
+    /// Returns the color of the badge of the state.
+    fn color(&self) -> &'static str {
+        match self {
+            UpstreamState::Healthy => "#1a7f37",
+            UpstreamState::Unhealthy => "#cf222e",
+            UpstreamState::Ejected => "#9a6700",
+        }
+    }
+}
+
+/// The row of an upstream server on the status page.
+#[derive(Debug, Clone)]
+pub struct UpstreamStatus {
+    /// The address of the upstream server.
+    pub address: String,
+
+    /// Its state.
+    pub state: UpstreamState,
+
+    /// Its requests in flight.
+    pub in_flight: usize,
+
+    /// Its requests and server errors in every bucket of the request rates, oldest first.
+    pub recent: Vec<(u64, u64)>,
+}
+
+/// The upstream servers of a pool on the status page.
+#[derive(Debug, Clone)]
+pub struct PoolStatus {
+    /// The name of the pool.
+    pub name: String,
+
+    /// Its upstream servers.
+    pub upstreams: Vec<UpstreamStatus>,
+}
+
+/// Renders the status page of the pools, a self-contained HTML document refreshing itself.
+///
+/// # Arguments
+///
+/// * `pools` - The pools, the name of a single pool isn't shown.
+pub fn render_dashboard(pools: &[PoolStatus]) -> String {
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <meta http-equiv=\"refresh\" content=\"{}\">\n<title>Load balancer status</title>\n\
+         <style>\nbody {{ font-family: system-ui, sans-serif; margin: 1em; color: #1f2328; }}\n\
+         table {{ border-collapse: collapse; width: 100%; margin-bottom: 1.5em; }}\n\
+         th, td {{ text-align: left; padding: 0.4em 0.6em; border-bottom: 1px solid #d0d7de; }}\n\
+         td.number {{ text-align: right; font-variant-numeric: tabular-nums; }}\n\
+         .badge {{ color: #fff; border-radius: 1em; padding: 0.1em 0.6em; font-size: 0.9em; }}\n\
+         </style>\n</head>\n<body>\n<h1>Upstream servers</h1>\n",
+        DASHBOARD_REFRESH.as_secs(),
+    );
+
+    for pool in pools {
+        if pools.len() > 1 {
+            let _ = writeln!(page, "<h2>{}</h2>", escape_html(&pool.name));
+        }
+        if pool.upstreams.is_empty() {
+            page.push_str("<p>No upstream server.</p>\n");
+            continue;
+        }
+        page.push_str("<table>\n<tr><th>Upstream</th><th>State</th><th>In flight</th><th>Errors</th><th>Requests</th></tr>\n");
+        for upstream in &pool.upstreams {
+            let (requests, errors) = upstream.recent.iter().fold((0, 0), |(requests, errors), bucket| (requests + bucket.0, errors + bucket.1));
+            let error_rate = match requests {
+                0 => "-".to_string(),
+                _ => format!("{:.1}%", errors as f64 * 100.0 / requests as f64),
+            };
+            let _ = writeln!(
+                page,
+                "<tr><td>{}</td><td><span class=\"badge\" style=\"background: {}\">{}</span></td>\
+                 <td class=\"number\">{}</td><td class=\"number\">{}</td><td>{}</td></tr>",
+                escape_html(&upstream.address),
+                upstream.state.color(),
+                upstream.state.as_str(),
+                upstream.in_flight,
+                error_rate,
+                sparkline(&upstream.recent.iter().map(|(requests, _)| *requests).collect::<Vec<_>>()),
+            );
+        }
+        page.push_str("</table>\n");
+    }
+
+    let _ = write!(page, "<p>Rates over the last {} seconds, refreshed every {} seconds.</p>\n</body>\n</html>\n", RATE_BUCKET.as_secs() * RATE_BUCKETS as u64, DASHBOARD_REFRESH.as_secs());
+    page
+}
+
+/// Draws `values` as an inline SVG line, scaled to the largest one.
+fn sparkline(values: &[u64]) -> String {
+    const WIDTH: usize = 120;
+    const HEIGHT: usize = 24;
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    let step = WIDTH as f64 / values.len().saturating_sub(1).max(1) as f64;
+    let points: Vec<String> = values.iter().enumerate()
+        .map(|(index, value)| format!("{:.1},{:.1}", index as f64 * step, HEIGHT as f64 - *value as f64 * HEIGHT as f64 / max as f64))
+        .collect();
+    format!(
+        "<svg width=\"{0}\" height=\"{1}\" viewBox=\"0 -1 {0} {2}\"><polyline fill=\"none\" stroke=\"#0969da\" stroke-width=\"1.5\" points=\"{3}\"/></svg>",
+        WIDTH, HEIGHT, HEIGHT + 2, points.join(" "),
+    )
+}
+
+/// Escapes the characters of `text` with a meaning in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
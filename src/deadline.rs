@@ -0,0 +1,143 @@
+//! # Deadline Module
+//!
+//! This module reads the deadline budget a client gives a request, so the proxy server stops working on requests
+//! nobody waits for anymore.
+//!
+//! With `--deadline-header X-Request-Deadline`, the header of a request is parsed as one of:
+//!
+//! - `250ms`, `1.5s`: A relative budget, counted from the moment the proxy server started reading the request.
+//! - `100m`, `2S`: A relative budget in the `grpc-timeout` format, an integer followed by `H`, `M`, `S`, `m`
+//!   (milliseconds), `u` or `n`.
+//! - `1767225600000`: An absolute deadline, in milliseconds since the Unix epoch.
+//!
+//! A request whose budget is spent on arrival is answered with 504 Gateway Timeout without contacting an upstream
+//! server. Otherwise the budget caps the time allowed to connect to an upstream server and to receive the first byte
+//! of its response, and the header of the forwarded request is rewritten with the budget left, in the same format,
+//! so the upstream server knows how long it has. Malformed values are ignored.
+//!
+//! ## Structures
+//!
+//! - `Deadline`: The instant a request is no longer useful, and the format its header was given in.
+//!
+//! ## Functions
+//!
+//! ### `read_deadline`
+//!
+//! This function parses the deadline of a request from its header, if it has a valid one.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http::header::{HeaderName, HeaderValue};
+use http::Request;
+
+/// The formats a deadline can be given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// A relative budget in milliseconds or seconds, such as `250ms`.
+    Relative,
+    /// A relative budget in the `grpc-timeout` format, such as `100m`.
+    Grpc,
+    /// An absolute deadline in milliseconds since the Unix epoch.
+    Absolute,
+}
+
+/// The instant a request is no longer useful, and the format its header was given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    /// The instant the budget of the request is spent.
+    pub expires_at: Instant,
+
+    /// The format of the header, kept when it is rewritten.
+    format: Format,
+
+    /// The absolute deadline, in milliseconds since the Unix epoch, forwarded unchanged.
+    epoch_millis: u64,
+}
+
+impl Deadline {
+    /// Parses a deadline header value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value of the header.
+    /// * `received_at` - The instant the proxy server started reading the request, the start of a relative budget.
+    /// * `now` - The current wall clock time, to place an absolute deadline.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Deadline>` - The deadline, or `None` if the value is malformed.
+    pub fn parse(value: &str, received_at: Instant, now: SystemTime) -> Option<Deadline> {
+        let value = value.trim();
+        if !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) {
+            let epoch_millis: u64 = value.parse().ok()?;
+            let deadline = UNIX_EPOCH.checked_add(Duration::from_millis(epoch_millis))?;
+            let remaining = deadline.duration_since(now).unwrap_or_default();
+            return Some(Deadline { expires_at: Instant::now() + remaining, format: Format::Absolute, epoch_millis });
+        }
+
+        let (budget, format) = parse_relative(value)?;
+        Some(Deadline { expires_at: received_at.checked_add(budget)?, format, epoch_millis: 0 })
+    }
+
+    /// Returns the budget left at `now`, zero once the deadline has passed.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.expires_at.saturating_duration_since(now)
+    }
+
+    /// Returns the header value telling the upstream server the budget left at `now`, in the format of the client.
+    pub fn header_value(&self, now: Instant) -> HeaderValue {
+        let remaining_millis = self.remaining(now).as_millis();
+        match self.format {
+            Format::Relative => HeaderValue::from_str(&format!("{}ms", remaining_millis)).unwrap(),
+            // grpc-timeout allows at most 8 digits
+            Format::Grpc => HeaderValue::from_str(&format!("{}m", remaining_millis.min(99_999_999))).unwrap(),
+            Format::Absolute => HeaderValue::from(self.epoch_millis),
+        }
+    }
+}
+
+/// Parses a relative budget, `250ms`, `1.5s` or in the `grpc-timeout` format.
+fn parse_relative(value: &str) -> Option<(Duration, Format)> {
+    if let Some(millis) = value.strip_suffix("ms") {
+        return Some((Duration::try_from_secs_f64(millis.parse::<f64>().ok()? / 1000.0).ok()?, Format::Relative));
+    }
+    if let Some(seconds) = value.strip_suffix('s') {
+        return Some((Duration::try_from_secs_f64(seconds.parse().ok()?).ok()?, Format::Relative));
+    }
+
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    if amount.is_empty() || amount.len() > 8 || !amount.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    let budget = match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some((budget, Format::Grpc))
+}
+
+/// Parses the deadline of a request from its header.
+///
+/// # Arguments
+///
+/// * `request` - The client request.
+/// * `header` - The name of the deadline header.
+/// * `received_at` - The instant the proxy server started reading the request.
+///
+/// # Returns
+///
+/// * `Option<Deadline>` - The deadline, or `None` if the request has no header or a malformed one.
+pub fn read_deadline(request: &Request<Vec<u8>>, header: &HeaderName, received_at: Instant) -> Option<Deadline> {
+    let value = request.headers().get(header)?;
+    let deadline = value.to_str().ok().and_then(|value| Deadline::parse(value, received_at, SystemTime::now()));
+    if deadline.is_none() {
+        log::debug!("Ignoring the malformed {} header {:?}", header, value);
+    }
+    deadline
+}
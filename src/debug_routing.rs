@@ -0,0 +1,133 @@
+//! # Debug Routing Module
+//!
+//! This module lets a trusted client force a request through a specific upstream server, for debugging.
+//!
+//! With `--debug-routing-header X-LB-Upstream`, a request carrying `X-LB-Upstream: 10.0.0.7:8080` skips the routing
+//! and the selection of an upstream server, and is sent to `10.0.0.7:8080`. The upstream server must be one of the
+//! configured upstream servers, of any pool or tier, and healthy: an unhealthy upstream server is only used with a
+//! `!` suffix, as `10.0.0.7:8080!`.
+//!
+//! The header is only honored from the `--debug-routing-from` networks, and always stripped from the requests before
+//! they are forwarded, so the upstream servers never see it and untrusted clients can't steer their requests.
+//!
+//! ## Structures
+//!
+//! - `DebugRouting`: The header carrying the overrides, and the networks it is honored from.
+//! - `UpstreamOverride`: The upstream server a request is forced through, parsed from `HOST:PORT` or `HOST:PORT!`.
+//!
+//! ## Enums
+//!
+//! - `Error`: The reasons an override is refused.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use http::header::HeaderName;
+use http::Request;
+use ipnet::IpNet;
+
+/// The header carrying the upstream overrides, and the networks it is honored from.
+#[derive(Debug, Clone)]
+pub struct DebugRouting {
+    /// Name of the header naming the upstream server of a request.
+    pub header: HeaderName,
+
+    /// Networks of the clients allowed to override the upstream server.
+    pub trusted_from: Vec<IpNet>,
+}
+
+impl DebugRouting {
+    /// Strips the override header from a request, and returns the override it carries if the client is trusted.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request to forward, without the header once returned.
+    /// * `client_ip` - The IP address of the connection the request came from.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Result<UpstreamOverride, String>>` - The override of a trusted client, or why its header is invalid.
+    ///   `None` if the request has no override header or the client isn't trusted.
+    pub fn take_override(&self, request: &mut Request<Vec<u8>>, client_ip: IpAddr) -> Option<Result<UpstreamOverride, String>> {
+        let value = request.headers_mut().remove(&self.header)?;
+        if !self.trusted_from.iter().any(|network| network.contains(&client_ip)) {
+            log::warn!("Ignoring the {} header of untrusted client {}", self.header, client_ip);
+            return None;
+        }
+
+        Some(value.to_str().map_err(|_| format!("invalid {} header", self.header)).and_then(str::parse))
+    }
+}
+
+/// The upstream server a request is forced through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamOverride {
+    /// Address of the upstream server, as configured.
+    pub address: String,
+
+    /// Use the upstream server even if it is unhealthy.
+    pub force: bool,
+}
+
+impl UpstreamOverride {
+    /// Checks the upstream server can be used, and returns its address.
+    ///
+    /// # Arguments
+    ///
+    /// * `configured` - The configured upstream servers, of every pool and tier.
+    /// * `healthy` - The upstream servers passing their health checks.
+    pub fn resolve(&self, configured: &[String], healthy: &[String]) -> Result<String, Error> {
+        if !configured.contains(&self.address) {
+            return Err(Error::UnknownUpstream { address: self.address.clone(), configured: configured.to_vec() });
+        }
+        if !self.force && !healthy.contains(&self.address) {
+            return Err(Error::Unhealthy { address: self.address.clone() });
+        }
+        Ok(self.address.clone())
+    }
+}
+
+impl FromStr for UpstreamOverride {
+    type Err = String;
+
+    /// Parses `HOST:PORT`, or `HOST:PORT!` to use the upstream server even if it is unhealthy.
+    fn from_str(value: &str) -> Result<UpstreamOverride, String> {
+        let value = value.trim();
+        let (address, force) = match value.strip_suffix('!') {
+            Some(address) => (address.trim_end(), true),
+            None => (value, false),
+        };
+        if address.is_empty() {
+            return Err("expected HOST:PORT or HOST:PORT!".to_string());
+        }
+
+        Ok(UpstreamOverride { address: address.to_string(), force })
+    }
+}
+
+impl fmt::Display for UpstreamOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.address, if self.force { "!" } else { "" })
+    }
+}
+
+/// The reasons an override is refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The address isn't one of the configured upstream servers.
+    UnknownUpstream { address: String, configured: Vec<String> },
+    /// The upstream server is unhealthy, and the override isn't forced.
+    Unhealthy { address: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownUpstream { address, configured } => {
+                write!(f, "{} is not a configured upstream server, expected one of: {}", address, configured.join(", "))
+            }
+            Error::Unhealthy { address } => write!(f, "upstream server {} is unhealthy, append ! to use it anyway", address),
+        }
+    }
+}
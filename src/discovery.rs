@@ -0,0 +1,410 @@
+//! # Discovery Module
+//!
+//! This module pulls the list of upstream servers from a service catalog instead of the static `--upstream` flags.
+//!
+//! The catalog is hidden behind the `Catalog` trait, `ConsulCatalog` being the implementation querying the Consul
+//! health API, so the watching and reconciliation logic can be driven with synthetic catalogs in tests.
+//!
+//! ## Structures
+//!
+//! - `ConsulCatalog`: Catalog returning the passing instances of a Consul service, with blocking queries.
+//! - `FileCatalog`: Catalog returning the upstream servers listed in a file, every time the file is edited.
+//! - `EndpointSlices`: The ready addresses of every EndpointSlice of a Kubernetes service, kept up to date from the
+//!   events of a watch (see the `kubernetes` module).
+//! - `Reconciliation`: The upstream servers added and removed by a reconciliation.
+//!
+//! ## Functions
+//!
+//! ### `watch_catalog`
+//!
+//! This function queries the catalog forever and publishes the discovered upstream servers on a watch channel.
+//! When the catalog can't be reached, the last known list keeps being used.
+//!
+//! - **Parameters:**
+//!   - `catalog`: The catalog to query.
+//!   - `upstreams`: The channel the discovered upstream servers are published on.
+//!   - `retry_interval`: The time to wait before querying the catalog again after a failure.
+//!
+//! ### `reconcile`
+//!
+//! This function applies a discovered list of upstream servers to the configured and active ones.
+//!
+//! ### `parse_consul_health`
+//!
+//! This function extracts the addresses of the instances from a response of the Consul health API.
+//!
+//! ### `upstream_address`
+//!
+//! This function formats a host and a port as the `host:port` address of an upstream server.
+//!
+//! ### `parse_upstreams_file`
+//!
+//! This function reads the upstream servers of a file watched by `FileCatalog`, one `host:port` per line.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration};
+
+/// Time to wait before querying the catalog again after a failure.
+pub const CATALOG_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest time Consul holds a blocking query before answering with an unchanged list.
+const CONSUL_BLOCKING_WAIT: Duration = Duration::from_secs(30);
+
+/// A source of upstream servers.
+pub trait Catalog {
+    /// Returns the addresses of the instances able to receive traffic, as `host:port`.
+    ///
+    /// Implementations may wait for the list to change before returning, as Consul blocking queries do, so the caller
+    /// can query the catalog again right away.
+    fn instances(&mut self) -> impl Future<Output = Result<Vec<String>, std::io::Error>> + Send;
+}
+
+/// Catalog returning the instances of a Consul service passing their health checks.
+///
+/// The first query returns immediately, the following ones are blocking queries returning once the list changed or
+/// after `CONSUL_BLOCKING_WAIT`.
+#[derive(Debug)]
+pub struct ConsulCatalog {
+    /// Address of the Consul agent, as `host:port`.
+    address: String,
+
+    /// Name of the service whose instances are the upstream servers.
+    service: String,
+
+    /// `X-Consul-Index` of the last response, the blocking queries wait for a change past it.
+    index: Option<u64>,
+}
+
+impl ConsulCatalog {
+    /// Creates a catalog querying the Consul agent at `address` for the instances of `service`.
+    pub fn new(address: String, service: String) -> ConsulCatalog {
+        ConsulCatalog { address, service, index: None }
+    }
+
+    /// Queries the health API, waiting for a change past the last index if there is one.
+    async fn query(&mut self) -> Result<Vec<String>, std::io::Error> {
+        let mut path = format!("/v1/health/service/{}?passing=1", self.service);
+        if let Some(index) = self.index {
+            path.push_str(&format!("&index={}&wait={}s", index, CONSUL_BLOCKING_WAIT.as_secs()));
+        }
+
+        // HTTP/1.0 keeps the response close-delimited, so it is complete when Consul closes the connection
+        let mut stream = TcpStream::connect(&self.address).await?;
+        let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n", path, self.address);
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let body_start = match parsed.parse(&response) {
+            Ok(httparse::Status::Complete(body_start)) => body_start,
+            _ => return Err(std::io::Error::other("malformed response from Consul")),
+        };
+        if parsed.code != Some(200) {
+            return Err(std::io::Error::other(format!("Consul answered with status {:?}", parsed.code)));
+        }
+
+        let index = parsed.headers.iter()
+            .find(|header| header.name.eq_ignore_ascii_case("X-Consul-Index"))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        let instances = parse_consul_health(&response[body_start..]).map_err(std::io::Error::other)?;
+
+        // an index going backwards means the Consul state was reset, start over from a non-blocking query
+        self.index = match (self.index, index) {
+            (Some(previous), Some(index)) if index < previous => None,
+            (_, index) => index.filter(|index| *index > 0),
+        };
+
+        Ok(instances)
+    }
+}
+
+impl Catalog for ConsulCatalog {
+    async fn instances(&mut self) -> Result<Vec<String>, std::io::Error> {
+        // the blocking query may legitimately take up to CONSUL_BLOCKING_WAIT, plus the jitter Consul adds
+        match timeout(CONSUL_BLOCKING_WAIT * 2, self.query()).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.index = None;
+                Err(std::io::Error::other("Consul query timed out"))
+            }
+        }
+    }
+}
+
+/// Catalog returning the upstream servers listed in a file.
+///
+/// The file is polled, and the list is returned once the file changed and then stayed unchanged for the debounce
+/// delay, so a burst of edits is applied once. An invalid edit is logged and ignored, the upstream servers of the last
+/// valid version of the file stay in use until the file is fixed.
+#[derive(Debug)]
+pub struct FileCatalog {
+    /// Path of the file listing the upstream servers.
+    path: PathBuf,
+
+    /// Interval between two reads of the file.
+    poll_interval: Duration,
+
+    /// Time the file must stay unchanged before its new version is applied.
+    debounce: Duration,
+
+    /// Contents of the file when it was last applied or rejected.
+    last_contents: Option<String>,
+}
+
+impl FileCatalog {
+    /// Creates a catalog watching the upstream servers listed in the file at `path`.
+    pub fn new(path: PathBuf, poll_interval: Duration, debounce: Duration) -> FileCatalog {
+        FileCatalog { path, poll_interval, debounce, last_contents: None }
+    }
+}
+
+impl Catalog for FileCatalog {
+    async fn instances(&mut self) -> Result<Vec<String>, std::io::Error> {
+        loop {
+            let contents = tokio::fs::read_to_string(&self.path).await?;
+
+            if self.last_contents.as_ref() != Some(&contents) {
+                // the first version is applied right away, the next ones once the edits are over
+                if self.last_contents.is_some() {
+                    sleep(self.debounce).await;
+                    if tokio::fs::read_to_string(&self.path).await? != contents {
+                        continue;
+                    }
+                }
+
+                let upstreams = parse_upstreams_file(&contents);
+                self.last_contents = Some(contents);
+                match upstreams {
+                    Ok(upstreams) => return Ok(upstreams),
+                    Err(e) => log::error!("Ignoring invalid upstreams file {}: {}", self.path.display(), e),
+                }
+            }
+
+            sleep(self.poll_interval).await;
+        }
+    }
+}
+
+/// Reads the upstream servers listed in a file, one `host:port` per line.
+///
+/// Blank lines and the lines starting with `#` are ignored.
+///
+/// # Arguments
+///
+/// * `contents` - The contents of the file.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The upstream servers, as `host:port`.
+/// * `Err(String)` - The first invalid line, if any.
+pub fn parse_upstreams_file(contents: &str) -> Result<Vec<String>, String> {
+    contents.lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let valid = match line.rsplit_once(':') {
+                Some((host, port)) => !host.is_empty() && !host.contains(char::is_whitespace) && port.parse::<u16>().is_ok(),
+                None => false,
+            };
+            if valid {
+                Ok(line.to_string())
+            } else {
+                Err(format!("line {}: expected host:port, got {:?}", number, line))
+            }
+        })
+        .collect()
+}
+
+/// Extracts the addresses of the instances from a `/v1/health/service/<name>` response of Consul.
+///
+/// The address of an instance is the address of its service registration, or the address of its node when the
+/// service was registered without one.
+///
+/// # Arguments
+///
+/// * `body` - The JSON body of the response.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` - The addresses of the instances, as `host:port`.
+/// * `Err(String)` - If the body isn't a valid health API response.
+pub fn parse_consul_health(body: &[u8]) -> Result<Vec<String>, String> {
+    let entries: serde_json::Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON from Consul: {}", e))?;
+    let entries = entries.as_array().ok_or("expected a list of service entries")?;
+
+    entries.iter().map(|entry| {
+        let service = &entry["Service"];
+        let address = match service["Address"].as_str() {
+            Some(address) if !address.is_empty() => address,
+            _ => entry["Node"]["Address"].as_str().ok_or("service entry without an address")?,
+        };
+        let port = service["Port"].as_u64().ok_or("service entry without a port")?;
+
+        Ok(upstream_address(address, port))
+    }).collect()
+}
+
+/// Formats a host and a port as the `host:port` address of an upstream server, IPv6 addresses in brackets.
+pub fn upstream_address(host: &str, port: impl std::fmt::Display) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// An event of a watch on the EndpointSlices of a service, with the ready addresses of the slice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SliceEvent {
+    /// A slice was created or updated.
+    Applied { name: String, addresses: Vec<String> },
+    /// A slice was deleted.
+    Deleted { name: String },
+    /// The watch restarted and lists every slice again, the slices applied until `Listed` replace the known ones.
+    Relisting,
+    /// Every slice was listed again.
+    Listed,
+}
+
+/// The ready addresses of every EndpointSlice of a Kubernetes service.
+///
+/// A service with many endpoints is split into several slices, the upstream servers are the addresses of all of them.
+/// While the watch lists the slices again after a restart, the known slices keep being used until the list is
+/// complete, so a disconnection from the API server never empties the upstream servers.
+#[derive(Debug, Default)]
+pub struct EndpointSlices {
+    /// Ready addresses of every slice, by name.
+    slices: BTreeMap<String, Vec<String>>,
+
+    /// Slices listed since the watch restarted, replacing `slices` once the list is complete.
+    relisted: Option<BTreeMap<String, Vec<String>>>,
+}
+
+impl EndpointSlices {
+    /// Applies an event of the watch.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Vec<String>)` - The sorted upstream servers, if the event changed them.
+    /// * `None` - If the upstream servers are unchanged.
+    pub fn handle(&mut self, event: SliceEvent) -> Option<Vec<String>> {
+        let before = self.upstreams();
+
+        match (event, self.relisted.as_mut()) {
+            (SliceEvent::Applied { name, addresses }, Some(relisted)) => {
+                relisted.insert(name, addresses);
+                return None;
+            }
+            (SliceEvent::Applied { name, addresses }, None) => {
+                self.slices.insert(name, addresses);
+            }
+            (SliceEvent::Deleted { name }, relisted) => {
+                if let Some(relisted) = relisted {
+                    relisted.remove(&name);
+                }
+                self.slices.remove(&name);
+            }
+            (SliceEvent::Relisting, _) => {
+                self.relisted = Some(BTreeMap::new());
+                return None;
+            }
+            (SliceEvent::Listed, _) => {
+                if let Some(relisted) = self.relisted.take() {
+                    self.slices = relisted;
+                }
+            }
+        }
+
+        let after = self.upstreams();
+        (after != before).then_some(after)
+    }
+
+    /// Returns the sorted addresses of every slice.
+    pub fn upstreams(&self) -> Vec<String> {
+        let mut upstreams: Vec<String> = self.slices.values().flatten().cloned().collect();
+        upstreams.sort_unstable();
+        upstreams.dedup();
+        upstreams
+    }
+}
+
+/// Queries the catalog forever and publishes the discovered upstream servers.
+///
+/// Only changes are published, the list being sorted so the order of the catalog doesn't matter. When the catalog
+/// can't be reached, the last known list stays published and the catalog is queried again after `retry_interval`.
+///
+/// # Arguments
+///
+/// * `catalog` - The catalog to query.
+/// * `upstreams` - The channel the discovered upstream servers are published on.
+/// * `retry_interval` - The time to wait before querying the catalog again after a failure.
+pub async fn watch_catalog<C: Catalog>(mut catalog: C, upstreams: &watch::Sender<Vec<String>>, retry_interval: Duration) {
+    loop {
+        match catalog.instances().await {
+            Ok(mut instances) => {
+                instances.sort_unstable();
+                instances.dedup();
+                upstreams.send_if_modified(|current| {
+                    if *current == instances {
+                        return false;
+                    }
+                    log::info!("Discovered upstream servers: {:?}", instances);
+                    *current = instances;
+                    true
+                });
+            }
+            Err(e) => {
+                log::warn!("Could not query the service catalog, keeping the last known upstream servers: {}", e);
+                sleep(retry_interval).await;
+            }
+        }
+    }
+}
+
+/// The upstream servers added and removed by a reconciliation.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Upstream servers that weren't configured before, they receive traffic once they pass a health check.
+    pub added: Vec<String>,
+
+    /// Upstream servers no longer configured, they stopped receiving new connections.
+    pub removed: Vec<String>,
+}
+
+/// Applies a discovered list of upstream servers to the configured and active ones.
+///
+/// The removed upstream servers leave the active list right away, so no new connection is sent to them, while the
+/// connections already using them finish their requests. The added ones are only health checked: they join the active
+/// list at the next health check they pass, like the upstream servers given at startup.
+///
+/// # Arguments
+///
+/// * `upstreams` - The configured upstream servers, replaced by `discovered`.
+/// * `active` - The active upstream servers, the removed ones are taken out of it.
+/// * `discovered` - The upstream servers of the catalog.
+///
+/// # Returns
+///
+/// The upstream servers added and removed.
+pub fn reconcile(upstreams: &mut Vec<String>, active: &mut Vec<String>, discovered: Vec<String>) -> Reconciliation {
+    let reconciliation = Reconciliation {
+        added: discovered.iter().filter(|address| !upstreams.contains(address)).cloned().collect(),
+        removed: upstreams.iter().filter(|address| !discovered.contains(address)).cloned().collect(),
+    };
+
+    active.retain(|address| discovered.contains(address));
+    *upstreams = discovered;
+
+    reconciliation
+}
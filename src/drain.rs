@@ -0,0 +1,70 @@
+//! # Drain Module
+//!
+//! This module puts the proxy server in drain mode while a file exists, for environments where signals can't be sent
+//! to the proxy server but a file can be created.
+//!
+//! While draining, the proxy server stops listening for new connections and closes the client connections once their
+//! in-flight request has been answered. The upstream connections idle between two requests are closed right away,
+//! rather than left open until the process exits. It resumes listening when the file is removed.
+//!
+//! ## Functions
+//!
+//! ### `watch_drain_file`
+//!
+//! This function polls the existence of the drain file and publishes the drain state on a watch channel.
+//!
+//! - **Parameters:**
+//!   - `path`: The path of the drain file.
+//!   - `draining`: The channel the drain state is published on, `true` while the file exists.
+//!
+//! ### `wait_for_drain_state`
+//!
+//! This function waits until the published drain state has the given value.
+
+use std::path::PathBuf;
+
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+
+/// Interval between two checks of the drain file.
+pub const DRAIN_FILE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Polls the existence of the drain file forever and publishes the drain state.
+///
+/// Only changes are published, so receivers are woken up when the proxy server enters or leaves drain mode.
+///
+/// # Arguments
+///
+/// * `path` - The path of the drain file.
+/// * `draining` - The channel the drain state is published on, `true` while the file exists.
+pub async fn watch_drain_file(path: PathBuf, draining: &watch::Sender<bool>) {
+    loop {
+        let exists = tokio::fs::try_exists(&path).await.unwrap_or(false);
+
+        draining.send_if_modified(|state| {
+            if *state == exists {
+                return false;
+            }
+            if exists {
+                log::warn!("Drain file {} found, draining", path.display());
+            } else {
+                log::warn!("Drain file {} removed, accepting connections again", path.display());
+            }
+            *state = exists;
+            true
+        });
+
+        sleep(DRAIN_FILE_POLL_INTERVAL).await;
+    }
+}
+
+/// Waits until the drain state is `draining`.
+///
+/// Never returns if the state can't change anymore because the sending side of the channel is gone.
+pub async fn wait_for_drain_state(receiver: &mut watch::Receiver<bool>, draining: bool) {
+    while *receiver.borrow_and_update() != draining {
+        if receiver.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
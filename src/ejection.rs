@@ -0,0 +1,112 @@
+//! # Ejection Module
+//!
+//! This module takes the upstream servers answering with server errors out of rotation (passive health checks).
+//!
+//! An upstream server can pass its health checks while its application fails every request, for example when the
+//! health check path doesn't touch its database. With `--eject-on-5xx`, the status of every response relayed from an
+//! upstream server is recorded: after a number of consecutive 5xx responses, the upstream server is ejected and no
+//! new connection is made to it for `EJECTION_DURATION`. Any other response resets the count, so the 4xx responses,
+//! which tell about the request rather than the upstream server, never eject it.
+//!
+//! Ejection never takes the last candidates out of rotation: when every candidate upstream server of a request is
+//! ejected, they are all used as if none was.
+//!
+//! ## Structures
+//!
+//! - `Ejector`: The consecutive server errors of every upstream server, and the ones ejected.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time an upstream server stays out of rotation once ejected.
+pub const EJECTION_DURATION: Duration = Duration::from_secs(30);
+
+/// The passive health state of an upstream server.
+#[derive(Debug, Default)]
+struct PassiveHealth {
+    /// Number of 5xx responses in a row since the last other response.
+    consecutive_5xx: u32,
+
+    /// The instant the upstream server is back in rotation, if ejected.
+    ejected_until: Option<Instant>,
+}
+
+/// The consecutive server errors of every upstream server, and the ones ejected.
+#[derive(Debug)]
+pub struct Ejector {
+    /// Number of consecutive 5xx responses ejecting an upstream server.
+    threshold: u32,
+
+    /// Time an ejected upstream server stays out of rotation.
+    duration: Duration,
+
+    /// The passive health state, by upstream address.
+    upstreams: Mutex<HashMap<String, PassiveHealth>>,
+
+    /// Number of ejections of every upstream server.
+    ejections: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Ejector {
+    /// Creates an ejector taking an upstream server out of rotation for `duration` after `threshold` consecutive 5xx
+    /// responses.
+    pub fn new(threshold: u32, duration: Duration) -> Ejector {
+        Ejector { threshold, duration, upstreams: Mutex::new(HashMap::new()), ejections: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Records the status of a response relayed from an upstream server, ejecting it after too many 5xx in a row.
+    pub fn record_status(&self, upstream_address: &str, status: u16) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let health = upstreams.entry(upstream_address.to_string()).or_default();
+        if !(500..600).contains(&status) {
+            health.consecutive_5xx = 0;
+            return;
+        }
+
+        health.consecutive_5xx += 1;
+        if health.consecutive_5xx >= self.threshold {
+            log::warn!("Ejecting upstream server {} for {:?} after {} server errors in a row", upstream_address, self.duration, health.consecutive_5xx);
+            health.consecutive_5xx = 0;
+            health.ejected_until = Some(Instant::now() + self.duration);
+            *self.ejections.lock().unwrap().entry(upstream_address.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Tells whether an upstream server is out of rotation.
+    pub fn is_ejected(&self, upstream_address: &str) -> bool {
+        self.upstreams.lock().unwrap().get(upstream_address)
+            .and_then(|health| health.ejected_until)
+            .is_some_and(|ejected_until| Instant::now() < ejected_until)
+    }
+
+    /// Adds the ejected candidates to the upstream servers excluded from a selection, unless every candidate is
+    /// ejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address_list` - The addresses of the candidate upstream servers.
+    /// * `excluded` - The upstream addresses that must not be selected, completed with the ejected ones.
+    pub fn exclude_ejected(&self, upstream_address_list: &[String], excluded: &mut HashSet<String>) {
+        let candidates: Vec<&String> = upstream_address_list.iter().filter(|address| !excluded.contains(*address)).collect();
+        let ejected: Vec<&String> = candidates.iter().copied().filter(|address| self.is_ejected(address)).collect();
+        if ejected.len() < candidates.len() {
+            excluded.extend(ejected.into_iter().cloned());
+        }
+    }
+
+    /// Returns the number of times an upstream server was ejected.
+    pub fn ejections(&self, upstream_address: &str) -> u64 {
+        self.ejections.lock().unwrap().get(upstream_address).copied().unwrap_or(0)
+    }
+
+    /// Renders the ejections as the `lb_upstream_ejections_total` counter, in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_upstream_ejections_total counter\n");
+        for (upstream_address, count) in self.ejections.lock().unwrap().iter() {
+            let _ = writeln!(rendered, "lb_upstream_ejections_total{{upstream=\"{}\"}} {}", upstream_address, count);
+        }
+        rendered
+    }
+}
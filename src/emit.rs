@@ -0,0 +1,231 @@
+//! # Emit Module
+//!
+//! This module frames the responses written to the clients, so the bytes on the wire always match the framing their
+//! head declares.
+//!
+//! The responses the proxy server writes itself, errors, redirects, static routes, CORS preflights and injected
+//! faults, are built as a `ProxyResponse`: a status, headers and a body held in memory. The `Content-Length` and
+//! `Transfer-Encoding` headers given to it are dropped, and `emit` declares the length of the body it writes:
+//!
+//! - 1xx, 204 No Content and 304 Not Modified responses have neither a body nor a `Content-Length`.
+//! - A response to a `HEAD` request declares the length of its body, but doesn't carry it.
+//! - Any other response declares the length of its body with `Content-Length`.
+//!
+//! A body streamed without a known length is declared with `Transfer-Encoding: chunked` by `emit_head`, each part is
+//! framed with `encode_chunk` and the body is ended with `LAST_CHUNK`.
+//!
+//! The responses relayed from the upstream servers keep their body as received, framed as the upstream server
+//! framed it. Their head goes through `reframe_head`, which drops a `Content-Length` sent alongside
+//! `Transfer-Encoding: chunked`, and folds repeated `Content-Length` values declaring the same length into one.
+//! The responses shared with the coalesced requests are these relayed bytes.
+//!
+//! ## Structures
+//!
+//! - `Framing`: How the end of a response body is found.
+//! - `ProxyResponse`: A response written by the proxy server itself.
+//!
+//! ## Functions
+//!
+//! ### `has_no_body`
+//!
+//! This function tells whether the responses of a status never have a body.
+//!
+//! ### `encode_chunk`
+//!
+//! This function frames a part of a body of unknown length as a chunk.
+//!
+//! ### `reframe_head`
+//!
+//! This function rewrites the stale framing headers of a relayed response head.
+
+use http::{Method, StatusCode};
+
+/// The chunk ending a body framed with `Transfer-Encoding: chunked`, without trailer fields.
+pub const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// How the end of a response body is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// The response has no body (`HEAD` requests, 1xx, 204 and 304 responses).
+    Empty,
+    /// The body is exactly this many bytes long.
+    ContentLength(usize),
+    /// The body is a sequence of chunks terminated by a zero-sized chunk and optional trailer fields.
+    Chunked,
+    /// The body ends when the connection is closed.
+    UntilClose,
+}
+
+/// A response written by the proxy server itself, framed when it is emitted.
+#[derive(Debug, Clone)]
+pub struct ProxyResponse {
+    status: StatusCode,
+    headers: Vec<(&'static str, Vec<u8>)>,
+    body: Vec<u8>,
+}
+
+impl ProxyResponse {
+    /// Creates a response with `status`, without headers nor body.
+    pub fn new(status: StatusCode) -> ProxyResponse {
+        ProxyResponse { status, headers: Vec::new(), body: Vec::new() }
+    }
+
+    /// Adds a header. The framing headers are set by `emit`, a `Content-Length` or `Transfer-Encoding` given here is
+    /// dropped, and so is a value holding a line break, which would end the header early.
+    pub fn header(mut self, name: &'static str, value: impl AsRef<[u8]>) -> ProxyResponse {
+        let value = value.as_ref();
+        if is_framing_header(name.as_bytes()) || value.iter().any(|byte| matches!(byte, b'\r' | b'\n')) {
+            log::debug!("Dropping the {} header of a response written by the proxy server", name);
+            return self;
+        }
+        self.headers.push((name, value.to_vec()));
+        self
+    }
+
+    /// Sets the body.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> ProxyResponse {
+        self.body = body.into();
+        self
+    }
+
+    /// Returns the status of the response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the raw response answering a `request_method` request: the head declaring the length of the body, and
+    /// the body unless the request or the status has none.
+    pub fn emit(&self, request_method: &Method) -> Vec<u8> {
+        let (mut response, framing) = self.emit_head(request_method, Some(self.body.len()));
+        if framing != Framing::Empty {
+            response.extend_from_slice(&self.body);
+        }
+        response
+    }
+
+    /// Returns the raw head of the response answering a `request_method` request, whose body is written separately,
+    /// and the framing the body must be written with. The body set on the response is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_method` - The method of the request, `HEAD` responses have no body.
+    /// * `length` - The length of the body, if known. A body of unknown length is chunked.
+    pub fn emit_head(&self, request_method: &Method, length: Option<usize>) -> (Vec<u8>, Framing) {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.as_u16(), self.status.canonical_reason().unwrap_or("")).into_bytes();
+        for (name, value) in &self.headers {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+
+        let framing = match length {
+            _ if has_no_body(self.status.as_u16()) => Framing::Empty,
+            Some(length) => {
+                head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+                Framing::ContentLength(length)
+            }
+            // the length of the body isn't known before it is sent, and the client doesn't read a HEAD body
+            None if *request_method == Method::HEAD => Framing::Empty,
+            None => {
+                head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+                Framing::Chunked
+            }
+        };
+        head.extend_from_slice(b"\r\n");
+
+        match *request_method == Method::HEAD {
+            true => (head, Framing::Empty),
+            false => (head, framing),
+        }
+    }
+}
+
+/// Tells whether the responses of `status` never have a body: 1xx, 204 No Content and 304 Not Modified.
+pub fn has_no_body(status: u16) -> bool {
+    (100..200).contains(&status) || status == 204 || status == 304
+}
+
+/// Frames `data`, a part of a body of unknown length, as a chunk. Empty data gives no bytes, an empty chunk would end
+/// the body.
+pub fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// Rewrites the stale framing headers of a relayed response head, so a client finds the end of the body where the
+/// relay does.
+///
+/// A head with chunked coding loses the `Content-Length` headers sent alongside `Transfer-Encoding`, a client honoring
+/// them would read the body differently. Otherwise repeated `Content-Length` headers, or a list of values, declaring
+/// the same length are folded into a single header. Different lengths are left to the caller, which rejects them for
+/// a response with a body. The other lines are kept as they were received.
+///
+/// # Arguments
+///
+/// * `head` - The status line and headers, ending with the empty line.
+///
+/// # Returns
+///
+/// * `Some(Vec<u8>)` - The rewritten head.
+/// * `None` - If the framing headers of the head are already consistent, or declare different lengths.
+pub fn reframe_head(head: &[u8]) -> Option<Vec<u8>> {
+    let lines: Vec<&[u8]> = head.split_inclusive(|&byte| byte == b'\n').collect();
+    let lengths = header_values(&lines, b"content-length");
+    let is_chunked = header_values(&lines, b"transfer-encoding").iter()
+        .flat_map(|value| value.split(|&byte| byte == b','))
+        .any(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"));
+
+    let declared = match is_chunked {
+        true if lengths.is_empty() => return None,
+        true => None,
+        false => {
+            if lengths.len() < 2 && !lengths.iter().any(|value| value.contains(&b',')) {
+                return None;
+            }
+            let mut values = lengths.iter().flat_map(|value| value.split(|&byte| byte == b',')).map(|value| value.trim_ascii());
+            let first = values.next()?;
+            if !values.all(|value| value == first) {
+                return None;
+            }
+            Some(first)
+        }
+    };
+
+    let mut reframed = Vec::with_capacity(head.len());
+    for (index, line) in lines.iter().enumerate() {
+        let name = line.split(|&byte| byte == b':').next().unwrap_or_default().trim_ascii();
+        if index > 0 && name.eq_ignore_ascii_case(b"content-length") {
+            continue;
+        }
+        // the declared length goes right before the empty line ending the head
+        if let (Some(length), true) = (declared, index == lines.len() - 1) {
+            reframed.extend_from_slice(b"Content-Length: ");
+            reframed.extend_from_slice(length);
+            reframed.extend_from_slice(b"\r\n");
+        }
+        reframed.extend_from_slice(line);
+    }
+    Some(reframed)
+}
+
+/// Returns the trimmed values of the header lines named `name`, in lowercase, skipping the status line.
+fn header_values<'a>(lines: &[&'a [u8]], name: &[u8]) -> Vec<&'a [u8]> {
+    lines.iter().skip(1)
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, |&byte| byte == b':');
+            let line_name = parts.next()?.trim_ascii();
+            line_name.eq_ignore_ascii_case(name).then(|| parts.next().unwrap_or_default().trim_ascii())
+        })
+        .collect()
+}
+
+/// Tells whether `name` is one of the headers framing the body, `Content-Length` or `Transfer-Encoding`.
+fn is_framing_header(name: &[u8]) -> bool {
+    name.eq_ignore_ascii_case(b"content-length") || name.eq_ignore_ascii_case(b"transfer-encoding")
+}
@@ -0,0 +1,274 @@
+//! # Explain Module
+//!
+//! This module explains how the proxy server would route a request, without sending anything to an upstream server.
+//!
+//! When a request reaches an unexpected upstream server, the rules and the strategy that sent it there are hard to
+//! guess. `POST /explain` on the metrics listener takes the description of a sample request as JSON, such as
+//! `{"method": "GET", "path": "/api/orders", "host": "shop.example.com", "headers": {"X-Canary": "true"},
+//! "client_ip": "10.0.0.7"}`, and answers with the trace of the decisions the proxy server would make for it:
+//!
+//! - `acl`: The indexes of the ACL rules matching the request, the first of which denies it.
+//! - `decision`: `denied`, `rejected` (with the status the request would be answered with), `preflight` for a CORS
+//!   preflight, `static` for a static route, `override` for a request forced through an upstream server, or
+//!   `upstream`.
+//! - `forwarded_host`: The `Host` header the request would be forwarded with.
+//! - `pools`: The chance of every pool (`default` or `canary`), and its candidate upstream servers with their health,
+//!   ejection, requests in flight, reported load, recent bytes and chance of being picked by the `strategy`.
+//! - `odds` and `pick`: The chance of every upstream server to receive the request, and the one that would, when
+//!   the decision is certain.
+//!
+//! The sample request goes through the same reading, ACL, host rewriting, CORS, static route, debug routing and canary
+//! rules as a real one, and the chances are those of the selection strategy at the time of the request. The load
+//! shedding, the coalescing and the deadlines depend on the traffic at the time of the real request and aren't
+//! explained. With several frontends, `"frontend": "NAME"` explains the routing of the frontend of that pool.
+//!
+//! ## Structures
+//!
+//! - `SampleRequest`: The description of a sample request, parsed from JSON.
+//!
+//! ## Functions
+//!
+//! ### `explain`
+//!
+//! This function traces the routing decisions for a sample request, as JSON.
+
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+
+use http::header::{HeaderName, HeaderValue};
+use http::Method;
+use serde_json::{json, Value};
+
+use crate::acl;
+use crate::cors;
+use crate::context::ProxyContext;
+use crate::request::{parse_client_request, real_client_ip, request_controller, Error};
+use crate::routing::{Pool, UpstreamPools};
+use crate::static_route;
+
+/// Size of the buffer the sample requests are read into.
+const SAMPLE_BUFFER_SIZE: usize = 8192;
+
+/// The description of a sample request, parsed from JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRequest {
+    /// The method of the request. Default is `GET`.
+    pub method: Method,
+
+    /// The request target, path and query. Default is `/`.
+    pub path: String,
+
+    /// The `Host` header of the request. Without it, the request is rejected unless `--default-host` is given.
+    pub host: Option<HeaderValue>,
+
+    /// The other headers of the request.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// The IP address of the client connection. Default is `127.0.0.1`.
+    pub client_ip: IpAddr,
+
+    /// The frontend whose routing is explained, the default one if `None`.
+    pub frontend: Option<String>,
+}
+
+impl SampleRequest {
+    /// Parses the description of a sample request, such as `{"path": "/api", "headers": {"X-Canary": "true"}}`.
+    pub fn from_json(body: &[u8]) -> Result<SampleRequest, String> {
+        let description: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+        let description = description.as_object().ok_or("expected a JSON object")?;
+        let field = |name: &str| -> Result<Option<&str>, String> {
+            match description.get(name) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::String(value)) => Ok(Some(value.as_str())),
+                Some(_) => Err(format!("expected {} to be a string", name)),
+            }
+        };
+
+        let method = match field("method")? {
+            Some(method) => Method::from_bytes(method.as_bytes()).map_err(|_| format!("invalid method {:?}", method))?,
+            None => Method::GET,
+        };
+        let path = field("path")?.unwrap_or("/").to_string();
+        if path.is_empty() || path.bytes().any(|byte| byte.is_ascii_whitespace() || byte.is_ascii_control()) {
+            return Err(format!("invalid path {:?}", path));
+        }
+        let host = field("host")?.map(|host| HeaderValue::from_str(host).map_err(|_| format!("invalid host {:?}", host))).transpose()?;
+        let client_ip = match field("client_ip")? {
+            Some(client_ip) => client_ip.parse().map_err(|_| format!("invalid client IP address {:?}", client_ip))?,
+            None => IpAddr::from([127, 0, 0, 1]),
+        };
+        let frontend = field("frontend")?.map(str::to_string);
+
+        let mut headers = Vec::new();
+        match description.get("headers") {
+            None | Some(Value::Null) => {}
+            Some(Value::Object(object)) => {
+                for (name, value) in object {
+                    let header = HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name {:?}", name))?;
+                    let value = value.as_str().and_then(|value| HeaderValue::from_str(value).ok()).ok_or(format!("invalid value of header {:?}", name))?;
+                    headers.push((header, value));
+                }
+            }
+            Some(_) => return Err("expected headers to be an object of names and values".to_string()),
+        }
+
+        Ok(SampleRequest { method, path, host, headers, client_ip, frontend })
+    }
+
+    /// Returns the request as a client would send it, without a body.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{} {} HTTP/1.1\r\n", self.method, self.path).into_bytes();
+        let host = self.host.iter().map(|host| (&http::header::HOST, host));
+        for (name, value) in host.chain(self.headers.iter().map(|(name, value)| (name, value))) {
+            bytes.extend_from_slice(name.as_str().as_bytes());
+            bytes.extend_from_slice(b": ");
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    }
+}
+
+/// Traces the decisions the proxy server would make for a sample request, without sending anything upstream.
+///
+/// # Arguments
+///
+/// * `sample` - The sample request.
+/// * `upstream_pools` - The active upstream servers of every pool, and the canary rule.
+/// * `context` - The context of the pool, whose request settings apply to the request, and whose limiter, selection
+///   strategy and ejections pick the upstream server.
+///
+/// # Returns
+///
+/// * `Value` - The trace, see the module documentation.
+pub async fn explain(sample: &SampleRequest, upstream_pools: &UpstreamPools, context: &ProxyContext) -> Value {
+    let request_config = &context.request_config;
+    let bytes = sample.to_bytes();
+    let client_address = SocketAddr::new(sample.client_ip, 0);
+    let mut trace = json!({
+        "request": {
+            "method": sample.method.as_str(),
+            "path": sample.path,
+            "host": sample.host.as_ref().map(|host| String::from_utf8_lossy(host.as_bytes()).into_owned()),
+            "client_ip": sample.client_ip.to_string(),
+        },
+    });
+
+    // every rule matching the request as the client sent it, the first one denies it
+    if let Ok(client_request) = parse_client_request(&bytes, request_config) {
+        let acl_ip = real_client_ip(&client_request, sample.client_ip, request_config).unwrap_or(sample.client_ip);
+        let matched: Vec<usize> = request_config.acl_rules.iter().enumerate()
+            .filter(|(_, rule)| rule.matches(&client_request, acl_ip))
+            .map(|(index, _)| index)
+            .collect();
+        debug_assert_eq!(matched.first().copied(), acl::evaluate(&request_config.acl_rules, &client_request, acl_ip));
+        trace["acl"] = json!(matched);
+    }
+
+    let mut buffer = vec![0; SAMPLE_BUFFER_SIZE];
+    let mut forwarded = match request_controller(&mut Cursor::new(bytes), client_address, &mut buffer, request_config).await {
+        Ok(forwarded) => forwarded,
+        Err(Error::Denied { rule, status, .. }) => {
+            trace["decision"] = json!("denied");
+            trace["acl_rule"] = json!(rule);
+            trace["status"] = json!(status.as_u16());
+            return trace;
+        }
+        Err(e) => {
+            trace["decision"] = json!("rejected");
+            trace["status"] = json!(rejected_status(&e));
+            trace["reason"] = json!(format!("{:?}", e));
+            return trace;
+        }
+    };
+    trace["forwarded_host"] = json!(forwarded.headers().get(http::header::HOST).map(|host| String::from_utf8_lossy(host.as_bytes()).into_owned()));
+
+    if request_config.cors.is_some() && cors::is_preflight(&forwarded) {
+        trace["decision"] = json!("preflight");
+        return trace;
+    }
+    if let Some(route) = static_route::find(&request_config.static_routes, &forwarded) {
+        trace["decision"] = json!("static");
+        trace["static_route"] = json!(route.path);
+        return trace;
+    }
+
+    // a trusted client forcing the request through an upstream server skips the routing
+    if let Some(upstream_override) = request_config.debug_routing.as_ref().and_then(|debug_routing| debug_routing.take_override(&mut forwarded, sample.client_ip)) {
+        let resolved = upstream_override.map_err(|e| (400, e)).and_then(|upstream_override| {
+            upstream_override.resolve(&upstream_pools.configured, &upstream_pools.healthy).map_err(|e| match e {
+                crate::debug_routing::Error::UnknownUpstream { .. } => (400, e.to_string()),
+                crate::debug_routing::Error::Unhealthy { .. } => (503, e.to_string()),
+            })
+        });
+        match resolved {
+            Ok(upstream_address) => {
+                trace["decision"] = json!("override");
+                trace["odds"] = json!({ upstream_address.as_str(): 1.0 });
+                trace["pick"] = json!(upstream_address);
+            }
+            Err((status, reason)) => {
+                trace["decision"] = json!("rejected");
+                trace["status"] = json!(status);
+                trace["reason"] = json!(reason);
+            }
+        }
+        return trace;
+    }
+
+    trace["decision"] = json!("upstream");
+    trace["strategy"] = json!(context.limiter.strategy());
+    let canary_share = upstream_pools.canary_share(&forwarded) as f64 / 100.0;
+    let mut pools = serde_json::Map::new();
+    let mut odds: Vec<(String, f64)> = Vec::new();
+    for (pool, name, share) in [(Pool::Default, "default", 1.0 - canary_share), (Pool::Canary, "canary", canary_share)] {
+        if share == 0.0 {
+            continue;
+        }
+        let candidates = upstream_pools.upstreams(pool);
+        let mut excluded = HashSet::new();
+        if let Some(ejector) = &context.ejector {
+            ejector.exclude_ejected(candidates, &mut excluded);
+        }
+
+        let mut explained = Vec::new();
+        for (upstream_address, chance) in context.limiter.odds(candidates, &excluded) {
+            explained.push(json!({
+                "address": upstream_address,
+                "healthy": upstream_pools.healthy.contains(&upstream_address),
+                "ejected": context.ejector.as_ref().is_some_and(|ejector| ejector.is_ejected(&upstream_address)),
+                "in_flight": context.limiter.in_flight(&upstream_address),
+                "max_in_flight": context.limiter.max_per_upstream(),
+                "reported_load": context.limiter.load_reports().and_then(|load_reports| load_reports.load(&upstream_address)),
+                "window_bytes": context.limiter.byte_volumes().map(|byte_volumes| byte_volumes.volume(&upstream_address, std::time::Instant::now())),
+                "odds": chance,
+            }));
+            match odds.iter_mut().find(|(address, _)| *address == upstream_address) {
+                Some((_, total)) => *total += share * chance,
+                None => odds.push((upstream_address, share * chance)),
+            }
+        }
+        pools.insert(name.to_string(), json!({ "odds": share, "candidates": explained }));
+    }
+
+    trace["pools"] = Value::Object(pools);
+    odds.retain(|(_, chance)| *chance > 0.0);
+    trace["pick"] = json!(odds.iter().find(|(_, chance)| (chance - 1.0).abs() < 1e-9).map(|(address, _)| address));
+    trace["odds"] = Value::Object(odds.into_iter().map(|(address, chance)| (address, json!(chance))).collect());
+    trace
+}
+
+/// Returns the status a request failing to be read is answered with, as the proxy server answers it.
+fn rejected_status(e: &Error) -> u16 {
+    match e {
+        Error::LoopDetected => 508,
+        Error::RequestTimeout => 408,
+        Error::UnsupportedTransferCoding { .. } => 501,
+        Error::UnsupportedContentCoding { .. } => 415,
+        Error::SpoolFailed { out_of_space: true } => 507,
+        Error::SpoolFailed { out_of_space: false } => 503,
+        _ => 400,
+    }
+}
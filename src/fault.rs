@@ -0,0 +1,182 @@
+//! # Fault Module
+//!
+//! This module injects artificial latency and errors into the proxied requests, to test how the clients behave when
+//! the upstream servers are slow or flaky without a separate chaos proxy.
+//!
+//! With `--enable-fault-injection`, the faults are set on the metrics listener by `POST /faults` with a JSON body
+//! such as `{"latency_ms": 500, "abort_pct": 5, "abort_status": 503, "match_path": "^/api/"}`, and cleared by
+//! `DELETE /faults`:
+//!
+//! - `latency_ms`: A delay added to the matching requests before they are forwarded.
+//! - `abort_pct`: The percentage of the matching requests answered by the proxy server with `abort_status`, 503
+//!   Service Unavailable by default, without reaching an upstream server. An aborted request is delayed first.
+//! - `match_path`: A regular expression searched in the path of the requests. Every request matches without it.
+//!
+//! The requests a fault was injected into are tagged with a `fault` field in the access log, and counted by the
+//! `lb_faults_injected_total` metric by kind of fault. The random draws are passed in by the caller, so the
+//! percentages can be checked in tests.
+//!
+//! ## Structures
+//!
+//! - `FaultSpec`: The faults to inject, parsed from JSON.
+//! - `InjectedFault`: The faults injected into a request, kept in its extensions for the access log.
+//! - `FaultInjector`: The faults currently injected, and the requests they were injected into.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use http::{Method, Request, StatusCode};
+use rand::Rng;
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::emit::ProxyResponse;
+
+/// Name of the upstream server reported in the access log for the requests aborted by an injected fault.
+pub const FAULT_UPSTREAM: &str = "fault";
+
+/// The faults to inject into the matching requests.
+#[derive(Debug, Clone)]
+pub struct FaultSpec {
+    /// Delay added to the matching requests before they are forwarded.
+    pub latency: Duration,
+
+    /// Percentage of the matching requests answered with `abort_status`.
+    pub abort_percent: f64,
+
+    /// Status of the aborted requests.
+    pub abort_status: StatusCode,
+
+    /// Regular expression searched in the path of the requests, every request matches without it.
+    pub match_path: Option<Regex>,
+}
+
+impl FaultSpec {
+    /// Parses the faults to inject, such as `{"latency_ms": 500, "abort_pct": 5, "match_path": "^/api/"}`.
+    pub fn from_json(body: &[u8]) -> Result<FaultSpec, String> {
+        let description: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+        let description = description.as_object().ok_or("expected a JSON object")?;
+        if let Some(unknown) = description.keys().find(|key| !["latency_ms", "abort_pct", "abort_status", "match_path"].contains(&key.as_str())) {
+            return Err(format!("unknown field {:?}, expected latency_ms, abort_pct, abort_status or match_path", unknown));
+        }
+
+        let latency_ms = match description.get("latency_ms") {
+            None => 0,
+            Some(latency_ms) => latency_ms.as_u64().ok_or("expected latency_ms to be a number of milliseconds")?,
+        };
+        let abort_percent = match description.get("abort_pct") {
+            None => 0.0,
+            Some(abort_pct) => abort_pct.as_f64().filter(|abort_pct| (0.0..=100.0).contains(abort_pct)).ok_or("expected abort_pct to be a percentage between 0 and 100")?,
+        };
+        let abort_status = match description.get("abort_status") {
+            None => StatusCode::SERVICE_UNAVAILABLE,
+            Some(abort_status) => abort_status.as_u64()
+                .and_then(|status| u16::try_from(status).ok())
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .ok_or("expected abort_status to be a 4xx or 5xx status")?,
+        };
+        let match_path = match description.get("match_path") {
+            None => None,
+            Some(Value::String(expression)) => Some(Regex::new(expression).map_err(|e| format!("invalid match_path expression {:?}: {}", expression, e))?),
+            Some(_) => return Err("expected match_path to be a regular expression".to_string()),
+        };
+
+        Ok(FaultSpec { latency: Duration::from_millis(latency_ms), abort_percent, abort_status, match_path })
+    }
+
+    /// Returns the faults as JSON, in the format they are set with.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "latency_ms": self.latency.as_millis() as u64,
+            "abort_pct": self.abort_percent,
+            "abort_status": self.abort_status.as_u16(),
+            "match_path": self.match_path.as_ref().map(Regex::as_str),
+        })
+    }
+}
+
+/// The faults injected into a request, kept in its extensions for the access log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectedFault {
+    /// Delay added before the request is forwarded or aborted.
+    pub latency: Duration,
+
+    /// Status the request is answered with by the proxy server, if aborted.
+    pub abort: Option<StatusCode>,
+}
+
+impl InjectedFault {
+    /// Returns the status and the raw response answering an aborted `request_method` request, `None` if the request
+    /// isn't aborted.
+    pub fn abort_response(&self, request_method: &Method) -> Option<(StatusCode, Vec<u8>)> {
+        let response = ProxyResponse::new(self.abort?).header("Content-Type", "text/plain").body("injected fault\n");
+        Some((response.status(), response.emit(request_method)))
+    }
+
+    /// Returns the fault as the `fault` field of the access log.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "latency_ms": self.latency.as_millis() as u64,
+            "abort_status": self.abort.map(|status| status.as_u16()),
+        })
+    }
+}
+
+/// The faults currently injected, and the number of requests they were injected into.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    spec: RwLock<Option<FaultSpec>>,
+    delayed: AtomicU64,
+    aborted: AtomicU64,
+}
+
+impl FaultInjector {
+    /// Sets the faults injected into the next requests, or clears them with `None`.
+    pub fn set(&self, spec: Option<FaultSpec>) {
+        match &spec {
+            Some(spec) => log::warn!("Injecting faults into the requests: {}", spec.to_json()),
+            None => log::warn!("No longer injecting faults into the requests"),
+        }
+        *self.spec.write().unwrap() = spec;
+    }
+
+    /// Returns the faults currently injected, if any.
+    pub fn spec(&self) -> Option<FaultSpec> {
+        self.spec.read().unwrap().clone()
+    }
+
+    /// Draws the faults injected into `request`, `None` if it is proxied normally.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request, matched by the path expression of the faults.
+    /// * `rng` - The random number generator drawing the aborted requests, seedable for reproducible tests.
+    pub fn inject<R: Rng>(&self, request: &Request<Vec<u8>>, rng: &mut R) -> Option<InjectedFault> {
+        let spec = self.spec.read().unwrap();
+        let spec = spec.as_ref().filter(|spec| spec.match_path.as_ref().is_none_or(|path| path.is_match(request.uri().path())))?;
+
+        let aborted = spec.abort_percent > 0.0 && rng.gen::<f64>() * 100.0 < spec.abort_percent;
+        if spec.latency.is_zero() && !aborted {
+            return None;
+        }
+        if !spec.latency.is_zero() {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+        }
+        if aborted {
+            self.aborted.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(InjectedFault { latency: spec.latency, abort: aborted.then_some(spec.abort_status) })
+    }
+
+    /// Renders the requests delayed and aborted as the `lb_faults_injected_total` counter, in the Prometheus text
+    /// format.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_faults_injected_total counter\n");
+        let _ = writeln!(rendered, "lb_faults_injected_total{{kind=\"latency\"}} {}", self.delayed.load(Ordering::Relaxed));
+        let _ = writeln!(rendered, "lb_faults_injected_total{{kind=\"abort\"}} {}", self.aborted.load(Ordering::Relaxed));
+        rendered
+    }
+}
@@ -0,0 +1,134 @@
+//! # Frontend Module
+//!
+//! This module lets a single process run independent load balancers, each listening on its own address for its own
+//! pool of upstream servers.
+//!
+//! The `--bind` listener balances the `--upstream` servers, the `default` pool. Every `--frontend b=127.0.0.1:8081`
+//! adds a listener balancing the pool `b`, whose upstream servers are given with `--pool-upstream b=HOST:PORT`. Every
+//! pool has its own proxy state: its own health checks, active upstream servers, limits and counters, so a pool going
+//! down never affects the requests of another one. The pools share the runtime and the metrics listener, where their
+//! metrics are told apart by a `pool` label.
+//!
+//! ## Structures
+//!
+//! - `Frontend`: A listener and the pool it balances, parsed from `POOL=HOST:PORT`.
+//! - `PoolUpstream`: An upstream server of a named pool, parsed from `POOL=HOST:PORT`.
+//!
+//! ## Functions
+//!
+//! ### `merge_metrics`
+//!
+//! This function merges the metrics of several pools, labeling every sample with the pool it belongs to.
+
+use std::str::FromStr;
+
+/// Name of the pool of the `--bind` listener and the `--upstream` servers.
+pub const DEFAULT_POOL: &str = "default";
+
+/// A listener and the pool of upstream servers it balances.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frontend {
+    /// Name of the pool the requests of the listener are balanced over.
+    pub pool: String,
+
+    /// Address the listener is bound to.
+    pub bind: String,
+}
+
+/// An upstream server of a named pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolUpstream {
+    /// Name of the pool.
+    pub pool: String,
+
+    /// Address of the upstream server.
+    pub address: String,
+}
+
+/// Parses `POOL=HOST:PORT` into the pool name and the address.
+fn parse_pool_address(value: &str) -> Result<(String, String), String> {
+    let (pool, address) = value.split_once('=').ok_or(format!("expected POOL=HOST:PORT, got {:?}", value))?;
+    let (pool, address) = (pool.trim(), address.trim());
+    if pool.is_empty() || !pool.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_') {
+        return Err(format!("invalid pool name {:?}, expected letters, digits, - and _", pool));
+    }
+    if pool == DEFAULT_POOL {
+        return Err(format!("the {} pool is the --upstream servers of the --bind listener", DEFAULT_POOL));
+    }
+    if address.is_empty() {
+        return Err(format!("expected POOL=HOST:PORT, got {:?}", value));
+    }
+
+    Ok((pool.to_string(), address.to_string()))
+}
+
+impl FromStr for Frontend {
+    type Err = String;
+
+    /// Parses a `POOL=HOST:PORT` frontend, such as `b=0.0.0.0:8081`.
+    fn from_str(frontend: &str) -> Result<Frontend, String> {
+        let (pool, bind) = parse_pool_address(frontend)?;
+        Ok(Frontend { pool, bind })
+    }
+}
+
+impl FromStr for PoolUpstream {
+    type Err = String;
+
+    /// Parses a `POOL=HOST:PORT` upstream server, such as `b=10.0.1.2:8080`.
+    fn from_str(upstream: &str) -> Result<PoolUpstream, String> {
+        let (pool, address) = parse_pool_address(upstream)?;
+        Ok(PoolUpstream { pool, address })
+    }
+}
+
+/// Merges the metrics of several pools, rendered in the Prometheus text format, into a single exposition.
+///
+/// Every sample gets a `pool` label, and the samples of a metric are grouped under a single `# TYPE` line whatever
+/// pool they come from, in the order the metrics first appear.
+///
+/// # Arguments
+///
+/// * `pools` - The name of every pool, with its rendered metrics.
+pub fn merge_metrics(pools: &[(&str, String)]) -> String {
+    // the type line and the samples of every metric, in the order they first appear
+    let mut families: Vec<(String, String, Vec<String>)> = Vec::new();
+    for (pool, rendered) in pools {
+        let mut family = None;
+        for line in rendered.lines() {
+            if let Some(declaration) = line.strip_prefix("# TYPE ") {
+                let name = declaration.split_whitespace().next().unwrap_or_default();
+                family = Some(match families.iter().position(|(existing, _, _)| existing == name) {
+                    Some(index) => index,
+                    None => {
+                        families.push((name.to_string(), line.to_string(), Vec::new()));
+                        families.len() - 1
+                    }
+                });
+            } else if let (Some(index), false) = (family, line.starts_with('#') || line.is_empty()) {
+                families[index].2.push(label_sample(line, pool));
+            }
+        }
+    }
+
+    let mut merged = String::new();
+    for (_, declaration, samples) in families {
+        merged.push_str(&declaration);
+        merged.push('\n');
+        for sample in samples {
+            merged.push_str(&sample);
+            merged.push('\n');
+        }
+    }
+    merged
+}
+
+/// Adds the `pool` label first to the labels of a sample line.
+fn label_sample(sample: &str, pool: &str) -> String {
+    match sample.find(['{', ' ']) {
+        Some(index) if sample[index..].starts_with("{}") => format!("{}{{pool=\"{}\"}}{}", &sample[..index], pool, &sample[index + 2..]),
+        Some(index) if sample[index..].starts_with('{') => format!("{}{{pool=\"{}\",{}", &sample[..index], pool, &sample[index + 1..]),
+        Some(index) => format!("{}{{pool=\"{}\"}}{}", &sample[..index], pool, &sample[index..]),
+        None => sample.to_string(),
+    }
+}
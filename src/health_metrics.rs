@@ -0,0 +1,206 @@
+//! # Health Metrics Module
+//!
+//! This module records how the health checks themselves behave, to tell whether the health checker is the problem
+//! when the pool of active upstream servers misbehaves.
+//!
+//! For every upstream server, the duration and outcome of its last health probe are kept, along with when that probe
+//! completed and when the upstream server last passed one. Every health cycle is timed as well: a cycle taking longer
+//! than the health check interval means the checks are falling behind, which is logged as a warning.
+//!
+//! The durations of all the probes of every upstream server are also kept in a histogram, so a slow-but-healthy
+//! upstream server shows up in the metrics, rendered in the Prometheus text format with `render_prometheus`:
+//!
+//! - `health_check_duration_seconds{upstream}`: Histogram of the durations of the health probes.
+//! - `health_check_failures_total{upstream}`: Number of failed health probes.
+//!
+//! ## Structures
+//!
+//! - `ProbeRecord`: The outcome of the last health probe of an upstream server.
+//! - `DurationHistogram`: The number of durations observed under each of the `DURATION_BUCKETS` bounds.
+//! - `HealthMetrics`: The probe records and probe duration histogram of every upstream server, and the duration of
+//!   the last health cycle.
+//!
+//! ## Functions
+//!
+//! - `run_probe`: Runs a timed health check on an upstream server, recorded later with `record_timed_probe`, so the
+//!   probes of a cycle can run without borrowing the metrics.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::http_health_checks::{http_health_check, HealthCheckRequest};
+
+/// Upper bounds in seconds of the buckets of the probe duration histograms, and of the request phase histograms.
+pub const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The outcome of the last health probe of an upstream server.
+#[derive(Debug, Clone)]
+pub struct ProbeRecord {
+    /// How long the probe took.
+    pub duration: Duration,
+
+    /// Whether the upstream server passed the probe.
+    pub healthy: bool,
+
+    /// When the probe completed.
+    pub completed_at: Instant,
+
+    /// When the upstream server last passed a probe, if ever.
+    pub last_success: Option<SystemTime>,
+
+    /// Number of probes of the upstream server, since the first start when the state is persisted.
+    pub probes: u64,
+
+    /// Number of failed probes of the upstream server, since the first start when the state is persisted.
+    pub failures: u64,
+}
+
+/// The number of durations observed under each of the `DURATION_BUCKETS` bounds.
+#[derive(Debug, Clone, Default)]
+pub struct DurationHistogram {
+    /// Number of durations at or under every bound of `DURATION_BUCKETS`, not cumulated.
+    pub buckets: [u64; DURATION_BUCKETS.len()],
+
+    /// Number of observed durations.
+    pub count: u64,
+
+    /// Sum of the observed durations, in seconds.
+    pub sum: f64,
+}
+
+impl DurationHistogram {
+    /// Observes a duration.
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// The probe records and probe duration histogram of every upstream server, and the duration of the last health
+/// cycle.
+#[derive(Debug, Default)]
+pub struct HealthMetrics {
+    /// Last probe record of every upstream server, by address.
+    probes: HashMap<String, ProbeRecord>,
+
+    /// Histogram of the probe durations of every upstream server, by address.
+    durations: BTreeMap<String, DurationHistogram>,
+
+    /// Duration of the last complete health cycle.
+    last_cycle: Option<Duration>,
+}
+
+/// Runs a timed health check on an upstream server, without recording it.
+///
+/// The health check fails once the timeout of the request expires, so a probe never takes much longer.
+///
+/// # Arguments
+///
+/// * `upstream_address` - The address of the upstream server.
+/// * `request` - The request used for the health check.
+///
+/// # Returns
+///
+/// * `(Duration, bool)` - How long the health check took, and whether the upstream server passed it.
+pub async fn run_probe(upstream_address: &str, request: &HealthCheckRequest) -> (Duration, bool) {
+    let started_at = Instant::now();
+    let healthy = http_health_check(upstream_address.to_string(), request).await.is_ok();
+    (started_at.elapsed(), healthy)
+}
+
+impl HealthMetrics {
+    /// Records the outcome of a health probe run with `run_probe`, its duration counted in the histogram.
+    pub fn record_timed_probe(&mut self, upstream_address: &str, duration: Duration, healthy: bool) {
+        self.durations.entry(upstream_address.to_string()).or_default().observe(duration);
+        self.record_probe(upstream_address, duration, healthy);
+    }
+
+    /// Records the outcome of a health probe of an upstream server.
+    pub fn record_probe(&mut self, upstream_address: &str, duration: Duration, healthy: bool) {
+        let previous = self.probes.get(upstream_address);
+        let previous_success = previous.and_then(|record| record.last_success);
+        let last_success = if healthy { Some(SystemTime::now()) } else { previous_success };
+        let probes = previous.map_or(0, |record| record.probes) + 1;
+        let failures = previous.map_or(0, |record| record.failures) + u64::from(!healthy);
+
+        log::debug!("Health probe of {} took {:?}, healthy: {}", upstream_address, duration, healthy);
+        let record = ProbeRecord { duration, healthy, completed_at: Instant::now(), last_success, probes, failures };
+        self.probes.insert(upstream_address.to_string(), record);
+    }
+
+    /// Records the duration of a complete health cycle.
+    ///
+    /// A cycle longer than the interval between two cycles means the checks are falling behind, a warning is logged.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the health checks are falling behind.
+    pub fn record_cycle(&mut self, duration: Duration, interval: Duration) -> bool {
+        self.last_cycle = Some(duration);
+
+        let falling_behind = duration > interval;
+        if falling_behind {
+            log::warn!("Health check cycle took {:?}, longer than the {:?} interval: health checks are falling behind", duration, interval);
+        }
+        falling_behind
+    }
+
+    /// Restores the probe record of an upstream server, loaded from the state file of a previous run.
+    pub fn restore_probe(&mut self, upstream_address: String, record: ProbeRecord) {
+        self.probes.insert(upstream_address, record);
+    }
+
+    /// Returns the last probe record of every upstream server, by address.
+    pub fn probe_records(&self) -> &HashMap<String, ProbeRecord> {
+        &self.probes
+    }
+
+    /// Returns the last probe record of an upstream server.
+    pub fn probe_record(&self, upstream_address: &str) -> Option<&ProbeRecord> {
+        self.probes.get(upstream_address)
+    }
+
+    /// Returns the time elapsed since the last probe of an upstream server completed.
+    pub fn since_last_probe(&self, upstream_address: &str) -> Option<Duration> {
+        self.probes.get(upstream_address).map(|record| record.completed_at.elapsed())
+    }
+
+    /// Returns the duration of the last complete health cycle.
+    pub fn last_cycle(&self) -> Option<Duration> {
+        self.last_cycle
+    }
+
+    /// Returns the histogram of the probe durations of an upstream server, if it was probed.
+    pub fn duration_histogram(&self, upstream_address: &str) -> Option<&DurationHistogram> {
+        self.durations.get(upstream_address)
+    }
+
+    /// Renders the probe duration histograms and failure counters in the Prometheus text format.
+    ///
+    /// The failure counters carry on from the state file when the state is persisted, the histograms start empty.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE health_check_duration_seconds histogram\n");
+        for (upstream_address, histogram) in &self.durations {
+            let mut cumulated = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulated += count;
+                let _ = writeln!(rendered, "health_check_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}", upstream_address, bound, cumulated);
+            }
+            let _ = writeln!(rendered, "health_check_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}", upstream_address, histogram.count);
+            let _ = writeln!(rendered, "health_check_duration_seconds_sum{{upstream=\"{}\"}} {}", upstream_address, histogram.sum);
+            let _ = writeln!(rendered, "health_check_duration_seconds_count{{upstream=\"{}\"}} {}", upstream_address, histogram.count);
+        }
+
+        rendered.push_str("# TYPE health_check_failures_total counter\n");
+        let records: BTreeMap<_, _> = self.probes.iter().collect();
+        for (upstream_address, record) in records {
+            let _ = writeln!(rendered, "health_check_failures_total{{upstream=\"{}\"}} {}", upstream_address, record.failures);
+        }
+        rendered
+    }
+}
@@ -2,6 +2,21 @@
 //!
 //! This module provides functions for performing HTTP health checks on upstream servers.
 //!
+//! The health check request is a `GET` of the health check path by default. Its method and body can be configured for
+//! the upstream servers only exposing their health through another method, such as `POST /rpc` with a JSON body.
+//!
+//! When the upstream servers are reached through an egress proxy, the health checks are tunneled through it as well,
+//! so they tell whether the upstream servers are reachable the way the proxied requests reach them.
+//!
+//! The health checks are async, and every one of them must complete within the timeout of its request, connection and
+//! tunnel included. A blackholed upstream server fails its check once the timeout expires instead of holding up the
+//! health cycle.
+//!
+//! ## Structures
+//!
+//! - `HealthCheckRequest`: The request sent to the upstream servers to check their health.
+//! - `HealthCheckBody`: The body of the health check request, given inline or as `@<file>`.
+//!
 //! ## Functions
 //!
 //! ### `basic_http_health_check`
@@ -17,41 +32,130 @@
 //!   - `Err(std::io::Error)`: If the health check fails, containing details about the error and the upstream server IP.
 //!
 //! - **Example:**
-//!   ```rust
-//!   use crate::http_health_checks::basic_http_health_check;
+//!   ```rust,no_run
+//!   use rust_loadbalancer::http_health_checks::basic_http_health_check;
 //!
-//!   match basic_http_health_check(String::from("127.0.0.1:8080"), String::from("/health")) {
+//!   # async fn check() {
+//!   match basic_http_health_check(String::from("127.0.0.1:8080"), String::from("/health")).await {
 //!       Ok(_) => println!("Health check successful!"),
 //!       Err(e) => eprintln!("Health check failed: {}", e),
 //!   }
+//!   # }
 //!   ```
 //!
-//! ### `simple_get_request`
+//! ### `http_health_check`
 //!
-//! This private function sends a simple GET request to the upstream server to check if it's healthy. It is used internally by `basic_http_health_check`.
+//! This function sends the configured health check request to the upstream server. `basic_http_health_check` is the
+//! same check with a `GET` request.
 //!
 //! - **Parameters:**
-//!   - `stream`: A mutable reference to a TcpStream.
-//!   - `path`: A String representing the path used for the health check.
+//!   - `upstream_ip`: A String containing the upstream server IP.
+//!   - `request`: The health check request to send.
+//!
+//! - **Returns:**
+//!   - `Ok(())`: If the health check is successful (200 OK response).
+//!   - `Err(std::io::Error)`: If the health check fails, containing details about the error and the upstream server IP.
+//!     The error is of kind `TimedOut` when the check didn't complete within the timeout of the request.
+//!
+//! ### `send_health_request`
+//!
+//! This private function sends the health check request on a connection to the upstream server and checks the response.
+//! It is used internally by `http_health_check`.
+//!
+//! - **Parameters:**
+//!   - `stream`: A mutable reference to a tokio TcpStream.
+//!   - `request`: The health check request to send.
 //!
 //! - **Returns:**
 //!   - `Ok(())`: If the health check is successful (200 OK response).
 //!   - `Err(std::io::Error)`: If the health check fails, containing details about the error.
 //!
 //! - **Example:**
-//!   ```rust
-//!   use crate::http_health_checks::simple_get_request;
-//!   use std::net::TcpStream;
+//!   ```rust,ignore
+//!   use crate::http_health_checks::{send_health_request, HealthCheckRequest};
+//!   use tokio::net::TcpStream;
 //!
-//!   let mut stream = TcpStream::connect("127.0.0.1:8080").unwrap();
-//!   match simple_get_request(&mut stream, String::from("/health")) {
+//!   let mut stream = TcpStream::connect("127.0.0.1:8080").await.unwrap();
+//!   match send_health_request(&mut stream, &HealthCheckRequest::get(String::from("/health"))).await {
 //!       Ok(_) => println!("Health check successful!"),
 //!       Err(e) => eprintln!("Health check failed: {}", e),
 //!   }
 //!   ```
+//!
+//! ### `format_health_request`
+//!
+//! This function serializes the health check request, with its `Content-Type` and `Content-Length` headers.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use http::header::HeaderValue;
+use http::Method;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use crate::upstream_proxy::UpstreamProxy;
+
+/// Largest body a health check request may carry.
+pub const MAX_HEALTH_BODY_SIZE: usize = 64 * 1024;
+
+/// Default time allowed for a health check to complete, connection included.
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default maximum number of health checks in progress at once.
+pub const DEFAULT_HEALTH_CHECK_CONCURRENCY: u32 = 64;
+
+/// The request sent to the upstream servers to check their health.
+#[derive(Debug, Clone)]
+pub struct HealthCheckRequest {
+    /// Method of the request.
+    pub method: Method,
+
+    /// Path of the request.
+    pub path: String,
+
+    /// Body of the request, sent with a `Content-Length` header when it isn't empty.
+    pub body: Vec<u8>,
+
+    /// `Content-Type` of the body, if any.
+    pub content_type: Option<HeaderValue>,
+
+    /// Egress proxy the health check is tunneled through, if any.
+    pub upstream_proxy: Option<UpstreamProxy>,
+
+    /// Time allowed for the health check to complete, from the connection to the response.
+    pub timeout: Duration,
+}
+
+impl HealthCheckRequest {
+    /// Creates a `GET` health check request of `path`, without a body, completing within the default timeout.
+    pub fn get(path: String) -> HealthCheckRequest {
+        HealthCheckRequest { method: Method::GET, path, body: Vec::new(), content_type: None, upstream_proxy: None, timeout: DEFAULT_HEALTH_CHECK_TIMEOUT }
+    }
+}
+
+/// The body of the health check request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheckBody(pub Vec<u8>);
+
+impl FromStr for HealthCheckBody {
+    type Err = String;
+
+    /// Parses an inline body, or reads the body from a file given as `@<file>`.
+    ///
+    /// The body is sent as-is, so it may be any bytes, but no more than `MAX_HEALTH_BODY_SIZE` of them.
+    fn from_str(body: &str) -> Result<HealthCheckBody, String> {
+        let body = match body.strip_prefix('@') {
+            Some(path) => std::fs::read(path).map_err(|e| format!("could not read {:?}: {}", path, e))?,
+            None => body.as_bytes().to_vec(),
+        };
+
+        if body.len() > MAX_HEALTH_BODY_SIZE {
+            return Err(format!("the body is {} bytes long, more than the {} bytes allowed", body.len(), MAX_HEALTH_BODY_SIZE));
+        }
+        Ok(HealthCheckBody(body))
+    }
+}
 
 /// Performs a basic HTTP health check on the upstream server.
 ///
@@ -70,50 +174,71 @@ use std::net::TcpStream;
 ///
 /// # Example
 ///
-/// ```rust
-/// use crate::http_health_checks::basic_http_health_check;
+/// ```rust,no_run
+/// use rust_loadbalancer::http_health_checks::basic_http_health_check;
 ///
-/// match basic_http_health_check(String::from("127.0.0.1:8080"), String::from("/health")) {
+/// # async fn check() {
+/// match basic_http_health_check(String::from("127.0.0.1:8080"), String::from("/health")).await {
 ///     Ok(_) => println!("Health check successful!"),
 ///     Err(e) => eprintln!("Health check failed: {}", e),
 /// }
+/// # }
 /// ``` 
-pub fn basic_http_health_check(upstream_ip : String, path : String) -> Result< (), std::io::Error> {
+pub async fn basic_http_health_check(upstream_ip : String, path : String) -> Result< (), std::io::Error> {
+    http_health_check(upstream_ip, &HealthCheckRequest::get(path)).await
+}
+
+
+/// Performs an HTTP health check on the upstream server with the configured request.
+///
+/// The health check is considered successful if the response contains "200 OK." It fails once the timeout of the
+/// request expires, whether the connection, the tunnel through the egress proxy or the response is late.
+///
+/// # Arguments
+///
+/// * `upstream_ip` - A String containing the upstream server IP.
+/// * `request` - The health check request to send.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the health check is successful (200 OK response).
+/// * `Err(std::io::Error)` - If the health check fails, containing details about the error and the upstream server IP,
+///   of kind `TimedOut` if the health check didn't complete in time.
+pub async fn http_health_check(upstream_ip: String, request: &HealthCheckRequest) -> Result<(), std::io::Error> {
     let upstream_address = upstream_ip;
 
-    // send a simple GET request to the upstream server to check if it's healthy
-    let mut upstream_stream = match TcpStream::connect(&upstream_address) {
-        Ok(stream) => stream,
-        Err(_) => {
-            //     return a simple error containing the upstream_address
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, upstream_address.to_string()));
-        }
-    };
+    match tokio::time::timeout(request.timeout, connect_and_check(&upstream_address, request)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err(std::io::Error::other(upstream_address)),
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, upstream_address)),
+    }
+}
 
 
-    // send a simple GET request to the upstream server to check if it's healthy returning 200 OK
-    return match simple_get_request(&mut upstream_stream, path) {
-        Ok(_) => {
-            //     return a simple Ok containing the upstream_address
-            Ok(())
-        },
-        Err(_) => {
-            //     return a simple error containing the upstream_address
-            Err(std::io::Error::new(std::io::ErrorKind::Other, upstream_address.to_string()))
+/// Connects to the upstream server, through the egress proxy if any, and sends it the health check request.
+async fn connect_and_check(upstream_address: &str, request: &HealthCheckRequest) -> Result<(), std::io::Error> {
+    let mut upstream_stream = match &request.upstream_proxy {
+        Some(upstream_proxy) => {
+            let mut stream = TcpStream::connect(&upstream_proxy.address).await?;
+            upstream_proxy.tunnel(&mut stream, upstream_address).await?;
+            stream
         }
-    }
-    
+        None => TcpStream::connect(upstream_address).await?,
+    };
+
+    // send the health check request to the upstream server, it is healthy if it returns 200 OK
+    send_health_request(&mut upstream_stream, request).await
 }
 
 
-/// Sends a simple GET request to the upstream server to check if it's healthy.
+/// Sends the health check request to the upstream server to check if it's healthy.
 ///
-/// This private function is used internally by `basic_http_health_check`.
+/// This private function is used internally by `http_health_check`.
 ///
 /// # Arguments
 ///
-/// * `stream` - A mutable reference to a TcpStream.
-/// * `path` - A String representing the path used for the health check.
+/// * `stream` - A mutable reference to a tokio TcpStream.
+/// * `request` - The health check request to send.
 ///
 /// # Returns
 ///
@@ -122,33 +247,62 @@ pub fn basic_http_health_check(upstream_ip : String, path : String) -> Result< (
 ///
 /// # Example
 ///
-/// ```rust
-/// use crate::http_health_checks::simple_get_request;
-/// use std::net::TcpStream;
+/// ```rust,ignore
+/// use crate::http_health_checks::{send_health_request, HealthCheckRequest};
+/// use tokio::net::TcpStream;
 ///
-/// let mut stream = TcpStream::connect("127.0.0.1:8080").unwrap();
-/// match simple_get_request(&mut stream, String::from("/health")) {
+/// let mut stream = TcpStream::connect("127.0.0.1:8080").await.unwrap();
+/// match send_health_request(&mut stream, &HealthCheckRequest::get(String::from("/health"))).await {
 ///     Ok(_) => println!("Health check successful!"),
 ///     Err(e) => eprintln!("Health check failed: {}", e),
 /// }
 /// ```
-fn simple_get_request(stream: &mut TcpStream, path : String) -> Result<(), std::io::Error> {
+async fn send_health_request(stream: &mut TcpStream, request: &HealthCheckRequest) -> Result<(), std::io::Error> {
 
 
     // send request on path to the upstream server
-
-    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
-    stream.write(request.as_bytes())?;
+    stream.write_all(&format_health_request(request)).await?;
 
     // check the http code
     let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
+    let bytes_read = stream.read(&mut buffer).await?;
     let response = String::from_utf8_lossy(&buffer[..bytes_read]);
 
     // check if the response contains 200 OK
     if !response.contains("200 OK") {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Non-200 OK response"));
+        return Err(std::io::Error::other("Non-200 OK response"));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+/// Serializes the health check request.
+///
+/// A body is announced with `Content-Length`, and so is the empty body of the methods expecting one (`POST`, `PUT`
+/// and `PATCH`), so the upstream server never waits for a body that isn't coming.
+///
+/// # Arguments
+///
+/// * `request` - The health check request.
+///
+/// # Returns
+///
+/// The bytes of the request, head and body.
+pub fn format_health_request(request: &HealthCheckRequest) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", request.method, request.path).into_bytes();
+
+    if let Some(content_type) = &request.content_type {
+        head.extend_from_slice(b"Content-Type: ");
+        head.extend_from_slice(content_type.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    let expects_body = [Method::POST, Method::PUT, Method::PATCH].contains(&request.method);
+    if !request.body.is_empty() || expects_body {
+        head.extend_from_slice(format!("Content-Length: {}\r\n", request.body.len()).as_bytes());
+    }
+
+    head.extend_from_slice(b"\r\n");
+    head.extend_from_slice(&request.body);
+    head
+}
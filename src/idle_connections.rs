@@ -0,0 +1,145 @@
+//! # Idle Connections Module
+//!
+//! This module caps the number of idle keep-alive connections kept open to every upstream server.
+//!
+//! Every client connection keeps its upstream connection open between two of its requests, so the upstream
+//! connections held idle grow with the number of client connections, and so do the file descriptors. With a cap, an
+//! upstream connection going idle is parked in the `IdleConnections` of its upstream server. Once the upstream server
+//! has more idle connections than the cap, the oldest one is evicted: its client connection closes it, and connects
+//! again on its next request. A connection leaves the registry when it is checked out for a request.
+//!
+//! ## Structures
+//!
+//! - `IdleConnections`: The idle connections of every upstream server, in the order they went idle, and the cap.
+//! - `IdleSlot`: An idle connection parked in the registry, removed from it when dropped.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+/// Identifier and eviction signal of the idle connections of an upstream server, the oldest first.
+type Parked = VecDeque<(u64, Arc<Notify>)>;
+
+/// The idle connections of every upstream server, in the order they went idle.
+#[derive(Debug)]
+pub struct IdleConnections {
+    /// Maximum number of idle connections kept open to an upstream server.
+    max_per_upstream: usize,
+
+    /// Idle connections of every upstream server holding at least one.
+    idle: Mutex<HashMap<String, Parked>>,
+
+    /// Identifier of the next connection parked.
+    next_id: AtomicU64,
+
+    /// Number of idle connections evicted.
+    evicted: AtomicU64,
+}
+
+impl IdleConnections {
+    /// Creates a registry keeping at most `max_per_upstream` idle connections open to every upstream server.
+    pub fn new(max_per_upstream: usize) -> IdleConnections {
+        IdleConnections { max_per_upstream, idle: Mutex::new(HashMap::new()), next_id: AtomicU64::new(0), evicted: AtomicU64::new(0) }
+    }
+
+    /// Returns the maximum number of idle connections kept open to an upstream server.
+    pub fn max_per_upstream(&self) -> usize {
+        self.max_per_upstream
+    }
+
+    /// Parks a connection to an upstream server going idle, evicting the oldest idle connections over the cap.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address` - The address of the upstream server the connection is open to.
+    ///
+    /// # Returns
+    ///
+    /// * `IdleSlot` - The slot of the connection, to keep while it is idle. Its `evicted` future completes once the
+    ///   connection must be closed, right away if the cap is 0.
+    pub fn park(self: &Arc<Self>, upstream_address: &str) -> IdleSlot {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let evicted = Arc::new(Notify::new());
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(upstream_address.to_string()).or_default();
+        connections.push_back((id, evicted.clone()));
+        while connections.len() > self.max_per_upstream {
+            // the permit is kept until the evicted connection waits for it
+            if let Some((_, oldest)) = connections.pop_front() {
+                oldest.notify_one();
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if connections.is_empty() {
+            idle.remove(upstream_address);
+        }
+
+        IdleSlot { connections: self.clone(), upstream_address: upstream_address.to_string(), id, evicted }
+    }
+
+    /// Returns the number of idle connections parked for an upstream server.
+    pub fn idle(&self, upstream_address: &str) -> usize {
+        self.idle.lock().unwrap().get(upstream_address).map_or(0, VecDeque::len)
+    }
+
+    /// Returns the number of idle connections evicted.
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Renders the idle connections of every upstream server as the `lb_idle_upstream_connections` gauge and the
+    /// evictions as the `lb_idle_upstream_evictions_total` counter, in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let idle = self.idle.lock().unwrap();
+        let mut upstreams: Vec<_> = idle.iter().collect();
+        upstreams.sort_by_key(|(upstream_address, _)| *upstream_address);
+
+        let mut rendered = String::from("# TYPE lb_idle_upstream_connections gauge\n");
+        for (upstream_address, connections) in upstreams {
+            let _ = writeln!(rendered, "lb_idle_upstream_connections{{upstream=\"{}\"}} {}", upstream_address, connections.len());
+        }
+        rendered.push_str("# TYPE lb_idle_upstream_evictions_total counter\n");
+        let _ = writeln!(rendered, "lb_idle_upstream_evictions_total {}", self.evicted());
+        rendered
+    }
+}
+
+/// An idle connection parked in the registry of its upstream server, removed from it when dropped.
+#[derive(Debug)]
+pub struct IdleSlot {
+    /// The registry the connection is parked in.
+    connections: Arc<IdleConnections>,
+
+    /// The address of the upstream server the connection is open to.
+    upstream_address: String,
+
+    /// Identifier of the connection in the registry.
+    id: u64,
+
+    /// Signaled when the connection is evicted.
+    evicted: Arc<Notify>,
+}
+
+impl IdleSlot {
+    /// Completes once the connection is evicted by newer idle connections to the same upstream server.
+    pub async fn evicted(&self) {
+        self.evicted.notified().await
+    }
+}
+
+impl Drop for IdleSlot {
+    fn drop(&mut self) {
+        let mut idle = self.connections.idle.lock().unwrap();
+
+        // an evicted connection was already removed, and the upstream servers without idle connections are forgotten
+        if let Some(connections) = idle.get_mut(&self.upstream_address) {
+            connections.retain(|(id, _)| *id != self.id);
+            if connections.is_empty() {
+                idle.remove(&self.upstream_address);
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! # Kubernetes Module
+//!
+//! This module discovers the upstream servers from the EndpointSlices of a Kubernetes service, when the crate is
+//! built with the `kubernetes` feature.
+//!
+//! The EndpointSlices are watched through the Kubernetes API. Every event of the watch is translated into a
+//! `SliceEvent`, and `EndpointSlices` of the `discovery` module turns them into the list of upstream servers, so the
+//! reconciliation logic is shared with the other catalogs and tested without an API server.
+//!
+//! ## Structures
+//!
+//! - `ServiceRef`: A Kubernetes service, given as `<namespace>/<name>`.
+//! - `KubernetesCatalog`: Catalog returning the ready endpoints of a service every time they change.
+//!
+//! ## Functions
+//!
+//! ### `ready_addresses`
+//!
+//! This function returns the `host:port` addresses of the ready endpoints of an EndpointSlice.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::runtime::watcher::{self, Event};
+use kube::runtime::WatchStreamExt;
+use kube::{Api, Client, Config};
+
+use crate::discovery::{upstream_address, Catalog, EndpointSlices, SliceEvent};
+
+/// A Kubernetes service, given as `<namespace>/<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceRef {
+    /// Namespace of the service.
+    pub namespace: String,
+
+    /// Name of the service.
+    pub name: String,
+}
+
+impl FromStr for ServiceRef {
+    type Err = String;
+
+    /// Parses a `<namespace>/<name>` reference, such as `default/web`.
+    fn from_str(service: &str) -> Result<ServiceRef, String> {
+        match service.split_once('/') {
+            Some((namespace, name)) if !namespace.is_empty() && !name.is_empty() && !name.contains('/') => {
+                Ok(ServiceRef { namespace: namespace.to_string(), name: name.to_string() })
+            }
+            _ => Err(format!("expected <namespace>/<name>, got {:?}", service)),
+        }
+    }
+}
+
+/// Catalog returning the ready endpoints of a Kubernetes service every time they change.
+///
+/// Disconnections from the API server are retried with an exponential backoff, and the last known endpoints keep
+/// being used meanwhile.
+pub struct KubernetesCatalog {
+    /// Events of the watch on the EndpointSlices of the service.
+    events: BoxStream<'static, Result<Event<EndpointSlice>, watcher::Error>>,
+
+    /// Ready addresses of every EndpointSlice of the service.
+    slices: EndpointSlices,
+}
+
+impl KubernetesCatalog {
+    /// Starts watching the EndpointSlices of `service`.
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The service whose endpoints are the upstream servers.
+    /// * `kubeconfig` - The kubeconfig file to connect with. Without it, the in-cluster configuration is used when
+    ///   running in a pod, and the `KUBECONFIG` or `~/.kube/config` file otherwise.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(KubernetesCatalog)` - The catalog, the watch starts with its first query.
+    /// * `Err(String)` - If the client configuration can't be loaded.
+    pub async fn new(service: &ServiceRef, kubeconfig: Option<PathBuf>) -> Result<KubernetesCatalog, String> {
+        let config = match kubeconfig {
+            Some(path) => {
+                let kubeconfig = Kubeconfig::read_from(&path).map_err(|e| format!("invalid kubeconfig {}: {}", path.display(), e))?;
+                Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await.map_err(|e| e.to_string())?
+            }
+            None => Config::infer().await.map_err(|e| e.to_string())?,
+        };
+        let client = Client::try_from(config).map_err(|e| e.to_string())?;
+
+        let api: Api<EndpointSlice> = Api::namespaced(client, &service.namespace);
+        let selector = format!("kubernetes.io/service-name={}", service.name);
+        let events = watcher::watcher(api, watcher::Config::default().labels(&selector)).default_backoff().boxed();
+
+        Ok(KubernetesCatalog { events, slices: EndpointSlices::default() })
+    }
+}
+
+impl Catalog for KubernetesCatalog {
+    async fn instances(&mut self) -> Result<Vec<String>, std::io::Error> {
+        loop {
+            let event = match self.events.next().await {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => return Err(std::io::Error::other(e)),
+                None => std::future::pending().await,
+            };
+
+            let event = match event {
+                Event::Apply(slice) | Event::InitApply(slice) => SliceEvent::Applied {
+                    name: slice.metadata.name.clone().unwrap_or_default(),
+                    addresses: ready_addresses(&slice),
+                },
+                Event::Delete(slice) => SliceEvent::Deleted { name: slice.metadata.name.unwrap_or_default() },
+                Event::Init => SliceEvent::Relisting,
+                Event::InitDone => SliceEvent::Listed,
+            };
+
+            if let Some(upstreams) = self.slices.handle(event) {
+                return Ok(upstreams);
+            }
+        }
+    }
+}
+
+/// Returns the `host:port` addresses of the ready endpoints of an EndpointSlice.
+///
+/// The port is the first port of the slice. Endpoints without a `ready` condition are ready, as the Kubernetes API
+/// documents.
+pub fn ready_addresses(slice: &EndpointSlice) -> Vec<String> {
+    let Some(port) = slice.ports.iter().flatten().find_map(|port| port.port) else {
+        return Vec::new();
+    };
+
+    slice.endpoints.iter()
+        .filter(|endpoint| endpoint.conditions.as_ref().and_then(|conditions| conditions.ready) != Some(false))
+        .flat_map(|endpoint| endpoint.addresses.iter())
+        .map(|address| upstream_address(address, port))
+        .collect()
+}
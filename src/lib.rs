@@ -0,0 +1,234 @@
+//! # Rust Load Balancer Library
+//!
+//! This library holds the building blocks of the proxy server that don't depend on its runtime state: reading and
+//! rewriting client requests, relaying upstream responses, routing requests and selecting upstream servers, pooling
+//! buffers, watching the drain file, discovering upstream servers and checking their health. The proxy server itself
+//! (`src/main.rs`) is built on top of it, and so are the benchmarks (`benches/`).
+//!
+//! ## Modules
+//!
+//! - `request`: Module for handling client requests.
+//! - `response`: Module for relaying upstream responses to the clients according to their framing.
+//! - `emit`: Module framing the responses written to the clients, declaring the length of the bodies they carry.
+//! - `selection`: Module for selecting the upstream server a request is sent to.
+//! - `routing`: Module for routing requests to the default or the canary pool of upstream servers.
+//! - `frontend`: Module running listeners for separate named pools of upstream servers in the same process.
+//! - `acl`: Module denying requests by method and path before they are routed.
+//! - `upstream_host`: Module choosing the `Host` header of the forwarded requests: preserved, fixed or from the vhost.
+//! - `timing`: Module breaking the time spent on a request down into its phases, for the access log and the phase
+//!   histograms.
+//! - `buffer_pool`: Module providing the bounded pool of per-connection buffers.
+//! - `spool`: Module spilling the request bodies over a memory limit to disk, deleted once the request is answered.
+//! - `connect`: Module opening the connections to the upstream servers, retrying the failed attempts.
+//! - `context`: Module grouping the settings, connector and feature state shared by the client connections of a pool.
+//! - `resolver`: Module resolving the upstream addresses, caching the answers for the TTL of their records.
+//! - `upstream_proxy`: Module tunneling the upstream connections through a SOCKS5 or HTTP CONNECT egress proxy.
+//! - `redirect`: Module redirecting the plaintext requests to HTTPS.
+//! - `coalesce`: Module letting identical requests in flight share a single upstream response.
+//! - `capacity`: Module capping the concurrent requests of every upstream server, queuing the requests over the cap.
+//! - `connection_limit`: Module capping the number of connections a client IP address can hold open.
+//! - `idle_connections`: Module capping the idle keep-alive connections to every upstream server, evicting the oldest.
+//! - `prewarm`: Module opening idle connections to the healthy upstream servers ahead of the first requests.
+//! - `drain`: Module putting the proxy server in drain mode while a file exists.
+//! - `discovery`: Module pulling the upstream servers from a service catalog, Consul or a watched file.
+//! - `kubernetes`: Module discovering the upstream servers from the EndpointSlices of a Kubernetes service, with the
+//!   `kubernetes` feature.
+//! - `http_health_checks`: Module for performing HTTP-based health checks on upstream servers.
+//! - `warmup`: Module warming up the upstream servers that just became healthy before they are admitted.
+//! - `health_metrics`: Module recording the duration and outcome of the health checks.
+//! - `deadline`: Module reading the deadline budget of the requests from a header.
+//! - `debug_routing`: Module letting trusted clients force a request through a specific upstream server, for debugging.
+//! - `ejection`: Module ejecting the upstream servers answering with server errors, for a while.
+//! - `load_shedding`: Module shedding a growing share of the requests while the proxy server is overloaded.
+//! - `fault`: Module injecting artificial latency and errors into the proxied requests, set on the metrics listener.
+//! - `load_report`: Module recording the loads reported by the upstream servers and preferring the least loaded ones.
+//! - `byte_volume`: Module counting the bytes transferred with every upstream server over a sliding window,
+//!   preferring the upstream servers with the fewest.
+//! - `metrics`: Module serving the metrics of the proxy server in the Prometheus text format.
+//! - `dashboard`: Module rendering the HTML status page of the upstream servers, served on the metrics listener.
+//! - `admin_auth`: Module requiring a bearer token from the requests of the metrics listener, rotated on `SIGHUP`.
+//! - `explain`: Module explaining how a sample request would be routed, without sending it to an upstream server.
+//! - `telemetry`: Module tracing every request as a span, exported over OTLP with the `otel` feature.
+//! - `supervisor`: Module restarting the long-running tasks that panic, and reporting the panics.
+//! - `static_route`: Module answering the requests for a few paths with a fixed response, without an upstream server.
+//! - `cors`: Module answering the CORS preflight requests and allowing the origins of the cross-origin responses.
+//! - `state_file`: Module persisting the health state of the upstream servers across restarts.
+//! - `test_active_health_check`: Module for testing active health check functionality.
+//! - `test_request`: Module for testing request handling functionality.
+//! - `test_request_properties`: Module for property testing the request reading path with arbitrary bytes.
+//! - `test_response`: Module for testing response relaying functionality.
+//! - `test_emit`: Module for property testing the framing of the responses, re-parsed from the bytes written.
+//! - `test_selection`: Module for testing upstream selection functionality.
+//! - `test_routing`: Module for testing request routing functionality.
+//! - `test_frontend`: Module for testing the frontend options and the merge of the metrics of several pools.
+//! - `test_acl`: Module for testing the ACL rules and their precedence.
+//! - `test_upstream_host`: Module for testing the preserved, fixed and vhost-derived `Host` headers.
+//! - `test_timing`: Module for testing the breakdown of the request timings.
+//! - `test_buffer_pool`: Module for testing buffer pool functionality.
+//! - `test_spool`: Module for testing the request bodies spilled to disk and the deletion of their files.
+//! - `test_connect`: Module for testing the upstream connection retries.
+//! - `test_context`: Module for testing the configuration and metrics of the features enabled in a proxy context.
+//! - `test_resolver`: Module for testing the caching of the resolved upstream addresses.
+//! - `test_upstream_proxy`: Module for testing the tunnels through SOCKS5 and HTTP CONNECT egress proxies.
+//! - `test_redirect`: Module for testing the redirects to HTTPS.
+//! - `test_coalesce`: Module for testing the coalescing of identical requests.
+//! - `test_capacity`: Module for testing the per upstream concurrency limit and the request queue.
+//! - `test_connection_limit`: Module for testing the per client IP connection limit.
+//! - `test_idle_connections`: Module for testing the cap on the idle upstream connections and their eviction.
+//! - `test_prewarm`: Module for testing the checkout, expiry and metrics of the pre-warmed connections.
+//! - `test_drain`: Module for testing the drain file watcher.
+//! - `test_warmup`: Module for testing the warm-up of the upstream servers.
+//! - `test_health_metrics`: Module for testing health check metrics.
+//! - `test_deadline`: Module for testing the parsing and rewriting of the request deadlines.
+//! - `test_debug_routing`: Module for testing the upstream overrides and the clients they are honored from.
+//! - `test_ejection`: Module for testing the ejection of the upstream servers on 5xx but not 4xx responses.
+//! - `test_load_shedding`: Module for testing the adjustment of the shed rate to the latency and in-flight signals.
+//! - `test_fault`: Module for testing the parsing of the faults and the share of the requests they are injected into.
+//! - `test_load_report`: Module for testing the selection by reported load.
+//! - `test_byte_volume`: Module for testing the sliding window of bytes and the selection by least bytes.
+//! - `test_metrics`: Module for testing the metrics listener.
+//! - `test_dashboard`: Module for testing the request rates and the status page with and without upstream servers.
+//! - `test_admin_auth`: Module for testing the bearer token checks, the exempt scrapes and the token rotation.
+//! - `test_explain`: Module for testing the explanation of the routing of sample requests.
+//! - `test_telemetry`: Module for testing the request spans and their attributes, exported with the `otel` feature.
+//! - `test_supervisor`: Module for testing the restart of panicking tasks.
+//! - `test_static_route`: Module for testing the static responses and their conditional requests.
+//! - `test_cors`: Module for testing the CORS preflight responses and the allowed origins.
+//! - `test_state_file`: Module for testing the saving and loading of the state file.
+//! - `test_discovery`: Module for testing service discovery with synthetic catalogs.
+//! - `test_kubernetes`: Module for testing the translation of the Kubernetes EndpointSlices, with the `kubernetes`
+//!   feature.
+
+pub mod request;
+pub mod response;
+pub mod emit;
+pub mod selection;
+pub mod routing;
+pub mod frontend;
+pub mod acl;
+pub mod upstream_host;
+pub mod timing;
+pub mod buffer_pool;
+pub mod spool;
+pub mod connect;
+pub mod context;
+pub mod resolver;
+pub mod upstream_proxy;
+pub mod redirect;
+pub mod coalesce;
+pub mod capacity;
+pub mod connection_limit;
+pub mod idle_connections;
+pub mod prewarm;
+pub mod drain;
+pub mod discovery;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+pub mod http_health_checks;
+pub mod warmup;
+pub mod health_metrics;
+pub mod deadline;
+pub mod debug_routing;
+pub mod ejection;
+pub mod load_shedding;
+pub mod fault;
+pub mod load_report;
+pub mod byte_volume;
+pub mod metrics;
+pub mod dashboard;
+pub mod admin_auth;
+pub mod explain;
+pub mod telemetry;
+pub mod state_file;
+pub mod static_route;
+pub mod cors;
+pub mod supervisor;
+
+#[cfg(test)]
+mod test_active_health_check;
+#[cfg(test)]
+mod test_request;
+#[cfg(test)]
+mod test_request_properties;
+#[cfg(test)]
+mod test_response;
+#[cfg(test)]
+mod test_emit;
+#[cfg(test)]
+mod test_selection;
+#[cfg(test)]
+mod test_routing;
+#[cfg(test)]
+mod test_frontend;
+#[cfg(test)]
+mod test_acl;
+#[cfg(test)]
+mod test_upstream_host;
+#[cfg(test)]
+mod test_timing;
+#[cfg(test)]
+mod test_buffer_pool;
+#[cfg(test)]
+mod test_spool;
+#[cfg(test)]
+mod test_connect;
+#[cfg(test)]
+mod test_context;
+#[cfg(test)]
+mod test_resolver;
+#[cfg(test)]
+mod test_upstream_proxy;
+#[cfg(test)]
+mod test_redirect;
+#[cfg(test)]
+mod test_coalesce;
+#[cfg(test)]
+mod test_capacity;
+#[cfg(test)]
+mod test_connection_limit;
+#[cfg(test)]
+mod test_idle_connections;
+#[cfg(test)]
+mod test_prewarm;
+#[cfg(test)]
+mod test_drain;
+#[cfg(test)]
+mod test_warmup;
+#[cfg(test)]
+mod test_health_metrics;
+#[cfg(test)]
+mod test_deadline;
+#[cfg(test)]
+mod test_debug_routing;
+#[cfg(test)]
+mod test_ejection;
+#[cfg(test)]
+mod test_load_shedding;
+#[cfg(test)]
+mod test_fault;
+#[cfg(test)]
+mod test_load_report;
+#[cfg(test)]
+mod test_byte_volume;
+#[cfg(test)]
+mod test_metrics;
+#[cfg(test)]
+mod test_dashboard;
+#[cfg(test)]
+mod test_admin_auth;
+#[cfg(test)]
+mod test_explain;
+#[cfg(test)]
+mod test_state_file;
+#[cfg(test)]
+mod test_static_route;
+#[cfg(test)]
+mod test_cors;
+#[cfg(test)]
+mod test_supervisor;
+#[cfg(test)]
+mod test_discovery;
+#[cfg(all(test, feature = "kubernetes"))]
+mod test_kubernetes;
+#[cfg(test)]
+mod test_telemetry;
@@ -0,0 +1,115 @@
+//! # Load Report Module
+//!
+//! This module prefers the upstream servers reporting the lowest load in their responses.
+//!
+//! Some upstream servers report their current load in every response, in the `X-Server-Load` header (for example
+//! `X-Server-Load: 0.7`). With `--reported-load`, the last load reported by every upstream server is recorded as its
+//! responses are relayed, and the upstream server of a request is picked among two candidates drawn at random, the
+//! least loaded of the two (power of two choices). Comparing two random candidates rather than sending every request
+//! to the least loaded one keeps a burst of requests from piling up on a single upstream server between two reports.
+//!
+//! An upstream server that hasn't reported its load yet is assumed to be as loaded as the average of the ones that
+//! did, so it is neither flooded nor starved while it starts.
+//!
+//! ## Structures
+//!
+//! - `LoadReports`: The last load reported by every upstream server.
+//!
+//! ## Functions
+//!
+//! ### `parse_load`
+//!
+//! This function parses the value of the load header.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use rand::Rng;
+
+/// Name of the response header the upstream servers report their load in.
+pub const LOAD_HEADER: &str = "X-Server-Load";
+
+/// The last load reported by every upstream server.
+#[derive(Debug, Default)]
+pub struct LoadReports {
+    /// The last reported load, by upstream address.
+    loads: Mutex<HashMap<String, f64>>,
+}
+
+impl LoadReports {
+    /// Records the load reported by an upstream server in a response.
+    pub fn record(&self, upstream_address: &str, load: f64) {
+        self.loads.lock().unwrap().insert(upstream_address.to_string(), load);
+    }
+
+    /// Returns the last load reported by an upstream server, if it reported one.
+    pub fn load(&self, upstream_address: &str) -> Option<f64> {
+        self.loads.lock().unwrap().get(upstream_address).copied()
+    }
+
+    /// Selects the less loaded of two upstream servers drawn at random, skipping the excluded ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address_list` - The addresses of the candidate upstream servers.
+    /// * `excluded` - The upstream addresses that must not be selected.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The selected upstream address, or `None` if every candidate is excluded or the list is
+    ///   empty.
+    pub fn select(&self, upstream_address_list: &[String], excluded: &HashSet<String>) -> Option<String> {
+        let candidates: Vec<&String> = upstream_address_list.iter().filter(|address| !excluded.contains(*address)).collect();
+        if candidates.len() < 2 {
+            return candidates.first().map(|address| address.to_string());
+        }
+
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..candidates.len());
+        let second = (first + rng.gen_range(1..candidates.len())) % candidates.len();
+
+        let loads = self.loads.lock().unwrap();
+        let unreported = match loads.len() {
+            0 => 0.0,
+            reported => loads.values().sum::<f64>() / reported as f64,
+        };
+        let load = |address: &String| loads.get(address).copied().unwrap_or(unreported);
+        let selected = if load(candidates[second]) < load(candidates[first]) { second } else { first };
+        Some(candidates[selected].to_string())
+    }
+
+    /// Returns the chance of every candidate upstream server to be selected by `select`, in the order of the list.
+    ///
+    /// Every ordered pair of distinct candidates is drawn with the same chance, and its first upstream server is kept
+    /// unless the second one is less loaded, so a candidate wins the pairs it is less loaded than the other in.
+    pub fn odds(&self, upstream_address_list: &[String], excluded: &HashSet<String>) -> Vec<(String, f64)> {
+        let candidates: Vec<&String> = upstream_address_list.iter().filter(|address| !excluded.contains(*address)).collect();
+        let loads = self.loads.lock().unwrap();
+        let unreported = match loads.len() {
+            0 => 0.0,
+            reported => loads.values().sum::<f64>() / reported as f64,
+        };
+        let load = |address: &String| loads.get(address).copied().unwrap_or(unreported);
+
+        let mut wins = vec![0usize; candidates.len()];
+        for first in 0..candidates.len() {
+            for second in (0..candidates.len()).filter(|second| *second != first) {
+                wins[if load(candidates[second]) < load(candidates[first]) { second } else { first }] += 1;
+            }
+        }
+        let pairs = candidates.len() * candidates.len().saturating_sub(1);
+        upstream_address_list.iter().map(|address| {
+            let odds = match candidates.iter().position(|candidate| *candidate == address) {
+                Some(_) if pairs == 0 => 1.0,
+                Some(index) => wins[index] as f64 / pairs as f64,
+                None => 0.0,
+            };
+            (address.clone(), odds)
+        }).collect()
+    }
+}
+
+/// Parses the value of the load header, a finite and non-negative number.
+pub fn parse_load(value: &[u8]) -> Option<f64> {
+    std::str::from_utf8(value).ok()?.trim().parse::<f64>().ok().filter(|load| load.is_finite() && *load >= 0.0)
+}
@@ -0,0 +1,208 @@
+//! # Load Shedding Module
+//!
+//! This module sheds a share of the requests early when the proxy server is overloaded, rather than letting every
+//! request time out slowly.
+//!
+//! The `LoadShedder` watches two signals: the mean latency of the requests answered over a rolling window, and the
+//! number of requests in flight. While one of them is over its high-water mark, the share of new requests rejected
+//! with 503 Service Unavailable grows by `SHED_PERCENT_STEP` every `ADJUSTMENT_INTERVAL`, up to `MAX_SHED_PERCENT`
+//! so some requests still measure the upstream servers. Once both signals are back under their marks, the share
+//! shrinks by the same step until nothing is shed anymore. Every request is shed at random with the current share.
+//!
+//! The clock and the random draws are passed in by the caller, so the controller can be driven in tests.
+//!
+//! ## Structures
+//!
+//! - `Watermarks`: The high-water marks of the signals, over which requests are shed.
+//! - `Signals`: The signals of the load of the proxy server at an instant.
+//! - `LoadShedder`: The shed rate, adjusted from the signals, and the requests shed.
+//! - `InFlight`: A request counted in flight until dropped.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// Name of the upstream server reported in the access log for the requests shed.
+pub const SHED_UPSTREAM: &str = "shed";
+
+/// Time between two adjustments of the shed rate.
+pub const ADJUSTMENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Change of the shed rate at every adjustment, in percent.
+pub const SHED_PERCENT_STEP: u8 = 10;
+
+/// Highest share of the requests shed, in percent.
+pub const MAX_SHED_PERCENT: u8 = 90;
+
+/// The high-water marks of the signals, over which requests are shed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Mean latency of the requests over the window, if watched.
+    pub latency: Option<Duration>,
+
+    /// Number of requests in flight, if watched.
+    pub in_flight: Option<usize>,
+}
+
+/// The signals of the load of the proxy server at an instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signals {
+    /// Mean latency of the requests answered over the window, `None` without any.
+    pub latency: Option<Duration>,
+
+    /// Number of requests in flight.
+    pub in_flight: usize,
+}
+
+impl Watermarks {
+    /// Tells whether one of the signals is over its high-water mark.
+    pub fn exceeded_by(&self, signals: &Signals) -> bool {
+        let latency = self.latency.zip(signals.latency).is_some_and(|(mark, latency)| latency > mark);
+        let in_flight = self.in_flight.is_some_and(|mark| signals.in_flight > mark);
+        latency || in_flight
+    }
+}
+
+/// The state adjusted over time.
+#[derive(Debug)]
+struct Controller {
+    /// Latency of every request answered over the window, with the instant it was answered.
+    latencies: VecDeque<(Instant, Duration)>,
+
+    /// Share of the new requests shed, in percent from 0 to `MAX_SHED_PERCENT`.
+    shed_percent: u8,
+
+    /// The instant the shed rate was last adjusted.
+    adjusted_at: Option<Instant>,
+}
+
+/// The shed rate, adjusted from the signals, and the requests shed.
+#[derive(Debug)]
+pub struct LoadShedder {
+    /// The marks over which requests are shed.
+    watermarks: Watermarks,
+
+    /// Period the mean latency is computed over.
+    window: Duration,
+
+    /// The latencies and the shed rate.
+    controller: Mutex<Controller>,
+
+    /// Number of requests in flight.
+    in_flight: AtomicUsize,
+
+    /// Number of requests shed.
+    shed: AtomicU64,
+}
+
+impl LoadShedder {
+    /// Creates a load shedder watching the latencies over `window` and the requests in flight.
+    pub fn new(watermarks: Watermarks, window: Duration) -> LoadShedder {
+        LoadShedder {
+            watermarks,
+            window,
+            controller: Mutex::new(Controller { latencies: VecDeque::new(), shed_percent: 0, adjusted_at: None }),
+            in_flight: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a request in flight until the returned guard is dropped.
+    pub fn start_request(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { shedder: self }
+    }
+
+    /// Records the latency of a request answered at `now`.
+    pub fn record_latency(&self, now: Instant, latency: Duration) {
+        let mut controller = self.controller.lock().unwrap();
+        controller.latencies.push_back((now, latency));
+        self.expire(&mut controller, now);
+    }
+
+    /// Returns the signals at `now`.
+    pub fn signals(&self, now: Instant) -> Signals {
+        let mut controller = self.controller.lock().unwrap();
+        self.expire(&mut controller, now);
+        let latency = (!controller.latencies.is_empty()).then(|| {
+            controller.latencies.iter().map(|(_, latency)| *latency).sum::<Duration>() / controller.latencies.len() as u32
+        });
+        Signals { latency, in_flight: self.in_flight.load(Ordering::Relaxed) }
+    }
+
+    /// Adjusts the shed rate from the signals, at most once per `ADJUSTMENT_INTERVAL`, and returns it.
+    pub fn adjust(&self, now: Instant, signals: &Signals) -> f64 {
+        let mut controller = self.controller.lock().unwrap();
+        if controller.adjusted_at.is_none_or(|adjusted_at| now.saturating_duration_since(adjusted_at) >= ADJUSTMENT_INTERVAL) {
+            let previous = controller.shed_percent;
+            controller.shed_percent = match self.watermarks.exceeded_by(signals) {
+                true => (previous + SHED_PERCENT_STEP).min(MAX_SHED_PERCENT),
+                false => previous.saturating_sub(SHED_PERCENT_STEP),
+            };
+            controller.adjusted_at = Some(now);
+            if controller.shed_percent != previous {
+                log::warn!("Shedding {}% of the requests ({:?})", controller.shed_percent, signals);
+            }
+        }
+        f64::from(controller.shed_percent) / 100.0
+    }
+
+    /// Tells whether a new request must be shed, adjusting the shed rate from the current signals first.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current instant.
+    /// * `rng` - The random number generator drawing the requests shed, seedable for reproducible tests.
+    pub fn should_shed<R: Rng>(&self, now: Instant, rng: &mut R) -> bool {
+        let signals = self.signals(now);
+        let shed_rate = self.adjust(now, &signals);
+        let shed = shed_rate > 0.0 && rng.gen::<f64>() < shed_rate;
+        if shed {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        shed
+    }
+
+    /// Returns the share of the new requests shed.
+    pub fn shed_rate(&self) -> f64 {
+        f64::from(self.controller.lock().unwrap().shed_percent) / 100.0
+    }
+
+    /// Returns the number of requests shed.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Renders the shed rate as the `lb_shed_rate` gauge and the requests shed as the `lb_shed_requests_total`
+    /// counter, in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_shed_rate gauge\n");
+        let _ = writeln!(rendered, "lb_shed_rate {}", self.shed_rate());
+        rendered.push_str("# TYPE lb_shed_requests_total counter\n");
+        let _ = writeln!(rendered, "lb_shed_requests_total {}", self.shed());
+        rendered
+    }
+
+    /// Forgets the latencies older than the window.
+    fn expire(&self, controller: &mut Controller, now: Instant) {
+        while controller.latencies.front().is_some_and(|(answered_at, _)| now.saturating_duration_since(*answered_at) > self.window) {
+            controller.latencies.pop_front();
+        }
+    }
+}
+
+/// A request counted in flight until dropped.
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    shedder: &'a LoadShedder,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shedder.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,195 @@
+//! # Metrics Module
+//!
+//! This module serves the metrics of the proxy server in the Prometheus text format.
+//!
+//! The metrics listener is separate from the listener of the proxied requests, so it can be kept on a private
+//! interface. It answers `GET /metrics` with the metrics rendered at the time of the request, `GET /debug/config` with
+//! the effective configuration of the proxy server as JSON, `GET /dashboard` with the status page of the upstream
+//! servers, see the `dashboard` module, and every other request with 404 Not Found. They are rendered by the proxy
+//! server, from the state it holds, which redacts the secrets out of the configuration.
+//!
+//! `POST /explain` takes the description of a sample request as JSON and answers with the trace of how the proxy
+//! server would route it, see the `explain` module, or with 400 Bad Request when the description is invalid.
+//!
+//! With fault injection enabled, `POST /faults` sets the faults injected into the proxied requests from its JSON body,
+//! see the `fault` module, and `DELETE /faults` clears them. Both answer with the faults now injected, `null` once
+//! cleared. Without fault injection, `/faults` is answered with 404 Not Found like any other path. Every request
+//! setting or clearing the faults is written to the standard output as a JSON audit log line, with the client address
+//! and the status it was answered with.
+//!
+//! With an admin token, the requests without it are answered with 401 Unauthorized, see the `admin_auth` module.
+//!
+//! ## Structures
+//!
+//! - `MetricsEndpoints`: The handlers of the requests of the metrics listener, and the token they must present.
+//!
+//! ## Functions
+//!
+//! - `serve_metrics`: Accepts the connections of the metrics listener and answers their request.
+//! - `handle_metrics`: Answers the request of a single connection.
+//! - `is_mutating`: Tells whether a request changes the behavior of the proxy server, and is audited.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::{Method, Request, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::admin_auth::AdminAuth;
+use crate::emit::ProxyResponse;
+use crate::request::{read_client_request, Error, RequestConfig};
+
+/// Size of the buffer the metrics requests are read into, their request line and headers must fit in it.
+pub const METRICS_BUFFER_SIZE: usize = 2048;
+
+/// Time allowed for the request line and headers of a metrics request to arrive.
+pub const METRICS_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The future returned by a renderer.
+pub type RenderFuture = Pin<Box<dyn Future<Output = String> + Send>>;
+
+/// Renders the body of a response of the metrics listener: the metrics in the Prometheus text format, the
+/// configuration as JSON, or the status page as HTML.
+pub type Renderer = Arc<dyn Fn() -> RenderFuture + Send + Sync>;
+
+/// The future returned by an explainer.
+pub type ExplainFuture = Pin<Box<dyn Future<Output = Result<String, String>> + Send>>;
+
+/// Explains the routing of the sample request described by a body, as JSON, or tells why the description is invalid.
+pub type Explainer = Arc<dyn Fn(Vec<u8>) -> ExplainFuture + Send + Sync>;
+
+/// Sets the faults injected into the proxied requests from a JSON body, or clears them without a body, and returns
+/// the faults now injected as JSON, or tells why the body is invalid.
+pub type FaultSetter = Arc<dyn Fn(Option<Vec<u8>>) -> ExplainFuture + Send + Sync>;
+
+/// The handlers of the requests of the metrics listener, and the token they must present.
+#[derive(Clone)]
+pub struct MetricsEndpoints {
+    /// Renders the metrics, called for every scrape.
+    pub render: Renderer,
+
+    /// Renders the effective configuration, called for every request of `/debug/config`.
+    pub render_config: Renderer,
+
+    /// Renders the status page, called for every request of `/dashboard`.
+    pub render_dashboard: Renderer,
+
+    /// Explains the routing of a sample request, called for every request of `/explain`.
+    pub explain: Explainer,
+
+    /// Sets or clears the injected faults, called for every request of `/faults`, if fault injection is enabled.
+    pub set_faults: Option<FaultSetter>,
+
+    /// The token the requests must present, if the listener is authenticated.
+    pub auth: Option<Arc<AdminAuth>>,
+}
+
+/// Accepts the connections of the metrics listener and answers their request, each in its own task.
+///
+/// # Arguments
+///
+/// * `listener` - The listener of the metrics connections.
+/// * `endpoints` - The handlers of the requests.
+pub async fn serve_metrics(listener: TcpListener, endpoints: MetricsEndpoints) {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, client_address)) => {
+                let endpoints = endpoints.clone();
+                tokio::spawn(async move { handle_metrics(&mut stream, client_address, &endpoints).await });
+            }
+            Err(e) => log::error!("Failed to accept a metrics connection: {}", e),
+        }
+    }
+}
+
+/// Reads the request of a metrics connection and answers it with the metrics, the configuration, the status page,
+/// the explanation of a sample request or the injected faults, or with an error. The requests changing the injected
+/// faults are written to the audit log, whether they were answered or refused.
+///
+/// # Arguments
+///
+/// * `stream` - The metrics connection, closed by the caller once answered.
+/// * `client_address` - The address of the client, written to the audit log.
+/// * `endpoints` - The handlers of the requests.
+pub async fn handle_metrics<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, client_address: SocketAddr, endpoints: &MetricsEndpoints) {
+    let mut buffer = [0; METRICS_BUFFER_SIZE];
+    let config = RequestConfig { header_read_timeout: Some(METRICS_HEADER_READ_TIMEOUT), ..RequestConfig::default() };
+
+    let response = match read_client_request(stream, &mut buffer, &config).await {
+        Ok(request) => {
+            let audited = is_mutating(request.method(), request.uri().path()).then(|| (request.method().clone(), request.uri().path().to_string()));
+            let response = answer(request, endpoints).await;
+            if let Some((method, path)) = audited {
+                println!("{}", audit_log_line(client_address, &method, &path, response.status()));
+            }
+            response
+        }
+        Err(Error::ClientClosedConnection) | Err(Error::ConnectionError) => return,
+        Err(Error::RequestTimeout) => ProxyResponse::new(StatusCode::REQUEST_TIMEOUT),
+        Err(_) => ProxyResponse::new(StatusCode::BAD_REQUEST),
+    };
+
+    // every connection is closed after its response
+    let response = response.header("Connection", "close").emit(&Method::GET);
+    if let Err(e) = stream.write_all(&response).await {
+        log::error!("Failed to write the metrics: {}", e);
+    }
+}
+
+/// Answers a request of the metrics listener, or refuses it with 401 Unauthorized without the token.
+async fn answer(request: Request<Vec<u8>>, endpoints: &MetricsEndpoints) -> ProxyResponse {
+    if endpoints.auth.as_ref().is_some_and(|auth| !auth.authorize(&request)) {
+        return ProxyResponse::new(StatusCode::UNAUTHORIZED).header("WWW-Authenticate", "Bearer realm=\"admin\"");
+    }
+
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            ProxyResponse::new(StatusCode::OK).header("Content-Type", "text/plain; version=0.0.4").body((endpoints.render)().await)
+        }
+        (&Method::GET, "/debug/config") => {
+            ProxyResponse::new(StatusCode::OK).header("Content-Type", "application/json").body((endpoints.render_config)().await)
+        }
+        (&Method::GET, "/dashboard") => {
+            // the page is rendered again on every refresh, a cached copy would show a stale state
+            ProxyResponse::new(StatusCode::OK).header("Content-Type", "text/html; charset=utf-8").header("Cache-Control", "no-store").body((endpoints.render_dashboard)().await)
+        }
+        (&Method::POST, "/explain") => json_or_bad_request((endpoints.explain)(request.into_body()).await),
+        (method, "/faults") if endpoints.set_faults.is_some() => {
+            let set_faults = endpoints.set_faults.as_ref().unwrap();
+            match *method {
+                Method::POST => json_or_bad_request(set_faults(Some(request.into_body())).await),
+                Method::DELETE => json_or_bad_request(set_faults(None).await),
+                _ => ProxyResponse::new(StatusCode::METHOD_NOT_ALLOWED).header("Allow", "POST, DELETE"),
+            }
+        }
+        _ => ProxyResponse::new(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Tells whether a request for `path` with `method` changes the behavior of the proxy server, and is audited.
+pub fn is_mutating(method: &Method, path: &str) -> bool {
+    path == "/faults" && matches!(*method, Method::POST | Method::DELETE)
+}
+
+/// Returns the audit log line of a request changing the behavior of the proxy server, as JSON.
+fn audit_log_line(client_address: SocketAddr, method: &Method, path: &str, status: StatusCode) -> String {
+    serde_json::json!({
+        "audit": "admin",
+        "client": client_address.to_string(),
+        "method": method.as_str(),
+        "target": path,
+        "status": status.as_u16(),
+    }).to_string()
+}
+
+/// Returns the response carrying a JSON body, or telling why the request is invalid with 400 Bad Request.
+fn json_or_bad_request(result: Result<String, String>) -> ProxyResponse {
+    match result {
+        Ok(body) => ProxyResponse::new(StatusCode::OK).header("Content-Type", "application/json").body(body),
+        Err(reason) => ProxyResponse::new(StatusCode::BAD_REQUEST).header("Content-Type", "text/plain").body(format!("{}\n", reason)),
+    }
+}
@@ -0,0 +1,186 @@
+//! # Pre-warm Module
+//!
+//! This module opens idle connections to the healthy upstream servers ahead of the first requests.
+//!
+//! A client connection connects to an upstream server on its first request, so right after startup every request
+//! pays for a connection handshake, and the upstream servers see a burst of new connections. With `--prewarm N`, once
+//! the first health check cycle has passed, `N` connections are opened to every healthy upstream server and parked in
+//! the `PrewarmPool`. A client connection connecting to an upstream server checks out one of its pre-warmed connections
+//! before opening a new one, and keeps it for its following requests like any other upstream connection.
+//!
+//! The listener waits for the connections to be opened before accepting clients, unless `--prewarm-concurrently`
+//! is given. A connection that can't be opened is only logged: the upstream server passed its health check and stays
+//! in rotation. A parked connection is closed once it has been idle longer than `--upstream-keepalive-timeout`, and a
+//! connection the upstream server closed is dropped when it is checked out.
+//!
+//! ## Structures
+//!
+//! - `PrewarmPool`: The pre-warmed connections of every upstream server, and the counts of the ones opened, reused and
+//!   expired.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+
+use crate::connect::Connector;
+
+/// The pre-warmed connections of every upstream server, waiting to be checked out by a client connection.
+#[derive(Debug)]
+pub struct PrewarmPool {
+    /// Number of connections opened to every upstream server.
+    per_upstream: usize,
+
+    /// Time a parked connection is kept open, forever if `None`.
+    idle_timeout: Option<Duration>,
+
+    /// Parked connections of every upstream server holding at least one, and the instant each was opened.
+    parked: Mutex<HashMap<String, Vec<(TcpStream, Instant)>>>,
+
+    /// Number of connections opened.
+    opened: AtomicU64,
+
+    /// Number of connections checked out by a client connection.
+    reused: AtomicU64,
+
+    /// Number of connections closed after being idle too long, or closed by their upstream server.
+    expired: AtomicU64,
+}
+
+impl PrewarmPool {
+    /// Creates a pool of `per_upstream` connections to every upstream server, kept open for at most `idle_timeout`.
+    pub fn new(per_upstream: usize, idle_timeout: Option<Duration>) -> PrewarmPool {
+        PrewarmPool {
+            per_upstream,
+            idle_timeout,
+            parked: Mutex::new(HashMap::new()),
+            opened: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of connections opened to every upstream server.
+    pub fn per_upstream(&self) -> usize {
+        self.per_upstream
+    }
+
+    /// Opens connections to every upstream server until it has `per_upstream` parked, logging the failures.
+    ///
+    /// # Arguments
+    ///
+    /// * `connector` - The connector opening the connections, with its retries and budget.
+    /// * `upstream_address_list` - The addresses of the healthy upstream servers.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The number of connections opened.
+    pub async fn fill(&self, connector: &Connector, upstream_address_list: &[String]) -> usize {
+        self.expire(Instant::now());
+        let mut opened = 0;
+        for upstream_address in upstream_address_list {
+            let missing = self.per_upstream.saturating_sub(self.parked(upstream_address));
+            for _ in 0..missing {
+                match connector.connect(upstream_address, connector.deadline()).await {
+                    Ok(stream) => {
+                        self.park(upstream_address, stream, Instant::now());
+                        self.opened.fetch_add(1, Ordering::Relaxed);
+                        opened += 1;
+                    }
+                    Err(e) => {
+                        // the upstream server passed its health check, the requests will connect on their own
+                        log::warn!("Failed to pre-warm a connection to upstream server {}: {:?}", upstream_address, e);
+                        break;
+                    }
+                }
+            }
+        }
+        opened
+    }
+
+    /// Parks a connection to an upstream server, opened at `opened_at`.
+    pub fn park(&self, upstream_address: &str, stream: TcpStream, opened_at: Instant) {
+        self.parked.lock().unwrap().entry(upstream_address.to_string()).or_default().push((stream, opened_at));
+    }
+
+    /// Checks out a parked connection to an upstream server, skipping the expired ones and the ones the upstream
+    /// server closed.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(TcpStream)` - A connection still open, counted as reused.
+    /// * `None` - If the upstream server has no connection left.
+    pub fn take(&self, upstream_address: &str, now: Instant) -> Option<TcpStream> {
+        let mut parked = self.parked.lock().unwrap();
+        let connections = parked.get_mut(upstream_address)?;
+
+        let mut taken = None;
+        while let Some((stream, opened_at)) = connections.pop() {
+            if !self.is_expired(opened_at, now) && is_open(&stream) {
+                taken = Some(stream);
+                break;
+            }
+            self.expired.fetch_add(1, Ordering::Relaxed);
+        }
+        if connections.is_empty() {
+            parked.remove(upstream_address);
+        }
+
+        if taken.is_some() {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+        }
+        taken
+    }
+
+    /// Closes the connections idle for longer than the idle timeout at `now`.
+    pub fn expire(&self, now: Instant) {
+        let mut parked = self.parked.lock().unwrap();
+        for connections in parked.values_mut() {
+            let before = connections.len();
+            connections.retain(|(_, opened_at)| !self.is_expired(*opened_at, now));
+            self.expired.fetch_add((before - connections.len()) as u64, Ordering::Relaxed);
+        }
+        parked.retain(|_, connections| !connections.is_empty());
+    }
+
+    /// Tells whether a connection opened at `opened_at` has been idle too long at `now`.
+    fn is_expired(&self, opened_at: Instant, now: Instant) -> bool {
+        self.idle_timeout.is_some_and(|idle_timeout| now.saturating_duration_since(opened_at) >= idle_timeout)
+    }
+
+    /// Returns the number of connections parked for an upstream server.
+    pub fn parked(&self, upstream_address: &str) -> usize {
+        self.parked.lock().unwrap().get(upstream_address).map_or(0, Vec::len)
+    }
+
+    /// Returns the number of connections checked out by a client connection.
+    pub fn reused(&self) -> u64 {
+        self.reused.load(Ordering::Relaxed)
+    }
+
+    /// Renders the parked connections of every upstream server as the `lb_prewarmed_connections` gauge, and the
+    /// connections opened, reused and expired as counters, in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let parked = self.parked.lock().unwrap();
+        let mut upstreams: Vec<_> = parked.iter().collect();
+        upstreams.sort_by_key(|(upstream_address, _)| *upstream_address);
+
+        let mut rendered = String::from("# TYPE lb_prewarmed_connections gauge\n");
+        for (upstream_address, connections) in upstreams {
+            let _ = writeln!(rendered, "lb_prewarmed_connections{{upstream=\"{}\"}} {}", upstream_address, connections.len());
+        }
+        for (name, count) in [("opened", &self.opened), ("reused", &self.reused), ("expired", &self.expired)] {
+            let _ = write!(rendered, "# TYPE lb_prewarmed_connections_{0}_total counter\nlb_prewarmed_connections_{0}_total {1}\n", name, count.load(Ordering::Relaxed));
+        }
+        rendered
+    }
+}
+
+/// Tells whether an idle connection is still open: the upstream server has neither closed it nor sent anything on it.
+fn is_open(stream: &TcpStream) -> bool {
+    matches!(stream.try_read(&mut [0; 1]), Err(e) if e.kind() == ErrorKind::WouldBlock)
+}
@@ -0,0 +1,123 @@
+//! # Redirect Module
+//!
+//! This module answers the plaintext HTTP requests with a redirect to the same URL over HTTPS.
+//!
+//! The redirect listener never contacts an upstream server: it reads just enough of a request to know its host and
+//! target, answers `301 Moved Permanently` with a `Location: https://<host><path>` header, and closes the connection.
+//! The requests are read with `read_client_request`, with a small buffer and a short header read timeout, as nothing
+//! but the request head matters. A request without a `Host` header is answered with 400 Bad Request.
+//!
+//! ## Functions
+//!
+//! - `serve_redirects`: Accepts the plaintext connections and redirects their request.
+//! - `handle_redirect`: Redirects the request of a single connection.
+//! - `redirect_response`: Builds the redirect answering a request.
+
+use std::time::Duration;
+
+use http::{Method, Request, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::emit::ProxyResponse;
+use crate::request::{read_client_request, Error, RequestConfig};
+
+/// Size of the buffer the redirected requests are read into, their request line and headers must fit in it.
+pub const REDIRECT_BUFFER_SIZE: usize = 2048;
+
+/// Time allowed for the request line and headers of a redirected request to arrive.
+pub const REDIRECT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepts the plaintext connections of the listener and redirects their request to HTTPS, each in its own task.
+///
+/// # Arguments
+///
+/// * `listener` - The listener of the plaintext connections.
+/// * `hsts_max_age` - The `max-age` of the `Strict-Transport-Security` header added to the redirects, if any.
+pub async fn serve_redirects(listener: TcpListener, hsts_max_age: Option<u64>) {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                tokio::spawn(async move { handle_redirect(&mut stream, hsts_max_age).await });
+            }
+            Err(e) => log::error!("Failed to accept a plaintext connection: {}", e),
+        }
+    }
+}
+
+/// Reads the request of a plaintext connection and answers it with a redirect to HTTPS, or with an error.
+///
+/// # Arguments
+///
+/// * `stream` - The plaintext connection, closed by the caller once answered.
+/// * `hsts_max_age` - The `max-age` of the `Strict-Transport-Security` header added to the redirect, if any.
+pub async fn handle_redirect<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, hsts_max_age: Option<u64>) {
+    let mut buffer = [0; REDIRECT_BUFFER_SIZE];
+    let config = RequestConfig { header_read_timeout: Some(REDIRECT_HEADER_READ_TIMEOUT), ..RequestConfig::default() };
+
+    let response = match read_client_request(stream, &mut buffer, &config).await {
+        Ok(request) => match redirect_response(&request, hsts_max_age) {
+            Ok(response) => response,
+            Err(_) => closing(StatusCode::BAD_REQUEST),
+        },
+        Err(Error::ClientClosedConnection) | Err(Error::ConnectionError) => return,
+        Err(Error::RequestTimeout) => closing(StatusCode::REQUEST_TIMEOUT),
+        Err(_) => closing(StatusCode::BAD_REQUEST),
+    };
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        log::error!("Failed to write the redirect: {}", e);
+    }
+}
+
+/// Builds the `301 Moved Permanently` response redirecting a request to the same URL over HTTPS.
+///
+/// The port of the `Host` header is dropped, it is the port of the plaintext listener. The path and query of the
+/// request are kept as they were received.
+///
+/// # Arguments
+///
+/// * `request` - The plaintext request.
+/// * `hsts_max_age` - The `max-age` of the `Strict-Transport-Security` header added to the redirect, if any.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The raw redirect response.
+/// * `Err(Error::InvalidHost)` - If the request has no usable `Host` header.
+pub fn redirect_response(request: &Request<Vec<u8>>, hsts_max_age: Option<u64>) -> Result<String, Error> {
+    let host = request.headers().get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(host_without_port)
+        .filter(|host| !host.is_empty())
+        .ok_or(Error::InvalidHost)?;
+
+    let path = match request.uri().path_and_query() {
+        Some(path_and_query) if path_and_query.as_str().starts_with('/') => path_and_query.as_str(),
+        _ => "/",
+    };
+
+    let mut response = ProxyResponse::new(StatusCode::MOVED_PERMANENTLY).header("Location", format!("https://{}{}", host, path));
+    if let Some(max_age) = hsts_max_age {
+        response = response.header("Strict-Transport-Security", format!("max-age={}", max_age));
+    }
+    let response = response.header("Connection", "close").emit(request.method());
+    // the host and the path were parsed from the request as visible ASCII
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+/// Returns the raw error response closing a plaintext connection.
+fn closing(status: StatusCode) -> String {
+    String::from_utf8_lossy(&ProxyResponse::new(status).header("Connection", "close").emit(&Method::GET)).into_owned()
+}
+
+/// Returns the host of a `Host` header value without its port, keeping the brackets of an IPv6 address.
+fn host_without_port(host: &str) -> &str {
+    let host = host.trim();
+    if host.starts_with('[') {
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
@@ -1,10 +1,243 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use http::header::{HeaderName, HeaderValue};
 use http::Request;
+use ipnet::IpNet;
+
+use crate::acl::{self, AclRule};
+use crate::cors::CorsPolicy;
+use crate::debug_routing::DebugRouting;
+use crate::spool::{self, BodySpool, SpooledBody};
+use crate::static_route::StaticRoute;
+use crate::upstream_host::{self, ClientHost, HostRule};
+
+/// Name of the header counting how many times a request went through a proxy of this load balancer.
+pub const HOPS_HEADER: &str = "X-LB-Hops";
+
+/// The registered content codings, passed through to the upstream servers. Requests with other content codings are
+/// passed through as well, unless `reject_unknown_content_coding` is set.
+pub const KNOWN_CONTENT_CODINGS: [&str; 8] = ["br", "compress", "deflate", "gzip", "identity", "x-compress", "x-gzip", "zstd"];
+
+/// Headers revealing the IP address of the client, stripped when the client IP must not be forwarded.
+pub const CLIENT_IP_HEADERS: [&str; 3] = ["X-Forwarded-For", "X-Real-IP", "Forwarded"];
+
+/// Longest chunk size line, and longest trailer section, accepted in a chunked request body.
+pub const MAX_CHUNK_LINE_LENGTH: usize = 4096;
+
+/// Default maximum number of proxies a request may go through.
+pub const DEFAULT_MAX_HOPS: u32 = 5;
+
+/// The loopback networks, trusted by default to send an `X-LB-Hops` header.
+pub const LOOPBACK_NETWORKS: [&str; 2] = ["127.0.0.0/8", "::1/128"];
+
+/// Settings applied by the proxy to every client request before it is forwarded.
+#[derive(Debug)]
+pub struct RequestConfig {
+    /// Maximum number of proxies a request may go through before it is rejected with 508 Loop Detected.
+    pub max_hops: u32,
+
+    /// Networks whose `X-LB-Hops` header is trusted. The header is stripped from requests of any other client.
+    pub trusted_hops_from: Vec<IpNet>,
+
+    /// Add the client IP address to the forwarded requests in the `forwarded_header_format` header. When disabled,
+    /// the client-supplied headers revealing a client IP address are stripped as well.
+    pub forward_client_ip: bool,
+
+    /// Header the client IP address is added to the forwarded requests in.
+    pub forwarded_header_format: ForwardedHeaderFormat,
+
+    /// Host supplied to the HTTP/1.1 requests without a `Host` header. Without it, those requests are rejected.
+    pub default_host: Option<HeaderValue>,
+
+    /// Networks of the proxies in front of this one (CDN, cloud load balancer) whose `real_ip_header` is trusted to
+    /// carry the client IP address. Empty, the client IP address is always the address of the connection.
+    pub real_ip_from: Vec<IpNet>,
+
+    /// Header the trusted proxies put the client IP address in.
+    pub real_ip_header: RealIpHeader,
+
+    /// Time allowed for the request line and headers of a request to arrive. Without it, a client can hold a
+    /// connection by sending them a byte at a time (slowloris).
+    pub header_read_timeout: Option<Duration>,
+
+    /// How the request targets that aren't valid URIs are handled.
+    pub uri_mode: UriMode,
+
+    /// How the `.` and `..` segments of the request paths are handled.
+    pub dot_segments: DotSegments,
+
+    /// Rules denying requests by method and path, evaluated in order before the requests are routed.
+    pub acl_rules: Vec<AclRule>,
+
+    /// Time an upstream connection is kept open while idle between two requests. The forwarded requests tell the
+    /// upstream servers about it with a `Keep-Alive: timeout=` hint.
+    pub upstream_keepalive_timeout: Option<Duration>,
+
+    /// Paths answered by the proxy server itself, after the ACL rules and before the requests are routed.
+    pub static_routes: Vec<StaticRoute>,
+
+    /// The CORS policy the preflight requests are answered with by the proxy server, after the ACL rules.
+    pub cors: Option<CorsPolicy>,
+
+    /// Add the `X-Forwarded-Proto`, `X-Forwarded-Port` and `X-Forwarded-Host` headers to the forwarded requests.
+    pub forward_scheme: bool,
+
+    /// Header carrying the deadline budget of the requests, rewritten with the budget left when forwarded.
+    pub deadline_header: Option<HeaderName>,
+
+    /// Header letting the trusted clients force their requests through an upstream server, if enabled.
+    pub debug_routing: Option<DebugRouting>,
+
+    /// Spool the request bodies over its memory limit are written to, if large bodies are spilled to disk.
+    pub body_spool: Option<Arc<BodySpool>>,
+
+    /// Rules choosing the `Host` header of the forwarded requests, the client's unless one of them replaces it.
+    pub upstream_host_rules: Vec<HostRule>,
+
+    /// Reject the request bodies with a content coding outside of `KNOWN_CONTENT_CODINGS` with 415 Unsupported Media
+    /// Type, for upstream servers known to choke on them. They are passed through untouched otherwise.
+    pub reject_unknown_content_coding: bool,
+}
+
+impl Default for RequestConfig {
+    /// Returns the settings of the proxy server started without options.
+    fn default() -> RequestConfig {
+        RequestConfig {
+            max_hops: DEFAULT_MAX_HOPS,
+            trusted_hops_from: LOOPBACK_NETWORKS.iter().map(|network| network.parse().unwrap()).collect(),
+            forward_client_ip: true,
+            forwarded_header_format: ForwardedHeaderFormat::XForwardedFor,
+            default_host: None,
+            real_ip_from: Vec::new(),
+            real_ip_header: RealIpHeader::XForwardedFor,
+            header_read_timeout: None,
+            uri_mode: UriMode::Strict,
+            dot_segments: DotSegments::Keep,
+            acl_rules: Vec::new(),
+            upstream_keepalive_timeout: None,
+            static_routes: Vec::new(),
+            cors: None,
+            forward_scheme: false,
+            deadline_header: None,
+            debug_routing: None,
+            body_spool: None,
+            upstream_host_rules: Vec::new(),
+            reject_unknown_content_coding: false,
+        }
+    }
+}
+
+/// How the request targets that aren't valid URIs, such as targets with spaces or raw UTF-8, are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UriMode {
+    /// Invalid request targets are rejected with 400 Bad Request.
+    Strict,
+    /// The bytes not allowed in a request target are percent-encoded, and the encoded target is forwarded.
+    Lax,
+}
+
+impl FromStr for UriMode {
+    type Err = String;
+
+    /// Parses `strict` or `lax`.
+    fn from_str(mode: &str) -> Result<UriMode, String> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "strict" => Ok(UriMode::Strict),
+            "lax" => Ok(UriMode::Lax),
+            _ => Err(format!("expected strict or lax, got {:?}", mode)),
+        }
+    }
+}
+
+/// How the `.` and `..` segments of the request paths, which can reach files outside of the served directory on a
+/// naive upstream server (path traversal), are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DotSegments {
+    /// The path is forwarded as it was sent.
+    Keep,
+    /// The dot segments are resolved as RFC 3986 section 5.2.4 describes, `/a/../b` is forwarded as `/b`.
+    Normalize,
+    /// Requests with dot segments are rejected with 400 Bad Request.
+    Reject,
+}
+
+impl FromStr for DotSegments {
+    type Err = String;
+
+    /// Parses `keep`, `normalize` or `reject`.
+    fn from_str(mode: &str) -> Result<DotSegments, String> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "keep" => Ok(DotSegments::Keep),
+            "normalize" => Ok(DotSegments::Normalize),
+            "reject" => Ok(DotSegments::Reject),
+            _ => Err(format!("expected keep, normalize or reject, got {:?}", mode)),
+        }
+    }
+}
+
+/// Header the proxies in front of this one put the client IP address in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RealIpHeader {
+    /// `X-Forwarded-For`, a list every proxy appends the address of its client to.
+    XForwardedFor,
+    /// `X-Real-IP`, a single address.
+    XRealIp,
+    /// `CF-Connecting-IP`, the single address set by Cloudflare.
+    CfConnectingIp,
+}
+
+impl RealIpHeader {
+    /// Returns the name of the header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RealIpHeader::XForwardedFor => "X-Forwarded-For",
+            RealIpHeader::XRealIp => "X-Real-IP",
+            RealIpHeader::CfConnectingIp => "CF-Connecting-IP",
+        }
+    }
+}
+
+impl FromStr for RealIpHeader {
+    type Err = String;
+
+    /// Parses the name of one of the supported headers, ignoring its case.
+    fn from_str(name: &str) -> Result<RealIpHeader, String> {
+        [RealIpHeader::XForwardedFor, RealIpHeader::XRealIp, RealIpHeader::CfConnectingIp]
+            .into_iter()
+            .find(|header| header.name().eq_ignore_ascii_case(name.trim()))
+            .ok_or(format!("expected X-Forwarded-For, X-Real-IP or CF-Connecting-IP, got {:?}", name))
+    }
+}
+
+/// Header the client IP address is forwarded to the upstream servers in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeaderFormat {
+    /// `X-Forwarded-For: 192.0.2.1`, the de facto standard.
+    XForwardedFor,
+    /// `Forwarded: for=192.0.2.1;proto=http`, the standard header of RFC 7239.
+    Forwarded,
+}
+
+impl FromStr for ForwardedHeaderFormat {
+    type Err = String;
+
+    /// Parses `x-forwarded-for` or `forwarded`, ignoring their case.
+    fn from_str(format: &str) -> Result<ForwardedHeaderFormat, String> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ForwardedHeaderFormat::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeaderFormat::Forwarded),
+            _ => Err(format!("expected x-forwarded-for or forwarded, got {:?}", format)),
+        }
+    }
+}
 
 /// Enum representing possible errors during request handling.
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client sent an invalid HTTP request.
     MalformedRequest,
@@ -13,44 +246,146 @@ pub enum Error {
     /// The request is partial, and we could stop parsing it. The path
     /// is not found in the router
     PartialRequest,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// Encountered an I/O error when reading/writing the client stream
     ConnectionError,
+    /// The request went through more proxies than allowed, it is most likely looping
+    LoopDetected,
+    /// An HTTP/1.1 request without a `Host` header, or a request with several of them
+    InvalidHost,
+    /// The request line and headers didn't arrive within the header read timeout
+    RequestTimeout,
+    /// The request of `client`, resolved through the `real_ip_from` proxies, matched the ACL rule at index `rule`, and
+    /// must be answered with `status`
+    Denied { rule: usize, status: http::StatusCode, client: IpAddr, method: http::Method, target: String },
+    /// The request body couldn't be spilled to disk, `out_of_space` if the disk is full or over quota
+    SpoolFailed { out_of_space: bool },
+    /// The request body is framed with a transfer coding the proxy doesn't implement, answered with 501 Not Implemented
+    UnsupportedTransferCoding { coding: String },
+    /// The request body has a content coding outside of `KNOWN_CONTENT_CODINGS`, rejected with 415 Unsupported Media
+    /// Type when `reject_unknown_content_coding` is set
+    UnsupportedContentCoding { coding: String },
 }
 
 /// Serializes a request to bytes and writes those bytes to the provided stream.
 ///
-/// This function serializes the given HTTP request to bytes and writes them to the provided TcpStream.
-/// It includes the request line, headers, and body.
+/// This function serializes the given HTTP request to bytes with `serialize_request` and writes them to the provided
+/// stream. It includes the request line, headers, and body. The request is written at once with `write_all`, a
+/// single system call unless the socket is under backpressure, in which case the short writes are retried until the
+/// whole request is delivered.
 ///
 /// # Arguments
 ///
 /// * `request` - The HTTP request to be serialized and sent.
-/// * `stream` - The TcpStream to which the serialized request will be written.
+/// * `stream` - The stream to which the serialized request will be written.
 ///
 /// # Returns
 ///
-/// * `Ok(())` - If the serialization and writing process is successful.
-/// * `Err(std::io::Error)` - If there is an error during the serialization or writing process.
-fn write_to_stream(request: &Request<Vec<u8>>,stream: &mut TcpStream) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes())?;
-    stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
+/// * `Ok(usize)` - The number of bytes written, if the serialization and writing process is successful.
+/// * `Err(std::io::Error)` - If the request can't be serialized, see `format_request_line`, or writing it failed.
+pub async fn write_to_stream<W: AsyncWrite + Unpin>(request: &Request<Vec<u8>>, stream: &mut W) -> Result<usize, std::io::Error> {
+    let bytes = serialize_request(request)?;
+    stream.write_all(&bytes).await?;
+    // a body spilled to disk is streamed after the head, from the start of its file on every call
+    match request.extensions().get::<SpooledBody>() {
+        Some(spooled) => {
+            let copied = spooled.copy_to(stream).await? as usize;
+            let end = body_end(request, spooled.len());
+            stream.write_all(end).await?;
+            Ok(bytes.len() + copied + end.len())
+        }
+        None => Ok(bytes.len()),
+    }
+}
+
+
+/// Serializes a request to the bytes sent to the upstream server: request line, headers and body.
+///
+/// The body is framed the way the request declares: as a single chunk if its `Transfer-Encoding` ends with `chunked`,
+/// and otherwise with a `Content-Length` header holding its actual length. A stale `Content-Length` is replaced in
+/// place, and one is added after the other headers if the request has a body without it. The body of a request
+/// spilled to disk isn't included: the bytes returned end where the content of its file goes.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP request to serialize.
+///
+/// # Returns
+///
+/// * `Ok(Vec<u8>)` - The serialized request.
+/// * `Err(std::io::Error)` - If the request can't be serialized, see `format_request_line`.
+pub fn serialize_request(request: &Request<Vec<u8>>) -> Result<Vec<u8>, std::io::Error> {
+    let request_line = format_request_line(request)?;
+    let spooled = request.extensions().get::<SpooledBody>();
+    let body_length = spooled.map_or(request.body().len() as u64, SpooledBody::len);
+    let chunked = is_chunked(request);
+    let headers_length: usize = request.headers().iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum();
+    let mut bytes = Vec::with_capacity(request_line.len() + headers_length + request.body().len() + 32);
+
+    bytes.extend_from_slice(request_line.as_bytes());
+    bytes.extend_from_slice(b"\r\n");
+    // without chunked framing, the first Content-Length header is replaced with the actual length of the body
+    let mut content_length = match chunked {
+        true => None,
+        false => Some(body_length.to_string()),
+    };
+    let mut content_length_written = chunked;
     for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes())?;
-        stream.write(header_value.as_bytes())?;
-        stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
+        let header_value = match header_name == http::header::CONTENT_LENGTH {
+            true => match content_length.take() {
+                Some(length) => length.into_bytes(),
+                None => continue,
+            },
+            false => header_value.as_bytes().to_vec(),
+        };
+        content_length_written |= header_name == http::header::CONTENT_LENGTH;
+        bytes.extend_from_slice(header_name.as_str().as_bytes());
+        bytes.extend_from_slice(b": ");
+        bytes.extend_from_slice(&header_value);
+        bytes.extend_from_slice(b"\r\n");
     }
-    stream.write(&['\r' as u8, '\n' as u8])?;
-    if request.body().len() > 0 {
-        stream.write(request.body())?;
+    if !content_length_written && body_length > 0 {
+        bytes.extend_from_slice(format!("content-length: {}\r\n", body_length).as_bytes());
+    }
+    bytes.extend_from_slice(b"\r\n");
+
+    if chunked && body_length > 0 {
+        bytes.extend_from_slice(format!("{:x}\r\n", body_length).as_bytes());
+    }
+    bytes.extend_from_slice(request.body());
+    if spooled.is_none() {
+        bytes.extend_from_slice(body_end(request, body_length));
+    }
+    Ok(bytes)
+}
+
+
+/// Tells whether the body of a request is framed with chunked encoding, the last of its transfer codings.
+fn is_chunked(request: &Request<Vec<u8>>) -> bool {
+    request.headers().get_all(http::header::TRANSFER_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+
+/// Returns the bytes ending a body of `body_length` bytes: the end of its chunk and the last chunk with chunked
+/// framing, nothing otherwise.
+fn body_end(request: &Request<Vec<u8>>, body_length: u64) -> &'static [u8] {
+    match (is_chunked(request), body_length) {
+        (false, _) => b"",
+        (true, 0) => b"0\r\n\r\n",
+        (true, _) => b"\r\n0\r\n\r\n",
     }
-    Ok(())
 }
 
 
 /// Formats the request line of an HTTP request.
 ///
-/// This function takes an HTTP request and returns a formatted string containing the request line,
-/// including the method, URI, and version.
+/// The request target is sent in origin-form, the path and the query, whatever the form of the URI: an absolute URI
+/// is sent without its scheme and authority, and an empty path as `/`. The asterisk-form of `OPTIONS *` and the
+/// authority-form of `CONNECT` are kept. The version is the wire token of HTTP/1.0 or HTTP/1.1, the only versions
+/// spoken to the upstream servers.
 ///
 /// # Arguments
 ///
@@ -58,31 +393,52 @@ fn write_to_stream(request: &Request<Vec<u8>>,stream: &mut TcpStream) -> Result<
 ///
 /// # Returns
 ///
-/// * `String` - The formatted request line.
-pub fn format_request_line(request: &Request<Vec<u8>>) -> String {
-    format!("{} {} {:?}", request.method(), request.uri(), request.version())
+/// * `Ok(String)` - The formatted request line.
+/// * `Err(std::io::Error)` - If the version of the request is neither HTTP/1.0 nor HTTP/1.1, with the
+///   `InvalidInput` kind.
+pub fn format_request_line(request: &Request<Vec<u8>>) -> Result<String, std::io::Error> {
+    let version = match request.version() {
+        http::Version::HTTP_10 => "HTTP/1.0",
+        http::Version::HTTP_11 => "HTTP/1.1",
+        version => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} requests can't be forwarded", version)));
+        }
+    };
+    let uri = request.uri();
+    let target = match (uri.path_and_query().map(|path_and_query| path_and_query.as_str()), uri.authority()) {
+        (None, Some(authority)) if request.method() == http::Method::CONNECT => authority.as_str(),
+        (Some(query), _) if query.starts_with('?') => return Ok(format!("{} /{} {}", request.method(), query, version)),
+        (Some(path_and_query), _) if !path_and_query.is_empty() => path_and_query,
+        _ => "/",
+    };
+    Ok(format!("{} {} {}", request.method(), target, version))
 }
 
 
-/// Controls the flow of incoming requests and handles the communication with the upstream server.
+/// Controls the flow of incoming requests before they are sent to the upstream server.
+///
+/// This function reads an HTTP request from the client and processes it into the request to forward to the upstream
+/// server. Requests that went through too many proxies are rejected here, before any upstream server is contacted.
 ///
-/// This function reads an HTTP request from the client, processes it, and sends the parsed request to the upstream server.
+/// The function works on any readable stream, so it can be driven with in-memory streams in tests.
 ///
 /// # Arguments
 ///
-/// * `client_stream` - A mutable reference to the TcpStream connected to the client.
-/// * `client_ip` - The IP address of the client.
-/// * `upstream_stream` - A mutable reference to the TcpStream connected to the upstream server.
+/// * `client_stream` - A mutable reference to the stream connected to the client.
+/// * `client_address` - The address of the client, used to trust its hop count and its `real_ip_header`. Its IP
+///   address is appended to the `forwarded_header_format` header, after the client a trusted proxy reported.
+/// * `buffer` - The connection's buffer, used to read the request.
+/// * `config` - The settings applied to the request before it is forwarded.
 ///
 /// # Returns
 ///
-/// * `Ok(())` - If the handling process is successful.
+/// * `Ok(Request<Vec<u8>>)` - The request to send to the upstream server, if the handling process is successful. Its
+///   extensions carry the `ClientIp` resolved through the `real_ip_from` proxies.
+/// * `Err(Error::Denied)` - If the request matches one of the `acl_rules`.
 /// * `Err(Error)` - If there is an error during the handling process.
-/// 
-/// 
-pub fn request_controller(client_stream: &mut TcpStream, client_ip: &str, upstream_stream: &mut TcpStream) -> Result<(), Error>{
+pub async fn request_controller<S: AsyncRead + Unpin>(client_stream: &mut S, client_address: SocketAddr, buffer: &mut [u8], config: &RequestConfig) -> Result<Request<Vec<u8>>, Error>{
 
-    let req= match read_client_request(client_stream){
+    let mut req= match read_client_request(client_stream, buffer, config).await{
         Ok(req) => req,
         Err(Error::ClientClosedConnection) => {
             log::info!("Client closed the connection");
@@ -95,65 +451,479 @@ pub fn request_controller(client_stream: &mut TcpStream, client_ip: &str, upstre
         }
     };
 
-    let parsed_request = match client_request_builder(client_ip, &req){
-        Ok(parsed_request) => parsed_request,
+    ensure_host(&mut req, config.default_host.as_ref())?;
+
+    // decided on the request of the client, before the keep-alive hint meant for the upstream server is added
+    if closes_connection(&req) {
+        req.extensions_mut().insert(CloseAfterResponse);
+    }
+
+    // count this proxy in the hops of the request, only trusting the count of known proxies
+    let hops = incoming_hops(&req, Some(client_address.ip()), config) + 1;
+    if hops > config.max_hops {
+        log::error!("Request went through {} proxies, rejecting it as a forwarding loop", hops);
+        return Err(Error::LoopDetected);
+    }
+
+    // behind a trusted proxy, the client is the one the proxy reports
+    let real_ip = real_client_ip(&req, client_address.ip(), config);
+    let client = real_ip.unwrap_or(client_address.ip());
+    req.extensions_mut().insert(ClientIp(client));
+
+    // deny the request at the edge, before it is routed
+    if let Some(rule) = acl::evaluate(&config.acl_rules, &req, client) {
+        log::info!("Request {} {} denied by ACL rule {}", req.method(), req.uri(), rule);
+        let status = config.acl_rules[rule].status;
+        return Err(Error::Denied { rule, status, client, method: req.method().clone(), target: req.uri().to_string() });
+    }
+
+    if let Some(real_ip) = real_ip {
+        log::debug!("Client {} resolved from {} sent by {}", real_ip, config.real_ip_header.name(), client_address);
+    }
+
+    // the chain gets the peer, the trusted proxy already reported the client before it
+    let client_ip = client_address.ip().to_string();
+    let client_ip = config.forward_client_ip.then_some(client_ip.as_str());
+    match client_request_builder(client_ip, config.forwarded_header_format, &req, hops, &config.upstream_host_rules){
+        Ok(mut parsed_request) => {
+            if let Some(timeout) = config.upstream_keepalive_timeout {
+                add_keep_alive_hint(&mut parsed_request, timeout);
+            }
+            Ok(parsed_request)
+        }
         Err(e) => {
             log::error!("Error building client request: {:?}", e);
-            return Err(e)
+            Err(e)
         }
-    };
+    }
+}
 
+
+/// Sends a request built by `request_controller` to the upstream server.
+///
+/// # Arguments
+///
+/// * `request` - The request to forward.
+/// * `upstream_stream` - A mutable reference to the stream connected to the upstream server.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of bytes sent to the upstream server.
+/// * `Err(std::io::Error)` - If the request could not be written to the upstream server.
+pub async fn forward_request<W: AsyncWrite + Unpin>(request: &Request<Vec<u8>>, upstream_stream: &mut W) -> Result<usize, std::io::Error> {
     // transform request into bytes and write to upstream stream
-    if let Err(error) = write_to_stream(&parsed_request, upstream_stream){
-        log::error!("Failed to send request to upstream server: {}", error);
-        return Err(Error::ConnectionError);
-    };
-    log::debug!("Request sent to upstream server");
-    
-    Ok(())
+    let bytes_written = write_to_stream(request, upstream_stream).await?;
+    log::debug!("Request sent to upstream server ({} bytes)", bytes_written);
+
+    Ok(bytes_written)
 }
 
 
-/// Reads the client's HTTP request from the provided TcpStream.
+/// Reads the client's HTTP request from the provided stream.
 ///
-/// This function attempts to read the client's HTTP request from the provided TcpStream.
+/// This function attempts to read the client's HTTP request from the provided stream.
 /// If successful, it returns the parsed HTTP request. If the client closes the connection or
 /// there is an error during the read operation, an appropriate error is returned.
+/// Nothing is written to the client here: answering with an error response is up to the caller.
+///
+/// A body announced with `Content-Length` is read as well and becomes the body of the returned request, unless it is
+/// larger than the memory limit of the body spool: it is then written to a spool file, and the request carries a
+/// `SpooledBody` in its extensions instead. A body framed with `Transfer-Encoding: chunked` is decoded with
+/// `read_chunked_body`, then spilled to disk the same way if it is over the memory limit. It keeps its
+/// `Transfer-Encoding` header, and is forwarded as a single chunk, see `serialize_request`.
 ///
 /// # Arguments
 ///
-/// * `client_stream` - A mutable reference to the TcpStream connected to the client.
+/// * `client_stream` - A mutable reference to the stream connected to the client.
+/// * `buffer` - The buffer the request is read into. The request line and headers must fit in it.
+/// * `config` - The settings of the request reading: the header read timeout and how the request target is parsed.
 ///
 /// # Returns
 ///
 /// * `Result<Request<Vec<u8>>, Error>` - The result containing the parsed HTTP request or an error.
-fn read_client_request(client_stream: &mut TcpStream) -> Result<Request<Vec<u8>>, Error>{
-    let mut buffer = [0; 1024];
-    let bytes_read = match client_stream.read(&mut buffer) {
-        Ok(bytes) => bytes,
-        Err(_) => {
-            // Error handling in case the client sends a malformed request
-            let response = "HTTP/1.1 400 Bad Request\r\n\r\n";
-            client_stream.write(response.as_bytes()).unwrap();
+///   `Error::RequestTimeout` if the request line and headers didn't arrive in time, `Error::UnsupportedTransferCoding`
+///   or `Error::UnsupportedContentCoding` if the body can't be framed or must not be forwarded, see `check_codings`.
+pub async fn read_client_request<S: AsyncRead + Unpin>(client_stream: &mut S, buffer: &mut [u8], config: &RequestConfig) -> Result<Request<Vec<u8>>, Error>{
+    let (head_length, bytes_read) = match config.header_read_timeout {
+        Some(header_read_timeout) => match tokio::time::timeout(header_read_timeout, read_request_head(client_stream, buffer, config.uri_mode)).await {
+            Ok(head) => head?,
+            Err(_) => {
+                log::error!("Request headers did not arrive within {:?}", header_read_timeout);
+                return Err(Error::RequestTimeout);
+            }
+        },
+        None => read_request_head(client_stream, buffer, config.uri_mode).await?,
+    };
+
+    let mut request = parse_client_request(&buffer[..head_length], config)?;
+
+    check_codings(&request, config)?;
+
+    if request.headers().contains_key(http::header::TRANSFER_ENCODING) {
+        let received = buffer[head_length..bytes_read].to_vec();
+        let body = read_chunked_body(&received, client_stream, buffer).await?;
+        match config.body_spool.as_ref().filter(|body_spool| body_spool.spills(body.len())) {
+            Some(body_spool) => {
+                let spooled = body_spool.spill(&body, &mut tokio::io::empty(), body.len(), buffer).await.map_err(spool_failed)?;
+                request.extensions_mut().insert(spooled);
+            }
+            None => *request.body_mut() = body,
+        }
+        return Ok(request);
+    }
+
+    let content_length = request_content_length(&request)?;
+
+    if let Some(body_spool) = config.body_spool.as_ref().filter(|body_spool| body_spool.spills(content_length)) {
+        let received = buffer[head_length..bytes_read.min(head_length + content_length)].to_vec();
+        let spooled = body_spool.spill(&received, client_stream, content_length, buffer).await.map_err(spool_failed)?;
+        request.extensions_mut().insert(spooled);
+        return Ok(request);
+    }
+
+    // the part of the body read along with the headers, then the rest of it
+    let body = request.body_mut();
+    body.extend_from_slice(&buffer[head_length..bytes_read.min(head_length + content_length)]);
+    while body.len() < content_length {
+        let remaining = (content_length - body.len()).min(buffer.len());
+        match client_stream.read(&mut buffer[..remaining]).await {
+            Ok(0) => {
+                log::error!("Client closed the connection in the middle of the request body");
+                return Err(Error::ConnectionError);
+            }
+            Ok(bytes) => body.extend_from_slice(&buffer[..bytes]),
+            Err(e) => {
+                log::error!("Failed to read from client: {}", e);
+                return Err(Error::ConnectionError);
+            }
+        }
+    }
+
+    Ok(request)
+}
+
+
+/// Logs why a request body couldn't be spilled to disk, and returns the error the request fails with.
+fn spool_failed(e: spool::Error) -> Error {
+    match e {
+        spool::Error::ClientFailed => {
+            log::error!("Client closed the connection in the middle of the request body");
+            Error::ConnectionError
+        }
+        spool::Error::Storage(e) => {
+            log::error!("Failed to spill the request body to disk: {}", e);
+            Error::SpoolFailed { out_of_space: spool::is_out_of_space(&e) }
+        }
+    }
+}
+
+
+/// Reads a request body framed with chunked encoding, as RFC 9112 section 7.1 describes, and returns it decoded.
+///
+/// The chunk extensions are ignored, and the trailer fields are read and dropped. The chunk size lines, chunk ends
+/// and trailer fields must end with CRLF, and a size line or the trailer section longer than
+/// `MAX_CHUNK_LINE_LENGTH` is rejected, so a client can't make the proxy buffer an endless line.
+///
+/// # Arguments
+///
+/// * `received` - The start of the body, read along with the request line and headers.
+/// * `client_stream` - A mutable reference to the stream connected to the client.
+/// * `buffer` - The buffer the rest of the body is read through.
+///
+/// # Returns
+///
+/// * `Ok(Vec<u8>)` - The decoded body.
+/// * `Err(Error::MalformedRequest)` - If a chunk size or a chunk end is invalid, or a line is too long.
+/// * `Err(Error::ConnectionError)` - If the client closed the connection, or the read failed, before the last chunk.
+pub async fn read_chunked_body<S: AsyncRead + Unpin>(received: &[u8], client_stream: &mut S, buffer: &mut [u8]) -> Result<Vec<u8>, Error> {
+    let mut pending = received.to_vec();
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_chunk_line(&mut pending, client_stream, buffer).await?;
+        let size = parse_chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        let chunk_end = size.checked_add(2).ok_or(Error::MalformedRequest)?;
+        while pending.len() < chunk_end {
+            read_more_body(&mut pending, client_stream, buffer).await?;
+        }
+        if &pending[size..chunk_end] != b"\r\n" {
+            log::error!("Chunk of {} bytes not followed by CRLF", size);
             return Err(Error::MalformedRequest);
         }
-    };
+        body.extend_from_slice(&pending[..size]);
+        pending.drain(..chunk_end);
+    }
+
+    // the trailer section ends with an empty line
+    let mut trailers_length = 0;
+    loop {
+        let line = read_chunk_line(&mut pending, client_stream, buffer).await?;
+        if line.is_empty() {
+            return Ok(body);
+        }
+        trailers_length += line.len() + 2;
+        if trailers_length > MAX_CHUNK_LINE_LENGTH {
+            log::error!("Trailer section of the chunked request body longer than {} bytes", MAX_CHUNK_LINE_LENGTH);
+            return Err(Error::MalformedRequest);
+        }
+    }
+}
+
+
+/// Takes the next line of a chunked body out of `pending`, without its CRLF, reading from the client until it's
+/// complete.
+async fn read_chunk_line<S: AsyncRead + Unpin>(pending: &mut Vec<u8>, client_stream: &mut S, buffer: &mut [u8]) -> Result<Vec<u8>, Error> {
+    loop {
+        let line_end = pending.iter().take(MAX_CHUNK_LINE_LENGTH + 2).position(|byte| *byte == b'\n');
+        if let Some(end) = line_end {
+            if end == 0 || pending[end - 1] != b'\r' {
+                log::error!("Line of the chunked request body ending with a bare LF");
+                return Err(Error::MalformedRequest);
+            }
+            let line = pending[..end - 1].to_vec();
+            pending.drain(..end + 1);
+            return Ok(line);
+        }
+        if pending.len() > MAX_CHUNK_LINE_LENGTH + 1 {
+            log::error!("Line of the chunked request body longer than {} bytes", MAX_CHUNK_LINE_LENGTH);
+            return Err(Error::MalformedRequest);
+        }
+        read_more_body(pending, client_stream, buffer).await?;
+    }
+}
+
+
+/// Reads the next bytes of a request body from the client into `pending`.
+async fn read_more_body<S: AsyncRead + Unpin>(pending: &mut Vec<u8>, client_stream: &mut S, buffer: &mut [u8]) -> Result<(), Error> {
+    match client_stream.read(buffer).await {
+        Ok(0) => {
+            log::error!("Client closed the connection in the middle of the request body");
+            Err(Error::ConnectionError)
+        }
+        Ok(bytes) => {
+            pending.extend_from_slice(&buffer[..bytes]);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to read from client: {}", e);
+            Err(Error::ConnectionError)
+        }
+    }
+}
+
+
+/// Parses the size of a chunk from its size line, such as `1a` or `1a;name=value`, ignoring the extensions.
+///
+/// The size must be made of hexadecimal digits only, at most 16 of them.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let size = line.split(|byte| *byte == b';').next().unwrap_or_default();
+    let size = size.trim_ascii_end();
+    if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+        log::error!("Invalid chunk size line {:?}", String::from_utf8_lossy(line));
+        return Err(Error::MalformedRequest);
+    }
+    let size = u64::from_str_radix(std::str::from_utf8(size).unwrap_or_default(), 16).map_err(|_| Error::MalformedRequest)?;
+    usize::try_from(size).map_err(|_| Error::MalformedRequest)
+}
+
+
+/// Reads the request line and headers of a client request into the buffer.
+///
+/// The request line and headers may arrive in several segments, reading goes on until they are complete.
+///
+/// # Arguments
+///
+/// * `client_stream` - A mutable reference to the stream connected to the client.
+/// * `buffer` - The buffer the request is read into. The request line and headers must fit in it.
+/// * `uri_mode` - How the request target is parsed, a lax target may hold spaces.
+///
+/// # Returns
+///
+/// * `Ok((usize, usize))` - The length of the request line and headers, and the number of bytes read, which may
+///   include the start of the body.
+/// * `Err(Error)` - If the client closed the connection, the read failed or the headers don't fit in the buffer.
+async fn read_request_head<S: AsyncRead + Unpin>(client_stream: &mut S, buffer: &mut [u8], uri_mode: UriMode) -> Result<(usize, usize), Error> {
+    let mut bytes_read = 0;
+
+    loop {
+        let bytes = match client_stream.read(&mut buffer[bytes_read..]).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                // the connection with the client failed, there is nobody left to answer
+                log::error!("Failed to read from client: {}", e);
+                return Err(Error::ConnectionError);
+            }
+        };
+
+        // If no bytes are read, the client closed the connection
+        if bytes == 0 {
+            log::info!("Client closed the connection");
+            return Err(Error::ClientClosedConnection);
+        }
+        bytes_read += bytes;
+
+        if let Some(head_length) = request_head_length(&buffer[..bytes_read], uri_mode) {
+            return Ok((head_length, bytes_read));
+        }
+
+        // the request line and headers don't fit in the buffer
+        if bytes_read == buffer.len() {
+            log::error!("Request headers do not fit in a {} bytes buffer", buffer.len());
+            return Err(Error::MalformedRequest);
+        }
+    }
+}
+
+
+/// Checks the transfer and content codings of a request body before it is read.
+///
+/// A transfer coding other than `chunked` can't be framed, so the request is answered with 501 Not Implemented as
+/// RFC 7230 section 3.3.1 recommends. A chunked body is only accepted chunked once, and without a `Content-Length`
+/// header: a request carrying both is how requests get smuggled, so it is rejected as RFC 9112 section 6.1 allows
+/// rather than trusting the upstream server to frame it the same way. The content codings are
+/// the upstream server's business: they are passed through untouched, the unknown ones only rejected with 415
+/// Unsupported Media Type if `reject_unknown_content_coding` is set. Every decision is logged with the coding.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the request can be forwarded as it is.
+/// * `Err(Error::UnsupportedTransferCoding)` - If a transfer coding isn't implemented.
+/// * `Err(Error::UnsupportedContentCoding)` - If an unknown content coding is rejected.
+/// * `Err(Error::MalformedRequest)` - If the body is chunked twice or has a `Content-Length` header too, or a coding
+///   header isn't a valid list.
+fn check_codings(request: &Request<Vec<u8>>, config: &RequestConfig) -> Result<(), Error> {
+    if request.headers().contains_key(http::header::TRANSFER_ENCODING) {
+        let transfer_codings = coding_list(request, http::header::TRANSFER_ENCODING)?;
+        if let Some(coding) = transfer_codings.iter().find(|coding| *coding != "chunked") {
+            log::error!("Rejecting the request with 501 Not Implemented, unsupported transfer coding {:?}", coding);
+            return Err(Error::UnsupportedTransferCoding { coding: coding.clone() });
+        }
+        if transfer_codings.len() != 1 || request.headers().contains_key(http::header::CONTENT_LENGTH) {
+            log::error!("Rejecting the chunked request body framed twice, or with a Content-Length header too");
+            return Err(Error::MalformedRequest);
+        }
+    }
+
+    for coding in coding_list(request, http::header::CONTENT_ENCODING)? {
+        if KNOWN_CONTENT_CODINGS.contains(&coding.as_str()) {
+            continue;
+        }
+        if config.reject_unknown_content_coding {
+            log::error!("Rejecting the request with 415 Unsupported Media Type, unknown content coding {:?}", coding);
+            return Err(Error::UnsupportedContentCoding { coding });
+        }
+        log::warn!("Passing the request with the unknown content coding {:?} through", coding);
+    }
+
+    Ok(())
+}
+
+
+/// Returns the lowercase codings listed by the `name` headers of a request, without their parameters.
+fn coding_list(request: &Request<Vec<u8>>, name: http::header::HeaderName) -> Result<Vec<String>, Error> {
+    let mut codings = Vec::new();
+    for value in request.headers().get_all(name) {
+        let value = value.to_str().map_err(|_| Error::MalformedRequest)?;
+        codings.extend(value.split(',')
+            .map(|coding| coding.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty()));
+    }
+    Ok(codings)
+}
+
+
+/// Returns the length of the body announced by the `Content-Length` header of a request, 0 without the header.
+///
+/// The value must be made of digits only. Several `Content-Length` headers are only accepted if they all hold the same
+/// value: an upstream server picking another one than the proxy would read the body differently, which is how
+/// requests get smuggled.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The length of the body.
+/// * `Err(Error::MalformedRequest)` - If a value is invalid or the values disagree.
+fn request_content_length(request: &Request<Vec<u8>>) -> Result<usize, Error> {
+    let mut content_length = None;
+
+    for value in request.headers().get_all(http::header::CONTENT_LENGTH) {
+        let value = value.to_str().map_err(|_| Error::MalformedRequest)?.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(Error::MalformedRequest);
+        }
+        let length = value.parse::<usize>().map_err(|_| Error::MalformedRequest)?;
+
+        if content_length.is_some_and(|previous| previous != length) {
+            return Err(Error::MalformedRequest);
+        }
+        content_length = Some(length);
+    }
+
+    Ok(content_length.unwrap_or(0))
+}
+
+
+/// Returns the length of the request line and headers, once the bytes read so far hold all of them.
+///
+/// Invalid bytes count as complete: reading more of them won't make the request valid, the parser will reject it.
+/// In lax mode, the request target may hold bytes the parser rejects, the head then ends with the first empty line.
+fn request_head_length(buffer: &[u8], uri_mode: UriMode) -> Option<usize> {
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut req = httparse::Request::new(&mut headers);
+
+    match req.parse(buffer) {
+        Ok(httparse::Status::Complete(head_length)) => Some(head_length),
+        Ok(httparse::Status::Partial) => None,
+        Err(_) if uri_mode == UriMode::Lax => {
+            let crlf_end = buffer.windows(4).position(|window| window == b"\r\n\r\n").map(|position| position + 4);
+            let lf_end = buffer.windows(2).position(|window| window == b"\n\n").map(|position| position + 2);
+            crlf_end.into_iter().chain(lf_end).min()
+        }
+        Err(_) => Some(buffer.len()),
+    }
+}
 
-    // If no bytes are read, the client closed the connection
-    if bytes_read == 0 {
-        log::info!("Client closed the connection");
-        // return Err(Error::ClientClosedConnection).expect("Client closed the connection. EXPECTED");
-    //     return and expect are not compatible
-    //     do something if the program panics
-        return Err(Error::ClientClosedConnection);
-    } 
+
+/// Parses the bytes of a client request into an HTTP request.
+///
+/// This function parses the request line and headers read from the client and normalizes the request target
+/// with `normalize_request_target` before building the request that will be forwarded.
+///
+/// In lax mode, the bytes of the request target that aren't allowed in a URI are percent-encoded first with
+/// `encode_request_line`.
+///
+/// # Arguments
+///
+/// * `buffer` - The bytes read from the client.
+/// * `config` - The settings telling how the request target is parsed.
+///
+/// # Returns
+///
+/// * `Result<Request<Vec<u8>>, Error>` - The result containing the parsed HTTP request or an error.
+pub fn parse_client_request(buffer: &[u8], config: &RequestConfig) -> Result<Request<Vec<u8>>, Error>{
+    let encoded_head;
+    let buffer = match config.uri_mode {
+        UriMode::Lax => match encode_request_line(buffer) {
+            Some(head) => {
+                encoded_head = head;
+                &encoded_head[..]
+            }
+            None => buffer,
+        },
+        UriMode::Strict => buffer,
+    };
 
     // read the request from the client
     let mut headers = [httparse::EMPTY_HEADER; 16];
 
     let mut req = httparse::Request::new(&mut headers as &mut [httparse::Header]);
 
-    let res = req.parse(&buffer).unwrap();
+    let res = match req.parse(buffer) {
+        Ok(res) => res,
+        Err(_) => return Err(Error::MalformedRequest),
+    };
 
     // if the request is partial, we could stop parsing
     if res.is_partial() {
@@ -171,11 +941,21 @@ fn read_client_request(client_stream: &mut TcpStream) -> Result<Request<Vec<u8>>
         }
     }
 
+    let method = req.method.unwrap();
+    let target = req.path.unwrap();
+    let uri = normalize_request_target(method, target)?;
+    let uri = handle_dot_segments(uri, config.dot_segments)?;
+
+    let version = match req.version {
+        Some(0) => http::Version::HTTP_10,
+        _ => http::Version::HTTP_11,
+    };
+
     // build parsed request with method, uri and version
     let mut parsed_request = http::Request::builder()
-        .method(req.method.unwrap())
-        .uri(req.path.unwrap())
-        .version(http::Version::HTTP_11);
+        .method(method)
+        .uri(uri)
+        .version(version);
 
     // add headers to parsed request
     for header in req.headers {
@@ -183,28 +963,511 @@ fn read_client_request(client_stream: &mut TcpStream) -> Result<Request<Vec<u8>>
     }
 
     // build parsed request with body and unwrap it
-    let parsed_request = parsed_request.body(Vec::<u8>::new()).unwrap();
+    let mut parsed_request = parsed_request.body(Vec::<u8>::new()).unwrap();
+
+    // the authority of an absolute-form target replaces the Host header, as RFC 7230 section 5.4 requires
+    if let Some(authority) = absolute_form_authority(target) {
+        parsed_request.headers_mut().insert(http::header::HOST, authority);
+    }
+
+    Ok(parsed_request)
+}
+
+
+/// Normalizes the request target of a client request into the URI forwarded to the upstream server.
+///
+/// The four request-target forms of RFC 7230 are handled as follows:
+///
+/// * origin-form (`/path?query`) - forwarded as-is.
+/// * absolute-form (`http://host/path?query`) - the path and query are extracted, so the upstream receives an origin-form target.
+///   The host becomes the `Host` header of the request, see `absolute_form_authority`.
+/// * asterisk-form (`*`) - only valid for `OPTIONS`, forwarded as-is.
+/// * authority-form (`host:port`) - only valid for `CONNECT`, rejected for any other method.
+///
+/// # Arguments
+///
+/// * `method` - The method of the client request.
+/// * `target` - The raw request target from the request line.
+///
+/// # Returns
+///
+/// * `Ok(http::Uri)` - The URI to forward to the upstream server.
+/// * `Err(Error::MalformedRequest)` - If the target is invalid or not allowed for the method.
+fn normalize_request_target(method: &str, target: &str) -> Result<http::Uri, Error> {
+    // asterisk-form is only meaningful for server-wide OPTIONS requests
+    if target == "*" {
+        return match method {
+            "OPTIONS" => Ok(http::Uri::from_static("*")),
+            _ => Err(Error::MalformedRequest),
+        };
+    }
+
+    // raw UTF-8 must be percent-encoded and a `%` must start an escape, which the `http` crate doesn't check
+    if !target.is_ascii() || has_invalid_escape(target.as_bytes()) {
+        return Err(Error::MalformedRequest);
+    }
+
+    let uri = match target.parse::<http::Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return Err(Error::MalformedRequest),
+    };
+
+    // origin-form
+    if target.starts_with('/') {
+        return Ok(uri);
+    }
+
+    // absolute-form: keep only the path and the query
+    if uri.scheme().is_some() {
+        let origin = uri.path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+        return origin.parse::<http::Uri>().map_err(|_| Error::MalformedRequest);
+    }
+
+    // authority-form
+    if uri.authority().is_some() && method == "CONNECT" {
+        return Ok(uri);
+    }
+
+    Err(Error::MalformedRequest)
+}
+
+
+/// Returns the host of an absolute-form request target (`http://host:port/path`), without its user information.
+///
+/// # Arguments
+///
+/// * `target` - The raw request target from the request line.
+///
+/// # Returns
+///
+/// * `Some(HeaderValue)` - The `Host` header value the request must be forwarded with.
+/// * `None` - If the target isn't in absolute-form.
+fn absolute_form_authority(target: &str) -> Option<HeaderValue> {
+    let uri = target.parse::<http::Uri>().ok()?;
+    uri.scheme()?;
+    let host = uri.authority()?.as_str().rsplit('@').next()?;
+    HeaderValue::from_str(host).ok()
+}
+
+
+/// Tells whether a `%` of the request target isn't followed by two hexadecimal digits.
+fn has_invalid_escape(target: &[u8]) -> bool {
+    target.iter().enumerate().any(|(position, byte)| *byte == b'%' && !is_escape(&target[position..]))
+}
+
+
+/// Tells whether `bytes` starts with a percent-encoded byte, such as `%2F`.
+fn is_escape(bytes: &[u8]) -> bool {
+    bytes.len() >= 3 && bytes[0] == b'%' && bytes[1].is_ascii_hexdigit() && bytes[2].is_ascii_hexdigit()
+}
+
+
+/// Percent-encodes the bytes of the request target that aren't allowed in a URI, for the lax mode.
+///
+/// The request target is everything between the first and the last space of the request line, so it may hold
+/// spaces. Raw UTF-8, spaces, control characters and the other bytes outside of the RFC 3986 characters are encoded,
+/// as is a `%` that doesn't start an escape. The original target is logged when it is rewritten.
+///
+/// # Arguments
+///
+/// * `head` - The request line and headers read from the client.
+///
+/// # Returns
+///
+/// * `Some(Vec<u8>)` - The head with the encoded request target.
+/// * `None` - If the request target doesn't need to be encoded, or the request line can't be split.
+pub fn encode_request_line(head: &[u8]) -> Option<Vec<u8>> {
+    let line_end = head.iter().position(|byte| *byte == b'\n')?;
+    let line = head[..line_end].strip_suffix(b"\r").unwrap_or(&head[..line_end]);
+
+    let target_start = line.iter().position(|byte| *byte == b' ')? + 1;
+    let target_end = line.iter().rposition(|byte| *byte == b' ')?;
+    if target_end <= target_start {
+        return None;
+    }
+    let target = &line[target_start..target_end];
+
+    let is_allowed = |position: usize, byte: u8| match byte {
+        b'%' => is_escape(&target[position..]),
+        _ => byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/?".contains(&byte),
+    };
+    if target.iter().enumerate().all(|(position, byte)| is_allowed(position, *byte)) {
+        return None;
+    }
+
+    let mut encoded = Vec::with_capacity(head.len() + target.len() * 2);
+    encoded.extend_from_slice(&line[..target_start]);
+    for (position, byte) in target.iter().enumerate() {
+        if is_allowed(position, *byte) {
+            encoded.push(*byte);
+        } else {
+            encoded.extend_from_slice(format!("%{:02X}", byte).as_bytes());
+        }
+    }
+    log::info!("Request target {:?} forwarded as {:?}", String::from_utf8_lossy(target), String::from_utf8_lossy(&encoded[target_start..]));
+
+    encoded.extend_from_slice(&line[target_end..]);
+    encoded.extend_from_slice(b"\r\n");
+    encoded.extend_from_slice(&head[line_end + 1..]);
+    Some(encoded)
+}
+
+
+/// A dot segment of a request path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DotSegment {
+    /// `.`, the current directory.
+    Current,
+    /// `..`, the parent directory.
+    Parent,
+}
+
+
+/// Returns the dot segment a path segment is, if any, its dots may be percent-encoded (`%2e`).
+///
+/// Overlong UTF-8 encodings of a dot (`%c0%ae`) aren't dots: they are forwarded as they are, and an upstream server
+/// decoding them as dots is broken.
+fn dot_segment(segment: &str) -> Option<DotSegment> {
+    match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+        "." => Some(DotSegment::Current),
+        ".." => Some(DotSegment::Parent),
+        _ => None,
+    }
+}
+
+
+/// Applies the dot segments mode to the path of a request URI.
+///
+/// # Arguments
+///
+/// * `uri` - The URI the request is forwarded with.
+/// * `dot_segments` - How the dot segments are handled.
+///
+/// # Returns
+///
+/// * `Ok(http::Uri)` - The URI, with its dot segments resolved when normalizing.
+/// * `Err(Error::MalformedRequest)` - If the path has dot segments and they are rejected.
+fn handle_dot_segments(uri: http::Uri, dot_segments: DotSegments) -> Result<http::Uri, Error> {
+    let path = uri.path();
+    if dot_segments == DotSegments::Keep || !path.starts_with('/') || !path.split('/').any(|segment| dot_segment(segment).is_some()) {
+        return Ok(uri);
+    }
+    if dot_segments == DotSegments::Reject {
+        log::error!("Request path {:?} has dot segments", path);
+        return Err(Error::MalformedRequest);
+    }
 
-    return Ok(parsed_request)
+    // resolve the segments as RFC 3986 section 5.2.4 does, a trailing dot segment leaves a trailing slash
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let mut output = Vec::with_capacity(segments.len());
+    for (position, segment) in segments.iter().enumerate() {
+        let is_last = position == segments.len() - 1;
+        match dot_segment(segment) {
+            Some(DotSegment::Current) => {}
+            Some(DotSegment::Parent) => {
+                output.pop();
+            }
+            None => output.push(*segment),
+        }
+        if is_last && dot_segment(segment).is_some() {
+            output.push("");
+        }
+    }
+
+    let mut normalized = format!("/{}", output.join("/"));
+    if let Some(query) = uri.query() {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    normalized.parse::<http::Uri>().map_err(|_| Error::MalformedRequest)
+}
+
+
+
+
+/// Makes sure the request carries a single `Host` header.
+///
+/// HTTP/1.1 requires the `Host` header, and the upstream servers need it to tell virtual hosts apart. An HTTP/1.1
+/// request without it gets `default_host` if one is configured and is rejected otherwise. HTTP/1.0 requests may omit
+/// it, they are forwarded with the address of their upstream server instead, see `supply_upstream_host`. Several
+/// `Host` headers holding the same host, ignoring case, are collapsed into the first one. A request with differing
+/// `Host` headers is always rejected, as RFC 7230 section 5.4 requires: the upstream servers could each pick another
+/// one.
+///
+/// # Arguments
+///
+/// * `req` - The client request, `default_host` is added to it if needed.
+/// * `default_host` - The host supplied to the requests without one, if any.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the request can be forwarded.
+/// * `Err(Error::InvalidHost)` - If the request must be answered with 400 Bad Request.
+pub fn ensure_host(req: &mut Request<Vec<u8>>, default_host: Option<&HeaderValue>) -> Result<(), Error> {
+    match req.headers().get_all(http::header::HOST).iter().count() {
+        1 => Ok(()),
+        0 if req.version() == http::Version::HTTP_10 => Ok(()),
+        0 => match default_host {
+            Some(default_host) => {
+                log::debug!("Request without Host header, supplying {:?}", default_host);
+                req.headers_mut().insert(http::header::HOST, default_host.clone());
+                Ok(())
+            }
+            None => {
+                log::error!("HTTP/1.1 request without Host header");
+                Err(Error::InvalidHost)
+            }
+        },
+        _ => {
+            let mut hosts = req.headers().get_all(http::header::HOST).iter();
+            let first = hosts.next().cloned().unwrap_or(HeaderValue::from_static(""));
+            if !hosts.all(|host| host.as_bytes().eq_ignore_ascii_case(first.as_bytes())) {
+                log::error!("Request with several differing Host headers");
+                return Err(Error::InvalidHost);
+            }
+            log::debug!("Collapsing the repeated Host headers {:?} into one", first);
+            req.headers_mut().insert(http::header::HOST, first);
+            Ok(())
+        }
+    }
 }
 
 
+/// Gives an HTTP/1.0 request without a `Host` header the address of the upstream server it is sent to, so the
+/// upstream servers requiring one don't reject it. A request with a `Host` header is left untouched.
+pub fn supply_upstream_host(request: &mut Request<Vec<u8>>, upstream_address: &str) {
+    if request.headers().contains_key(http::header::HOST) {
+        return;
+    }
+    if let Ok(host) = HeaderValue::from_str(upstream_address) {
+        log::debug!("Request without Host header, forwarding it with the upstream address {}", upstream_address);
+        request.headers_mut().insert(http::header::HOST, host);
+    }
+}
 
 
-/// Builds a modified client request by adding the client's IP and returns the new request.
+/// Returns the number of proxies the request already went through, according to its `X-LB-Hops` header.
+///
+/// The header is only trusted when the request comes from one of the `trusted_hops_from` networks, so clients can't
+/// fake it to bypass the loop detection or to get their requests rejected. Requests from other clients, and requests
+/// with an invalid header, count as not having been proxied yet.
 ///
 /// # Arguments
 ///
-/// * `client_ip` - A string representing the client's IP address.
+/// * `req` - The client request.
+/// * `peer_ip` - The IP address of the peer that sent the request, if known.
+/// * `config` - The request settings, holding the trusted networks.
+///
+/// # Returns
+///
+/// * `u32` - The number of hops the request already made.
+pub fn incoming_hops(req: &Request<Vec<u8>>, peer_ip: Option<IpAddr>, config: &RequestConfig) -> u32 {
+    let trusted = match peer_ip {
+        Some(ip) => config.trusted_hops_from.iter().any(|network| network.contains(&ip)),
+        None => false,
+    };
+    if !trusted {
+        return 0;
+    }
+
+    req.headers()
+        .get(HOPS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+}
+
+
+
+
+/// Returns the client IP address reported by a trusted proxy in the `real_ip_header` of the request.
+///
+/// Only the proxies of the `real_ip_from` networks are trusted, the header sent by any other peer is ignored. In an
+/// `X-Forwarded-For` chain every proxy appends the address of its own client, so the chain is read from the right and
+/// the first address that isn't a trusted proxy is the client: the addresses on its left were written by the client
+/// itself and can't be trusted. If every address of the chain is a trusted proxy, the leftmost one is the client.
+///
+/// # Arguments
+///
+/// * `req` - The client request.
+/// * `peer_ip` - The IP address of the connection the request came from.
+/// * `config` - The settings holding the trusted networks and the header to read.
+///
+/// # Returns
+///
+/// * `Some(IpAddr)` - The client IP address reported by the trusted peer.
+/// * `None` - If the peer isn't trusted, or the header is missing or invalid. The client is then the peer itself.
+pub fn real_client_ip(req: &Request<Vec<u8>>, peer_ip: IpAddr, config: &RequestConfig) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| config.real_ip_from.iter().any(|network| network.contains(ip));
+    if !is_trusted(&peer_ip) {
+        return None;
+    }
+
+    let mut values = req.headers().get_all(config.real_ip_header.name()).iter();
+    if config.real_ip_header != RealIpHeader::XForwardedFor {
+        // a single address, a repeated header can't be trusted
+        let value = values.next()?;
+        return match values.next() {
+            Some(_) => None,
+            None => parse_forwarded_ip(value.to_str().ok()?),
+        };
+    }
+
+    let chain = values.map(|value| value.to_str().ok()).collect::<Option<Vec<_>>>()?;
+    let mut client_ip = None;
+    for entry in chain.iter().flat_map(|value| value.split(',')).rev() {
+        let ip = parse_forwarded_ip(entry)?;
+        client_ip = Some(ip);
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client_ip
+}
+
+
+/// Returns the node of a client address in a `Forwarded` element, quoted when it holds a port or an IPv6 address as
+/// RFC 7239 section 6 requires (`192.0.2.1`, `"192.0.2.1:1234"`, `"[2001:db8::1]"`).
+fn forwarded_node(client_ip: &str) -> String {
+    match client_ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.to_string(),
+        Ok(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        Err(_) => format!("\"{}\"", client_ip),
+    }
+}
+
+
+/// Parses an address of a forwarding header, with or without a port (`192.0.2.1`, `192.0.2.1:1234`, `[2001:db8::1]:1234`).
+fn parse_forwarded_ip(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    entry.parse::<IpAddr>().ok().or_else(|| entry.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
+
+
+/// Marks a request whose client closes its connection after the response, carried in the extensions of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CloseAfterResponse;
+
+
+/// The IP address of the client of a request, reported by a `real_ip_from` proxy or else the address of the peer,
+/// carried in the extensions of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+
+/// Returns the options of the `Connection` headers of a request, such as `close` or `keep-alive`, in lowercase.
+fn connection_options(request: &Request<Vec<u8>>) -> impl Iterator<Item = String> + '_ {
+    request.headers().get_all(http::header::CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+}
+
+
+/// Tells whether the client of a request closes the connection after the response.
+///
+/// An HTTP/1.1 connection is persistent unless the request carries `Connection: close`. An HTTP/1.0 connection is
+/// closed after the response unless the request carries `Connection: keep-alive`.
+pub fn closes_connection(request: &Request<Vec<u8>>) -> bool {
+    match request.version() {
+        http::Version::HTTP_10 => !connection_options(request).any(|option| option == "keep-alive"),
+        _ => connection_options(request).any(|option| option == "close"),
+    }
+}
+
+
+/// Tells whether a request can be sent again without changing its effect: its method is `GET`, `HEAD`, `OPTIONS`,
+/// `TRACE`, `PUT` or `DELETE` (RFC 9110, section 9.2.2).
+pub fn is_idempotent(request: &Request<Vec<u8>>) -> bool {
+    matches!(request.method().as_str(), "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+}
+
+
+/// Asks the upstream server to keep the connection open for `timeout` after the request, with the `Connection:
+/// keep-alive` and `Keep-Alive: timeout=` headers.
+///
+/// A request closing its connection is left as-is, and the `Keep-Alive` header sent by the client is replaced.
+///
+/// # Arguments
+///
+/// * `request` - The request forwarded to the upstream server.
+/// * `timeout` - The time the proxy keeps the upstream connection open while idle.
+pub fn add_keep_alive_hint(request: &mut Request<Vec<u8>>, timeout: Duration) {
+    if connection_options(request).any(|option| option == "close") {
+        return;
+    }
+    request.headers_mut().insert(http::header::CONNECTION, HeaderValue::from_static("keep-alive"));
+    let keep_alive = HeaderValue::from_str(&format!("timeout={}", timeout.as_secs())).unwrap();
+    request.headers_mut().insert(http::header::HeaderName::from_static("keep-alive"), keep_alive);
+}
+
+
+/// Tells the upstream server how the client reached the proxy server, in the `X-Forwarded-Proto`,
+/// `X-Forwarded-Port` and `X-Forwarded-Host` headers, so it can build absolute URLs.
+///
+/// Behind a trusted proxy of the `real_ip_from` networks, which may have terminated TLS, the headers it sent are
+/// kept and only the missing ones are added. The headers sent by any other client are replaced, so clients can't
+/// fake them.
+///
+/// # Arguments
+///
+/// * `request` - The request forwarded to the upstream server.
+/// * `scheme` - The scheme of the listener the request arrived on, `http` or `https`.
+/// * `port` - The port of the listener the request arrived on.
+/// * `peer_ip` - The IP address of the connection the request came from.
+/// * `config` - The settings holding the trusted proxy networks.
+pub fn add_forwarded_scheme(request: &mut Request<Vec<u8>>, scheme: &str, port: u16, peer_ip: IpAddr, config: &RequestConfig) {
+    let trusted = config.real_ip_from.iter().any(|network| network.contains(&peer_ip));
+    // the host the client asked for, even if the request is forwarded with another one
+    let host = match request.extensions().get::<ClientHost>() {
+        Some(ClientHost(client_host)) => Some(client_host.clone()),
+        None => request.headers().get(http::header::HOST).cloned(),
+    };
+    let values = [
+        ("x-forwarded-proto", Some(HeaderValue::from_str(scheme).unwrap())),
+        ("x-forwarded-port", Some(HeaderValue::from(port))),
+        ("x-forwarded-host", host),
+    ];
+
+    for (name, value) in values {
+        if trusted && request.headers().contains_key(name) {
+            continue;
+        }
+        request.headers_mut().remove(name);
+        if let Some(value) = value {
+            request.headers_mut().insert(name, value);
+        }
+    }
+}
+
+
+/// Builds a modified client request by adding the client's IP and the hop count, and returns the new request.
+///
+/// The body of the client request is kept as-is.
+///
+/// In the `Forwarded` format, the element of this proxy is appended to the elements of the `Forwarded` headers sent
+/// by the client, in a single header. Without a client IP, no forwarding header is added and the client-supplied
+/// `CLIENT_IP_HEADERS` are stripped, so the upstream server learns nothing about the client's address.
+///
+/// The client's `Host` header is forwarded, unless the first of the `host_rules` matching the request replaces it with
+/// a fixed host or one derived from the virtual host. The client's one is then kept in a `ClientHost` extension.
+///
+/// # Arguments
+///
+/// * `client_ip` - A string representing the client's IP address, or `None` if it must not be forwarded.
+/// * `format` - The header the client's IP address is added in.
 /// * `req` - A reference to the original client request.
+/// * `hops` - The number of proxies the request went through, including this one.
+/// * `host_rules` - The rules choosing the `Host` header of the forwarded request.
 ///
 /// # Returns
 ///
 /// * `Ok(Request<Vec<u8>>)` - If the modified client request is successfully created.
 /// * `Err(Error)` - If an error occurs during the building process.
+pub fn client_request_builder (client_ip: Option<&str>, format: ForwardedHeaderFormat, req: &Request<Vec<u8>>, hops: u32, host_rules: &[HostRule]) -> Result<Request<Vec<u8>>, Error>{
 
-
-fn client_request_builder (client_ip: &str, req: &Request<Vec<u8>>) -> Result<Request<Vec<u8>>, Error>{
+    // the Host header the upstream server receives, if not the client's
+    let upstream_host = upstream_host::upstream_host(host_rules, req);
 
     // build parsed request with method, uri and version
     let mut parsed_request = Request::builder()
@@ -212,20 +1475,53 @@ fn client_request_builder (client_ip: &str, req: &Request<Vec<u8>>) -> Result<Re
         .uri(req.uri())
         .version(http::Version::HTTP_11);
 
-    // add headers to parsed request
+    // add headers to parsed request, the client's hop count is replaced by ours
     for header in req.headers() {
+        if header.0.as_str().eq_ignore_ascii_case(HOPS_HEADER) {
+            continue;
+        }
+        if upstream_host.is_some() && header.0 == http::header::HOST {
+            continue;
+        }
+        // the client's own forwarding headers would reveal its address too
+        if client_ip.is_none() && CLIENT_IP_HEADERS.iter().any(|name| header.0.as_str().eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        // the client's Forwarded elements are merged with ours below
+        if client_ip.is_some() && format == ForwardedHeaderFormat::Forwarded && header.0 == http::header::FORWARDED {
+            continue;
+        }
         parsed_request = parsed_request.header(header.0, header.1);
     }
 
 
-    parsed_request = parsed_request.header("X-Forwarded-For", client_ip);
+    match (client_ip, format) {
+        (Some(client_ip), ForwardedHeaderFormat::XForwardedFor) => {
+            parsed_request = parsed_request.header("X-Forwarded-For", client_ip);
+        }
+        (Some(client_ip), ForwardedHeaderFormat::Forwarded) => {
+            let mut elements: Vec<&[u8]> = req.headers().get_all(http::header::FORWARDED).iter().map(|value| value.as_bytes()).collect();
+            let element = format!("for={};proto=http", forwarded_node(client_ip));
+            elements.push(element.as_bytes());
+            let value = HeaderValue::from_bytes(&elements.join(&b", "[..])).map_err(|_| Error::MalformedRequest)?;
+            parsed_request = parsed_request.header(http::header::FORWARDED, value);
+        }
+        (None, _) => {}
+    }
+    parsed_request = parsed_request.header(HOPS_HEADER, hops.to_string());
+    if let Some(upstream_host) = &upstream_host {
+        parsed_request = parsed_request.header(http::header::HOST, upstream_host);
+    }
 
-    // build parsed request with body and unwrap it
-    let parsed_request = parsed_request.body(Vec::<u8>::new()).unwrap();
+    // build parsed request with the client's body, and its spooled body if any, and unwrap it
+    let mut parsed_request = parsed_request.body(req.body().clone()).unwrap();
+    *parsed_request.extensions_mut() = req.extensions().clone();
+    if let (Some(_), Some(client_host)) = (&upstream_host, req.headers().get(http::header::HOST)) {
+        parsed_request.extensions_mut().insert(ClientHost(client_host.clone()));
+    }
 
-    println!("\nParsed Request: {:?}", parsed_request);
     log::info!("\nParsed Request: {:?}", parsed_request);
 
     // return parsed request
     Ok(parsed_request)
-}
\ No newline at end of file
+}
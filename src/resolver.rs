@@ -0,0 +1,112 @@
+//! # Resolver Module
+//!
+//! This module resolves the addresses of the upstream servers, caching the answers for the TTL of their records.
+//!
+//! In a dynamic environment, the IP addresses behind an upstream host name change: resolving the name once at
+//! startup sends traffic to addresses long gone, and resolving it on every connection loads the DNS servers. The
+//! `CachingResolver` keeps every answer until its TTL expires, then resolves the name again. The TTL is the one the
+//! resolver reports for the records, unless it is overridden; the system resolver doesn't report TTLs, its answers
+//! are kept for `DEFAULT_TTL`.
+//!
+//! Resolvers implement the `Resolver` trait, so the connector can be given a stub resolver in tests.
+//!
+//! ## Structures
+//!
+//! - `Resolved`: The socket addresses an upstream address resolved to, with the TTL of the records when known.
+//! - `SystemResolver`: The resolver of the operating system (`getaddrinfo`), through tokio.
+//! - `CachingResolver`: A resolver caching the answers of another one for their TTL.
+//!
+//! ## Traits
+//!
+//! - `Resolver`: Resolves an upstream address to socket addresses.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time the answers are cached for when the resolver doesn't report the TTL of the records.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// The future returned by `Resolver::resolve`.
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<Resolved, std::io::Error>> + Send + 'a>>;
+
+/// The socket addresses an upstream address resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    /// The socket addresses, in the order they should be tried.
+    pub addresses: Vec<SocketAddr>,
+
+    /// The TTL of the records, if the resolver reports it.
+    pub ttl: Option<Duration>,
+}
+
+/// Resolves an upstream address to socket addresses.
+pub trait Resolver: Debug + Send + Sync {
+    /// Resolves an upstream address, as `HOST:PORT`, to socket addresses.
+    fn resolve<'a>(&'a self, upstream_address: &'a str) -> ResolveFuture<'a>;
+}
+
+/// The resolver of the operating system, which doesn't report the TTL of the records.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, upstream_address: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let addresses = tokio::net::lookup_host(upstream_address).await?.collect();
+            Ok(Resolved { addresses, ttl: None })
+        })
+    }
+}
+
+/// A resolver caching the answers of another resolver until their TTL expires.
+///
+/// Failed resolutions aren't cached: the next connection resolves the address again.
+#[derive(Debug)]
+pub struct CachingResolver {
+    /// The resolver whose answers are cached.
+    inner: Arc<dyn Resolver>,
+
+    /// TTL applied to every answer instead of the TTL of the records.
+    ttl_override: Option<Duration>,
+
+    /// The cached answers by upstream address, with the instant they expire.
+    cache: Mutex<HashMap<String, (Vec<SocketAddr>, Instant)>>,
+}
+
+impl CachingResolver {
+    /// Creates a resolver caching the answers of `inner`, for `ttl_override` if set, or for the TTL of the records.
+    pub fn new(inner: Arc<dyn Resolver>, ttl_override: Option<Duration>) -> CachingResolver {
+        CachingResolver { inner, ttl_override, cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the number of upstream addresses with a cached answer, expired or not.
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve<'a>(&'a self, upstream_address: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let now = Instant::now();
+            if let Some((addresses, expires_at)) = self.cache.lock().unwrap().get(upstream_address) {
+                if now < *expires_at {
+                    let ttl = expires_at.duration_since(now);
+                    return Ok(Resolved { addresses: addresses.clone(), ttl: Some(ttl) });
+                }
+            }
+
+            let resolved = self.inner.resolve(upstream_address).await?;
+            let ttl = self.ttl_override.or(resolved.ttl).unwrap_or(DEFAULT_TTL);
+            log::debug!("Resolved {} to {:?}, cached for {:?}", upstream_address, resolved.addresses, ttl);
+
+            self.cache.lock().unwrap().insert(upstream_address.to_string(), (resolved.addresses.clone(), Instant::now() + ttl));
+            Ok(Resolved { addresses: resolved.addresses, ttl: Some(ttl) })
+        })
+    }
+}
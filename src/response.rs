@@ -0,0 +1,703 @@
+//! # Response Module
+//!
+//! This module relays the responses of the upstream servers back to the clients.
+//!
+//! ## Functions
+//!
+//! ### `relay_response`
+//!
+//! This function reads the status line and headers of the upstream response, forwards them to the client as they were
+//! received, and then streams the body according to its framing: `Content-Length`, `Transfer-Encoding: chunked`
+//! (including the trailer fields sent after the last chunk), or until the upstream server closes the connection. A
+//! `Content-Length` contradicting the framing, sent alongside chunked coding or repeated, is rewritten first, and a
+//! response repeating `Content-Length` with different lengths is rejected as malformed.
+//! The body is streamed chunk by chunk using the connection's buffer, so a response never has to fit in memory at once.
+//!
+//! - **Parameters:**
+//!   - `upstream_stream`: The stream connected to the upstream server.
+//!   - `client_stream`: The stream connected to the client.
+//!   - `buffer`: The connection's buffer. A status line must fit in it, the headers are moved aside as they arrive
+//!     when they don't.
+//!   - `request_method`: The method of the request the response answers, `HEAD` responses have no body.
+//!   - `added_headers`: The headers added to the final response: a `Server-Timing` header reporting the upstream
+//!     time to first byte since the instant the request was forwarded at, and the `Access-Control-Allow-Origin` of
+//!     a cross-origin request. The default leaves the response head untouched.
+//!   - `limits`: The limits of the response. A response declaring a `Content-Length` over the maximum body size is
+//!     rejected before anything is sent to the client, a larger body is cut once it exceeds it. A head over the
+//!     maximum head size, or whose end doesn't arrive within the head read timeout of its first byte, is rejected
+//!     before a byte of it is sent to the client.
+//!   - `close_connection`: Whether the client closes the connection after the response, which then carries
+//!     `Connection: close` in place of the `Connection` and `Keep-Alive` headers sent by the upstream server.
+//!
+//! - **Returns:**
+//!   - `Ok(RelayedResponse)`: The status code of the response, the number of bytes relayed to the client, whether the
+//!     response was delimited by the upstream server closing the connection, when the time was spent, and the load
+//!     reported by the upstream server.
+//!   - `Err(Error)`: If the response is malformed, over its limits, or reading from the upstream server or writing
+//!     to the client failed.
+//!
+//! ### `is_status_line_prefix`
+//!
+//! This function tells whether the first bytes of a response can start a valid `HTTP/1.x` status line. The response
+//! head is checked with it as it arrives, so garbage sent by a server that doesn't speak HTTP fails the response with
+//! `MalformedResponse` on its first bytes, answered with 502 Bad Gateway, instead of waiting for the end of a head.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::header::HeaderValue;
+use http::Method;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::emit::{has_no_body, reframe_head, Framing};
+use crate::load_report::{parse_load, LOAD_HEADER};
+
+/// Enum representing possible errors while relaying a response.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the response from the upstream server failed, or the upstream server closed the connection before
+    /// the end of the response. `bytes_relayed` bytes were already sent to the client. `kind` is the kind of the read
+    /// error, `None` if the upstream server closed the connection. Without `response_started`, not a byte of the
+    /// response was received: the upstream server closed the connection before processing the request.
+    UpstreamReadFailed { bytes_relayed: usize, kind: Option<std::io::ErrorKind>, response_started: bool },
+    /// The upstream server sent something that isn't a valid HTTP/1.1 response. `bytes_relayed` bytes were already
+    /// sent to the client.
+    MalformedResponse { bytes_relayed: usize },
+    /// Writing the response to the client failed.
+    ClientWriteFailed(std::io::Error),
+    /// The response body is larger than the maximum size. `bytes_relayed` bytes were already sent to the client.
+    ResponseTooLarge { bytes_relayed: usize },
+    /// The status line and headers of the response are larger than the maximum head size. Not a byte of them was sent
+    /// to the client, `bytes_relayed` bytes of the interim responses preceding them were.
+    HeadTooLarge { bytes_relayed: usize },
+    /// The end of the status line and headers didn't arrive within the head read timeout of their first byte. Not a
+    /// byte of them was sent to the client, `bytes_relayed` bytes of the interim responses preceding them were.
+    HeadTimedOut { bytes_relayed: usize },
+}
+
+/// Default maximum size in bytes of the status line and headers of a response.
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// The limits a response is relayed within.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseLimits {
+    /// Maximum size in bytes of the response body, headers excluded, if limited.
+    pub max_body_size: Option<usize>,
+
+    /// Maximum size in bytes of the status line and headers of every response head, interim ones included.
+    pub max_head_size: usize,
+
+    /// Time allowed for a response head to arrive in full once its first byte was received, if limited.
+    pub head_read_timeout: Option<Duration>,
+}
+
+impl Default for ResponseLimits {
+    fn default() -> ResponseLimits {
+        ResponseLimits { max_body_size: None, max_head_size: DEFAULT_MAX_HEAD_SIZE, head_read_timeout: None }
+    }
+}
+
+/// Settings applied by the proxy to every upstream response before it is relayed.
+#[derive(Debug, Clone)]
+pub struct ResponseConfig {
+    /// Add a `Server-Timing` header reporting the upstream latency to the relayed responses.
+    pub server_timing: bool,
+
+    /// Maximum size in bytes of a response body, headers excluded. Larger responses are not relayed in full.
+    pub max_body_size: Option<usize>,
+
+    /// Maximum size in bytes of the response bodies of some upstream servers, by address, in place of
+    /// `max_body_size`. A limit of 0 leaves the responses of the upstream server unlimited.
+    pub upstream_max_body_sizes: Arc<HashMap<String, usize>>,
+
+    /// Maximum size in bytes of the status line and headers of a response.
+    pub max_head_size: usize,
+
+    /// Time allowed for the status line and headers of a response to arrive once its first byte was received.
+    pub head_read_timeout: Option<Duration>,
+
+    /// Tell the clients why the upstream server failed in the body of the 502, 503 and 504 responses.
+    pub expose_error_detail: bool,
+
+    /// Print an access log line, with the time spent in every phase of the request, for every relayed response.
+    pub access_log: bool,
+
+    /// Number of times an idempotent request is sent again after the upstream server closed a new connection without
+    /// answering it.
+    pub empty_response_retries: u32,
+}
+
+impl Default for ResponseConfig {
+    fn default() -> ResponseConfig {
+        ResponseConfig {
+            server_timing: false,
+            max_body_size: None,
+            upstream_max_body_sizes: Arc::default(),
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            head_read_timeout: None,
+            expose_error_detail: false,
+            access_log: false,
+            empty_response_retries: 0,
+        }
+    }
+}
+
+impl ResponseConfig {
+    /// Returns the limits the responses of `upstream_address` are relayed within.
+    pub fn limits_of(&self, upstream_address: &str) -> ResponseLimits {
+        ResponseLimits {
+            max_body_size: self.max_body_size_of(upstream_address),
+            max_head_size: self.max_head_size,
+            head_read_timeout: self.head_read_timeout,
+        }
+    }
+
+    /// Returns the maximum size of the response bodies of `upstream_address`, `None` if unlimited.
+    pub fn max_body_size_of(&self, upstream_address: &str) -> Option<usize> {
+        match self.upstream_max_body_sizes.get(upstream_address) {
+            Some(0) => None,
+            Some(max_body_size) => Some(*max_body_size),
+            None => self.max_body_size,
+        }
+    }
+}
+
+/// The maximum size of the response bodies of an upstream server, overriding `--max-response-size`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamResponseLimit {
+    /// Address of the upstream server, as given to `--upstream`.
+    pub address: String,
+
+    /// Maximum size in bytes of a response body, 0 for unlimited.
+    pub max_body_size: usize,
+}
+
+impl FromStr for UpstreamResponseLimit {
+    type Err = String;
+
+    /// Parses a `HOST:PORT=BYTES` limit, such as `10.0.0.2:8080=1048576`.
+    fn from_str(limit: &str) -> Result<UpstreamResponseLimit, String> {
+        let (address, max_body_size) = limit.rsplit_once('=').ok_or(format!("expected HOST:PORT=BYTES, got {:?}", limit))?;
+        let address = address.trim();
+        if address.is_empty() {
+            return Err(format!("expected HOST:PORT=BYTES, got {:?}", limit));
+        }
+        let max_body_size = max_body_size.trim().parse().map_err(|e| format!("invalid size {:?}: {}", max_body_size, e))?;
+        Ok(UpstreamResponseLimit { address: address.to_string(), max_body_size })
+    }
+}
+
+/// Headers the proxy server adds to the final response it relays.
+#[derive(Debug, Clone, Default)]
+pub struct AddedHeaders {
+    /// The instant the request was forwarded at, to report the upstream time to first byte in a `Server-Timing`
+    /// header.
+    pub server_timing: Option<Instant>,
+
+    /// The `Access-Control-Allow-Origin` of a cross-origin request, replacing the one of the upstream server. An
+    /// echoed origin comes with `Vary: Origin`.
+    pub allow_origin: Option<HeaderValue>,
+}
+
+/// Outcome of a successfully relayed response.
+#[derive(Debug)]
+pub struct RelayedResponse {
+    /// Status code of the response.
+    pub status: u16,
+
+    /// Number of bytes relayed to the client.
+    pub bytes_relayed: usize,
+
+    /// The end of the response was signaled by the upstream server closing the connection. The client connection
+    /// must be closed as well, it is the only way for the client to find the end of the response.
+    pub close_delimited: bool,
+
+    /// The instant the first byte of the response was received.
+    pub first_byte_at: Instant,
+
+    /// Time spent writing the response to the client.
+    pub client_write_time: Duration,
+
+    /// The load the upstream server reported in the `X-Server-Load` header of the response, if any.
+    pub reported_load: Option<f64>,
+}
+
+/// Reads the upstream response through the connection's buffer and forwards it to the client.
+///
+/// `buffer[start..end]` holds the bytes read from the upstream server that haven't been forwarded yet.
+struct ResponseRelay<'a, U, C> {
+    upstream_stream: &'a mut U,
+    client_stream: &'a mut C,
+    buffer: &'a mut [u8],
+    start: usize,
+    end: usize,
+    bytes_relayed: usize,
+    body_bytes: usize,
+    limits: ResponseLimits,
+    spilled_head: Vec<u8>,
+    first_byte_at: Option<Instant>,
+    client_write_time: Duration,
+    reported_load: Option<f64>,
+    close_connection: bool,
+}
+
+impl<U, C> ResponseRelay<'_, U, C>
+where
+    U: AsyncRead + Unpin,
+    C: AsyncWrite + Unpin,
+{
+    /// Reads more bytes from the upstream server, after moving the pending bytes to the start of the buffer.
+    ///
+    /// Fails with `MalformedResponse` if the buffer is already full of pending bytes, and with `UpstreamReadFailed`
+    /// if the upstream server closed the connection.
+    async fn fill(&mut self) -> Result<(), Error> {
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        if self.end == self.buffer.len() {
+            log::error!("Upstream response line does not fit in a {} bytes buffer", self.buffer.len());
+            return Err(Error::MalformedResponse { bytes_relayed: self.bytes_relayed });
+        }
+
+        match self.upstream_stream.read(&mut self.buffer[self.end..]).await {
+            Ok(0) => Err(Error::UpstreamReadFailed { bytes_relayed: self.bytes_relayed, kind: None, response_started: self.first_byte_at.is_some() }),
+            Ok(bytes_read) => {
+                self.first_byte_at.get_or_insert_with(Instant::now);
+                self.end += bytes_read;
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Failed to read response from upstream server: {}", e);
+                Err(Error::UpstreamReadFailed { bytes_relayed: self.bytes_relayed, kind: Some(e.kind()), response_started: self.first_byte_at.is_some() })
+            }
+        }
+    }
+
+    /// Forwards the first `length` pending bytes to the client.
+    async fn forward(&mut self, length: usize) -> Result<(), Error> {
+        let started_at = Instant::now();
+        let written = self.client_stream.write_all(&self.buffer[self.start..self.start + length]).await;
+        self.client_write_time += started_at.elapsed();
+        if let Err(e) = written {
+            return Err(Error::ClientWriteFailed(e));
+        }
+        self.start += length;
+        self.bytes_relayed += length;
+        Ok(())
+    }
+
+    /// Counts `length` more body bytes, failing with `ResponseTooLarge` if the body would exceed its maximum size.
+    fn count_body(&mut self, length: usize) -> Result<(), Error> {
+        self.body_bytes += length;
+        match self.limits.max_body_size {
+            Some(max_body_size) if self.body_bytes > max_body_size => {
+                log::error!("Upstream response body exceeds the maximum size of {} bytes", max_body_size);
+                Err(Error::ResponseTooLarge { bytes_relayed: self.bytes_relayed })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits until the pending bytes contain `delimiter` and returns the length of the pending bytes up to and
+    /// including it.
+    async fn pending_until(&mut self, delimiter: &[u8]) -> Result<usize, Error> {
+        loop {
+            let pending = &self.buffer[self.start..self.end];
+            if let Some(position) = pending.windows(delimiter.len()).position(|window| window == delimiter) {
+                return Ok(position + delimiter.len());
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads more bytes of a response head from the upstream server, failing with `HeadTimedOut` once the head read
+    /// timeout has passed since its first byte, received at `head_started_at`.
+    async fn fill_head(&mut self, head_started_at: Option<Instant>) -> Result<(), Error> {
+        let (Some(head_read_timeout), Some(head_started_at)) = (self.limits.head_read_timeout, head_started_at) else {
+            return self.fill().await;
+        };
+        match tokio::time::timeout_at((head_started_at + head_read_timeout).into(), self.fill()).await {
+            Ok(filled) => filled,
+            Err(_) => {
+                log::error!("Upstream response head didn't arrive within {:?} of its first byte", head_read_timeout);
+                Err(Error::HeadTimedOut { bytes_relayed: self.bytes_relayed })
+            }
+        }
+    }
+
+    /// Waits until the pending bytes contain the empty line ending a response head and returns the length of the
+    /// pending bytes up to and including it.
+    ///
+    /// A head that doesn't fit in the buffer is moved to `spilled_head` as it arrives, except for its last bytes which
+    /// may start the empty line: the head is then the spilled bytes followed by the returned length of pending bytes.
+    /// Fails with `HeadTooLarge` once the head is over the maximum head size, before its end was even received.
+    async fn pending_head(&mut self, head_started_at: Option<Instant>) -> Result<usize, Error> {
+        const END_OF_HEAD: &[u8] = b"\r\n\r\n";
+        loop {
+            let pending = &self.buffer[self.start..self.end];
+            let head_end = pending.windows(END_OF_HEAD.len()).position(|window| window == END_OF_HEAD).map(|position| position + END_OF_HEAD.len());
+            if self.spilled_head.len() + head_end.unwrap_or(pending.len()) > self.limits.max_head_size {
+                log::error!("Upstream response head exceeds the maximum size of {} bytes", self.limits.max_head_size);
+                return Err(Error::HeadTooLarge { bytes_relayed: self.bytes_relayed });
+            }
+            if let Some(head_end) = head_end {
+                return Ok(head_end);
+            }
+
+            if self.start == 0 && self.end == self.buffer.len() {
+                let spilled = self.end - (END_OF_HEAD.len() - 1);
+                self.spilled_head.extend_from_slice(&self.buffer[..spilled]);
+                self.start = spilled;
+            }
+            self.fill_head(head_started_at).await?;
+        }
+    }
+
+    /// Forwards exactly `length` bytes of the response, reading them from the upstream server as needed.
+    async fn forward_exactly(&mut self, mut length: usize) -> Result<(), Error> {
+        while length > 0 {
+            if self.start == self.end {
+                self.fill().await?;
+            }
+            let available = length.min(self.end - self.start);
+            self.forward(available).await?;
+            length -= available;
+        }
+        Ok(())
+    }
+
+    /// Forwards everything the upstream server sends until it closes the connection.
+    async fn forward_until_close(&mut self) -> Result<(), Error> {
+        loop {
+            let pending = self.end - self.start;
+            self.count_body(pending)?;
+            self.forward(pending).await?;
+            match self.fill().await {
+                Ok(()) => (),
+                Err(Error::UpstreamReadFailed { .. }) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads the status line and headers, forwards them to the client and returns the status code and the framing of
+    /// the body.
+    ///
+    /// With `server_timing`, a `Server-Timing` header reporting the time elapsed since then is added after the
+    /// received headers. It is a list header, so the entries sent by the upstream server are kept alongside it. An
+    /// `allow_origin` replaces the `Access-Control-Allow-Origin` headers sent by the upstream server instead, a second
+    /// value would make the browsers reject the response. Stale framing headers are rewritten by `reframe_head`, so the
+    /// client finds the end of the body where the relay does.
+    async fn forward_head(&mut self, request_method: &Method, added_headers: &AddedHeaders) -> Result<(u16, Framing), Error> {
+        // a server that doesn't speak HTTP is caught on its first bytes, rather than once its head would be complete
+        let mut head_started_at = (self.end > self.start).then(Instant::now);
+        loop {
+            let pending = &self.buffer[self.start..self.end];
+            if !is_status_line_prefix(pending) {
+                log::error!("Upstream response doesn't start with an HTTP/1.x status line");
+                return Err(Error::MalformedResponse { bytes_relayed: self.bytes_relayed });
+            }
+            if pending.contains(&b'\n') {
+                break;
+            }
+            self.fill_head(head_started_at).await?;
+            head_started_at.get_or_insert_with(Instant::now);
+        }
+        let head_length = self.pending_head(head_started_at).await?;
+
+        // nothing of the head was forwarded yet, a head that didn't fit in the buffer is put back together
+        let spilled_head = std::mem::take(&mut self.spilled_head);
+        let received = match spilled_head.is_empty() {
+            true => Cow::Borrowed(&self.buffer[self.start..self.start + head_length]),
+            false => Cow::Owned([spilled_head.as_slice(), &self.buffer[self.start..self.start + head_length]].concat()),
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        let framing = match response.parse(&received) {
+            Ok(httparse::Status::Complete(_)) => body_framing(&response, request_method)?,
+            _ => return Err(Error::MalformedResponse { bytes_relayed: self.bytes_relayed }),
+        };
+        let status = response.code.unwrap_or_default();
+        self.reported_load = response.headers.iter()
+            .find(|header| header.name.eq_ignore_ascii_case(LOAD_HEADER))
+            .and_then(|header| parse_load(header.value));
+
+        // the declared length is known before the head is forwarded, the client can still be answered with an error
+        if let (Framing::ContentLength(length), Some(max_body_size)) = (&framing, self.limits.max_body_size) {
+            if *length > max_body_size {
+                log::error!("Upstream response declares a {} bytes body, over the maximum size of {} bytes", length, max_body_size);
+                return Err(Error::ResponseTooLarge { bytes_relayed: self.bytes_relayed });
+            }
+        }
+
+        // the time to first byte is reported on the final response only, and the client connection is closed after
+        // it. A 101 Switching Protocols keeps its `Connection: upgrade`
+        let server_timing = added_headers.server_timing.filter(|_| !is_interim(status));
+        let allow_origin = added_headers.allow_origin.as_ref().filter(|_| !is_interim(status));
+        let close_connection = self.close_connection && !is_interim(status) && status != 101;
+        let reframed = reframe_head(&received);
+        if server_timing.is_none() && allow_origin.is_none() && !close_connection && reframed.is_none() && spilled_head.is_empty() {
+            self.forward(head_length).await?;
+            return Ok((status, framing));
+        }
+
+        let received = reframed.unwrap_or_else(|| received.into_owned());
+        let mut head = if close_connection { with_connection_close(&received) } else { received };
+        if let Some(allow_origin) = allow_origin {
+            head = without_header(&head, b"access-control-allow-origin");
+            let mut header = [b"Access-Control-Allow-Origin: ", allow_origin.as_bytes(), b"\r\n"].concat();
+            if allow_origin != "*" {
+                header.extend_from_slice(b"Vary: Origin\r\n");
+            }
+            let end = head.len() - 2;
+            head.splice(end..end, header);
+        }
+        if let Some(forwarded_at) = server_timing {
+            // insert the header before the empty line ending the head, the head is still written at once
+            let elapsed = forwarded_at.elapsed().as_secs_f64() * 1000.0;
+            let header = format!("Server-Timing: upstream;dur={:.3}\r\n", elapsed);
+            let end = head.len() - 2;
+            head.splice(end..end, header.into_bytes());
+        }
+        let started_at = Instant::now();
+        let written = self.client_stream.write_all(&head).await;
+        self.client_write_time += started_at.elapsed();
+        if let Err(e) = written {
+            return Err(Error::ClientWriteFailed(e));
+        }
+        self.start += head_length;
+        self.bytes_relayed += head.len();
+        Ok((status, framing))
+    }
+
+    /// Forwards a chunked body, including the trailer fields sent after the last chunk.
+    async fn forward_chunked_body(&mut self) -> Result<(), Error> {
+        loop {
+            let size_line_length = self.pending_until(b"\r\n").await?;
+            let chunk_size = parse_chunk_size(&self.buffer[self.start..self.start + size_line_length - 2])
+                .ok_or(Error::MalformedResponse { bytes_relayed: self.bytes_relayed })?;
+            self.forward(size_line_length).await?;
+
+            if chunk_size == 0 {
+                return self.forward_trailers().await;
+            }
+
+            // chunk data followed by its CRLF
+            self.count_body(chunk_size)?;
+            self.forward_exactly(chunk_size + 2).await?;
+        }
+    }
+
+    /// Forwards the trailer fields following the last chunk, up to and including the final empty line.
+    async fn forward_trailers(&mut self) -> Result<(), Error> {
+        loop {
+            let line_length = self.pending_until(b"\r\n").await?;
+            if line_length == 2 {
+                return self.forward(line_length).await;
+            }
+
+            let mut trailer = [httparse::EMPTY_HEADER; 1];
+            let line = &self.buffer[self.start..self.start + line_length];
+            match httparse::parse_headers(&[line, b"\r\n"].concat(), &mut trailer) {
+                Ok(httparse::Status::Complete(_)) => log::debug!("Forwarding trailer field {}", trailer[0].name),
+                _ => return Err(Error::MalformedResponse { bytes_relayed: self.bytes_relayed }),
+            }
+            self.forward(line_length).await?;
+        }
+    }
+}
+
+/// Relays one upstream response to the client.
+///
+/// The status line and headers are forwarded as they were received, which keeps end-to-end declarations such as
+/// `Trailer` intact. The interim 1xx responses preceding the final response are forwarded as well, as RFC 7231
+/// section 6.2 requires of a proxy, and the status returned is the one of the final response. The body is then streamed according to its framing, chunk by chunk, so the memory used by a
+/// connection is bounded by the size of its buffer whatever the size of the response. For `Content-Length` and
+/// chunked bodies the function returns as soon as the response is complete, without waiting for the upstream server
+/// to close the connection.
+///
+/// # Arguments
+///
+/// * `upstream_stream` - The stream connected to the upstream server.
+/// * `client_stream` - The stream connected to the client.
+/// * `buffer` - The connection's buffer. The status line of the response must fit in it, its headers may not.
+/// * `request_method` - The method of the request the response answers.
+/// * `added_headers` - The headers added to the final response, the `Server-Timing` header reporting the upstream
+///   time to first byte and the `Access-Control-Allow-Origin` of a cross-origin request.
+/// * `limits` - The limits of the response. The declared `Content-Length` is checked against the maximum body size
+///   before the head is forwarded, the chunked and close-delimited bodies are checked as they are streamed. Every
+///   head is received in full, within the maximum head size and the head read timeout, before it is forwarded.
+/// * `close_connection` - Whether the client closes the connection after the response, the final response then
+///   carries `Connection: close` in place of the `Connection` and `Keep-Alive` headers of the upstream server.
+///
+/// # Returns
+///
+/// * `Ok(RelayedResponse)` - The status code, the number of bytes relayed to the client, whether the response was
+///   close-delimited, when its first byte was received and the time spent writing it to the client.
+/// * `Err(Error)` - If the response is malformed or over its limits, or reading from the upstream server or writing
+///   to the client failed.
+pub async fn relay_response<U, C>(upstream_stream: &mut U, client_stream: &mut C, buffer: &mut [u8], request_method: &Method, added_headers: &AddedHeaders, limits: &ResponseLimits, close_connection: bool) -> Result<RelayedResponse, Error>
+where
+    U: AsyncRead + Unpin,
+    C: AsyncWrite + Unpin,
+{
+    let mut relay = ResponseRelay {
+        upstream_stream, client_stream, buffer, start: 0, end: 0, bytes_relayed: 0, body_bytes: 0, limits: *limits, spilled_head: Vec::new(),
+        first_byte_at: None, client_write_time: Duration::ZERO, reported_load: None, close_connection,
+    };
+
+    // interim responses (100 Continue, 103 Early Hints) come before the final response, and are forwarded as received
+    let (status, framing) = loop {
+        let (status, framing) = relay.forward_head(request_method, added_headers).await?;
+        if !is_interim(status) {
+            break (status, framing);
+        }
+        log::debug!("Forwarded interim {} response from upstream server", status);
+    };
+    match framing {
+        Framing::Empty => (),
+        Framing::ContentLength(length) => relay.forward_exactly(length).await?,
+        Framing::Chunked => relay.forward_chunked_body().await?,
+        Framing::UntilClose => relay.forward_until_close().await?,
+    }
+
+    let started_at = Instant::now();
+    if let Err(e) = relay.client_stream.flush().await {
+        return Err(Error::ClientWriteFailed(e));
+    }
+    relay.client_write_time += started_at.elapsed();
+
+    Ok(RelayedResponse {
+        status,
+        bytes_relayed: relay.bytes_relayed,
+        close_delimited: framing == Framing::UntilClose,
+        // the head was read, so at least one byte was received
+        first_byte_at: relay.first_byte_at.unwrap_or_else(Instant::now),
+        client_write_time: relay.client_write_time,
+        reported_load: relay.reported_load,
+    })
+}
+
+/// Rewrites the head at the start of a response so the client connection is closed after it: the `Connection` and
+/// `Keep-Alive` headers are replaced with `Connection: close`. The bytes following the head are kept as they are.
+///
+/// # Arguments
+///
+/// * `response` - The response, or only its head, starting with the status line.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - The response with the rewritten head, or unchanged if its head isn't complete.
+pub fn with_connection_close(response: &[u8]) -> Vec<u8> {
+    let Some(head_length) = response.windows(4).position(|window| window == b"\r\n\r\n").map(|position| position + 4) else {
+        return response.to_vec();
+    };
+
+    let mut closed = Vec::with_capacity(response.len() + 19);
+    // the status line and the header lines, each with its CRLF, without the empty line ending the head
+    for (index, line) in response[..head_length - 2].split_inclusive(|&byte| byte == b'\n').enumerate() {
+        let name = line.split(|&byte| byte == b':').next().unwrap_or_default().trim_ascii();
+        if index > 0 && (name.eq_ignore_ascii_case(b"connection") || name.eq_ignore_ascii_case(b"keep-alive")) {
+            continue;
+        }
+        closed.extend_from_slice(line);
+    }
+    closed.extend_from_slice(b"Connection: close\r\n\r\n");
+    closed.extend_from_slice(&response[head_length..]);
+    closed
+}
+
+/// Removes the header lines named `name`, in lowercase, from a response head. The status line is kept as it is.
+fn without_header(head: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut kept = Vec::with_capacity(head.len());
+    for (index, line) in head.split_inclusive(|&byte| byte == b'\n').enumerate() {
+        let line_name = line.split(|&byte| byte == b':').next().unwrap_or_default().trim_ascii();
+        if index > 0 && line_name.eq_ignore_ascii_case(name) {
+            continue;
+        }
+        kept.extend_from_slice(line);
+    }
+    kept
+}
+
+/// Tells whether `received`, the first bytes of a response, can be the start of a valid status line: `HTTP/1.x`, a
+/// three digit status code, and an optional reason phrase after a space.
+///
+/// Once the end of the line was received, the whole line must be valid. Before that, the bytes received must match
+/// the beginning of a status line, so an upstream server that doesn't speak HTTP is rejected on its first bytes.
+pub fn is_status_line_prefix(received: &[u8]) -> bool {
+    const TEMPLATE: &[u8] = b"HTTP/1.# ### ";
+    let (line, complete) = match received.iter().position(|&byte| byte == b'\n') {
+        Some(end) => (&received[..end], true),
+        None => (received, false),
+    };
+    // a carriage return ending the received bytes may be the start of the line ending
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    let matches_template = line.iter().zip(TEMPLATE).all(|(&byte, &expected)| match expected {
+        b'#' => byte.is_ascii_digit(),
+        _ => byte == expected,
+    });
+    matches_template && (!complete || line.len() >= TEMPLATE.len() - 1)
+}
+
+/// Tells whether a status is the one of an interim response, followed by the final response to the same request.
+///
+/// `101 Switching Protocols` is final for HTTP: the connection speaks another protocol after it.
+fn is_interim(status: u16) -> bool {
+    (100..200).contains(&status) && status != 101
+}
+
+/// Determines how the end of the response body is delimited, following RFC 7230 section 3.3.3.
+///
+/// # Arguments
+///
+/// * `response` - The parsed status line and headers of the response.
+/// * `request_method` - The method of the request the response answers.
+///
+/// # Returns
+///
+/// * `Ok(Framing)` - The framing of the body.
+/// * `Err(Error::MalformedResponse)` - If the `Content-Length` header is invalid, or repeated with another length.
+fn body_framing(response: &httparse::Response, request_method: &Method) -> Result<Framing, Error> {
+    let status = response.code.unwrap_or(0);
+    if *request_method == Method::HEAD || has_no_body(status) {
+        return Ok(Framing::Empty);
+    }
+
+    let is_chunked = response.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Transfer-Encoding")
+            && String::from_utf8_lossy(header.value).split(',').any(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+    });
+    if is_chunked {
+        return Ok(Framing::Chunked);
+    }
+
+    // repeated Content-Length headers, or a list of values, must all declare the same length
+    let mut length = None;
+    for header in response.headers.iter().filter(|header| header.name.eq_ignore_ascii_case("Content-Length")) {
+        let values = std::str::from_utf8(header.value).map_err(|_| Error::MalformedResponse { bytes_relayed: 0 })?;
+        for value in values.split(',') {
+            let value = value.trim().parse::<usize>().map_err(|_| Error::MalformedResponse { bytes_relayed: 0 })?;
+            if length.is_some_and(|length| length != value) {
+                return Err(Error::MalformedResponse { bytes_relayed: 0 });
+            }
+            length = Some(value);
+        }
+    }
+    Ok(length.map_or(Framing::UntilClose, Framing::ContentLength))
+}
+
+/// Parses the size of a chunk from its size line (without the CRLF), ignoring chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    usize::from_str_radix(size, 16).ok()
+}
@@ -0,0 +1,203 @@
+//! # Routing Module
+//!
+//! This module decides which pool of upstream servers a request is sent to, before an upstream server is selected
+//! in that pool.
+//!
+//! Requests carrying the canary header (for example `X-Canary: true`) are routed to the canary pool. Of the other
+//! requests, the configured percentage is routed to the canary pool at random and the rest to the default pool.
+//! Requests fall back to the default pool while no canary upstream is active.
+//!
+//! The default pool is made of priority tiers: the `--upstream` servers are tier 0, and the `--tier-upstream` servers
+//! the tiers 1 and above. Only the lowest-numbered tier with active upstream servers receives traffic, so a tier is
+//! used when every tier above it is unhealthy, and left as soon as one of them recovers.
+//!
+//! ## Structures
+//!
+//! - `HeaderMatch`: A header name and value a request must carry, parsed from `NAME=VALUE`.
+//! - `TieredUpstream`: An upstream server of a failover tier, parsed from `TIER=HOST:PORT`.
+//! - `UpstreamPools`: The active upstreams of every pool along with the canary rule, as seen by a client connection.
+//!
+//! ## Functions
+//!
+//! ### `failover_upstreams`
+//!
+//! This function returns the active upstream servers of the lowest-numbered tier that has any.
+//!
+//! ## Enums
+//!
+//! - `Pool`: The pools of upstream servers a request can be routed to.
+
+use std::str::FromStr;
+
+use http::header::{HeaderName, HeaderValue};
+use http::Request;
+use rand::Rng;
+
+/// A header name and value a request must carry to match a routing rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderMatch {
+    /// Name of the header, matched case-insensitively.
+    pub name: HeaderName,
+
+    /// Value of the header, matched exactly.
+    pub value: HeaderValue,
+}
+
+impl HeaderMatch {
+    /// Tells whether one of the values of the header in `request` is the expected value.
+    pub fn matches(&self, request: &Request<Vec<u8>>) -> bool {
+        request.headers().get_all(&self.name).iter().any(|value| value == self.value)
+    }
+}
+
+impl FromStr for HeaderMatch {
+    type Err = String;
+
+    /// Parses a `NAME=VALUE` rule, such as `X-Canary=true`.
+    fn from_str(rule: &str) -> Result<HeaderMatch, String> {
+        let (name, value) = rule.split_once('=').ok_or(format!("expected NAME=VALUE, got {:?}", rule))?;
+        let name = HeaderName::from_str(name.trim()).map_err(|e| format!("invalid header name {:?}: {}", name, e))?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|e| format!("invalid header value {:?}: {}", value, e))?;
+
+        Ok(HeaderMatch { name, value })
+    }
+}
+
+/// An upstream server of a failover tier, used only while every lower-numbered tier has no active upstream server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TieredUpstream {
+    /// Priority tier of the upstream server, 1 or above: tier 0 is the `--upstream` servers.
+    pub tier: u8,
+
+    /// Address of the upstream server.
+    pub address: String,
+}
+
+impl FromStr for TieredUpstream {
+    type Err = String;
+
+    /// Parses a `TIER=HOST:PORT` upstream server, such as `1=10.0.0.2:8080`.
+    fn from_str(upstream: &str) -> Result<TieredUpstream, String> {
+        let (tier, address) = upstream.split_once('=').ok_or(format!("expected TIER=HOST:PORT, got {:?}", upstream))?;
+        let tier = match tier.trim().parse() {
+            Ok(0) => return Err("tier 0 is the --upstream servers, failover tiers start at 1".to_string()),
+            Ok(tier) => tier,
+            Err(e) => return Err(format!("invalid tier {:?}: {}", tier, e)),
+        };
+
+        Ok(TieredUpstream { tier, address: address.trim().to_string() })
+    }
+}
+
+/// Returns the active upstream servers of the lowest-numbered tier that has any.
+///
+/// # Arguments
+///
+/// * `primary` - The active upstream servers of tier 0.
+/// * `tiers` - The active upstream servers of the failover tiers, in any order.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The upstream servers requests must be sent to, empty if no tier has an active upstream server.
+pub fn failover_upstreams(primary: &[String], tiers: &[TieredUpstream]) -> Vec<String> {
+    if !primary.is_empty() {
+        return primary.to_vec();
+    }
+
+    let Some(tier) = tiers.iter().map(|upstream| upstream.tier).min() else {
+        return Vec::new();
+    };
+    tiers.iter().filter(|upstream| upstream.tier == tier).map(|upstream| upstream.address.clone()).collect()
+}
+
+/// The pools of upstream servers a request can be routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pool {
+    /// The upstream servers given with `--upstream`, or those of the failover tier in use.
+    Default,
+    /// The upstream servers given with `--canary-upstream`.
+    Canary,
+}
+
+/// The active upstream servers of every pool, and the rule routing requests to the canary pool.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamPools {
+    /// Active upstream servers of the default pool, those of the failover tier in use.
+    pub default: Vec<String>,
+
+    /// Active upstream servers of the canary pool.
+    pub canary: Vec<String>,
+
+    /// Header routing the requests carrying it to the canary pool.
+    pub canary_header: Option<HeaderMatch>,
+
+    /// Percentage (0 to 100) of the requests not matching the canary header routed to the canary pool.
+    pub canary_percent: u8,
+
+    /// Every configured upstream server, of every pool and tier, that a request can be forced through.
+    pub configured: Vec<String>,
+
+    /// The configured upstream servers passing their health checks, of every pool and tier.
+    pub healthy: Vec<String>,
+}
+
+impl UpstreamPools {
+    /// Routes a request to a pool.
+    ///
+    /// The canary rule is evaluated first: a request carrying the canary header goes to the canary pool. Every other
+    /// request goes to the canary pool with a probability of `canary_percent`, and to the default pool otherwise.
+    /// Nothing goes to the canary pool while no canary upstream is active.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client request.
+    /// * `rng` - The random number generator drawing the canary share, seedable for reproducible tests.
+    ///
+    /// # Returns
+    ///
+    /// * `Pool` - The pool the upstream server must be selected from.
+    pub fn route<R: Rng>(&self, request: &Request<Vec<u8>>, rng: &mut R) -> Pool {
+        let is_canary = match &self.canary_header {
+            Some(canary_header) if canary_header.matches(request) => true,
+            _ => self.canary_percent > 0 && rng.gen_range(0..100) < self.canary_percent,
+        };
+
+        if !is_canary {
+            return Pool::Default;
+        }
+        if self.canary.is_empty() {
+            log::warn!("No canary upstream is active, routing the canary request to the default pool");
+            return Pool::Default;
+        }
+        Pool::Canary
+    }
+
+    /// Returns the chance in percent that `route` sends a request to the canary pool: 100 for a request carrying the
+    /// canary header, `canary_percent` for the other ones, and 0 while no canary upstream is active.
+    pub fn canary_share(&self, request: &Request<Vec<u8>>) -> u8 {
+        if self.canary.is_empty() {
+            return 0;
+        }
+        match &self.canary_header {
+            Some(canary_header) if canary_header.matches(request) => 100,
+            _ => self.canary_percent.min(100),
+        }
+    }
+
+    /// Returns the pool an upstream server forced with a debug routing header belongs to, the canary pool for a
+    /// canary upstream and the default pool otherwise.
+    pub fn pool_of(&self, upstream_address: &str) -> Pool {
+        match self.canary.iter().any(|canary| canary == upstream_address) {
+            true => Pool::Canary,
+            false => Pool::Default,
+        }
+    }
+
+    /// Returns the active upstream servers of `pool`.
+    pub fn upstreams(&self, pool: Pool) -> &[String] {
+        match pool {
+            Pool::Default => &self.default,
+            Pool::Canary => &self.canary,
+        }
+    }
+}
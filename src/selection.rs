@@ -0,0 +1,75 @@
+//! # Selection Module
+//!
+//! This module picks the upstream server a request attempt is sent to.
+//!
+//! ## Functions
+//!
+//! ### `select_upstream`
+//!
+//! This function randomly selects one of the candidate upstream servers, skipping the ones excluded for the current
+//! attempt. It is a pure function of its inputs (apart from the random number generator) and doesn't allocate besides
+//! the returned address, so it can be benchmarked on its own (see `benches/selection.rs`).
+//!
+//! - **Parameters:**
+//!   - `upstream_address_list`: The addresses of the candidate upstream servers.
+//!   - `excluded`: The upstream addresses that must not be selected for this attempt.
+//!
+//! - **Returns:**
+//!   - `Some(String)`: The selected upstream address.
+//!   - `None`: If every candidate is excluded or the list is empty.
+//!
+//! ### `selection_odds`
+//!
+//! This function returns the chance of every candidate to be selected by `select_upstream`, for the explanations of
+//! the routing decisions.
+
+use std::collections::HashSet;
+
+use rand::Rng;
+
+/// Randomly selects an upstream server from the provided list, skipping the excluded ones.
+///
+/// This function is the single place where an upstream is picked for a request attempt. Callers can forbid specific
+/// upstreams for the current attempt (for example an upstream that already failed to accept the connection) by adding
+/// their address to the exclusion set.
+///
+/// # Arguments
+///
+/// * `upstream_address_list` - A slice containing the addresses of the candidate upstream servers.
+/// * `excluded` - A set of upstream addresses that must not be selected for this attempt.
+///
+/// # Returns
+///
+/// * `Option<String>` - The selected upstream address, or `None` if every candidate is excluded or the list is empty.
+///
+/// # Example
+///
+/// ```rust
+/// use std::collections::HashSet;
+/// use rust_loadbalancer::selection::select_upstream;
+///
+/// let upstream_addresses = vec!["127.0.0.1:8081".to_string(), "127.0.0.1:8082".to_string()];
+/// let excluded = HashSet::from(["127.0.0.1:8081".to_string()]);
+/// assert_eq!(select_upstream(&upstream_addresses, &excluded), Some("127.0.0.1:8082".to_string()));
+/// ```
+pub fn select_upstream(upstream_address_list: &[String], excluded: &HashSet<String>) -> Option<String> {
+    let is_candidate = |address: &&String| !excluded.contains(*address);
+
+    // count the candidates and pick one by index, so no list of candidates has to be allocated
+    let candidates = upstream_address_list.iter().filter(is_candidate).count();
+    if candidates == 0 {
+        return None;
+    }
+
+    let selected = rand::thread_rng().gen_range(0..candidates);
+    upstream_address_list.iter().filter(is_candidate).nth(selected).cloned()
+}
+
+/// Returns the chance of every candidate upstream server to be selected by `select_upstream`, in the order of the list:
+/// the candidates that aren't excluded share it evenly.
+pub fn selection_odds(upstream_address_list: &[String], excluded: &HashSet<String>) -> Vec<(String, f64)> {
+    let candidates = upstream_address_list.iter().filter(|address| !excluded.contains(*address)).count();
+    upstream_address_list.iter()
+        .map(|address| (address.clone(), if excluded.contains(address) { 0.0 } else { 1.0 / candidates as f64 }))
+        .collect()
+}
@@ -0,0 +1,181 @@
+//! # Spool Module
+//!
+//! This module spills the large request bodies to disk instead of holding them in memory.
+//!
+//! A request body is kept until the request is answered, so the request can be sent again on a new connection when
+//! the upstream server closed a reused one. With `--body-memory-limit`, the bodies up to the limit stay in memory,
+//! and the larger ones are written to a file of the `--spool-dir` directory as they are read from the client. The
+//! file is streamed to the upstream server after the request head, and read again from its start if the request is
+//! sent again.
+//!
+//! The file is deleted when the last handle of its `SpooledBody` is dropped: once the request is answered, when it
+//! fails, or when the client connection is dropped while the body is being spilled. A disk full or over quota fails
+//! the request with 507 Insufficient Storage, any other failure of the spool with 503 Service Unavailable.
+//!
+//! ## Structures
+//!
+//! - `BodySpool`: The memory limit and the directory of the spilled bodies, with the requests and bytes spilled.
+//! - `SpooledBody`: A request body spilled to a file, deleted with its last handle.
+//!
+//! ## Enums
+//!
+//! - `Error`: The reasons a body can't be spilled.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The memory limit and the directory of the spilled bodies, with the requests and bytes spilled.
+#[derive(Debug)]
+pub struct BodySpool {
+    /// Size in bytes over which a body is spilled to disk.
+    memory_limit: usize,
+
+    /// Directory the spilled bodies are written to.
+    dir: PathBuf,
+
+    /// Sequence number of the next file, unique within the process.
+    next_file: AtomicU64,
+
+    /// Number of request bodies spilled to disk.
+    spilled: AtomicU64,
+
+    /// Number of bytes spilled to disk.
+    spilled_bytes: AtomicU64,
+}
+
+/// The reasons a body can't be spilled.
+#[derive(Debug)]
+pub enum Error {
+    /// The client closed the connection, or failed, before the whole body was read.
+    ClientFailed,
+    /// The spool file couldn't be created or written.
+    Storage(std::io::Error),
+}
+
+impl BodySpool {
+    /// Creates a spool writing the bodies larger than `memory_limit` bytes to `dir`.
+    pub fn new(memory_limit: usize, dir: PathBuf) -> BodySpool {
+        BodySpool { memory_limit, dir, next_file: AtomicU64::new(0), spilled: AtomicU64::new(0), spilled_bytes: AtomicU64::new(0) }
+    }
+
+    /// Returns the directory the spilled bodies are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Tells whether a body of `content_length` bytes must be spilled to disk.
+    pub fn spills(&self, content_length: usize) -> bool {
+        content_length > self.memory_limit
+    }
+
+    /// Writes a request body to a new spool file, as it is read from the client.
+    ///
+    /// The file is deleted if writing it fails, or if the returned future is dropped before it completes.
+    ///
+    /// # Arguments
+    ///
+    /// * `received` - The start of the body, read along with the request head.
+    /// * `client_stream` - The client connection the rest of the body is read from.
+    /// * `content_length` - The length of the whole body.
+    /// * `buffer` - The buffer the body is read through.
+    pub async fn spill<S: AsyncRead + Unpin>(&self, received: &[u8], client_stream: &mut S, content_length: usize, buffer: &mut [u8]) -> Result<SpooledBody, Error> {
+        let path = self.dir.join(format!("lb-body-{}-{}", std::process::id(), self.next_file.fetch_add(1, Ordering::Relaxed)));
+        let mut file = tokio::fs::File::create(&path).await.map_err(Error::Storage)?;
+        // from here on, the file is deleted whatever happens to the request
+        let spooled = SpooledBody { file: Arc::new(SpoolFile { path, len: content_length as u64 }) };
+
+        file.write_all(received).await.map_err(Error::Storage)?;
+        let mut written = received.len();
+        while written < content_length {
+            let remaining = (content_length - written).min(buffer.len());
+            let bytes = match client_stream.read(&mut buffer[..remaining]).await {
+                Ok(0) | Err(_) => return Err(Error::ClientFailed),
+                Ok(bytes) => bytes,
+            };
+            file.write_all(&buffer[..bytes]).await.map_err(Error::Storage)?;
+            written += bytes;
+        }
+        file.flush().await.map_err(Error::Storage)?;
+
+        self.spilled.fetch_add(1, Ordering::Relaxed);
+        self.spilled_bytes.fetch_add(content_length as u64, Ordering::Relaxed);
+        Ok(spooled)
+    }
+
+    /// Returns the number of request bodies spilled to disk.
+    pub fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes spilled to disk.
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spilled_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Renders the bodies spilled as the `lb_spilled_requests_total` and `lb_spilled_bytes_total` counters, in the
+    /// Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_spilled_requests_total counter\n");
+        let _ = writeln!(rendered, "lb_spilled_requests_total {}", self.spilled());
+        rendered.push_str("# TYPE lb_spilled_bytes_total counter\n");
+        let _ = writeln!(rendered, "lb_spilled_bytes_total {}", self.spilled_bytes());
+        rendered
+    }
+}
+
+/// A spool file, deleted when dropped.
+#[derive(Debug)]
+struct SpoolFile {
+    /// Path of the file.
+    path: PathBuf,
+
+    /// Length of the body it holds.
+    len: u64,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::error!("Failed to delete the spool file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A request body spilled to a file, carried in the extensions of its request. The file is deleted with the last
+/// handle.
+#[derive(Debug, Clone)]
+pub struct SpooledBody {
+    file: Arc<SpoolFile>,
+}
+
+impl SpooledBody {
+    /// Returns the path of the spool file.
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    /// Returns the length of the body.
+    pub fn len(&self) -> u64 {
+        self.file.len
+    }
+
+    /// Tells whether the body is empty, which a spilled body never is.
+    pub fn is_empty(&self) -> bool {
+        self.file.len == 0
+    }
+
+    /// Writes the whole body to `writer`, read from the start of the file.
+    pub async fn copy_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<u64, std::io::Error> {
+        let mut file = tokio::fs::File::open(&self.file.path).await?;
+        tokio::io::copy(&mut file, writer).await
+    }
+}
+
+/// Tells whether a spool failure is the disk being full or over quota, answered with 507 Insufficient Storage.
+pub fn is_out_of_space(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded)
+}
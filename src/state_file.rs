@@ -0,0 +1,142 @@
+//! # State File Module
+//!
+//! This module persists the health state of the upstream servers across restarts of the proxy server.
+//!
+//! Without it, every restart forgets which upstream servers are down, and no upstream server receives traffic until
+//! the first health cycle completed. With a state file, the probe record of every upstream server is saved after
+//! every health cycle and on shutdown, and loaded on startup: the upstream servers that were healthy serve traffic
+//! right away, the ones that were down stay out of rotation until they pass a health check, and the probe counters
+//! carry on from their saved values.
+//!
+//! The state file is JSON:
+//!
+//! ```json
+//! {"saved_at": 1760000000, "upstreams": {"10.0.0.1:80": {"healthy": true, "last_success": 1759999995, "probes": 120, "failures": 2}}}
+//! ```
+//!
+//! Times are seconds since the Unix epoch. A state file older than the configured maximum age is ignored, the state
+//! of the upstream servers may have changed since.
+//!
+//! ## Functions
+//!
+//! ### `save_state`
+//!
+//! This function writes the probe records of the health metrics to the state file.
+//!
+//! ### `load_state`
+//!
+//! This function reads the probe records saved in the state file, unless it is too old.
+//!
+//! ### `parse_state`
+//!
+//! This function parses the contents of a state file into probe records.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::health_metrics::{HealthMetrics, ProbeRecord};
+
+/// Writes the probe records of the health metrics to the state file.
+///
+/// The state is written to a temporary file renamed over the state file, so a crash while saving never leaves a
+/// truncated state file behind.
+///
+/// # Arguments
+///
+/// * `path` - The path of the state file.
+/// * `health_metrics` - The health metrics holding the probe records to save.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the state file was written.
+/// * `Err(std::io::Error)` - If the state file couldn't be written.
+pub fn save_state(path: &Path, health_metrics: &HealthMetrics) -> Result<(), std::io::Error> {
+    let now = SystemTime::now();
+
+    let upstreams: Map<String, Value> = health_metrics.probe_records().iter()
+        .map(|(address, record)| {
+            let upstream = json!({
+                "healthy": record.healthy,
+                "last_success": record.last_success.map(unix_seconds),
+                "probes": record.probes,
+                "failures": record.failures,
+            });
+            (address.clone(), upstream)
+        })
+        .collect();
+    let state = json!({ "saved_at": unix_seconds(now), "upstreams": upstreams });
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, state.to_string())?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Reads the probe records saved in the state file.
+///
+/// # Arguments
+///
+/// * `path` - The path of the state file.
+/// * `max_age` - How old the state file may be for its state to be used.
+///
+/// # Returns
+///
+/// * `Ok(HashMap<String, ProbeRecord>)` - The saved probe record of every upstream server, by address.
+/// * `Err(String)` - If the state file can't be read, is corrupt or is older than `max_age`.
+pub fn load_state(path: &Path, max_age: Duration) -> Result<HashMap<String, ProbeRecord>, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    parse_state(&contents, SystemTime::now(), max_age)
+}
+
+/// Parses the contents of a state file into probe records.
+///
+/// The restored records tell when their probe completed from the age of the state file, and carry no probe duration.
+///
+/// # Arguments
+///
+/// * `contents` - The contents of the state file.
+/// * `now` - The current time, to tell the age of the state.
+/// * `max_age` - How old the state may be to be used.
+///
+/// # Returns
+///
+/// * `Ok(HashMap<String, ProbeRecord>)` - The saved probe record of every upstream server, by address.
+/// * `Err(String)` - If the contents are not a valid state, or if the state is older than `max_age`.
+pub fn parse_state(contents: &[u8], now: SystemTime, max_age: Duration) -> Result<HashMap<String, ProbeRecord>, String> {
+    let state: Value = serde_json::from_slice(contents).map_err(|e| format!("invalid state file: {}", e))?;
+
+    let saved_at = state["saved_at"].as_u64().ok_or("invalid state file: no saved_at time")?;
+    let age = now.duration_since(UNIX_EPOCH + Duration::from_secs(saved_at)).unwrap_or(Duration::ZERO);
+    if age > max_age {
+        return Err(format!("the state file is {}s old, older than the {}s allowed", age.as_secs(), max_age.as_secs()));
+    }
+    let completed_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+
+    let upstreams = state["upstreams"].as_object().ok_or("invalid state file: no upstreams")?;
+    upstreams.iter()
+        .map(|(address, upstream)| {
+            let invalid = || format!("invalid state file: invalid state of {}", address);
+            let last_success = match &upstream["last_success"] {
+                Value::Null => None,
+                last_success => Some(UNIX_EPOCH + Duration::from_secs(last_success.as_u64().ok_or_else(invalid)?)),
+            };
+            let record = ProbeRecord {
+                duration: Duration::ZERO,
+                healthy: upstream["healthy"].as_bool().ok_or_else(invalid)?,
+                completed_at,
+                last_success,
+                probes: upstream["probes"].as_u64().ok_or_else(invalid)?,
+                failures: upstream["failures"].as_u64().ok_or_else(invalid)?,
+            };
+            Ok((address.clone(), record))
+        })
+        .collect()
+}
+
+/// Returns the number of seconds between the Unix epoch and `time`.
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
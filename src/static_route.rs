@@ -0,0 +1,123 @@
+//! # Static Route Module
+//!
+//! This module answers the requests for a few paths with a fixed response, without contacting an upstream server.
+//!
+//! Endpoints such as `/robots.txt` or a small `/version` document don't need a backend. They are given with
+//! `--static-route`, as the path followed by `key=value` settings:
+//!
+//! - `file=./robots.txt`: A file the body is read from, its modification time being the `Last-Modified` date.
+//! - `body={"version":"1.4.2"}`: The body itself, without spaces, dated from the start of the proxy server.
+//! - `content_type=text/plain`: The `Content-Type` of the response. Default is `application/octet-stream`.
+//!
+//! The bodies are read when the options are parsed, so a missing file stops the proxy server at startup, and are
+//! capped to `MAX_STATIC_BODY_SIZE` so the proxy server doesn't turn into a file server. `GET` and `HEAD` requests
+//! are answered with the body, or with 304 Not Modified when their `If-Modified-Since` date isn't older than the
+//! body. Other methods are answered with 405 Method Not Allowed.
+//!
+//! ## Structures
+//!
+//! - `StaticRoute`: A path and the fixed response it is answered with.
+//!
+//! ## Functions
+//!
+//! ### `find`
+//!
+//! This function returns the static route of a request, if any.
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::header::{HeaderValue, IF_MODIFIED_SINCE};
+use http::{Method, Request, StatusCode};
+
+use crate::emit::ProxyResponse;
+
+/// Maximum size of the body of a static route.
+pub const MAX_STATIC_BODY_SIZE: usize = 64 * 1024;
+
+/// Name of the upstream server reported in the access log for the requests answered by a static route.
+pub const STATIC_UPSTREAM: &str = "static";
+
+/// A path answered with a fixed response.
+#[derive(Debug, Clone)]
+pub struct StaticRoute {
+    /// Path of the requests answered, matched exactly.
+    pub path: String,
+
+    /// Content type of the body.
+    pub content_type: HeaderValue,
+
+    /// The body of the responses.
+    pub body: Vec<u8>,
+
+    /// Date of the body, to the second.
+    pub last_modified: SystemTime,
+}
+
+impl StaticRoute {
+    /// Returns the status and the raw response answering `request`.
+    pub fn respond(&self, request: &Request<Vec<u8>>) -> (StatusCode, Vec<u8>) {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            let response = ProxyResponse::new(StatusCode::METHOD_NOT_ALLOWED).header("Allow", "GET, HEAD");
+            return (response.status(), response.emit(request.method()));
+        }
+
+        let last_modified = httpdate::fmt_http_date(self.last_modified);
+        let not_modified = request.headers().get(IF_MODIFIED_SINCE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .is_some_and(|date| self.last_modified <= date);
+        let response = match not_modified {
+            true => ProxyResponse::new(StatusCode::NOT_MODIFIED).header("Last-Modified", last_modified),
+            false => ProxyResponse::new(StatusCode::OK)
+                .header("Content-Type", &self.content_type)
+                .header("Last-Modified", last_modified)
+                .body(self.body.clone()),
+        };
+        (response.status(), response.emit(request.method()))
+    }
+}
+
+impl FromStr for StaticRoute {
+    type Err = String;
+
+    /// Parses a route such as `/robots.txt file=./robots.txt content_type=text/plain`, reading its file.
+    fn from_str(route: &str) -> Result<StaticRoute, String> {
+        let mut words = route.split_whitespace();
+        let path = words.next().filter(|path| path.starts_with('/')).ok_or(format!("expected a route starting with a path, got {:?}", route))?;
+
+        let mut body = None;
+        let mut content_type = HeaderValue::from_static("application/octet-stream");
+        for setting in words {
+            let (key, value) = setting.split_once('=').ok_or(format!("expected key=value, got {:?}", setting))?;
+            match key {
+                "file" => {
+                    let metadata = std::fs::metadata(value).map_err(|e| format!("can't read {:?}: {}", value, e))?;
+                    if metadata.len() > MAX_STATIC_BODY_SIZE as u64 {
+                        return Err(format!("{:?} is larger than {} bytes", value, MAX_STATIC_BODY_SIZE));
+                    }
+                    let contents = std::fs::read(value).map_err(|e| format!("can't read {:?}: {}", value, e))?;
+                    body = Some((contents, metadata.modified().unwrap_or_else(|_| SystemTime::now())));
+                }
+                "body" => body = Some((value.as_bytes().to_vec(), SystemTime::now())),
+                "content_type" => {
+                    content_type = HeaderValue::from_str(value).map_err(|_| format!("invalid content type {:?}", value))?;
+                }
+                _ => return Err(format!("unknown setting {:?}, expected file, body or content_type", key)),
+            }
+        }
+
+        let (body, modified) = body.ok_or(format!("expected a file or body setting in {:?}", route))?;
+        if body.len() > MAX_STATIC_BODY_SIZE {
+            return Err(format!("the body of {} is larger than {} bytes", path, MAX_STATIC_BODY_SIZE));
+        }
+        // HTTP dates have a precision of a second
+        let seconds = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        Ok(StaticRoute { path: path.to_string(), content_type, body, last_modified: UNIX_EPOCH + Duration::from_secs(seconds) })
+    }
+}
+
+/// Returns the static route answering `request`, or `None` if it must be proxied.
+pub fn find<'a>(routes: &'a [StaticRoute], request: &Request<Vec<u8>>) -> Option<&'a StaticRoute> {
+    routes.iter().find(|route| route.path == request.uri().path())
+}
@@ -0,0 +1,141 @@
+//! # Supervisor Module
+//!
+//! This module keeps the long-running tasks of the proxy server alive when they panic.
+//!
+//! A panic only unwinds the task it happens in: without supervision, a panic in the health check loop would stop the
+//! health checks for good while the proxy server keeps running on a frozen set of upstream servers. `supervise` runs
+//! a task and starts it again whenever it panics, after a backoff doubling from `INITIAL_RESTART_BACKOFF` up to
+//! `MAX_RESTART_BACKOFF`, and counts the restarts of every task. The backoff starts over once a task has run for
+//! longer than the maximum backoff.
+//!
+//! The panic hook installed by `install_panic_hook` reports the panics, with their location and backtrace, through
+//! the logging system when a logger is set up, and on the standard error otherwise.
+//!
+//! ## Structures
+//!
+//! - `TaskRestarts`: The restarts of every supervised task, and the panics of the connection tasks.
+//!
+//! ## Functions
+//!
+//! - `supervise`: Runs a task, restarting it after a backoff whenever it panics.
+//! - `install_panic_hook`: Reports the panics through the logging system.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+/// Delay before the first restart of a task that panicked.
+pub const INITIAL_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum delay before restarting a task that keeps panicking.
+pub const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The restarts of every supervised task, and the panics of the connection tasks.
+#[derive(Debug, Default)]
+pub struct TaskRestarts {
+    /// Number of restarts of every supervised task, by task name.
+    restarts: Mutex<BTreeMap<&'static str, u64>>,
+
+    /// Number of connection tasks that panicked.
+    connection_panics: AtomicU64,
+}
+
+impl TaskRestarts {
+    /// Counts a restart of `task`.
+    pub fn record_restart(&self, task: &'static str) {
+        *self.restarts.lock().unwrap().entry(task).or_insert(0) += 1;
+    }
+
+    /// Returns the number of restarts of `task`.
+    pub fn restarts(&self, task: &str) -> u64 {
+        self.restarts.lock().unwrap().get(task).copied().unwrap_or(0)
+    }
+
+    /// Counts a connection task that panicked.
+    pub fn record_connection_panic(&self) {
+        self.connection_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of connection tasks that panicked.
+    pub fn connection_panics(&self) -> u64 {
+        self.connection_panics.load(Ordering::Relaxed)
+    }
+
+    /// Renders the restarts as the `lb_task_restarts_total` counter by task, and the connection panics as the
+    /// `lb_connection_panics_total` counter, in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_task_restarts_total counter\n");
+        for (task, count) in self.restarts.lock().unwrap().iter() {
+            let _ = writeln!(rendered, "lb_task_restarts_total{{task=\"{}\"}} {}", task, count);
+        }
+        rendered.push_str("# TYPE lb_connection_panics_total counter\n");
+        let _ = writeln!(rendered, "lb_connection_panics_total {}", self.connection_panics());
+        rendered
+    }
+}
+
+/// Runs a task in its own tokio task, and starts it again whenever it panics.
+///
+/// Returns once the task returns without panicking, or if it is cancelled.
+///
+/// # Arguments
+///
+/// * `task` - The name of the task, labeling its restarts.
+/// * `restarts` - The counters the restarts are recorded in.
+/// * `start` - Creates the future of the task, called for every run.
+pub async fn supervise<F, Fut>(task: &'static str, restarts: &TaskRestarts, mut start: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut backoff = INITIAL_RESTART_BACKOFF;
+    loop {
+        let started_at = Instant::now();
+        match tokio::spawn(start()).await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => {
+                // a task that ran for a while before panicking isn't failing in a loop
+                if started_at.elapsed() > MAX_RESTART_BACKOFF {
+                    backoff = INITIAL_RESTART_BACKOFF;
+                }
+                log::error!("Task {} panicked, restarting it in {:?}", task, backoff);
+                restarts.record_restart(task);
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Installs a panic hook reporting the panics with their location and backtrace, through the logging system when a
+/// logger is set up, and on the standard error otherwise.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        let message = format!(
+            "Thread '{}' panicked at {}: {}\n{}",
+            thread.name().unwrap_or("<unnamed>"),
+            info.location().map_or_else(|| "unknown location".to_string(), |location| location.to_string()),
+            panic_message(info.payload()),
+            std::backtrace::Backtrace::force_capture(),
+        );
+        if log::log_enabled!(log::Level::Error) {
+            log::error!("{}", message);
+        } else {
+            eprintln!("{}", message);
+        }
+    }));
+}
+
+/// Returns the message a panic was raised with, from its payload.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
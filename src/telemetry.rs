@@ -0,0 +1,114 @@
+//! # Telemetry Module
+//!
+//! This module traces every proxied request as a span, exported to an OpenTelemetry collector.
+//!
+//! A `RequestSpan` is opened once a request has been read, and closed when it has been answered. It carries the
+//! method, the path and the client address of the request, then the upstream server the request was sent to, the
+//! status it was answered with and the time taken, as the attributes `upstream.address`,
+//! `http.response.status_code` and `duration_ms`. A request answered by the proxy server itself records `static` as
+//! its upstream server, and an error answered before an upstream server was selected records no upstream server.
+//!
+//! The spans are built with the `tracing` crate and cost next to nothing until a subscriber is installed. With the
+//! `otel` feature, `--otel-endpoint` installs a subscriber exporting them over OTLP (gRPC) in batches, with the
+//! `rust_loadbalancer` service name.
+//!
+//! ## Structures
+//!
+//! - `RequestSpan`: The span of a request, and the instant the request started being read.
+//!
+//! ## Functions
+//!
+//! ### `otlp_provider` (`otel` feature)
+//!
+//! This function creates the tracer provider exporting the spans to an OTLP endpoint.
+//!
+//! ### `subscriber` (`otel` feature)
+//!
+//! This function creates the subscriber turning the spans into OpenTelemetry spans of a tracer provider.
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use http::Request;
+use tracing::field::Empty;
+use tracing::Span;
+
+/// Name of the service the spans are exported for.
+pub const SERVICE_NAME: &str = "rust_loadbalancer";
+
+/// The span of a request, from the moment it started being read until it is answered.
+#[derive(Debug)]
+pub struct RequestSpan {
+    /// The span, closed when dropped.
+    span: Span,
+
+    /// The instant the request started being read.
+    started: Instant,
+}
+
+impl RequestSpan {
+    /// Opens the span of a request read from `client_address`, started being read at `started`.
+    pub fn start(request: &Request<Vec<u8>>, client_address: SocketAddr, started: Instant) -> RequestSpan {
+        let span = tracing::info_span!(
+            "request",
+            otel.kind = "server",
+            http.request.method = %request.method(),
+            url.path = request.uri().path(),
+            client.address = %client_address.ip(),
+            upstream.address = Empty,
+            http.response.status_code = Empty,
+            duration_ms = Empty,
+        );
+        RequestSpan { span, started }
+    }
+
+    /// Returns the span of the request.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Records the upstream server the request is sent to.
+    pub fn record_upstream(&self, upstream_address: &str) {
+        self.span.record("upstream.address", upstream_address);
+    }
+
+    /// Records the status the request was answered with, and the time taken until `answered_at`.
+    pub fn record_status(&self, status: u16, answered_at: Instant) {
+        self.span.record("http.response.status_code", status);
+        self.span.record("duration_ms", answered_at.saturating_duration_since(self.started).as_millis() as u64);
+    }
+}
+
+/// Creates the tracer provider exporting the spans to an OTLP endpoint over gRPC, such as `http://localhost:4317`.
+///
+/// The spans are exported in batches from a background thread, so the requests never wait for the collector. The
+/// provider must be created from within the Tokio runtime.
+///
+/// # Returns
+///
+/// * `Ok(SdkTracerProvider)` - The provider, to shut down before exiting so the last spans are exported.
+/// * `Err(String)` - If the endpoint is invalid.
+#[cfg(feature = "otel")]
+pub fn otlp_provider(endpoint: &str) -> Result<opentelemetry_sdk::trace::SdkTracerProvider, String> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| format!("invalid OTLP endpoint {:?}: {}", endpoint, e))?;
+    let resource = opentelemetry_sdk::Resource::builder().with_service_name(SERVICE_NAME).build();
+
+    Ok(opentelemetry_sdk::trace::SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource).build())
+}
+
+/// Creates the subscriber turning the spans into OpenTelemetry spans of `provider`, to install as the global default
+/// or, in tests, for a scope.
+#[cfg(feature = "otel")]
+pub fn subscriber(provider: &opentelemetry_sdk::trace::SdkTracerProvider) -> impl tracing::Subscriber + Send + Sync {
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let tracer = provider.tracer(SERVICE_NAME);
+    tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer))
+}
@@ -0,0 +1,104 @@
+use std::io::Cursor;
+use std::net::IpAddr;
+
+use http::{Request, StatusCode};
+
+use crate::acl::{evaluate, AclRule};
+use crate::request::{request_controller, Error, RequestConfig};
+
+
+fn request(method: &str, path: &str) -> Request<Vec<u8>> {
+    Request::builder().method(method).uri(path).header("Host", "localhost").body(Vec::new()).unwrap()
+}
+
+
+fn rules(rules: &[&str]) -> Vec<AclRule> {
+    rules.iter().map(|rule| rule.parse().unwrap()).collect()
+}
+
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+
+#[test]
+fn test_method_rule() {
+    let rules = rules(&["deny method=TRACE"]);
+
+    assert_eq!(evaluate(&rules, &request("TRACE", "/"), ip("192.0.2.1")), Some(0));
+    assert_eq!(evaluate(&rules, &request("GET", "/"), ip("192.0.2.1")), None);
+    assert_eq!(rules[0].status, StatusCode::FORBIDDEN);
+}
+
+
+#[test]
+fn test_path_rule_with_status() {
+    let rules = rules(&[r"deny path=^/\.git/ status=404"]);
+
+    assert_eq!(evaluate(&rules, &request("GET", "/.git/config"), ip("192.0.2.1")), Some(0));
+    assert_eq!(evaluate(&rules, &request("GET", "/docs/.git/config"), ip("192.0.2.1")), None);
+    assert_eq!(evaluate(&rules, &request("GET", "/.gitignore"), ip("192.0.2.1")), None);
+    assert_eq!(rules[0].status, StatusCode::NOT_FOUND);
+}
+
+
+#[test]
+fn test_method_and_path_rule_with_exception() {
+    let rules = rules(&["deny method=DELETE path=^/api/ except_cidr=10.0.0.0/8 except_cidr=2001:db8::/32"]);
+
+    assert_eq!(evaluate(&rules, &request("DELETE", "/api/users/1"), ip("192.0.2.1")), Some(0));
+    assert_eq!(evaluate(&rules, &request("DELETE", "/api/users/1"), ip("10.1.2.3")), None);
+    assert_eq!(evaluate(&rules, &request("DELETE", "/api/users/1"), ip("2001:db8::1")), None);
+    // both conditions must hold
+    assert_eq!(evaluate(&rules, &request("GET", "/api/users/1"), ip("192.0.2.1")), None);
+    assert_eq!(evaluate(&rules, &request("DELETE", "/static/logo.png"), ip("192.0.2.1")), None);
+}
+
+
+#[test]
+fn test_first_matching_rule_applies() {
+    let rules = rules(&["deny method=DELETE path=^/api/admin/ status=404", "deny method=DELETE path=^/api/ status=405", "deny method=DELETE"]);
+
+    assert_eq!(evaluate(&rules, &request("DELETE", "/api/admin/users"), ip("192.0.2.1")), Some(0));
+    assert_eq!(evaluate(&rules, &request("DELETE", "/api/users"), ip("192.0.2.1")), Some(1));
+    assert_eq!(evaluate(&rules, &request("DELETE", "/"), ip("192.0.2.1")), Some(2));
+}
+
+
+#[test]
+fn test_invalid_rules_are_rejected() {
+    for rule in [
+        "allow method=GET",
+        "deny",
+        "deny status=404",
+        "deny path=^/(",
+        "deny method=GET except_cidr=10.0.0.0",
+        "deny method=GET status=200",
+        "deny method=GET status=teapot",
+        "deny method=GET host=example.com",
+        "deny method",
+    ] {
+        assert!(rule.parse::<AclRule>().is_err(), "{:?} was accepted", rule);
+    }
+}
+
+
+#[tokio::test]
+async fn test_denied_request_is_reported_by_request_controller() {
+    let config = RequestConfig { acl_rules: rules(&["deny method=TRACE status=405"]), ..RequestConfig::default() };
+    let mut buffer = vec![0; 1024];
+
+    let mut stream = Cursor::new(b"TRACE /debug HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+    let denied = request_controller(&mut stream, "192.0.2.1:1234".parse().unwrap(), &mut buffer, &config).await;
+    match denied {
+        Err(Error::Denied { rule, status, client, method, target }) => {
+            assert_eq!((rule, status, method.as_str(), target.as_str()), (0, StatusCode::METHOD_NOT_ALLOWED, "TRACE", "/debug"));
+            assert_eq!(client, "192.0.2.1".parse::<IpAddr>().unwrap());
+        }
+        other => panic!("expected the request to be denied, got {:?}", other),
+    }
+
+    let mut stream = Cursor::new(b"GET /debug HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+    assert!(request_controller(&mut stream, "192.0.2.1:1234".parse().unwrap(), &mut buffer, &config).await.is_ok());
+}
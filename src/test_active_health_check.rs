@@ -1,101 +1,147 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use http::header::HeaderValue;
+use http::Method;
 
+use crate::http_health_checks::{basic_http_health_check, format_health_request, http_health_check, HealthCheckBody, HealthCheckRequest, DEFAULT_HEALTH_CHECK_TIMEOUT, MAX_HEALTH_BODY_SIZE};
 
 
+/// Starts a local upstream on port 0 answering a single health check with `response`, and returns its address.
+fn scripted_upstream(response: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
 
-/// Performs a basic HTTP health check on an upstream server.
-///
-/// This function sends a simple GET request to the upstream server to check if it's healthy.
-/// The health check is successful if the response contains "200 OK."
-///
-/// # Arguments
-///
-/// * `upstream_ip` - A String containing the IP address and port of the upstream server.
-/// * `path` - A String representing the path for the GET request.
-///
-/// # Returns
-///
-/// * `Result<String, std::io::Error>` - If the health check is successful, returns Ok with the upstream address.
-///                                    If the health check fails, returns an Err with an I/O error containing the upstream address.
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
+    });
 
+    address
+}
 
-pub fn basic_http_health_check(upstream_ip : String, path : String ) -> Result< String, std::io::Error> {
-    let upstream_address = upstream_ip;
 
-    // send a simple GET request to the upstream server to check if it's healthy
-    let mut upstream_stream = match TcpStream::connect(&upstream_address) {
-        Ok(stream) => stream,
-        Err(_) => {
-            //     return a simple error containing the upstream_address
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, upstream_address.to_string()));
-        }
-    };
-
-
-    // send a simple GET request to the upstream server to check if it's healthy returning 200 OK
-    return match simple_get_request(&mut upstream_stream, path) {
-        Ok(_) => {
-            //     return a simple Ok containing the upstream_address
-            Ok(upstream_address.to_string())
-        },
-        Err(_) => {
-            //     return a simple error containing the upstream_address
-            Err(std::io::Error::new(std::io::ErrorKind::Other, upstream_address.to_string()))
+#[tokio::test]
+async fn test_active_health_check() {
+    let upstream_address = scripted_upstream("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+
+    assert!(basic_http_health_check(upstream_address, "/".to_string()).await.is_ok());
+}
+
+
+#[tokio::test]
+async fn test_unhealthy_status_fails_health_check() {
+    let upstream_address = scripted_upstream("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+
+    assert!(basic_http_health_check(upstream_address, "/".to_string()).await.is_err());
+}
+
+
+#[tokio::test]
+async fn test_inactive_health_check() {
+    // reserve a port and release it so nothing is listening on it
+    let closed_address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+    assert!(basic_http_health_check(closed_address, "/".to_string()).await.is_err());
+
+    // an address without a port can't be connected to
+    assert!(basic_http_health_check("1.1.1.1".to_string(), "/".to_string()).await.is_err());
+}
+
+
+#[tokio::test]
+async fn test_silent_upstream_fails_health_check_on_timeout() {
+    // the connection is accepted by the backlog, but the upstream never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let request = HealthCheckRequest { timeout: Duration::from_millis(200), ..HealthCheckRequest::get("/".to_string()) };
+
+    let started_at = Instant::now();
+    let checked = http_health_check(upstream_address, &request).await;
+
+    assert_eq!(checked.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    assert!(started_at.elapsed() < Duration::from_secs(2), "check took {:?}", started_at.elapsed());
+    drop(listener);
+}
+
+
+/// Starts a local upstream on port 0 answering a single health check with 200 OK only if it is exactly `expected`.
+fn rpc_upstream(expected: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+        // the request may come in several reads
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while request.len() < expected.len() {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(length) => request.extend_from_slice(&buffer[..length]),
+            }
         }
-    }
+
+        let response = if request == expected { "HTTP/1.1 200 OK\r\n\r\n" } else { "HTTP/1.1 405 Method Not Allowed\r\n\r\n" };
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+
+    address
 }
 
 
-/// Send a simple GET request to the upstream server to check if it's healthy.
-///
-/// This function takes a mutable reference to a TcpStream and returns a Result containing a unit type or an error.
-/// The health check is successful if the response contains "200 OK."
-///
-/// # Arguments
-///
-/// * `stream` - A mutable reference to a TcpStream connected to the upstream server.
-/// * `path` - A String representing the path for the GET request.
-///
-/// # Returns
-///
-/// * `Result<(), std::io::Error>` - If the health check is successful, returns Ok.
-///                                If the health check fails, returns an Err with an I/O error.
-fn simple_get_request(stream: &mut TcpStream, path : String) -> Result<(), std::io::Error> {
-
-
-    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
-    stream.write(request.as_bytes())?;
-
-    // check the http code
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
-    let response = String::from_utf8_lossy(&buffer[..bytes_read]);
-
-    // check if the response contains 200 OK
-    if !response.contains("200 OK") {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Non-200 OK response"));
+fn rpc_request(body: &[u8]) -> HealthCheckRequest {
+    HealthCheckRequest {
+        method: Method::POST,
+        path: "/rpc".to_string(),
+        body: body.to_vec(),
+        content_type: Some(HeaderValue::from_static("application/json")),
+        upstream_proxy: None,
+        timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
     }
+}
+
+
+#[tokio::test]
+async fn test_health_check_with_method_and_body() {
+    const EXPECTED: &[u8] = b"POST /rpc HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 17\r\n\r\n{\"method\":\"ping\"}";
+
+    assert!(http_health_check(rpc_upstream(EXPECTED), &rpc_request(br#"{"method":"ping"}"#)).await.is_ok());
 
-    Ok(())
+    // the upstream server only answers 200 OK to the right body
+    assert!(http_health_check(rpc_upstream(EXPECTED), &rpc_request(br#"{"method":"pong"}"#)).await.is_err());
+    assert!(basic_http_health_check(rpc_upstream(EXPECTED), "/rpc".to_string()).await.is_err());
 }
 
-#[test]
-fn test_active_health_check() {
 
-    let status = basic_http_health_check("171.67.215.200:80".to_string(), "/".to_string())
-    .map_or(-1, |_| 1);
+#[test]
+fn test_format_health_request() {
+    assert_eq!(format_health_request(&HealthCheckRequest::get("/health".to_string())), b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n");
 
-    assert_eq!(status, 1);
+    // a POST without a body still announces it has none
+    let request = HealthCheckRequest { method: Method::POST, path: "/".to_string(), body: Vec::new(), content_type: None, upstream_proxy: None, timeout: DEFAULT_HEALTH_CHECK_TIMEOUT };
+    assert_eq!(format_health_request(&request), b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
 }
 
 
 #[test]
-fn test_inactive_health_check() {
+fn test_health_check_body_parsing() {
+    assert_eq!("ping".parse(), Ok(HealthCheckBody(b"ping".to_vec())));
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let path = std::env::temp_dir().join(format!("lb-health-body-{}-{}", std::process::id(), nanos));
+    std::fs::write(&path, [0xff, 0x00, 0x01]).unwrap();
+    assert_eq!(format!("@{}", path.display()).parse(), Ok(HealthCheckBody(vec![0xff, 0x00, 0x01])));
+
+    std::fs::write(&path, vec![b'a'; MAX_HEALTH_BODY_SIZE + 1]).unwrap();
+    assert!(format!("@{}", path.display()).parse::<HealthCheckBody>().is_err());
+    std::fs::remove_file(&path).unwrap();
 
-    let status = basic_http_health_check("1.1.1.1".to_string(), "/".to_string())
-    .map_or(-1, |_| 1);
-    
-    assert_eq!(status, -1);
+    assert!("@/nonexistent/health-body".parse::<HealthCheckBody>().is_err());
 }
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::{Method, Request};
+
+use crate::admin_auth::{constant_time_eq, AdminAuth};
+
+
+/// Returns a path in the temporary directory that doesn't exist yet.
+fn token_file() -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    std::env::temp_dir().join(format!("lb-admin-token-{}-{}", std::process::id(), nanos))
+}
+
+
+fn request(method: Method, path: &str, authorization: Option<&str>) -> Request<Vec<u8>> {
+    let mut request = Request::builder().method(method).uri(path);
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    request.body(Vec::new()).unwrap()
+}
+
+
+#[test]
+fn test_only_the_bearer_token_is_authorized() {
+    let auth = AdminAuth::new("s3cret\n", false).unwrap();
+
+    assert!(auth.authorize(&request(Method::POST, "/faults", Some("Bearer s3cret"))));
+    assert!(auth.authorize(&request(Method::GET, "/debug/config", Some("bearer  s3cret "))));
+    assert!(!auth.authorize(&request(Method::POST, "/faults", None)));
+    assert!(!auth.authorize(&request(Method::POST, "/faults", Some("Bearer s3cre"))));
+    assert!(!auth.authorize(&request(Method::POST, "/faults", Some("Bearer s3cret2"))));
+    assert!(!auth.authorize(&request(Method::POST, "/faults", Some("Basic s3cret"))));
+    assert!(!auth.authorize(&request(Method::POST, "/faults", Some("s3cret"))));
+    // the scrapes aren't exempt unless asked to
+    assert!(!auth.authorize(&request(Method::GET, "/metrics", None)));
+
+    assert!(AdminAuth::new(" \n", false).is_err());
+}
+
+
+#[test]
+fn test_metrics_scrapes_can_be_exempt() {
+    let auth = AdminAuth::new("s3cret", true).unwrap();
+
+    assert!(auth.authorize(&request(Method::GET, "/metrics", None)));
+    assert!(!auth.authorize(&request(Method::POST, "/metrics", None)));
+    assert!(!auth.authorize(&request(Method::GET, "/dashboard", None)));
+    assert!(!auth.authorize(&request(Method::DELETE, "/faults", None)));
+}
+
+
+#[test]
+fn test_rotated_token_replaces_the_old_one() {
+    let path = token_file();
+    std::fs::write(&path, "old-token\n").unwrap();
+    let auth = AdminAuth::from_file(path.clone(), false).unwrap();
+    assert!(auth.authorize(&request(Method::POST, "/faults", Some("Bearer old-token"))));
+
+    std::fs::write(&path, "new-token\n").unwrap();
+    assert_eq!(auth.reload(), Ok(true));
+    assert!(auth.authorize(&request(Method::POST, "/faults", Some("Bearer new-token"))));
+    assert!(!auth.authorize(&request(Method::POST, "/faults", Some("Bearer old-token"))));
+
+    // an emptied or removed file keeps the current token
+    std::fs::write(&path, "").unwrap();
+    assert!(auth.reload().is_err());
+    std::fs::remove_file(&path).unwrap();
+    assert!(auth.reload().is_err());
+    assert!(auth.authorize(&request(Method::POST, "/faults", Some("Bearer new-token"))));
+
+    assert!(AdminAuth::from_file(path, false).is_err());
+    assert_eq!(AdminAuth::new("fixed", false).unwrap().reload(), Ok(false));
+}
+
+
+#[test]
+fn test_constant_time_comparison() {
+    assert!(constant_time_eq(b"token", b"token"));
+    assert!(constant_time_eq(b"", b""));
+    assert!(!constant_time_eq(b"token", b"tokem"));
+    assert!(!constant_time_eq(b"token", b"token1"));
+    assert!(!constant_time_eq(b"Token", b"token"));
+}
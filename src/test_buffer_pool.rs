@@ -0,0 +1,45 @@
+use crate::buffer_pool::BufferPool;
+
+
+#[test]
+fn test_checkout_reuses_returned_buffers() {
+    let pool = BufferPool::new(1024, 4);
+
+    let buffer = pool.checkout();
+    assert_eq!(buffer.len(), 1024);
+    assert_eq!(pool.stats(), (0, 1));
+
+    pool.checkin(buffer);
+    assert_eq!(pool.idle(), 1);
+
+    let buffer = pool.checkout();
+    assert_eq!(buffer.len(), 1024);
+    assert_eq!(pool.stats(), (1, 1));
+    assert_eq!(pool.idle(), 0);
+}
+
+
+#[test]
+fn test_pool_size_is_bounded() {
+    let pool = BufferPool::new(1024, 2);
+
+    let buffers: Vec<Vec<u8>> = (0..5).map(|_| pool.checkout()).collect();
+    assert_eq!(pool.stats(), (0, 5));
+
+    for buffer in buffers {
+        pool.checkin(buffer);
+    }
+
+    // only two buffers are kept, the others are released
+    assert_eq!(pool.idle(), 2);
+}
+
+
+#[test]
+fn test_foreign_buffers_are_not_pooled() {
+    let pool = BufferPool::new(1024, 2);
+
+    pool.checkin(vec![0; 16]);
+
+    assert_eq!(pool.idle(), 0);
+}
@@ -0,0 +1,63 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::byte_volume::ByteVolumes;
+
+
+fn addresses(addresses: &[&str]) -> Vec<String> {
+    addresses.iter().map(|address| address.to_string()).collect()
+}
+
+
+#[test]
+fn test_volumes_slide_with_the_window() {
+    let volumes = ByteVolumes::new(Duration::from_secs(3));
+    let start = Instant::now();
+    let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+    volumes.record("10.0.0.1:80", 1000, at(0));
+    volumes.record("10.0.0.1:80", 500, at(1));
+    volumes.record("10.0.0.1:80", 20, at(2));
+    assert_eq!(volumes.volume("10.0.0.1:80", at(2)), 1520);
+
+    // the bytes of a second leave the window once it is over, and their bucket is reused
+    assert_eq!(volumes.volume("10.0.0.1:80", at(3)), 520);
+    volumes.record("10.0.0.1:80", 7, at(3));
+    assert_eq!(volumes.volume("10.0.0.1:80", at(3)), 527);
+    assert_eq!(volumes.volume("10.0.0.1:80", at(10)), 0);
+    assert_eq!(volumes.volume("10.0.0.2:80", at(3)), 0);
+
+    assert_eq!(volumes.render_prometheus(at(3)), "# TYPE lb_upstream_window_bytes gauge\nlb_upstream_window_bytes{upstream=\"10.0.0.1:80\"} 527\n");
+}
+
+
+#[test]
+fn test_selection_prefers_the_fewest_bytes() {
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+    let volumes = ByteVolumes::new(Duration::from_secs(10));
+    let now = Instant::now();
+    volumes.record("10.0.0.1:80", 10 * 1024 * 1024, now);
+    volumes.record("10.0.0.2:80", 1024, now);
+
+    // the upstream server without any traffic wins, then the one with the smallest transfers
+    assert_eq!(volumes.select(&upstreams, &HashSet::new(), now), Some("10.0.0.3:80".to_string()));
+    let excluded = HashSet::from(["10.0.0.3:80".to_string()]);
+    assert_eq!(volumes.select(&upstreams, &excluded, now), Some("10.0.0.2:80".to_string()));
+
+    let excluded: HashSet<String> = upstreams.iter().cloned().collect();
+    assert_eq!(volumes.select(&upstreams, &excluded, now), None);
+}
+
+
+#[test]
+fn test_ties_are_broken_at_random() {
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80"]);
+    let volumes = ByteVolumes::new(Duration::from_secs(10));
+
+    let mut selections = HashMap::new();
+    for _ in 0..200 {
+        *selections.entry(volumes.select(&upstreams, &HashSet::new(), Instant::now()).unwrap()).or_insert(0) += 1;
+    }
+
+    assert!(selections.values().all(|count| *count > 50), "{:?}", selections);
+}
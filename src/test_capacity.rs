@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::capacity::{Error, UpstreamLimiter};
+
+
+fn addresses(addresses: &[&str]) -> Vec<String> {
+    addresses.iter().map(|address| address.to_string()).collect()
+}
+
+
+#[tokio::test]
+async fn test_upstreams_at_capacity_are_skipped() {
+    let limiter = Arc::new(UpstreamLimiter::new(Some(1), Duration::ZERO));
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80"]);
+
+    let first = limiter.acquire(&upstreams, &HashSet::new()).await.unwrap();
+    let second = limiter.acquire(&upstreams, &HashSet::new()).await.unwrap();
+
+    assert_ne!(first.upstream_address(), second.upstream_address());
+    assert_eq!(limiter.in_flight("10.0.0.1:80"), 1);
+    assert_eq!(limiter.in_flight("10.0.0.2:80"), 1);
+}
+
+
+#[tokio::test]
+async fn test_queued_request_gets_the_slot_given_back() {
+    let limiter = Arc::new(UpstreamLimiter::new(Some(1), Duration::from_secs(5)));
+    let upstreams = addresses(&["10.0.0.1:80"]);
+    let held = limiter.acquire(&upstreams, &HashSet::new()).await.unwrap();
+
+    let waiting_limiter = limiter.clone();
+    let waiting_upstreams = upstreams.clone();
+    let waiting = tokio::spawn(async move {
+        let started_at = Instant::now();
+        let slot = waiting_limiter.acquire(&waiting_upstreams, &HashSet::new()).await;
+        (slot, started_at.elapsed())
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(held);
+
+    let (slot, waited) = waiting.await.unwrap();
+    assert_eq!(slot.unwrap().upstream_address(), "10.0.0.1:80");
+    assert!(waited >= Duration::from_millis(100));
+    assert!(waited < Duration::from_secs(5));
+}
+
+
+#[tokio::test]
+async fn test_queued_request_times_out() {
+    let limiter = Arc::new(UpstreamLimiter::new(Some(2), Duration::from_millis(100)));
+    let upstreams = addresses(&["10.0.0.1:80"]);
+    let _held = [
+        limiter.acquire(&upstreams, &HashSet::new()).await.unwrap(),
+        limiter.acquire(&upstreams, &HashSet::new()).await.unwrap(),
+    ];
+    let started_at = Instant::now();
+
+    let result = limiter.acquire(&upstreams, &HashSet::new()).await;
+
+    assert_eq!(result.unwrap_err(), Error::QueueTimeout);
+    assert!(started_at.elapsed() >= Duration::from_millis(100));
+}
+
+
+#[tokio::test]
+async fn test_excluded_upstreams_are_not_waited_for() {
+    let limiter = Arc::new(UpstreamLimiter::new(Some(1), Duration::from_secs(5)));
+    let upstreams = addresses(&["10.0.0.1:80"]);
+    let excluded = HashSet::from(["10.0.0.1:80".to_string()]);
+
+    let result = limiter.acquire(&upstreams, &excluded).await;
+
+    assert_eq!(result.unwrap_err(), Error::NoUpstream);
+}
+
+
+#[tokio::test]
+async fn test_unlimited_limiter_never_queues() {
+    let limiter = Arc::new(UpstreamLimiter::unlimited());
+    let upstreams = addresses(&["10.0.0.1:80"]);
+
+    let slots: Vec<_> = (0..100).map(|_| limiter.try_acquire("10.0.0.1:80").unwrap()).collect();
+
+    assert!(limiter.acquire(&upstreams, &HashSet::new()).await.is_ok());
+    assert_eq!(limiter.in_flight("10.0.0.1:80"), 0);
+    drop(slots);
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::Request;
+use tokio::io::AsyncWriteExt;
+
+use crate::coalesce::{Coalescer, Flight, Recorder, RequestKey, SharedResponse};
+
+
+fn request(method: &str, host: &str, target: &str) -> Request<Vec<u8>> {
+    Request::builder().method(method).uri(target).header("Host", host).body(Vec::new()).unwrap()
+}
+
+
+fn key(target: &str) -> RequestKey {
+    RequestKey::of(&request("GET", "localhost", target)).unwrap()
+}
+
+
+fn shared(body: &str) -> SharedResponse {
+    SharedResponse {
+        bytes: Arc::new(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()),
+        status: 200,
+        close_delimited: false,
+        upstream_address: "10.0.0.1:80".to_string(),
+    }
+}
+
+
+#[test]
+fn test_request_identity() {
+    assert_eq!(RequestKey::of(&request("GET", "localhost", "/a?x=1")), RequestKey::of(&request("GET", "LOCALHOST", "/a?x=1")));
+    assert_ne!(RequestKey::of(&request("GET", "localhost", "/a?x=1")), RequestKey::of(&request("GET", "localhost", "/a?x=2")));
+    assert_ne!(RequestKey::of(&request("GET", "a.example", "/")), RequestKey::of(&request("GET", "b.example", "/")));
+    assert_ne!(RequestKey::of(&request("GET", "localhost", "/")), RequestKey::of(&request("HEAD", "localhost", "/")));
+}
+
+
+#[test]
+fn test_only_anonymous_reads_are_coalesced() {
+    assert!(RequestKey::of(&request("HEAD", "localhost", "/")).is_some());
+    assert!(RequestKey::of(&request("POST", "localhost", "/")).is_none());
+    assert!(RequestKey::of(&request("DELETE", "localhost", "/")).is_none());
+
+    let with_header = |name: &str| Request::builder().uri("/").header("Host", "localhost").header(name, "secret").body(Vec::new()).unwrap();
+    assert!(RequestKey::of(&with_header("Authorization")).is_none());
+    assert!(RequestKey::of(&with_header("Cookie")).is_none());
+
+    let with_body = Request::builder().uri("/").header("Host", "localhost").body(b"query".to_vec()).unwrap();
+    assert!(RequestKey::of(&with_body).is_none());
+}
+
+
+#[test]
+fn test_requests_accepting_other_representations_are_not_coalesced() {
+    let with_header = |name: &str, value: &str| Request::builder().uri("/").header("Host", "localhost").header(name, value).body(Vec::new()).unwrap();
+
+    for (name, value) in [("Range", "bytes=0-99"), ("If-None-Match", "\"v1\""), ("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT"), ("If-Range", "\"v1\"")] {
+        assert!(RequestKey::of(&with_header(name, value)).is_none(), "{}", name);
+    }
+    assert_ne!(RequestKey::of(&with_header("Accept-Encoding", "gzip")), RequestKey::of(&request("GET", "localhost", "/")));
+    assert_ne!(RequestKey::of(&with_header("Accept", "application/json")), RequestKey::of(&with_header("Accept", "text/html")));
+    assert_eq!(RequestKey::of(&with_header("Accept-Encoding", "gzip")), RequestKey::of(&with_header("Accept-Encoding", "gzip")));
+}
+
+
+#[tokio::test]
+async fn test_plain_request_does_not_follow_a_ranged_one() {
+    let coalescer = Coalescer::default();
+    let ranged = Request::builder().uri("/video").header("Host", "localhost").header("Range", "bytes=0-99").body(Vec::new()).unwrap();
+
+    // the ranged request is proxied on its own, so its 206 is never shared with the plain request
+    assert!(RequestKey::of(&ranged).is_none());
+    let Flight::Leader(mut leader) = coalescer.join(key("/video")) else { panic!("the plain request must lead its own flight") };
+    let Flight::Follower(follower) = coalescer.join(key("/video")) else { panic!("an identical plain request must follow") };
+
+    leader.share(shared("full"));
+    drop(leader);
+    assert!(follower.response().await.unwrap().bytes.ends_with(b"\r\n\r\nfull"));
+}
+
+
+#[tokio::test]
+async fn test_followers_share_the_response_of_the_leader() {
+    let coalescer = Arc::new(Coalescer::default());
+    let Flight::Leader(mut leader) = coalescer.join(key("/hot")) else { panic!("the first request must lead") };
+
+    let mut followers = Vec::new();
+    for _ in 0..10 {
+        let coalescer = coalescer.clone();
+        followers.push(tokio::spawn(async move {
+            match coalescer.join(key("/hot")) {
+                Flight::Follower(follower) => follower.response().await,
+                Flight::Leader(_) => panic!("a request joined while the leader is in flight must follow"),
+            }
+        }));
+    }
+    // a different request isn't part of the flight
+    assert!(matches!(coalescer.join(key("/cold")), Flight::Leader(_)));
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    leader.share(shared("hot"));
+    drop(leader);
+
+    for follower in followers {
+        let response = follower.await.unwrap().unwrap();
+        assert!(response.bytes.ends_with(b"\r\n\r\nhot"));
+    }
+    assert_eq!(coalescer.coalesced(), 10);
+
+    // the flight is over, the next request leads a new one
+    assert!(matches!(coalescer.join(key("/hot")), Flight::Leader(_)));
+}
+
+
+#[tokio::test]
+async fn test_followers_are_released_when_the_leader_fails() {
+    let coalescer = Coalescer::default();
+    let leader = coalescer.join(key("/hot"));
+    let Flight::Follower(follower) = coalescer.join(key("/hot")) else { panic!("the second request must follow") };
+
+    drop(leader);
+
+    assert!(follower.response().await.is_none());
+    assert_eq!(coalescer.coalesced(), 0);
+}
+
+
+#[tokio::test]
+async fn test_recorder_copies_what_it_writes_up_to_its_limit() {
+    let mut written = Vec::new();
+    let mut recorder = Recorder::new(&mut written, 8);
+    recorder.write_all(b"HTTP/1.1").await.unwrap();
+    assert_eq!(recorder.into_recorded(), Some(b"HTTP/1.1".to_vec()));
+
+    let mut recorder = Recorder::new(&mut written, 8);
+    recorder.write_all(b"HTTP/1.1 200 OK").await.unwrap();
+    assert_eq!(recorder.into_recorded(), None);
+
+    // the writes always go through
+    assert_eq!(written, b"HTTP/1.1HTTP/1.1 200 OK");
+}
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::connect::{Connector, Error, FailureKind};
+
+
+/// Returns a local address nothing is listening on.
+fn closed_address() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+
+#[tokio::test]
+async fn test_connect_on_first_attempt() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connector = Connector::new(2, Duration::from_millis(10), None);
+
+    assert!(connector.connect(&listener.local_addr().unwrap().to_string(), connector.deadline()).await.is_ok());
+    assert_eq!(connector.stats(), (1, 0));
+}
+
+
+#[tokio::test]
+async fn test_refused_connection_is_retried() {
+    let address = closed_address();
+    let connector = Connector::new(3, Duration::from_millis(100), None);
+
+    // the first attempt is refused, the upstream server listens by the time of the retry
+    let listening_address = address.clone();
+    let listener = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        TcpListener::bind(listening_address).await.unwrap()
+    });
+
+    assert!(connector.connect(&address, connector.deadline()).await.is_ok());
+    assert_eq!(connector.stats(), (0, 1));
+    drop(listener.await.unwrap());
+}
+
+
+#[tokio::test]
+async fn test_connect_fails_once_retries_are_spent() {
+    let connector = Connector::new(2, Duration::from_millis(10), None);
+
+    assert!(matches!(connector.connect(&closed_address(), None).await, Err(Error::ConnectFailed(_))));
+    assert_eq!(connector.stats(), (0, 0));
+}
+
+
+#[tokio::test]
+async fn test_retries_stop_when_budget_is_spent() {
+    let connector = Connector::new(100, Duration::from_millis(20), Some(Duration::from_millis(100)));
+    let started_at = Instant::now();
+
+    assert!(matches!(connector.connect(&closed_address(), connector.deadline()).await, Err(Error::BudgetExhausted)));
+
+    // 100 retries would take seconds, the last attempt may end right at the deadline
+    assert!(started_at.elapsed() < Duration::from_millis(200));
+}
+
+
+#[tokio::test]
+async fn test_refused_connections_are_counted() {
+    let address = closed_address();
+    let connector = Connector::new(2, Duration::from_millis(10), None);
+
+    let connected = connector.connect(&address, None).await;
+    assert_eq!(connected.unwrap_err().kind(), Some(FailureKind::Refused));
+
+    // every attempt counts, the retries included
+    assert_eq!(connector.failure_count(&address, FailureKind::Refused), 3);
+    assert_eq!(connector.failure_count(&address, FailureKind::TimedOut), 0);
+    assert!(connector.render_failures().contains(&format!("lb_upstream_errors_total{{upstream=\"{}\",kind=\"refused\"}} 3\n", address)));
+}
+
+
+#[tokio::test]
+async fn test_blackholed_connections_are_counted_as_timeouts() {
+    // a listener that never accepts, with its backlog filled, leaves the next connections unanswered
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let mut backlog = Vec::new();
+    for _ in 0..16 {
+        match tokio::time::timeout(Duration::from_millis(50), TcpStream::connect(&address)).await {
+            Ok(stream) => backlog.push(stream.unwrap()),
+            Err(_) => break,
+        }
+    }
+
+    let connector = Connector::new(0, Duration::from_millis(10), Some(Duration::from_millis(100)));
+    let connected = connector.connect(&address, connector.deadline()).await;
+
+    assert!(matches!(connected, Err(Error::BudgetExhausted)));
+    assert_eq!(connector.failure_count(&address, FailureKind::TimedOut), 1);
+}
+
+
+#[tokio::test]
+async fn test_resolution_failures_are_counted_and_not_retried() {
+    let connector = Connector::new(3, Duration::from_millis(10), None);
+
+    let connected = connector.connect("upstream.invalid:80", None).await;
+
+    assert!(matches!(connected, Err(Error::ResolveFailed(_))));
+    assert_eq!(connector.failure_count("upstream.invalid:80", FailureKind::Dns), 1);
+}
+
+
+#[test]
+fn test_io_errors_are_classified() {
+    use std::io::ErrorKind;
+
+    assert_eq!(FailureKind::from_io_kind(ErrorKind::ConnectionRefused), FailureKind::Refused);
+    assert_eq!(FailureKind::from_io_kind(ErrorKind::TimedOut), FailureKind::TimedOut);
+    assert_eq!(FailureKind::from_io_kind(ErrorKind::ConnectionReset), FailureKind::Reset);
+    assert_eq!(FailureKind::from_io_kind(ErrorKind::BrokenPipe), FailureKind::Reset);
+    assert_eq!(FailureKind::from_io_kind(ErrorKind::PermissionDenied), FailureKind::Other);
+}
@@ -0,0 +1,37 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::connection_limit::ConnectionLimiter;
+
+
+#[test]
+fn test_connections_over_the_limit_are_refused() {
+    let limiter = Arc::new(ConnectionLimiter::new(2));
+    let client: IpAddr = "192.168.1.10".parse().unwrap();
+    let other_client: IpAddr = "192.168.1.11".parse().unwrap();
+
+    let first = limiter.acquire(client).unwrap();
+    let _second = limiter.acquire(client).unwrap();
+    assert!(limiter.acquire(client).is_none());
+    assert_eq!(limiter.open_connections(client), 2);
+
+    // the limit applies to every client IP address on its own
+    assert!(limiter.acquire(other_client).is_some());
+
+    // closing a connection frees its slot
+    drop(first);
+    assert_eq!(limiter.open_connections(client), 1);
+    assert!(limiter.acquire(client).is_some());
+}
+
+
+#[test]
+fn test_clients_without_connections_are_forgotten() {
+    let limiter = Arc::new(ConnectionLimiter::new(1));
+
+    let guard = limiter.acquire("10.0.0.1".parse().unwrap()).unwrap();
+    assert_eq!(limiter.clients(), 1);
+
+    drop(guard);
+    assert_eq!(limiter.clients(), 0);
+}
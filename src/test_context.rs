@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::coalesce::Coalescer;
+use crate::connect::{Connector, FailureKind};
+use crate::context::ProxyContext;
+use crate::ejection::Ejector;
+use crate::idle_connections::IdleConnections;
+use crate::request::RequestConfig;
+use crate::response::ResponseConfig;
+
+
+fn context() -> ProxyContext {
+    ProxyContext::new(RequestConfig::default(), ResponseConfig::default(), Connector::new(2, Duration::from_millis(10), None))
+}
+
+
+#[test]
+fn test_features_are_disabled_by_default() {
+    let context = context();
+
+    let rendered = context.to_json();
+    assert_eq!(rendered["connect_retries"], 2);
+    for feature in ["coalesce", "eject_on_5xx", "load_shedding", "fault_injection"] {
+        assert_eq!(rendered[feature], false, "{}", feature);
+    }
+    assert!(rendered["max_idle_per_upstream"].is_null());
+    assert!(context.limiter.max_per_upstream().is_none());
+
+    // only the upstream failures and the request phases are rendered
+    let metrics = context.render_prometheus();
+    assert!(metrics.contains("# TYPE lb_upstream_errors_total counter\n"));
+    assert!(metrics.contains("lb_request_phase_duration_seconds_count{phase=\"total\"} 0\n"));
+    assert!(!metrics.contains("lb_upstream_ejections_total"));
+    assert!(!metrics.contains("lb_idle_upstream_connections"));
+}
+
+
+#[test]
+fn test_enabled_features_are_rendered() {
+    let context = ProxyContext {
+        coalescer: Some(Coalescer::default()),
+        ejector: Some(Ejector::new(1, Duration::from_secs(30))),
+        idle_connections: Some(Arc::new(IdleConnections::new(4))),
+        ..context()
+    };
+    context.connector.record_failure("10.0.0.1:80", FailureKind::Refused);
+    context.ejector.as_ref().unwrap().record_status("10.0.0.1:80", 500);
+
+    let rendered = context.to_json();
+    assert_eq!(rendered["coalesce"], true);
+    assert_eq!(rendered["eject_on_5xx"], true);
+    assert_eq!(rendered["max_idle_per_upstream"], 4);
+
+    let metrics = context.render_prometheus();
+    assert!(metrics.contains("lb_upstream_errors_total{upstream=\"10.0.0.1:80\",kind=\"refused\"} 1\n"));
+    assert!(metrics.contains("lb_upstream_ejections_total{upstream=\"10.0.0.1:80\"} 1\n"));
+    assert!(metrics.contains("# TYPE lb_idle_upstream_connections gauge\n"));
+}
@@ -0,0 +1,79 @@
+use http::{Request, StatusCode};
+
+use crate::cors::{is_preflight, CorsOrigin, CorsPolicy};
+
+
+fn request(method: &str, headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+    let mut request = Request::builder().method(method).uri("/api/orders").header("Host", "localhost");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Vec::new()).unwrap()
+}
+
+
+fn policy(origins: &[&str]) -> CorsPolicy {
+    CorsPolicy {
+        origins: origins.iter().map(|origin| origin.parse().unwrap()).collect(),
+        methods: vec![http::Method::GET, http::Method::PUT],
+        headers: vec![http::header::AUTHORIZATION, http::header::CONTENT_TYPE],
+    }
+}
+
+
+#[test]
+fn test_origins_are_parsed() {
+    assert_eq!("*".parse::<CorsOrigin>().unwrap(), CorsOrigin::Any);
+    assert_eq!("https://App.example.com:8443".parse::<CorsOrigin>().unwrap(), CorsOrigin::Exact("https://app.example.com:8443".to_string()));
+
+    for invalid in ["", "app.example.com", "https://", "https://app.example.com/", "https://app.example.com/path", "ht tp://app.example.com"] {
+        assert!(invalid.parse::<CorsOrigin>().is_err(), "{}", invalid);
+    }
+}
+
+
+#[test]
+fn test_preflight_is_detected() {
+    assert!(is_preflight(&request("OPTIONS", &[("Origin", "https://app.example.com"), ("Access-Control-Request-Method", "PUT")])));
+
+    // a plain OPTIONS request, or one without a requested method, is forwarded
+    assert!(!is_preflight(&request("OPTIONS", &[])));
+    assert!(!is_preflight(&request("OPTIONS", &[("Origin", "https://app.example.com")])));
+    assert!(!is_preflight(&request("GET", &[("Origin", "https://app.example.com"), ("Access-Control-Request-Method", "PUT")])));
+}
+
+
+#[test]
+fn test_preflight_allows_the_configured_origins() {
+    let policy = policy(&["https://app.example.com"]);
+    let preflight = |origin: &str| request("OPTIONS", &[("Origin", origin), ("Access-Control-Request-Method", "PUT")]);
+
+    let (status, response) = policy.preflight_response(&preflight("https://app.example.com"));
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(String::from_utf8(response).unwrap(), "HTTP/1.1 204 No Content\r\n\
+Access-Control-Allow-Origin: https://app.example.com\r\nAccess-Control-Allow-Methods: GET, PUT\r\n\
+Access-Control-Allow-Headers: authorization, content-type\r\nVary: Origin\r\n\r\n");
+
+    // another origin is answered without the allow headers, the browser doesn't send the request
+    let (status, response) = policy.preflight_response(&preflight("https://evil.example.com"));
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(String::from_utf8(response).unwrap(), "HTTP/1.1 204 No Content\r\nVary: Origin\r\n\r\n");
+}
+
+
+#[test]
+fn test_allowed_origin_of_the_actual_requests() {
+    let exact = policy(&["https://app.example.com", "https://admin.example.com"]);
+    let from = |origin: &str| request("GET", &[("Origin", origin)]);
+
+    assert_eq!(exact.allow_origin(&from("https://admin.example.com")).unwrap(), "https://admin.example.com");
+    assert_eq!(exact.allow_origin(&from("HTTPS://APP.example.com")).unwrap(), "HTTPS://APP.example.com");
+    assert_eq!(exact.allow_origin(&from("https://app.example.com:8443")), None);
+    // a same-origin request has no Origin header, its response is left alone
+    assert_eq!(exact.allow_origin(&request("GET", &[])), None);
+
+    let any = policy(&["*"]);
+    assert_eq!(any.allow_origin(&from("https://evil.example.com")).unwrap(), "*");
+    let (_, response) = any.preflight_response(&request("OPTIONS", &[("Origin", "https://evil.example.com"), ("Access-Control-Request-Method", "GET")]));
+    assert!(!String::from_utf8(response).unwrap().contains("Vary"));
+}
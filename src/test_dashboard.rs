@@ -0,0 +1,77 @@
+use std::time::Instant;
+
+use crate::dashboard::{render_dashboard, PoolStatus, RequestRates, UpstreamState, UpstreamStatus, DASHBOARD_REFRESH, RATE_BUCKET, RATE_BUCKETS};
+
+
+fn upstream(address: &str, state: UpstreamState, in_flight: usize, recent: Vec<(u64, u64)>) -> UpstreamStatus {
+    UpstreamStatus { address: address.to_string(), state, in_flight, recent }
+}
+
+
+#[test]
+fn test_request_rates_slide_over_the_buckets() {
+    let rates = RequestRates::default();
+    let start = Instant::now();
+    let at = |buckets: u32| start + RATE_BUCKET * buckets;
+
+    rates.record("10.0.0.1:80", 200, at(0));
+    rates.record("10.0.0.1:80", 503, at(0));
+    rates.record("10.0.0.1:80", 404, at(1));
+
+    // the current bucket comes last, the server errors are the statuses from 500 on
+    let recent = rates.recent("10.0.0.1:80", at(1));
+    assert_eq!(recent.len(), RATE_BUCKETS);
+    assert_eq!(recent[RATE_BUCKETS - 2..], [(2, 1), (1, 0)]);
+    assert!(recent[..RATE_BUCKETS - 2].iter().all(|bucket| *bucket == (0, 0)));
+
+    // the requests of a bucket leave the window once it is over, and the bucket is reused
+    let recent = rates.recent("10.0.0.1:80", at(RATE_BUCKETS as u32));
+    assert_eq!(recent[0], (1, 0));
+    rates.record("10.0.0.1:80", 200, at(RATE_BUCKETS as u32));
+    assert_eq!(rates.recent("10.0.0.1:80", at(RATE_BUCKETS as u32))[RATE_BUCKETS - 1], (1, 0));
+    assert!(rates.recent("10.0.0.1:80", at(10 * RATE_BUCKETS as u32)).iter().all(|bucket| *bucket == (0, 0)));
+    assert!(rates.recent("10.0.0.2:80", at(1)).iter().all(|bucket| *bucket == (0, 0)));
+}
+
+
+#[test]
+fn test_status_page_shows_every_upstream_with_its_state() {
+    let mut recent = vec![(0, 0); RATE_BUCKETS];
+    recent[RATE_BUCKETS - 1] = (8, 2);
+    let page = render_dashboard(&[PoolStatus {
+        name: "default".to_string(),
+        upstreams: vec![
+            upstream("10.0.0.1:80", UpstreamState::Healthy, 3, recent),
+            upstream("10.0.0.2:80", UpstreamState::Unhealthy, 0, vec![(0, 0); RATE_BUCKETS]),
+            upstream("10.0.0.3:80", UpstreamState::Ejected, 0, Vec::new()),
+        ],
+    }]);
+
+    assert!(page.starts_with("<!DOCTYPE html>\n"));
+    assert!(page.contains(&format!("<meta http-equiv=\"refresh\" content=\"{}\">", DASHBOARD_REFRESH.as_secs())));
+    for (address, state) in [("10.0.0.1:80", "healthy"), ("10.0.0.2:80", "unhealthy"), ("10.0.0.3:80", "ejected")] {
+        let row = page.lines().find(|line| line.contains(address)).unwrap();
+        assert!(row.contains(&format!(">{}</span>", state)), "{}", row);
+    }
+    let row = page.lines().find(|line| line.contains("10.0.0.1:80")).unwrap();
+    assert!(row.contains("<td class=\"number\">3</td><td class=\"number\">25.0%</td>"), "{}", row);
+    assert!(row.contains("<svg "));
+    // nothing is loaded from elsewhere
+    assert!(!page.contains("<script") && !page.contains("<link") && !page.contains("http://") && !page.contains("https://"));
+    // a single pool goes without its name
+    assert!(!page.contains("<h2>"));
+}
+
+
+#[test]
+fn test_status_page_renders_without_upstreams() {
+    let page = render_dashboard(&[
+        PoolStatus { name: "default".to_string(), upstreams: Vec::new() },
+        PoolStatus { name: "<admin>".to_string(), upstreams: Vec::new() },
+    ]);
+
+    assert!(page.contains("<h2>default</h2>\n<p>No upstream server.</p>"));
+    assert!(page.contains("<h2>&lt;admin&gt;</h2>"));
+    assert!(!page.contains("<table>"));
+    assert!(page.ends_with("</html>\n"));
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http::Request;
+
+use crate::deadline::{read_deadline, Deadline};
+
+
+fn budget(value: &str) -> Option<Duration> {
+    let received_at = Instant::now();
+    Deadline::parse(value, received_at, SystemTime::now()).map(|deadline| deadline.expires_at - received_at)
+}
+
+
+#[test]
+fn test_relative_deadlines() {
+    assert_eq!(budget("250ms"), Some(Duration::from_millis(250)));
+    assert_eq!(budget(" 1.5s "), Some(Duration::from_millis(1500)));
+    assert_eq!(budget("100m"), Some(Duration::from_millis(100)));
+    assert_eq!(budget("2S"), Some(Duration::from_secs(2)));
+    assert_eq!(budget("1M"), Some(Duration::from_secs(60)));
+    assert_eq!(budget("1H"), Some(Duration::from_secs(3600)));
+    assert_eq!(budget("500u"), Some(Duration::from_micros(500)));
+    assert_eq!(budget("0m"), Some(Duration::ZERO));
+}
+
+
+#[test]
+fn test_malformed_deadlines_are_ignored() {
+    for value in ["", "soon", "-5s", "ms", "m", "1.5m", "123456789m", "10x", "NaNs", "infs", "1e400s"] {
+        assert_eq!(budget(value), None, "{:?} was accepted", value);
+    }
+}
+
+
+#[test]
+fn test_absolute_deadline() {
+    let now = UNIX_EPOCH + Duration::from_millis(1_767_225_600_000);
+
+    let deadline = Deadline::parse("1767225602000", Instant::now(), now).unwrap();
+    let remaining = deadline.remaining(Instant::now());
+    assert!(remaining <= Duration::from_secs(2) && remaining > Duration::from_millis(1900), "{:?}", remaining);
+    // the deadline is the same wherever the request goes, it is forwarded unchanged
+    assert_eq!(deadline.header_value(Instant::now()), "1767225602000");
+
+    let passed = Deadline::parse("1767225599000", Instant::now(), now).unwrap();
+    assert!(passed.remaining(Instant::now()).is_zero());
+}
+
+
+#[test]
+fn test_header_is_rewritten_with_the_budget_left() {
+    let received_at = Instant::now();
+    let spent = received_at + Duration::from_millis(100);
+
+    let deadline = Deadline::parse("250ms", received_at, SystemTime::now()).unwrap();
+    assert_eq!(deadline.header_value(spent), "150ms");
+
+    let deadline = Deadline::parse("1S", received_at, SystemTime::now()).unwrap();
+    assert_eq!(deadline.header_value(spent), "900m");
+
+    // once the budget is spent, nothing is left to give
+    assert_eq!(deadline.header_value(received_at + Duration::from_secs(5)), "0m");
+}
+
+
+#[test]
+fn test_deadline_is_read_from_the_configured_header() {
+    let request = Request::builder().uri("/").header("X-Request-Deadline", "250ms").header("grpc-timeout", "bogus").body(Vec::new()).unwrap();
+
+    assert!(read_deadline(&request, &"x-request-deadline".parse().unwrap(), Instant::now()).is_some());
+    assert!(read_deadline(&request, &"grpc-timeout".parse().unwrap(), Instant::now()).is_none());
+    assert!(read_deadline(&request, &"x-deadline".parse().unwrap(), Instant::now()).is_none());
+}
@@ -0,0 +1,58 @@
+use http::Request;
+
+use crate::debug_routing::{DebugRouting, Error, UpstreamOverride};
+
+
+fn debug_routing() -> DebugRouting {
+    DebugRouting { header: "x-lb-upstream".parse().unwrap(), trusted_from: vec!["10.0.0.0/8".parse().unwrap()] }
+}
+
+
+fn request(upstream: &str) -> Request<Vec<u8>> {
+    Request::builder().uri("/").header("Host", "localhost").header("X-LB-Upstream", upstream).body(Vec::new()).unwrap()
+}
+
+
+fn upstreams(addresses: &[&str]) -> Vec<String> {
+    addresses.iter().map(|address| address.to_string()).collect()
+}
+
+
+#[test]
+fn test_override_parsing() {
+    assert_eq!("10.0.0.7:8080".parse(), Ok(UpstreamOverride { address: "10.0.0.7:8080".to_string(), force: false }));
+    assert_eq!(" 10.0.0.7:8080! ".parse(), Ok(UpstreamOverride { address: "10.0.0.7:8080".to_string(), force: true }));
+    assert!("!".parse::<UpstreamOverride>().is_err());
+    assert!("".parse::<UpstreamOverride>().is_err());
+    assert_eq!("10.0.0.7:8080!".parse::<UpstreamOverride>().unwrap().to_string(), "10.0.0.7:8080!");
+}
+
+
+#[test]
+fn test_header_is_honored_from_trusted_clients_only() {
+    let mut trusted = request("10.0.0.7:8080");
+    let upstream_override = debug_routing().take_override(&mut trusted, "10.1.2.3".parse().unwrap());
+    assert_eq!(upstream_override, Some(Ok(UpstreamOverride { address: "10.0.0.7:8080".to_string(), force: false })));
+
+    let mut untrusted = request("10.0.0.7:8080");
+    assert_eq!(debug_routing().take_override(&mut untrusted, "192.0.2.1".parse().unwrap()), None);
+
+    // the header is stripped before forwarding either way
+    assert!(!trusted.headers().contains_key("X-LB-Upstream"));
+    assert!(!untrusted.headers().contains_key("X-LB-Upstream"));
+}
+
+
+#[test]
+fn test_override_targets() {
+    let configured = upstreams(&["10.0.0.7:8080", "10.0.0.8:8080"]);
+    let healthy = upstreams(&["10.0.0.7:8080"]);
+    let resolve = |value: &str| value.parse::<UpstreamOverride>().unwrap().resolve(&configured, &healthy);
+
+    assert_eq!(resolve("10.0.0.7:8080"), Ok("10.0.0.7:8080".to_string()));
+    assert_eq!(resolve("10.0.0.8:8080"), Err(Error::Unhealthy { address: "10.0.0.8:8080".to_string() }));
+    assert_eq!(resolve("10.0.0.8:8080!"), Ok("10.0.0.8:8080".to_string()));
+
+    let unknown = resolve("10.0.0.9:8080!").unwrap_err();
+    assert_eq!(unknown.to_string(), "10.0.0.9:8080 is not a configured upstream server, expected one of: 10.0.0.7:8080, 10.0.0.8:8080");
+}
@@ -0,0 +1,227 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, Duration};
+
+use crate::discovery::{
+    parse_consul_health, parse_upstreams_file, reconcile, watch_catalog, Catalog, ConsulCatalog, EndpointSlices, FileCatalog, Reconciliation,
+    SliceEvent,
+};
+
+
+/// Result of a catalog query.
+type CatalogResult = Result<Vec<String>, std::io::Error>;
+
+
+/// Catalog answering with the results sent by the test, one query at a time.
+struct ChannelCatalog {
+    results: mpsc::UnboundedReceiver<CatalogResult>,
+}
+
+impl Catalog for ChannelCatalog {
+    async fn instances(&mut self) -> CatalogResult {
+        match self.results.recv().await {
+            Some(result) => result,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+
+/// Starts watching a catalog driven by the returned sender, and returns the receiving side of the published upstreams.
+fn watch_channel_catalog() -> (mpsc::UnboundedSender<CatalogResult>, watch::Receiver<Vec<String>>) {
+    let (results, catalog_results) = mpsc::unbounded_channel();
+    let (sender, receiver) = watch::channel(vec!["10.0.0.1:80".to_string()]);
+    tokio::spawn(async move { watch_catalog(ChannelCatalog { results: catalog_results }, &sender, Duration::from_millis(1)).await });
+    (results, receiver)
+}
+
+
+fn instances(addresses: &[&str]) -> CatalogResult {
+    Ok(addresses.iter().map(|address| address.to_string()).collect())
+}
+
+
+/// Returns the next published list, or `None` if nothing is published for a while.
+async fn next_published(receiver: &mut watch::Receiver<Vec<String>>) -> Option<Vec<String>> {
+    match timeout(Duration::from_millis(200), receiver.changed()).await {
+        Ok(Ok(())) => Some(receiver.borrow_and_update().clone()),
+        _ => None,
+    }
+}
+
+
+#[tokio::test]
+async fn test_catalog_changes_are_published() {
+    let (results, mut receiver) = watch_channel_catalog();
+
+    results.send(instances(&["10.0.0.2:80", "10.0.0.1:80"])).unwrap();
+    assert_eq!(next_published(&mut receiver).await, Some(vec!["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()]));
+
+    // the same instances in another order aren't a change
+    results.send(instances(&["10.0.0.1:80", "10.0.0.2:80"])).unwrap();
+    assert_eq!(next_published(&mut receiver).await, None);
+
+    results.send(instances(&["10.0.0.2:80"])).unwrap();
+    assert_eq!(next_published(&mut receiver).await, Some(vec!["10.0.0.2:80".to_string()]));
+}
+
+
+#[tokio::test]
+async fn test_unreachable_catalog_keeps_last_known_upstreams() {
+    let (results, mut receiver) = watch_channel_catalog();
+    results.send(instances(&["10.0.0.2:80"])).unwrap();
+    assert_eq!(next_published(&mut receiver).await, Some(vec!["10.0.0.2:80".to_string()]));
+
+    results.send(Err(std::io::Error::other("connection refused"))).unwrap();
+    results.send(Err(std::io::Error::other("connection refused"))).unwrap();
+    assert_eq!(next_published(&mut receiver).await, None);
+    assert_eq!(*receiver.borrow(), vec!["10.0.0.2:80"]);
+
+    // the catalog is queried again after the failures
+    results.send(instances(&["10.0.0.3:80", "10.0.0.2:80"])).unwrap();
+    assert_eq!(next_published(&mut receiver).await, Some(vec!["10.0.0.2:80".to_string(), "10.0.0.3:80".to_string()]));
+}
+
+
+#[test]
+fn test_reconcile_removes_from_active_and_waits_for_health_checks() {
+    let mut upstreams = vec!["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()];
+    let mut active = upstreams.clone();
+
+    let reconciliation = reconcile(&mut upstreams, &mut active, vec!["10.0.0.2:80".to_string(), "10.0.0.3:80".to_string()]);
+
+    assert_eq!(reconciliation, Reconciliation { added: vec!["10.0.0.3:80".to_string()], removed: vec!["10.0.0.1:80".to_string()] });
+    assert_eq!(upstreams, vec!["10.0.0.2:80", "10.0.0.3:80"]);
+    // the new upstream server isn't active until it passes a health check
+    assert_eq!(active, vec!["10.0.0.2:80"]);
+}
+
+
+#[test]
+fn test_parse_consul_health() {
+    let body = br#"[
+        {"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "10.1.0.1", "Port": 8080}},
+        {"Node": {"Address": "10.0.0.2"}, "Service": {"Address": "", "Port": 8081}},
+        {"Node": {"Address": "10.0.0.3"}, "Service": {"Address": "2001:db8::3", "Port": 8082}}
+    ]"#;
+
+    assert_eq!(parse_consul_health(body).unwrap(), vec!["10.1.0.1:8080", "10.0.0.2:8081", "[2001:db8::3]:8082"]);
+    assert_eq!(parse_consul_health(b"[]").unwrap(), Vec::<String>::new());
+
+    assert!(parse_consul_health(b"{}").is_err());
+    assert!(parse_consul_health(br#"[{"Node": {"Address": "10.0.0.1"}, "Service": {}}]"#).is_err());
+}
+
+
+#[tokio::test]
+async fn test_consul_catalog_uses_blocking_queries() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    // local Consul agent answering two queries and recording their request lines
+    let agent = tokio::spawn(async move {
+        let mut request_lines = Vec::new();
+        for index in [7, 9] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let length = stream.read(&mut request).await.unwrap();
+            request_lines.push(String::from_utf8_lossy(&request[..length]).lines().next().unwrap().to_string());
+
+            let body = format!(r#"[{{"Node": {{"Address": "10.0.0.1"}}, "Service": {{"Address": "", "Port": {}}}}}]"#, index);
+            let response = format!("HTTP/1.1 200 OK\r\nX-Consul-Index: {}\r\nContent-Type: application/json\r\n\r\n{}", index, body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        request_lines
+    });
+
+    let mut catalog = ConsulCatalog::new(address, "web".to_string());
+    assert_eq!(catalog.instances().await.unwrap(), vec!["10.0.0.1:7"]);
+    assert_eq!(catalog.instances().await.unwrap(), vec!["10.0.0.1:9"]);
+
+    let request_lines = agent.await.unwrap();
+    assert_eq!(request_lines[0], "GET /v1/health/service/web?passing=1 HTTP/1.0");
+    assert_eq!(request_lines[1], "GET /v1/health/service/web?passing=1&index=7&wait=30s HTTP/1.0");
+}
+
+
+#[test]
+fn test_parse_upstreams_file() {
+    let contents = "# web servers\n127.0.0.1:8081\n\n  backend.internal:8082  \n[::1]:8083\n";
+    assert_eq!(parse_upstreams_file(contents).unwrap(), vec!["127.0.0.1:8081", "backend.internal:8082", "[::1]:8083"]);
+
+    assert_eq!(parse_upstreams_file("127.0.0.1:8081\n127.0.0.1\n").unwrap_err(), "line 2: expected host:port, got \"127.0.0.1\"");
+    assert!(parse_upstreams_file("127.0.0.1:99999").is_err());
+    assert!(parse_upstreams_file("bad host:80").is_err());
+}
+
+
+#[tokio::test]
+async fn test_file_catalog_applies_edits_after_debounce() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let path = std::env::temp_dir().join(format!("lb-upstreams-{}-{}", std::process::id(), nanos));
+    std::fs::write(&path, "10.0.0.1:80\n").unwrap();
+
+    let (sender, mut receiver) = watch::channel(Vec::new());
+    let catalog = FileCatalog::new(path.clone(), Duration::from_millis(10), Duration::from_millis(100));
+    let watcher = tokio::spawn(async move { watch_catalog(catalog, &sender, Duration::from_millis(10)).await });
+
+    // the first version is applied right away
+    assert_eq!(next_published(&mut receiver).await, Some(vec!["10.0.0.1:80".to_string()]));
+
+    // a burst of edits is applied once, after the file stopped changing
+    let edited_at = Instant::now();
+    for contents in ["10.0.0.1:80\n10.0.0.2", "10.0.0.1:80\n10.0.0.2:80", "10.0.0.1:80\n10.0.0.2:80\n10.0.0.3:80\n"] {
+        std::fs::write(&path, contents).unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+    }
+    let published = timeout(Duration::from_secs(5), receiver.changed()).await;
+    assert!(published.is_ok() && edited_at.elapsed() >= Duration::from_millis(100 + 60));
+    assert_eq!(*receiver.borrow_and_update(), vec!["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+
+    // an invalid edit keeps the running upstream servers
+    std::fs::write(&path, "10.0.0.1:80\nnot an upstream\n").unwrap();
+    assert_eq!(next_published(&mut receiver).await, None);
+    assert_eq!(*receiver.borrow(), vec!["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+
+    watcher.abort();
+    std::fs::remove_file(&path).unwrap();
+}
+
+
+fn applied(name: &str, addresses: &[&str]) -> SliceEvent {
+    SliceEvent::Applied { name: name.to_string(), addresses: addresses.iter().map(|address| address.to_string()).collect() }
+}
+
+
+#[test]
+fn test_endpoint_slices_follow_watch_events() {
+    let mut slices = EndpointSlices::default();
+
+    // the upstream servers are the addresses of every slice
+    assert_eq!(slices.handle(applied("web-a", &["10.1.0.2:80", "10.1.0.1:80"])), Some(vec!["10.1.0.1:80".to_string(), "10.1.0.2:80".to_string()]));
+    assert_eq!(slices.handle(applied("web-b", &["10.1.0.3:80"])), Some(vec!["10.1.0.1:80".to_string(), "10.1.0.2:80".to_string(), "10.1.0.3:80".to_string()]));
+
+    // an update not changing the addresses isn't a change
+    assert_eq!(slices.handle(applied("web-b", &["10.1.0.3:80"])), None);
+
+    assert_eq!(slices.handle(SliceEvent::Deleted { name: "web-a".to_string() }), Some(vec!["10.1.0.3:80".to_string()]));
+}
+
+
+#[test]
+fn test_endpoint_slices_keep_serving_while_relisting() {
+    let mut slices = EndpointSlices::default();
+    slices.handle(applied("web-a", &["10.1.0.1:80"]));
+    slices.handle(applied("web-b", &["10.1.0.2:80"]));
+
+    // after a disconnection, the known slices are used until the list is complete
+    assert_eq!(slices.handle(SliceEvent::Relisting), None);
+    assert_eq!(slices.handle(applied("web-b", &["10.1.0.2:80", "10.1.0.4:80"])), None);
+    assert_eq!(slices.upstreams(), vec!["10.1.0.1:80", "10.1.0.2:80"]);
+
+    // the slice deleted while disconnected is gone once the list is complete
+    assert_eq!(slices.handle(SliceEvent::Listed), Some(vec!["10.1.0.2:80".to_string(), "10.1.0.4:80".to_string()]));
+}
@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::watch;
+use tokio::time::{timeout, Duration};
+
+use crate::drain::{wait_for_drain_state, watch_drain_file};
+
+
+/// Returns a path in the temporary directory that no other test uses.
+fn unique_drain_file() -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    std::env::temp_dir().join(format!("lb-drain-{}-{}", std::process::id(), nanos))
+}
+
+
+#[tokio::test]
+async fn test_drain_state_follows_drain_file() {
+    let path = unique_drain_file();
+    let (sender, mut receiver) = watch::channel(false);
+    let watched_path = path.clone();
+    let watcher = tokio::spawn(async move { watch_drain_file(watched_path, &sender).await });
+
+    std::fs::write(&path, b"").unwrap();
+    timeout(Duration::from_secs(5), wait_for_drain_state(&mut receiver, true)).await.unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+    timeout(Duration::from_secs(5), wait_for_drain_state(&mut receiver, false)).await.unwrap();
+
+    watcher.abort();
+}
+
+
+#[tokio::test]
+async fn test_wait_for_current_drain_state_returns_immediately() {
+    let (_sender, mut receiver) = watch::channel(true);
+
+    timeout(Duration::from_millis(100), wait_for_drain_state(&mut receiver, true)).await.unwrap();
+    assert!(timeout(Duration::from_millis(100), wait_for_drain_state(&mut receiver, false)).await.is_err());
+}
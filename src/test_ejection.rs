@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::ejection::Ejector;
+
+
+fn upstreams(addresses: &[&str]) -> Vec<String> {
+    addresses.iter().map(|address| address.to_string()).collect()
+}
+
+
+#[test]
+fn test_consecutive_server_errors_eject_the_upstream() {
+    let ejector = Ejector::new(3, Duration::from_secs(30));
+
+    for status in [500, 502] {
+        ejector.record_status("10.0.0.1:80", status);
+    }
+    assert!(!ejector.is_ejected("10.0.0.1:80"));
+
+    ejector.record_status("10.0.0.1:80", 503);
+    assert!(ejector.is_ejected("10.0.0.1:80"));
+    assert!(!ejector.is_ejected("10.0.0.2:80"));
+    assert_eq!(ejector.ejections("10.0.0.1:80"), 1);
+    assert!(ejector.render_prometheus().contains("lb_upstream_ejections_total{upstream=\"10.0.0.1:80\"} 1\n"));
+}
+
+
+#[test]
+fn test_client_errors_never_eject_the_upstream() {
+    let ejector = Ejector::new(3, Duration::from_secs(30));
+
+    for _ in 0..100 {
+        ejector.record_status("10.0.0.1:80", 404);
+    }
+    assert!(!ejector.is_ejected("10.0.0.1:80"));
+
+    // a client error breaks a run of server errors like a success does
+    for status in [500, 500, 429, 500, 500, 200, 500, 500] {
+        ejector.record_status("10.0.0.1:80", status);
+    }
+    assert!(!ejector.is_ejected("10.0.0.1:80"));
+    assert_eq!(ejector.ejections("10.0.0.1:80"), 0);
+}
+
+
+#[test]
+fn test_ejection_expires() {
+    let ejector = Ejector::new(1, Duration::from_millis(50));
+
+    ejector.record_status("10.0.0.1:80", 500);
+    assert!(ejector.is_ejected("10.0.0.1:80"));
+
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!ejector.is_ejected("10.0.0.1:80"));
+}
+
+
+#[test]
+fn test_last_candidates_are_never_ejected() {
+    let ejector = Ejector::new(1, Duration::from_secs(30));
+    let upstream_address_list = upstreams(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+    ejector.record_status("10.0.0.1:80", 500);
+    ejector.record_status("10.0.0.2:80", 500);
+
+    let mut excluded = HashSet::new();
+    ejector.exclude_ejected(&upstream_address_list, &mut excluded);
+    assert_eq!(excluded, HashSet::from(["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()]));
+
+    // once the healthy upstream failed, the ejected ones are the only candidates left
+    let mut excluded = HashSet::from(["10.0.0.3:80".to_string()]);
+    ejector.exclude_ejected(&upstream_address_list, &mut excluded);
+    assert_eq!(excluded, HashSet::from(["10.0.0.3:80".to_string()]));
+}
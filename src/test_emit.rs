@@ -0,0 +1,267 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use http::header::HeaderValue;
+use http::{Method, Request, StatusCode};
+use proptest::prelude::*;
+use tokio::io::AsyncWriteExt;
+
+use crate::cors::CorsPolicy;
+use crate::emit::{encode_chunk, reframe_head, Framing, ProxyResponse, LAST_CHUNK};
+use crate::fault::InjectedFault;
+use crate::redirect::redirect_response;
+use crate::response::{relay_response, AddedHeaders, ResponseLimits};
+use crate::static_route::StaticRoute;
+
+
+/// Re-parses a response the way a strict client does, and returns its status and body.
+///
+/// The response must declare a single framing, and hold exactly the bytes it declares: nothing is left after the
+/// body, nor missing from it.
+fn reparse(response: &[u8], request_method: &Method) -> Result<(u16, Vec<u8>), String> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_length = match parsed.parse(response) {
+        Ok(httparse::Status::Complete(head_length)) => head_length,
+        other => return Err(format!("invalid head: {:?}", other)),
+    };
+    let status = parsed.code.unwrap_or_default();
+    let values = |name: &str| parsed.headers.iter().filter(|header| header.name.eq_ignore_ascii_case(name)).map(|header| header.value).collect::<Vec<_>>();
+    let lengths = values("Content-Length");
+    let codings = values("Transfer-Encoding");
+    if lengths.len() > 1 || !codings.is_empty() && !lengths.is_empty() {
+        return Err(format!("ambiguous framing: {:?} and {:?}", lengths, codings));
+    }
+
+    let rest = &response[head_length..];
+    let no_body = *request_method == Method::HEAD || (100..200).contains(&status) || status == 204 || status == 304;
+    if no_body {
+        if ((100..200).contains(&status) || status == 204) && (!lengths.is_empty() || !codings.is_empty()) {
+            return Err(format!("a {} response declares a body", status));
+        }
+        return match rest.is_empty() {
+            true => Ok((status, Vec::new())),
+            false => Err(format!("{} bytes after a response without body", rest.len())),
+        };
+    }
+
+    if codings == [b"chunked"] {
+        let mut body = Vec::new();
+        let mut rest = rest;
+        loop {
+            let line_end = rest.windows(2).position(|window| window == b"\r\n").ok_or("unterminated chunk size")?;
+            let size = usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).map_err(|e| e.to_string())?, 16).map_err(|e| e.to_string())?;
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                return match rest == b"\r\n" {
+                    true => Ok((status, body)),
+                    false => Err(format!("{} bytes after the last chunk", rest.len())),
+                };
+            }
+            if rest.len() < size + 2 || &rest[size..size + 2] != b"\r\n" {
+                return Err("truncated chunk".to_string());
+            }
+            body.extend_from_slice(&rest[..size]);
+            rest = &rest[size + 2..];
+        }
+    }
+
+    let length: usize = match lengths.first() {
+        Some(length) => std::str::from_utf8(length).ok().and_then(|length| length.parse().ok()).ok_or("invalid Content-Length")?,
+        None => return Err(format!("a {} response without framing", status)),
+    };
+    match rest.len() == length {
+        true => Ok((status, rest.to_vec())),
+        false => Err(format!("Content-Length {} but {} bytes of body", length, rest.len())),
+    }
+}
+
+
+fn request(method: &Method) -> Request<Vec<u8>> {
+    Request::builder().method(method.clone()).uri("/path?query=1").header("Host", "example.com").header("Origin", "https://app.example.com")
+        .header("Access-Control-Request-Method", "GET").body(Vec::new()).unwrap()
+}
+
+
+/// Relays `response` from an upstream server that stays open after sending it, and returns what the client received.
+fn relay(response: &[u8], request_method: &Method) -> Vec<u8> {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let (mut upstream_writer, mut upstream_stream) = tokio::io::duplex(64 * 1024);
+        upstream_writer.write_all(response).await.unwrap();
+
+        let mut client_stream = Vec::new();
+        let mut buffer = vec![0; 8 * 1024];
+        relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, request_method, &AddedHeaders::default(), &ResponseLimits::default(), false).await.unwrap();
+        client_stream
+    })
+}
+
+
+/// Splits `body` at the given positions.
+fn parts<'a>(body: &'a [u8], splits: &[usize]) -> Vec<&'a [u8]> {
+    let mut positions: Vec<usize> = splits.iter().map(|split| split % (body.len() + 1)).collect();
+    positions.push(0);
+    positions.push(body.len());
+    positions.sort_unstable();
+    positions.windows(2).map(|part| &body[part[0]..part[1]]).collect()
+}
+
+
+fn method() -> impl Strategy<Value = Method> {
+    prop_oneof![Just(Method::GET), Just(Method::HEAD), Just(Method::POST)]
+}
+
+
+fn status() -> impl Strategy<Value = StatusCode> {
+    (100u16..600).prop_filter_map("invalid status", |status| StatusCode::from_u16(status).ok().filter(|status| *status != StatusCode::SWITCHING_PROTOCOLS))
+}
+
+
+/// Bodies of assorted sizes, empty, small and over a few reads.
+fn body() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![Just(Vec::new()), prop::collection::vec(any::<u8>(), 1..64), prop::collection::vec(any::<u8>(), 64..20_000)]
+}
+
+
+/// Number of cases of every property, 500 unless overridden with `PROPTEST_CASES`.
+fn cases() -> u32 {
+    std::env::var("PROPTEST_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(500)
+}
+
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(cases()))]
+
+    #[test]
+    fn emitted_responses_declare_their_body(status in status(), method in method(), body in body(), stale_length in any::<u16>(), chunked in any::<bool>()) {
+        // the stale framing headers given to the response are dropped
+        let mut response = ProxyResponse::new(status).header("Content-Type", "text/plain").header("Content-Length", stale_length.to_string());
+        if chunked {
+            response = response.header("Transfer-Encoding", "chunked");
+        }
+        let emitted = response.body(body.clone()).emit(&method);
+
+        let (reparsed_status, reparsed_body) = reparse(&emitted, &method).map_err(TestCaseError::fail)?;
+        prop_assert_eq!(reparsed_status, status.as_u16());
+        let expected = match method == Method::HEAD || (100..200).contains(&status.as_u16()) || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+            true => Vec::new(),
+            false => body,
+        };
+        prop_assert_eq!(reparsed_body, expected);
+    }
+
+
+    #[test]
+    fn streamed_bodies_match_their_head(method in method(), body in body(), splits in prop::collection::vec(any::<usize>(), 0..8), known_length in any::<bool>()) {
+        let response = ProxyResponse::new(StatusCode::OK).header("Content-Type", "application/octet-stream");
+        let (mut emitted, framing) = response.emit_head(&method, known_length.then_some(body.len()));
+        for part in parts(&body, &splits) {
+            match framing {
+                Framing::Chunked => emitted.extend(encode_chunk(part)),
+                Framing::ContentLength(_) => emitted.extend_from_slice(part),
+                _ => (),
+            }
+        }
+        if framing == Framing::Chunked {
+            emitted.extend_from_slice(LAST_CHUNK);
+        }
+
+        let (_, reparsed_body) = reparse(&emitted, &method).map_err(TestCaseError::fail)?;
+        prop_assert_eq!(reparsed_body, if method == Method::HEAD { Vec::new() } else { body });
+    }
+
+
+    #[test]
+    fn static_routes_declare_their_body(method in method(), body in body(), if_modified_since in prop::option::of(0u64..2_000_000_000)) {
+        let route = StaticRoute {
+            path: "/path".to_string(),
+            content_type: HeaderValue::from_static("text/plain"),
+            body: body.clone(),
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_000_000_000),
+        };
+        let mut request = request(&method);
+        if let Some(seconds) = if_modified_since {
+            let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(seconds));
+            request.headers_mut().insert(http::header::IF_MODIFIED_SINCE, HeaderValue::from_str(&date).unwrap());
+        }
+
+        let (status, response) = route.respond(&request);
+        let (reparsed_status, reparsed_body) = reparse(&response, &method).map_err(TestCaseError::fail)?;
+        prop_assert_eq!(reparsed_status, status.as_u16());
+        if status == StatusCode::OK && method == Method::GET {
+            prop_assert_eq!(reparsed_body, body);
+        }
+    }
+
+
+    #[test]
+    fn relayed_responses_keep_a_single_framing(method in method(), body in body(), splits in prop::collection::vec(any::<usize>(), 0..8), stale_lengths in prop::collection::vec(any::<u16>(), 0..3), chunked in any::<bool>()) {
+        // an upstream server framing a chunked body with a Content-Length as well, or repeating its Content-Length
+        let mut upstream_response = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n".to_vec();
+        match chunked {
+            true => {
+                for stale_length in &stale_lengths {
+                    upstream_response.extend_from_slice(format!("Content-Length: {}\r\n", stale_length).as_bytes());
+                }
+                upstream_response.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+                for part in parts(&body, &splits) {
+                    upstream_response.extend(encode_chunk(part));
+                }
+                upstream_response.extend_from_slice(LAST_CHUNK);
+            }
+            false => {
+                for _ in 0..=stale_lengths.len() {
+                    upstream_response.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+                }
+                upstream_response.extend_from_slice(b"\r\n");
+                upstream_response.extend_from_slice(&body);
+            }
+        }
+
+        let relayed = relay(&upstream_response, &method);
+        let (_, reparsed_body) = reparse(&relayed, &method).map_err(TestCaseError::fail)?;
+        prop_assert_eq!(reparsed_body, if method == Method::HEAD { Vec::new() } else { body });
+    }
+}
+
+
+#[test]
+fn responses_written_by_the_proxy_are_framed() {
+    for method in [Method::GET, Method::HEAD, Method::POST] {
+        let request = request(&method);
+
+        let redirect = redirect_response(&request, Some(31_536_000)).unwrap();
+        assert_eq!(reparse(redirect.as_bytes(), &method), Ok((301, Vec::new())));
+
+        let fault = InjectedFault { latency: Duration::ZERO, abort: Some(StatusCode::SERVICE_UNAVAILABLE) };
+        let (_, abort) = fault.abort_response(&method).unwrap();
+        let expected_body = if method == Method::HEAD { Vec::new() } else { b"injected fault\n".to_vec() };
+        assert_eq!(reparse(&abort, &method), Ok((503, expected_body)));
+
+        let cors = CorsPolicy { origins: vec!["https://app.example.com".parse().unwrap()], methods: vec![Method::GET], headers: Vec::new() };
+        let (_, preflight) = cors.preflight_response(&request);
+        assert_eq!(reparse(&preflight, &method), Ok((204, Vec::new())));
+    }
+
+    // a 502 without detail used to end with the connection, it now declares its empty body
+    assert_eq!(ProxyResponse::new(StatusCode::BAD_GATEWAY).emit(&Method::GET), b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n");
+}
+
+
+#[test]
+fn stale_framing_headers_are_rewritten() {
+    let head = b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\nTransfer-Encoding: chunked\r\ncontent-length : 3\r\nX-Id: 1\r\n\r\n";
+    assert_eq!(reframe_head(head).unwrap(), b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nX-Id: 1\r\n\r\n");
+
+    let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5, 5\r\nX-Id: 1\r\n\r\n";
+    assert_eq!(reframe_head(head).unwrap(), b"HTTP/1.1 200 OK\r\nX-Id: 1\r\nContent-Length: 5\r\n\r\n");
+
+    // the length a 304 response declares for its resource is kept, once
+    let head = b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n";
+    assert_eq!(reframe_head(head).unwrap(), b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n");
+
+    // consistent heads are relayed as they were received
+    assert_eq!(reframe_head(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"), None);
+    assert_eq!(reframe_head(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"), None);
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::byte_volume::ByteVolumes;
+use crate::capacity::UpstreamLimiter;
+use crate::connect::Connector;
+use crate::context::ProxyContext;
+use crate::explain::{explain, SampleRequest};
+use crate::load_report::LoadReports;
+use crate::request::RequestConfig;
+use crate::response::ResponseConfig;
+use crate::routing::UpstreamPools;
+
+
+fn pools(default: &[&str], canary: &[&str], canary_percent: u8) -> UpstreamPools {
+    let default: Vec<String> = default.iter().map(|address| address.to_string()).collect();
+    let canary: Vec<String> = canary.iter().map(|address| address.to_string()).collect();
+    let all: Vec<String> = default.iter().chain(&canary).cloned().collect();
+    UpstreamPools { default, canary, canary_header: Some("X-Canary=true".parse().unwrap()), canary_percent, configured: all.clone(), healthy: all }
+}
+
+
+fn context(request_config: RequestConfig) -> ProxyContext {
+    ProxyContext::new(request_config, ResponseConfig::default(), Connector::new(0, Duration::ZERO, None))
+}
+
+
+/// Parses a sample request, for the `localhost` host unless it names one.
+fn sample(json: &str) -> SampleRequest {
+    let mut sample = SampleRequest::from_json(json.as_bytes()).unwrap();
+    sample.host.get_or_insert(http::HeaderValue::from_static("localhost"));
+    sample
+}
+
+
+#[test]
+fn test_sample_request_is_parsed_with_defaults() {
+    let parsed = SampleRequest::from_json(br#"{"headers": {"X-Canary": "true"}}"#).unwrap();
+    assert_eq!(parsed.method, http::Method::GET);
+    assert_eq!(parsed.path, "/");
+    assert_eq!(parsed.host, None);
+    assert_eq!(parsed.client_ip, "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+    assert_eq!(parsed.headers.len(), 1);
+
+    for invalid in ["[]", "{", r#"{"path": "/a b"}"#, r#"{"method": "G T"}"#, r#"{"client_ip": "localhost"}"#, r#"{"headers": {"X Canary": "true"}}"#, r#"{"headers": ["X-Canary"]}"#, r#"{"path": 1}"#] {
+        assert!(SampleRequest::from_json(invalid.as_bytes()).is_err(), "{}", invalid);
+    }
+}
+
+
+#[tokio::test]
+async fn test_denied_request_names_its_rule() {
+    let context = context(RequestConfig { acl_rules: vec!["deny method=TRACE".parse().unwrap(), "deny path=^/admin".parse().unwrap(), "deny path=^/admin/users status=404".parse().unwrap()], ..RequestConfig::default() });
+
+    let trace = explain(&sample(r#"{"path": "/admin/users"}"#), &pools(&["10.0.0.1:80"], &[], 0), &context).await;
+
+    assert_eq!(trace["decision"], "denied");
+    assert_eq!(trace["acl_rule"], 1);
+    assert_eq!(trace["status"], 403);
+    // the later rules matching the request are listed, but only the first one applies
+    assert_eq!(trace["acl"], json!([1, 2]));
+    assert!(trace.get("pick").is_none());
+
+    // as a real HTTP/1.1 request, a request without a host is rejected
+    let trace = explain(&SampleRequest::from_json(b"{}").unwrap(), &pools(&["10.0.0.1:80"], &[], 0), &context).await;
+    assert_eq!(trace["decision"], "rejected");
+    assert_eq!(trace["status"], 400);
+}
+
+
+#[tokio::test]
+async fn test_canary_header_picks_the_canary_pool() {
+    let pools = pools(&["10.0.0.1:80"], &["10.0.0.9:80"], 0);
+
+    let trace = explain(&sample(r#"{"headers": {"X-Canary": "true"}}"#), &pools, &context(RequestConfig::default())).await;
+    assert_eq!(trace["decision"], "upstream");
+    assert_eq!(trace["pick"], "10.0.0.9:80");
+    assert_eq!(trace["pools"]["canary"]["odds"], 1.0);
+    assert!(trace["pools"].get("default").is_none());
+
+    let trace = explain(&sample("{}"), &pools, &context(RequestConfig::default())).await;
+    assert_eq!(trace["pick"], "10.0.0.1:80");
+}
+
+
+#[tokio::test]
+async fn test_canary_percent_splits_the_odds() {
+    let pools = pools(&["10.0.0.1:80", "10.0.0.2:80"], &["10.0.0.9:80"], 20);
+
+    let trace = explain(&sample("{}"), &pools, &context(RequestConfig::default())).await;
+
+    assert_eq!(trace["strategy"], "random");
+    assert_eq!(trace["pick"], Value::Null);
+    let odds = |address: &str| trace["odds"][address].as_f64().unwrap();
+    assert!((odds("10.0.0.1:80") - 0.4).abs() < 1e-9);
+    assert!((odds("10.0.0.2:80") - 0.4).abs() < 1e-9);
+    assert!((odds("10.0.0.9:80") - 0.2).abs() < 1e-9);
+}
+
+
+#[tokio::test]
+async fn test_reported_loads_give_the_odds_of_two_choices() {
+    let load_reports = Arc::new(LoadReports::default());
+    for (address, load) in [("10.0.0.1:80", 1.0), ("10.0.0.2:80", 2.0), ("10.0.0.3:80", 3.0)] {
+        load_reports.record(address, load);
+    }
+    let context = ProxyContext { limiter: Arc::new(UpstreamLimiter::unlimited().with_load_reports(Some(load_reports))), ..context(RequestConfig::default()) };
+
+    let trace = explain(&sample("{}"), &pools(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"], &[], 0), &context).await;
+
+    assert_eq!(trace["strategy"], "reported_load");
+    let candidates = trace["pools"]["default"]["candidates"].as_array().unwrap();
+    assert_eq!(candidates[0]["reported_load"], 1.0);
+    // the least loaded of two distinct candidates: the lowest load wins both of its pairs out of three
+    let odds = |address: &str| trace["odds"].get(address).and_then(Value::as_f64).unwrap_or(0.0);
+    assert!((odds("10.0.0.1:80") - 4.0 / 6.0).abs() < 1e-9);
+    assert!((odds("10.0.0.2:80") - 2.0 / 6.0).abs() < 1e-9);
+    assert_eq!(odds("10.0.0.3:80"), 0.0);
+}
+
+
+#[tokio::test]
+async fn test_least_bytes_and_capacity_settle_the_pick() {
+    let byte_volumes = Arc::new(ByteVolumes::new(Duration::from_secs(60)));
+    byte_volumes.record("10.0.0.1:80", 10, Instant::now());
+    byte_volumes.record("10.0.0.2:80", 1000, Instant::now());
+    let context = ProxyContext { limiter: Arc::new(UpstreamLimiter::new(Some(1), Duration::ZERO).with_byte_volumes(Some(byte_volumes))), ..context(RequestConfig::default()) };
+    let pools = pools(&["10.0.0.1:80", "10.0.0.2:80"], &[], 0);
+
+    let trace = explain(&sample("{}"), &pools, &context).await;
+    assert_eq!(trace["strategy"], "least_bytes");
+    assert_eq!(trace["pick"], "10.0.0.1:80");
+    assert_eq!(trace["pools"]["default"]["candidates"][0]["window_bytes"], 10);
+
+    // an upstream server at capacity isn't a candidate any more
+    let _slot = context.limiter.try_acquire("10.0.0.1:80").unwrap();
+    let trace = explain(&sample("{}"), &pools, &context).await;
+    assert_eq!(trace["pick"], "10.0.0.2:80");
+    assert_eq!(trace["pools"]["default"]["candidates"][0]["in_flight"], 1);
+}
+
+
+#[tokio::test]
+async fn test_static_route_is_answered_by_the_proxy() {
+    let context = context(RequestConfig { static_routes: vec![r#"/version body={"version":"1.0"}"#.parse().unwrap()], ..RequestConfig::default() });
+
+    let trace = explain(&sample(r#"{"path": "/version", "host": "shop.example.com"}"#), &pools(&["10.0.0.1:80"], &[], 0), &context).await;
+
+    assert_eq!(trace["decision"], "static");
+    assert_eq!(trace["static_route"], "/version");
+    assert_eq!(trace["forwarded_host"], "shop.example.com");
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use http::{Method, Request, StatusCode};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::fault::{FaultInjector, FaultSpec, InjectedFault};
+
+
+fn request(path: &str) -> Request<Vec<u8>> {
+    Request::builder().uri(path).header("Host", "localhost").body(Vec::new()).unwrap()
+}
+
+
+fn injector(json: &str) -> FaultInjector {
+    let injector = FaultInjector::default();
+    injector.set(Some(FaultSpec::from_json(json.as_bytes()).unwrap()));
+    injector
+}
+
+
+#[test]
+fn test_faults_are_parsed_with_defaults() {
+    let spec = FaultSpec::from_json(br#"{"latency_ms": 500, "abort_pct": 5, "match_path": "^/api/"}"#).unwrap();
+    assert_eq!(spec.latency, Duration::from_millis(500));
+    assert_eq!(spec.abort_percent, 5.0);
+    assert_eq!(spec.abort_status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(spec.match_path.unwrap().as_str(), "^/api/");
+
+    let invalid = [
+        "[]", "{", r#"{"latency_ms": -1}"#, r#"{"latency_ms": "500"}"#, r#"{"abort_pct": 101}"#,
+        r#"{"abort_status": 200}"#, r#"{"abort_status": 1000}"#, r#"{"match_path": "("}"#, r#"{"abort_percent": 5}"#,
+    ];
+    for invalid in invalid {
+        assert!(FaultSpec::from_json(invalid.as_bytes()).is_err(), "{}", invalid);
+    }
+}
+
+
+#[test]
+fn test_abort_percentage_is_drawn_per_request() {
+    let injector = injector(r#"{"abort_pct": 20, "abort_status": 500}"#);
+    let mut rng = StdRng::seed_from_u64(42);
+
+    let draws = 10_000;
+    let aborted = (0..draws).filter(|_| injector.inject(&request("/"), &mut rng).is_some()).count();
+    assert!((1800..2200).contains(&aborted), "{} aborted out of {}", aborted, draws);
+
+    let fault = InjectedFault { latency: Duration::ZERO, abort: Some(StatusCode::INTERNAL_SERVER_ERROR) };
+    let (status, response) = fault.abort_response(&Method::GET).unwrap();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(String::from_utf8(response).unwrap().starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(injector.render_prometheus().contains(&format!("lb_faults_injected_total{{kind=\"abort\"}} {}\n", aborted)));
+}
+
+
+#[test]
+fn test_latency_applies_to_matching_paths_until_cleared() {
+    let injector = injector(r#"{"latency_ms": 250, "match_path": "^/api/"}"#);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let fault = injector.inject(&request("/api/orders"), &mut rng).unwrap();
+    assert_eq!(fault, InjectedFault { latency: Duration::from_millis(250), abort: None });
+    assert_eq!(fault.abort_response(&Method::GET), None);
+    assert_eq!(injector.inject(&request("/health"), &mut rng), None);
+
+    injector.set(None);
+    assert_eq!(injector.inject(&request("/api/orders"), &mut rng), None);
+    assert!(injector.render_prometheus().contains("lb_faults_injected_total{kind=\"latency\"} 1\n"));
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use http::Request;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use rust_loadbalancer::request::{client_request_builder, incoming_hops, ForwardedHeaderFormat, RequestConfig, HOPS_HEADER};
+use crate::{check_forwarding_loop, serve, CmdOptions, ProxyState};
+
+
+fn request_config() -> RequestConfig {
+    RequestConfig { max_hops: 3, trusted_hops_from: vec!["10.0.0.0/8".parse().unwrap()], ..Default::default() }
+}
+
+
+fn request_with_hops(hops: &str) -> Request<Vec<u8>> {
+    Request::builder()
+        .uri("/")
+        .header("Host", "localhost")
+        .header(HOPS_HEADER, hops)
+        .body(Vec::new())
+        .unwrap()
+}
+
+
+#[test]
+fn test_hops_trusted_only_from_configured_networks() {
+    let config = request_config();
+    let request = request_with_hops("2");
+
+    assert_eq!(incoming_hops(&request, Some("10.1.2.3".parse().unwrap()), &config), 2);
+    assert_eq!(incoming_hops(&request, Some("192.168.1.1".parse().unwrap()), &config), 0);
+    assert_eq!(incoming_hops(&request, None, &config), 0);
+
+    // an invalid count from a trusted proxy is ignored
+    assert_eq!(incoming_hops(&request_with_hops("many"), Some("10.1.2.3".parse().unwrap()), &config), 0);
+}
+
+
+#[test]
+fn test_client_hops_header_is_replaced() {
+    let request = request_with_hops("0");
+
+    let forwarded = client_request_builder(Some("192.168.1.1"), ForwardedHeaderFormat::XForwardedFor, &request, 1, &[]).unwrap();
+
+    let hops: Vec<_> = forwarded.headers().get_all(HOPS_HEADER).iter().collect();
+    assert_eq!(hops, vec!["1"]);
+}
+
+
+#[test]
+fn test_option_defaults_match_the_default_config() {
+    let args = CmdOptions::parse_from(["rust_loadbalancer", "--upstream", "127.0.0.1:8081"]);
+    let config = RequestConfig::default();
+
+    assert_eq!(args.max_hops, config.max_hops);
+    assert_eq!(args.trusted_hops_from, config.trusted_hops_from);
+}
+
+
+#[tokio::test]
+async fn test_upstream_equal_to_listener_is_rejected() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let listener_address = listener.local_addr().unwrap();
+    let other_address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let upstreams = vec![other_address.to_string(), listener_address.to_string()];
+    assert_eq!(check_forwarding_loop(listener_address, &upstreams).await, Err(listener_address.to_string()));
+
+    let upstreams = vec![other_address.to_string()];
+    assert_eq!(check_forwarding_loop(listener_address, &upstreams).await, Ok(()));
+}
+
+
+#[tokio::test]
+async fn test_upstream_on_local_address_of_wildcard_listener_is_rejected() {
+    let listener_address = "0.0.0.0:18080".parse().unwrap();
+
+    let upstreams = vec!["127.0.0.1:18080".to_string()];
+    assert_eq!(check_forwarding_loop(listener_address, &upstreams).await, Err("127.0.0.1:18080".to_string()));
+
+    let upstreams = vec!["127.0.0.1:18081".to_string()];
+    assert_eq!(check_forwarding_loop(listener_address, &upstreams).await, Ok(()));
+}
+
+
+#[tokio::test]
+async fn test_proxy_as_its_own_upstream_answers_508() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_address = listener.local_addr().unwrap().to_string();
+
+    // the proxy is configured as its only upstream and considered healthy
+    let args = CmdOptions::parse_from(["rust_loadbalancer", "--upstream", &proxy_address, "--max-hops", "3"]);
+    let mut state = ProxyState::new(args);
+    state.active_upstream_addresses = vec![proxy_address.clone()];
+    tokio::spawn(serve(listener, Arc::new(Mutex::new(state))));
+
+    let mut client = TcpStream::connect(&proxy_address).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+    // the 508 declares its empty body, the outer hops keep the client connection open after it
+    let mut response = vec![0; 1024];
+    let length = tokio::time::timeout(Duration::from_secs(5), client.read(&mut response)).await.unwrap().unwrap();
+
+    assert!(String::from_utf8_lossy(&response[..length]).starts_with("HTTP/1.1 508 Loop Detected"));
+}
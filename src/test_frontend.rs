@@ -0,0 +1,32 @@
+use crate::frontend::{merge_metrics, Frontend, PoolUpstream};
+
+
+#[test]
+fn test_parse_frontends_and_pool_upstreams() {
+    assert_eq!("b=127.0.0.1:8081".parse(), Ok(Frontend { pool: "b".to_string(), bind: "127.0.0.1:8081".to_string() }));
+    assert_eq!(" api-v2 = 10.0.1.2:8080 ".parse(), Ok(PoolUpstream { pool: "api-v2".to_string(), address: "10.0.1.2:8080".to_string() }));
+
+    assert!("127.0.0.1:8081".parse::<Frontend>().is_err());
+    assert!("=127.0.0.1:8081".parse::<Frontend>().is_err());
+    assert!("b=".parse::<PoolUpstream>().is_err());
+    assert!("a pool=10.0.1.2:8080".parse::<PoolUpstream>().is_err());
+    // the default pool is the --upstream servers
+    assert!("default=10.0.1.2:8080".parse::<PoolUpstream>().is_err());
+}
+
+
+#[test]
+fn test_merged_metrics_are_labeled_by_pool() {
+    let a = "# TYPE lb_upstream_errors_total counter\nlb_upstream_errors_total{upstream=\"10.0.0.1:80\",kind=\"refused\"} 2\n# TYPE lb_connection_panics_total counter\nlb_connection_panics_total 0\n";
+    let b = "# TYPE lb_upstream_errors_total counter\n# TYPE lb_connection_panics_total counter\nlb_connection_panics_total 1\n";
+
+    let merged = merge_metrics(&[("default", a.to_string()), ("b", b.to_string())]);
+
+    assert_eq!(merged, concat!(
+        "# TYPE lb_upstream_errors_total counter\n",
+        "lb_upstream_errors_total{pool=\"default\",upstream=\"10.0.0.1:80\",kind=\"refused\"} 2\n",
+        "# TYPE lb_connection_panics_total counter\n",
+        "lb_connection_panics_total{pool=\"default\"} 0\n",
+        "lb_connection_panics_total{pool=\"b\"} 1\n",
+    ));
+}
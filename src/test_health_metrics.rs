@@ -0,0 +1,96 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use crate::health_metrics::{run_probe, HealthMetrics};
+use crate::http_health_checks::HealthCheckRequest;
+
+
+/// Starts a local upstream answering a single health check with `response` after waiting for `delay`.
+fn slow_upstream(response: &'static str, delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0; 1024];
+        let _ = stream.read(&mut buffer).unwrap();
+        thread::sleep(delay);
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+
+    address
+}
+
+
+#[tokio::test]
+async fn test_probe_duration_and_outcome_recorded() {
+    let upstream_address = slow_upstream("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", Duration::from_millis(200));
+    let mut metrics = HealthMetrics::default();
+
+    let (duration, healthy) = run_probe(&upstream_address, &HealthCheckRequest::get("/".to_string())).await;
+    assert!(healthy);
+    metrics.record_timed_probe(&upstream_address, duration, healthy);
+
+    let record = metrics.probe_record(&upstream_address).unwrap();
+    assert!(record.healthy);
+    assert!(record.duration >= Duration::from_millis(200), "probe took {:?}", record.duration);
+    assert!(record.last_success.is_some());
+    assert!(metrics.since_last_probe(&upstream_address).unwrap() < Duration::from_secs(5));
+}
+
+
+#[test]
+fn test_failed_probe_keeps_last_success() {
+    let mut metrics = HealthMetrics::default();
+
+    metrics.record_probe("127.0.0.1:8081", Duration::from_millis(5), true);
+    let last_success = metrics.probe_record("127.0.0.1:8081").unwrap().last_success;
+    metrics.record_probe("127.0.0.1:8081", Duration::from_millis(5), false);
+
+    let record = metrics.probe_record("127.0.0.1:8081").unwrap();
+    assert!(!record.healthy);
+    assert_eq!(record.last_success, last_success);
+
+    metrics.record_probe("127.0.0.1:8082", Duration::from_millis(5), false);
+    assert!(metrics.probe_record("127.0.0.1:8082").unwrap().last_success.is_none());
+}
+
+
+#[tokio::test]
+async fn test_slow_cycle_reported_as_falling_behind() {
+    let upstream_address = slow_upstream("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", Duration::from_millis(300));
+    let mut metrics = HealthMetrics::default();
+
+    let started_at = std::time::Instant::now();
+    let (duration, healthy) = run_probe(&upstream_address, &HealthCheckRequest::get("/".to_string())).await;
+    metrics.record_timed_probe(&upstream_address, duration, healthy);
+    let cycle = started_at.elapsed();
+
+    assert!(metrics.record_cycle(cycle, Duration::from_millis(100)));
+    assert_eq!(metrics.last_cycle(), Some(cycle));
+    assert!(!metrics.record_cycle(Duration::from_millis(10), Duration::from_secs(1)));
+}
+
+
+#[tokio::test]
+async fn test_probe_durations_rendered_as_histogram() {
+    let upstream_address = slow_upstream("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", Duration::from_millis(30));
+    let mut metrics = HealthMetrics::default();
+    let (duration, healthy) = run_probe(&upstream_address, &HealthCheckRequest::get("/".to_string())).await;
+    assert!(healthy);
+    metrics.record_timed_probe(&upstream_address, duration, healthy);
+    metrics.record_probe("127.0.0.1:1", Duration::from_millis(5), false);
+
+    let histogram = metrics.duration_histogram(&upstream_address).unwrap();
+    assert_eq!(histogram.count, 1);
+    assert!(histogram.sum >= 0.03);
+
+    // the probe took over 25ms, it is counted from the 50ms bucket on
+    let rendered = metrics.render_prometheus();
+    assert!(rendered.contains(&format!("health_check_duration_seconds_bucket{{upstream=\"{}\",le=\"0.025\"}} 0\n", upstream_address)), "{}", rendered);
+    assert!(rendered.contains(&format!("health_check_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} 1\n", upstream_address)));
+    assert!(rendered.contains(&format!("health_check_failures_total{{upstream=\"{}\"}} 0\n", upstream_address)));
+    assert!(rendered.contains("health_check_failures_total{upstream=\"127.0.0.1:1\"} 1\n"));
+}
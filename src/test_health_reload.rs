@@ -0,0 +1,153 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::sync::{mpsc, Mutex};
+
+use rust_loadbalancer::discovery::Catalog;
+use rust_loadbalancer::http_health_checks::HealthCheckRequest;
+use crate::{health_check_loop, probe_upstreams, spawn_discovery, CmdOptions, ProxyState};
+
+
+/// Catalog answering with the upstream lists sent by the test, one reload at a time.
+struct ChannelCatalog {
+    reloads: mpsc::UnboundedReceiver<Vec<String>>,
+}
+
+impl Catalog for ChannelCatalog {
+    async fn instances(&mut self) -> Result<Vec<String>, std::io::Error> {
+        match self.reloads.recv().await {
+            Some(upstreams) => Ok(upstreams),
+            None => std::future::pending().await,
+        }
+    }
+}
+
+
+/// The health checks in progress on an upstream server of a test, and the most that ever ran at once.
+#[derive(Default)]
+struct Checks {
+    in_progress: AtomicUsize,
+    most_concurrent: AtomicUsize,
+    total: AtomicUsize,
+}
+
+
+/// Starts an upstream server answering every health check with 200 OK after `delay`, and returns its address.
+fn slow_upstream(delay: Duration, checks: Arc<Checks>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let checks = checks.clone();
+            std::thread::spawn(move || {
+                let in_progress = checks.in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+                checks.most_concurrent.fetch_max(in_progress, Ordering::SeqCst);
+                checks.total.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.read(&mut [0; 1024]);
+                std::thread::sleep(delay);
+                checks.in_progress.fetch_sub(1, Ordering::SeqCst);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            });
+        }
+    });
+    address
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_reload_mid_cycle_keeps_the_checks_of_the_remaining_upstreams() {
+    let (removed_checks, kept_checks, added_checks) = (Arc::new(Checks::default()), Arc::new(Checks::default()), Arc::new(Checks::default()));
+    let removed = slow_upstream(Duration::from_millis(300), removed_checks.clone());
+    let kept = slow_upstream(Duration::from_millis(300), kept_checks.clone());
+    let added = slow_upstream(Duration::from_millis(300), added_checks.clone());
+    let args = CmdOptions::parse_from(["rust_loadbalancer", "--upstream", &removed, "--upstream", &kept, "--interval", "5"]);
+    let shared_state = Arc::new(Mutex::new(ProxyState::new(args)));
+
+    let health_checks = tokio::spawn(health_check_loop(shared_state.clone()));
+    let (reloads, catalog_reloads) = mpsc::unbounded_channel();
+    spawn_discovery(ChannelCatalog { reloads: catalog_reloads }, shared_state.clone());
+
+    // the checks of the first cycle run at once, without holding the state lock
+    while removed_checks.total.load(Ordering::SeqCst) == 0 || kept_checks.total.load(Ordering::SeqCst) == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(100), shared_state.lock()).await.is_ok());
+
+    // reload while the first cycle is checking the upstream server being removed
+    reloads.send(vec![kept.clone(), added.clone()]).unwrap();
+
+    // the cycle keeps the check of the remaining upstream server, and only checks the added one
+    let mut expected = vec![kept.clone(), added];
+    expected.sort();
+    let mut active = Vec::new();
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        active = shared_state.lock().await.active_upstream_addresses.clone();
+        active.sort();
+        if active == expected {
+            break;
+        }
+    }
+    health_checks.abort();
+
+    assert_eq!(active, expected);
+    let state = shared_state.lock().await;
+    assert!(state.health_metrics.probe_record(&removed).is_none());
+    assert_eq!(state.health_metrics.probe_record(&kept).unwrap().probes, 1);
+    for checks in [&removed_checks, &kept_checks, &added_checks] {
+        assert_eq!(checks.total.load(Ordering::SeqCst), 1);
+    }
+    // the checks of an upstream server never overlap
+    for checks in [removed_checks, kept_checks, added_checks] {
+        assert_eq!(checks.most_concurrent.load(Ordering::SeqCst), 1);
+    }
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_catalog_changing_during_every_round_doesnt_starve_the_health_checks() {
+    let checks = Arc::new(Checks::default());
+    let kept = slow_upstream(Duration::from_millis(200), checks.clone());
+    let args = CmdOptions::parse_from(["rust_loadbalancer", "--upstream", &kept, "--interval", "5"]);
+    let shared_state = Arc::new(Mutex::new(ProxyState::new(args)));
+
+    let (reloads, catalog_reloads) = mpsc::unbounded_channel();
+    spawn_discovery(ChannelCatalog { reloads: catalog_reloads }, shared_state.clone());
+    let health_checks = tokio::spawn(health_check_loop(shared_state.clone()));
+
+    // an upstream server is added during every round of the cycle
+    let mut upstreams = vec![kept.clone()];
+    let mut active = Vec::new();
+    for _ in 0..30 {
+        upstreams.push(slow_upstream(Duration::from_millis(200), checks.clone()));
+        reloads.send(upstreams.clone()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        active = shared_state.lock().await.active_upstream_addresses.clone();
+        if active.contains(&kept) {
+            break;
+        }
+    }
+    health_checks.abort();
+
+    // the cycle completed after its last round, the upstream servers still unchecked wait for the next one
+    assert!(active.contains(&kept), "{:?}", active);
+    assert!(active.len() < upstreams.len());
+}
+
+
+#[tokio::test]
+async fn test_health_checks_are_capped_at_the_concurrency() {
+    let checks = Arc::new(Checks::default());
+    let upstreams: Vec<String> = (0..6).map(|_| slow_upstream(Duration::from_millis(100), checks.clone())).collect();
+
+    let outcomes = probe_upstreams(upstreams.clone(), Arc::new(HealthCheckRequest::get("/".to_string())), 2).await;
+
+    assert_eq!(outcomes.len(), 6);
+    assert!(upstreams.iter().all(|address| outcomes[address].1));
+    assert_eq!(checks.total.load(Ordering::SeqCst), 6);
+    assert_eq!(checks.most_concurrent.load(Ordering::SeqCst), 2);
+}
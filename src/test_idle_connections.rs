@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::idle_connections::{IdleConnections, IdleSlot};
+
+
+/// Tells whether an idle connection was evicted, without waiting for it.
+async fn is_evicted(slot: &IdleSlot) -> bool {
+    timeout(Duration::from_millis(10), slot.evicted()).await.is_ok()
+}
+
+
+#[tokio::test]
+async fn test_oldest_idle_connections_are_evicted_over_the_cap() {
+    let connections = Arc::new(IdleConnections::new(2));
+
+    let first = connections.park("10.0.0.1:80");
+    let second = connections.park("10.0.0.1:80");
+    let third = connections.park("10.0.0.1:80");
+    let fourth = connections.park("10.0.0.1:80");
+
+    // the pool never holds more than the cap, the two oldest connections must be closed
+    assert_eq!(connections.idle("10.0.0.1:80"), 2);
+    assert!(is_evicted(&first).await);
+    assert!(is_evicted(&second).await);
+    assert!(!is_evicted(&third).await);
+    assert!(!is_evicted(&fourth).await);
+    assert_eq!(connections.evicted(), 2);
+
+    // the cap applies to every upstream server on its own
+    let _other = connections.park("10.0.0.2:80");
+    assert_eq!(connections.idle("10.0.0.2:80"), 1);
+    assert_eq!(connections.evicted(), 2);
+
+    // dropping an evicted connection leaves the parked ones
+    drop(first);
+    assert_eq!(connections.idle("10.0.0.1:80"), 2);
+}
+
+
+#[tokio::test]
+async fn test_checked_out_connections_leave_the_pool() {
+    let connections = Arc::new(IdleConnections::new(1));
+
+    let slot = connections.park("10.0.0.1:80");
+    drop(slot);
+    assert_eq!(connections.idle("10.0.0.1:80"), 0);
+
+    // the slot freed by the checkout is taken without evicting anything
+    let slot = connections.park("10.0.0.1:80");
+    assert!(!is_evicted(&slot).await);
+    assert_eq!(connections.evicted(), 0);
+    assert!(connections.render_prometheus().contains("lb_idle_upstream_connections{upstream=\"10.0.0.1:80\"} 1\n"));
+
+    drop(slot);
+    assert_eq!(connections.render_prometheus(), concat!(
+        "# TYPE lb_idle_upstream_connections gauge\n",
+        "# TYPE lb_idle_upstream_evictions_total counter\n",
+        "lb_idle_upstream_evictions_total 0\n",
+    ));
+}
+
+
+#[tokio::test]
+async fn test_no_connection_is_kept_idle_with_a_cap_of_zero() {
+    let connections = Arc::new(IdleConnections::new(0));
+
+    let slot = connections.park("10.0.0.1:80");
+
+    assert!(is_evicted(&slot).await);
+    assert_eq!(connections.idle("10.0.0.1:80"), 0);
+}
@@ -0,0 +1,38 @@
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+
+use crate::kubernetes::{ready_addresses, ServiceRef};
+
+
+#[test]
+fn test_service_ref_parsing() {
+    assert_eq!("prod/web".parse(), Ok(ServiceRef { namespace: "prod".to_string(), name: "web".to_string() }));
+
+    assert!("web".parse::<ServiceRef>().is_err());
+    assert!("/web".parse::<ServiceRef>().is_err());
+    assert!("prod/web/extra".parse::<ServiceRef>().is_err());
+}
+
+
+#[test]
+fn test_ready_addresses_of_endpoint_slice() {
+    let slice: EndpointSlice = serde_json::from_value(serde_json::json!({
+        "metadata": {"name": "web-abc12"},
+        "addressType": "IPv4",
+        "ports": [{"name": "http", "port": 8080}],
+        "endpoints": [
+            {"addresses": ["10.1.0.1"], "conditions": {"ready": true}},
+            {"addresses": ["10.1.0.2"], "conditions": {"ready": false}},
+            {"addresses": ["10.1.0.3"]},
+        ],
+    })).unwrap();
+
+    assert_eq!(ready_addresses(&slice), vec!["10.1.0.1:8080", "10.1.0.3:8080"]);
+
+    // without a port, the endpoints can't be reached
+    let slice: EndpointSlice = serde_json::from_value(serde_json::json!({
+        "metadata": {"name": "web-abc12"},
+        "addressType": "IPv6",
+        "endpoints": [{"addresses": ["2001:db8::1"]}],
+    })).unwrap();
+    assert!(ready_addresses(&slice).is_empty());
+}
@@ -0,0 +1,119 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+
+use crate::capacity::UpstreamLimiter;
+use crate::load_report::{parse_load, LoadReports};
+use crate::response::{relay_response, AddedHeaders, ResponseLimits};
+
+
+fn addresses(addresses: &[&str]) -> Vec<String> {
+    addresses.iter().map(|address| address.to_string()).collect()
+}
+
+
+/// Selects an upstream server `rounds` times and counts the selections of every upstream server.
+fn count_selections(reports: &LoadReports, upstreams: &[String], rounds: usize) -> HashMap<String, usize> {
+    let mut selections = HashMap::new();
+    for _ in 0..rounds {
+        *selections.entry(reports.select(upstreams, &HashSet::new()).unwrap()).or_insert(0) += 1;
+    }
+    selections
+}
+
+
+#[test]
+fn test_parse_load() {
+    assert_eq!(parse_load(b"0.7"), Some(0.7));
+    assert_eq!(parse_load(b" 12 "), Some(12.0));
+    assert_eq!(parse_load(b"-1"), None);
+    assert_eq!(parse_load(b"NaN"), None);
+    assert_eq!(parse_load(b"busy"), None);
+}
+
+
+#[test]
+fn test_selection_favors_the_least_loaded_upstream() {
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+    let reports = LoadReports::default();
+    reports.record("10.0.0.1:80", 0.9);
+    reports.record("10.0.0.2:80", 0.5);
+    reports.record("10.0.0.3:80", 0.1);
+
+    let selections = count_selections(&reports, &upstreams, 3000);
+
+    // the least loaded upstream server wins every draw it is part of, the most loaded one none
+    let count = |address: &str| selections.get(address).copied().unwrap_or(0);
+    assert!(count("10.0.0.3:80") > 1700, "{:?}", selections);
+    assert!(count("10.0.0.2:80") > 700, "{:?}", selections);
+    assert_eq!(count("10.0.0.1:80"), 0, "{:?}", selections);
+
+    // a new report changes the preference
+    reports.record("10.0.0.1:80", 0.0);
+    let selections = count_selections(&reports, &upstreams, 300);
+    assert!(selections["10.0.0.1:80"] > 150, "{:?}", selections);
+}
+
+
+#[test]
+fn test_unreported_upstream_counts_as_average() {
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+    let reports = LoadReports::default();
+    reports.record("10.0.0.1:80", 0.2);
+    reports.record("10.0.0.2:80", 0.8);
+
+    // the new upstream server is preferred over the loaded one, not over the idle one
+    let selections = count_selections(&reports, &upstreams, 3000);
+    assert!(selections["10.0.0.3:80"] > 700, "{:?}", selections);
+    assert!(!selections.contains_key("10.0.0.2:80"), "{:?}", selections);
+
+    // without any report, the selection is random
+    let selections = count_selections(&LoadReports::default(), &upstreams, 3000);
+    assert_eq!(selections.len(), 3, "{:?}", selections);
+}
+
+
+#[test]
+fn test_selection_skips_excluded_upstreams() {
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80"]);
+    let reports = LoadReports::default();
+    reports.record("10.0.0.1:80", 0.0);
+    reports.record("10.0.0.2:80", 1.0);
+
+    let excluded = HashSet::from(["10.0.0.1:80".to_string()]);
+    assert_eq!(reports.select(&upstreams, &excluded), Some("10.0.0.2:80".to_string()));
+    let excluded = HashSet::from(["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()]);
+    assert_eq!(reports.select(&upstreams, &excluded), None);
+}
+
+
+#[tokio::test]
+async fn test_limiter_picks_by_reported_load() {
+    let reports = Arc::new(LoadReports::default());
+    reports.record("10.0.0.1:80", 5.0);
+    reports.record("10.0.0.2:80", 1.0);
+    let limiter = Arc::new(UpstreamLimiter::new(None, Duration::ZERO).with_load_reports(Some(reports)));
+
+    let upstreams = addresses(&["10.0.0.1:80", "10.0.0.2:80"]);
+    for _ in 0..20 {
+        let slot = limiter.acquire(&upstreams, &HashSet::new()).await.unwrap();
+        assert_eq!(slot.upstream_address(), "10.0.0.2:80");
+    }
+}
+
+
+#[tokio::test]
+async fn test_relayed_response_carries_reported_load() {
+    let (mut upstream_writer, mut upstream_stream) = tokio::io::duplex(4096);
+    upstream_writer.write_all(b"HTTP/1.1 200 OK\r\nX-Server-Load: 0.7\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &http::Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await.unwrap();
+
+    assert_eq!(relayed.reported_load, Some(0.7));
+    // the header is relayed to the client as received
+    assert!(client_stream.starts_with(b"HTTP/1.1 200 OK\r\nX-Server-Load: 0.7\r\n"));
+}
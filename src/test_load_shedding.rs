@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::load_shedding::{LoadShedder, Signals, Watermarks, ADJUSTMENT_INTERVAL};
+
+
+fn shedder() -> LoadShedder {
+    LoadShedder::new(Watermarks { latency: Some(Duration::from_millis(100)), in_flight: Some(10) }, Duration::from_secs(1))
+}
+
+
+fn signals(latency_ms: u64, in_flight: usize) -> Signals {
+    Signals { latency: Some(Duration::from_millis(latency_ms)), in_flight }
+}
+
+
+#[test]
+fn test_watermarks() {
+    let watermarks = Watermarks { latency: Some(Duration::from_millis(100)), in_flight: Some(10) };
+
+    assert!(!watermarks.exceeded_by(&signals(100, 10)));
+    assert!(watermarks.exceeded_by(&signals(101, 0)));
+    assert!(watermarks.exceeded_by(&signals(0, 11)));
+    assert!(!watermarks.exceeded_by(&Signals { latency: None, in_flight: 0 }));
+
+    let in_flight_only = Watermarks { latency: None, in_flight: Some(10) };
+    assert!(!in_flight_only.exceeded_by(&signals(10_000, 0)));
+}
+
+
+#[test]
+fn test_shed_rate_grows_under_load_and_backs_off() {
+    let shedder = shedder();
+    let start = Instant::now();
+    let at = |adjustments: u32| start + ADJUSTMENT_INTERVAL * adjustments;
+
+    assert_eq!(shedder.adjust(at(0), &signals(500, 0)), 0.1);
+    // at most one adjustment per interval
+    assert_eq!(shedder.adjust(at(0) + ADJUSTMENT_INTERVAL / 2, &signals(500, 0)), 0.1);
+    for adjustment in 1..20 {
+        shedder.adjust(at(adjustment), &signals(500, 0));
+    }
+    assert_eq!(shedder.shed_rate(), 0.9);
+
+    // the rate shrinks a step at a time once the signals recover
+    assert_eq!(shedder.adjust(at(20), &signals(50, 0)), 0.8);
+    for adjustment in 21..40 {
+        shedder.adjust(at(adjustment), &signals(50, 0));
+    }
+    assert_eq!(shedder.shed_rate(), 0.0);
+}
+
+
+#[test]
+fn test_signals_over_the_window() {
+    let shedder = shedder();
+    let start = Instant::now();
+
+    assert_eq!(shedder.signals(start), Signals { latency: None, in_flight: 0 });
+    shedder.record_latency(start, Duration::from_millis(300));
+    shedder.record_latency(start + Duration::from_millis(500), Duration::from_millis(100));
+    let first = shedder.start_request();
+    let second = shedder.start_request();
+    assert_eq!(shedder.signals(start + Duration::from_millis(500)), signals(200, 2));
+
+    // the oldest latency left the window, the requests were answered
+    drop((first, second));
+    assert_eq!(shedder.signals(start + Duration::from_millis(1200)), signals(100, 0));
+}
+
+
+#[test]
+fn test_requests_are_shed_at_the_shed_rate() {
+    let shedder = shedder();
+    let mut rng = StdRng::seed_from_u64(42);
+    let start = Instant::now();
+
+    assert!((0..100).all(|_| !shedder.should_shed(start, &mut rng)));
+    let _in_flight: Vec<_> = (0..11).map(|_| shedder.start_request()).collect();
+    for adjustment in 1..=9 {
+        shedder.should_shed(start + ADJUSTMENT_INTERVAL * adjustment, &mut rng);
+    }
+
+    let shed_before = shedder.shed();
+    let now = start + ADJUSTMENT_INTERVAL * 9;
+    let shed = (0..1000).filter(|_| shedder.should_shed(now, &mut rng)).count();
+    assert!((850..950).contains(&shed), "{}", shed);
+    assert_eq!(shedder.shed() - shed_before, shed as u64);
+    assert!(shedder.render_prometheus().contains("lb_shed_rate 0.9\n"));
+}
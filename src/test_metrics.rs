@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use http::Method;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::admin_auth::AdminAuth;
+use crate::metrics::{handle_metrics, is_mutating, FaultSetter, MetricsEndpoints};
+
+
+/// Answers a raw request on the metrics listener and returns the response.
+async fn scrape(request: &[u8]) -> String {
+    scrape_with_faults(request, None).await
+}
+
+
+/// Answers a raw request on the metrics listener, with fault injection if `set_faults` is given.
+async fn scrape_with_faults(request: &[u8], set_faults: Option<FaultSetter>) -> String {
+    scrape_with(request, &MetricsEndpoints { set_faults, ..endpoints() }).await
+}
+
+
+/// Returns the handlers of a metrics listener without fault injection nor admin token.
+fn endpoints() -> MetricsEndpoints {
+    MetricsEndpoints {
+        render: Arc::new(|| Box::pin(async { "# TYPE up gauge\nup 1\n".to_string() })),
+        render_config: Arc::new(|| Box::pin(async { r#"{"upstreams":["10.0.0.1:80"]}"#.to_string() })),
+        render_dashboard: Arc::new(|| Box::pin(async { "<!DOCTYPE html>\n".to_string() })),
+        explain: Arc::new(|body| Box::pin(async move {
+            match body.is_empty() {
+                true => Err("expected a JSON object".to_string()),
+                false => Ok(format!(r#"{{"explained":{}}}"#, String::from_utf8(body).unwrap())),
+            }
+        })),
+        set_faults: None,
+        auth: None,
+    }
+}
+
+
+/// Answers a raw request on the metrics listener with `endpoints`.
+async fn scrape_with(request: &[u8], endpoints: &MetricsEndpoints) -> String {
+    let (mut client, mut server) = tokio::io::duplex(4096);
+    client.write_all(request).await.unwrap();
+
+    handle_metrics(&mut server, "127.0.0.1:50000".parse().unwrap(), endpoints).await;
+    drop(server);
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+
+#[tokio::test]
+async fn test_metrics_are_served_on_metrics_path() {
+    let response = scrape(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(response.ends_with("\r\n\r\n# TYPE up gauge\nup 1\n"));
+}
+
+
+#[tokio::test]
+async fn test_config_is_served_on_debug_config_path() {
+    let response = scrape(b"GET /debug/config HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.ends_with("\r\n\r\n{\"upstreams\":[\"10.0.0.1:80\"]}"));
+}
+
+
+#[tokio::test]
+async fn test_status_page_is_served_on_dashboard_path() {
+    let response = scrape(b"GET /dashboard HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    assert!(response.contains("Cache-Control: no-store\r\n"));
+    assert!(response.ends_with("\r\n\r\n<!DOCTYPE html>\n"));
+}
+
+
+#[tokio::test]
+async fn test_other_paths_are_not_found() {
+    let response = scrape(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+
+#[tokio::test]
+async fn test_sample_request_is_explained_on_explain_path() {
+    let response = scrape(b"POST /explain HTTP/1.1\r\nHost: localhost\r\nContent-Length: 14\r\n\r\n{\"path\": \"/a\"}").await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.ends_with("\r\n\r\n{\"explained\":{\"path\": \"/a\"}}"));
+
+    // an invalid description is refused with the reason, and only POST explains
+    let response = scrape(b"POST /explain HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.ends_with("\r\n\r\nexpected a JSON object\n"));
+    let response = scrape(b"GET /explain HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+
+#[tokio::test]
+async fn test_faults_are_set_and_cleared_only_when_enabled() {
+    let set_faults: FaultSetter = Arc::new(|body| Box::pin(async move {
+        match body {
+            Some(body) if body.is_empty() => Err("expected a JSON object".to_string()),
+            Some(body) => Ok(String::from_utf8(body).unwrap()),
+            None => Ok("null".to_string()),
+        }
+    }));
+
+    let response = scrape_with_faults(b"POST /faults HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n{\"abort_pct\": 50}", Some(set_faults.clone())).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n{\"abort_pct\": 50}"), "{}", response);
+    let response = scrape_with_faults(b"DELETE /faults HTTP/1.1\r\nHost: localhost\r\n\r\n", Some(set_faults.clone())).await;
+    assert!(response.ends_with("\r\n\r\nnull"), "{}", response);
+    let response = scrape_with_faults(b"POST /faults HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n", Some(set_faults.clone())).await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    let response = scrape_with_faults(b"GET /faults HTTP/1.1\r\nHost: localhost\r\n\r\n", Some(set_faults)).await;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+
+    // without fault injection, the path doesn't exist
+    let response = scrape(b"DELETE /faults HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+}
+
+
+#[tokio::test]
+async fn test_requests_without_the_admin_token_are_unauthorized() {
+    let auth = Arc::new(AdminAuth::new("s3cret", true).unwrap());
+    let endpoints = MetricsEndpoints { auth: Some(auth), ..endpoints() };
+
+    let response = scrape_with(b"GET /debug/config HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\n\r\n", &endpoints).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+    for authorization in ["", "Authorization: Bearer wrong\r\n", "Authorization: Basic s3cret\r\n"] {
+        let request = format!("GET /debug/config HTTP/1.1\r\nHost: localhost\r\n{}\r\n", authorization);
+        let response = scrape_with(request.as_bytes(), &endpoints).await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+        assert!(response.contains("WWW-Authenticate: Bearer realm=\"admin\"\r\n"));
+        assert!(!response.contains("10.0.0.1:80"));
+    }
+
+    // the scrapes are exempt, but nothing else is
+    let response = scrape_with(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n", &endpoints).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let response = scrape_with(b"GET /dashboard HTTP/1.1\r\nHost: localhost\r\n\r\n", &endpoints).await;
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+}
+
+
+#[test]
+fn test_only_fault_changes_are_audited() {
+    assert!(is_mutating(&Method::POST, "/faults"));
+    assert!(is_mutating(&Method::DELETE, "/faults"));
+    assert!(!is_mutating(&Method::GET, "/faults"));
+    assert!(!is_mutating(&Method::POST, "/explain"));
+    assert!(!is_mutating(&Method::GET, "/metrics"));
+}
@@ -0,0 +1,97 @@
+use clap::Parser;
+
+use crate::{dedupe_upstreams, validate_options, CmdOptions};
+
+
+/// Parses the options following the program name.
+fn parse(options: &[&str]) -> CmdOptions {
+    CmdOptions::parse_from(std::iter::once("rust_loadbalancer").chain(options.iter().copied()))
+}
+
+
+/// Validates the options following the program name.
+fn validate(options: &[&str]) -> Result<(), String> {
+    validate_options(&parse(options))
+}
+
+
+#[test]
+fn test_consistent_options_are_accepted() {
+    assert!(validate(&["--upstream", "127.0.0.1:8081"]).is_ok());
+    assert!(validate(&["--watch-config", "upstreams.txt"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--canary-upstream", "127.0.0.1:9081", "--canary-header", "X-Canary=true"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--canary-upstream", "127.0.0.1:9081", "--canary-percent", "5"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--redirect-http-to-https", "--metrics-bind", "127.0.0.1:9090"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--connect-retries", "2", "--connect-budget-ms", "500"]).is_ok());
+    // without retries, the delay doesn't matter
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--connect-budget-ms", "10"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--bind", "127.0.0.1:0", "--metrics-bind", "127.0.0.1:0"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--frontend", "b=127.0.0.1:0", "--pool-upstream", "b=127.0.0.1:9081"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--tier-upstream", "1=127.0.0.1:8082", "--upstream-max-response-size", "127.0.0.1:8082=0"]).is_ok());
+    assert!(validate(&["--upstream", "127.0.0.1:8081", "--metrics-bind", "127.0.0.1:9090", "--admin-token", "s3cret", "--admin-token-exempt-metrics"]).is_ok());
+    // the discovered upstream servers aren't known at startup
+    assert!(validate(&["--watch-config", "upstreams.txt", "--upstream-max-response-size", "127.0.0.1:8082=1024"]).is_ok());
+}
+
+
+#[test]
+fn test_inconsistent_options_are_rejected() {
+    let error = validate(&[]).unwrap_err();
+    assert!(error.contains("--upstream"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--canary-upstream", "127.0.0.1:9081"]).unwrap_err();
+    assert!(error.contains("--canary-header or a --canary-percent"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--bind", "0.0.0.0:80", "--redirect-http-to-https"]).unwrap_err();
+    assert!(error.contains("--redirect-http-to-https and --bind"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--metrics-bind", "0.0.0.0:8080"]).unwrap_err();
+    assert!(error.contains("--metrics-bind and --bind"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--connect-retries", "1", "--connect-budget-ms", "50"]).unwrap_err();
+    assert!(error.contains("--connect-budget-ms (50)"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--frontend", "b=127.0.0.1:0"]).unwrap_err();
+    assert!(error.contains("--frontend b has no upstream server"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--pool-upstream", "b=127.0.0.1:9081"]).unwrap_err();
+    assert!(error.contains("belongs to no --frontend"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--metrics-bind", "127.0.0.1:9090", "--admin-token-exempt-metrics"]).unwrap_err();
+    assert!(error.contains("--admin-token-exempt-metrics requires --admin-token or --admin-token-file"), "{}", error);
+
+    let error = validate(&["--upstream", "127.0.0.1:8081", "--upstream-max-response-size", "127.0.0.1:9081=1024"]).unwrap_err();
+    assert!(error.contains("--upstream-max-response-size 127.0.0.1:9081=1024 names no upstream server"), "{}", error);
+
+    let error = validate(&[
+        "--upstream", "127.0.0.1:8081",
+        "--frontend", "b=127.0.0.1:0", "--frontend", "b=127.0.0.1:0",
+        "--pool-upstream", "b=127.0.0.1:9081",
+    ]).unwrap_err();
+    assert!(error.contains("given more than once"), "{}", error);
+}
+
+
+#[test]
+fn test_duplicate_upstreams_are_rejected_or_removed() {
+    let duplicated = [
+        "--upstream", "127.0.0.1:8081", "--upstream", "127.0.0.1:8082", "--tier-upstream", "1=LOCALHOST:8083",
+        "--tier-upstream", "2=localhost:8083", "--canary-upstream", "127.0.0.1:8081", "--canary-header", "X-Canary=true",
+        "--frontend", "b=127.0.0.1:0", "--pool-upstream", "b=127.0.0.1:8081", "--pool-upstream", "b=127.0.0.1:8081",
+    ];
+
+    let error = validate(&duplicated).unwrap_err();
+    assert!(error.contains("--tier-upstream 2=localhost:8083 is given more than once"), "{}", error);
+    assert!(error.contains("--dedupe-upstreams"), "{}", error);
+
+    // with the flag, the first occurrence of every duplicate is kept. The pools are lists of their own
+    let mut args = parse(&[&duplicated[..], &["--dedupe-upstreams"]].concat());
+    assert!(validate_options(&args).is_ok());
+    assert_eq!(dedupe_upstreams(&mut args), ["--tier-upstream 2=localhost:8083", "--pool-upstream b=127.0.0.1:8081"]);
+    assert_eq!(args.upstream, ["127.0.0.1:8081", "127.0.0.1:8082"]);
+    assert_eq!(args.tier_upstream.len(), 1);
+    assert_eq!(args.canary_upstream, ["127.0.0.1:8081"]);
+    assert_eq!(args.pool_upstream.len(), 1);
+
+    assert!(dedupe_upstreams(&mut parse(&["--upstream", "127.0.0.1:8081", "--upstream", "127.0.0.1:8082"])).is_empty());
+}
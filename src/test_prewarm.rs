@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::connect::Connector;
+use crate::prewarm::PrewarmPool;
+
+
+/// Opens a connection to a listener, and returns it with the accepted end.
+async fn connection(listener: &TcpListener) -> (TcpStream, TcpStream) {
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    (stream, accepted)
+}
+
+
+#[tokio::test]
+async fn test_fill_opens_the_missing_connections_of_every_upstream() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let open_address = listener.local_addr().unwrap().to_string();
+    // reserve a port and release it so nothing is listening on it
+    let closed_address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+    let connector = Connector::new(0, Duration::from_millis(10), None);
+    let prewarm = PrewarmPool::new(2, None);
+
+    assert_eq!(prewarm.fill(&connector, &[open_address.clone(), closed_address.clone()]).await, 2);
+    assert_eq!(prewarm.parked(&open_address), 2);
+    // a failed connection is only logged
+    assert_eq!(prewarm.parked(&closed_address), 0);
+
+    // an upstream server holding its connections isn't connected to again
+    assert_eq!(prewarm.fill(&connector, std::slice::from_ref(&open_address)).await, 0);
+}
+
+
+#[tokio::test]
+async fn test_connections_are_checked_out_once() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let prewarm = PrewarmPool::new(1, None);
+    let (stream, _accepted) = connection(&listener).await;
+    prewarm.park("10.0.0.1:80", stream, Instant::now());
+
+    assert!(prewarm.take("10.0.0.2:80", Instant::now()).is_none());
+    assert!(prewarm.take("10.0.0.1:80", Instant::now()).is_some());
+    assert!(prewarm.take("10.0.0.1:80", Instant::now()).is_none());
+    assert_eq!(prewarm.reused(), 1);
+}
+
+
+#[tokio::test]
+async fn test_expired_and_closed_connections_are_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let prewarm = PrewarmPool::new(3, Some(Duration::from_secs(5)));
+    let opened_at = Instant::now();
+
+    // the upstream server closed this one
+    let (closed, accepted) = connection(&listener).await;
+    drop(accepted);
+    let (expiring, _expiring_accepted) = connection(&listener).await;
+    let (open, _open_accepted) = connection(&listener).await;
+    prewarm.park("10.0.0.1:80", open, opened_at + Duration::from_secs(4));
+    prewarm.park("10.0.0.1:80", closed, opened_at + Duration::from_secs(4));
+    prewarm.park("10.0.0.1:80", expiring, opened_at);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    prewarm.expire(opened_at + Duration::from_secs(6));
+    assert_eq!(prewarm.parked("10.0.0.1:80"), 2);
+    assert!(prewarm.take("10.0.0.1:80", opened_at + Duration::from_secs(6)).is_some());
+    assert_eq!(prewarm.parked("10.0.0.1:80"), 0);
+
+    let rendered = prewarm.render_prometheus();
+    assert!(rendered.contains("lb_prewarmed_connections_reused_total 1\n"), "{}", rendered);
+    assert!(rendered.contains("lb_prewarmed_connections_expired_total 2\n"), "{}", rendered);
+    assert!(!rendered.contains("lb_prewarmed_connections{"), "{}", rendered);
+}
@@ -0,0 +1,65 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::redirect::{handle_redirect, redirect_response};
+use crate::request::{parse_client_request, Error, RequestConfig};
+
+
+/// Builds the redirect answering a raw request.
+fn redirect(request: &[u8], hsts_max_age: Option<u64>) -> Result<String, Error> {
+    redirect_response(&parse_client_request(request, &RequestConfig::default()).unwrap(), hsts_max_age)
+}
+
+
+#[test]
+fn test_redirect_keeps_path_and_query() {
+    let response = redirect(b"GET /search?q=rust&page=2 HTTP/1.1\r\nHost: example.com\r\n\r\n", None).unwrap();
+
+    assert_eq!(response, "HTTP/1.1 301 Moved Permanently\r\nLocation: https://example.com/search?q=rust&page=2\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+}
+
+
+#[test]
+fn test_redirect_drops_plaintext_port() {
+    for (host, location) in [
+        ("example.com:80", "https://example.com/"),
+        ("127.0.0.1:8080", "https://127.0.0.1/"),
+        ("[::1]:80", "https://[::1]/"),
+        ("[::1]", "https://[::1]/"),
+    ] {
+        let response = redirect(format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host).as_bytes(), None).unwrap();
+        assert!(response.contains(&format!("Location: {}\r\n", location)), "{}", response);
+    }
+}
+
+
+#[test]
+fn test_redirect_with_hsts() {
+    let response = redirect(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n", Some(31536000)).unwrap();
+
+    assert!(response.contains("Strict-Transport-Security: max-age=31536000\r\n"));
+}
+
+
+#[test]
+fn test_redirect_without_host_is_rejected() {
+    assert!(matches!(redirect(b"GET / HTTP/1.0\r\n\r\n", None), Err(Error::InvalidHost)));
+}
+
+
+#[tokio::test]
+async fn test_handle_redirect_answers_bad_requests() {
+    for (request, status) in [
+        (&b"GET / HTTP/1.0\r\n\r\n"[..], "HTTP/1.1 400 Bad Request"),
+        (&b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n"[..], "HTTP/1.1 301 Moved Permanently"),
+    ] {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+
+        handle_redirect(&mut server, None).await;
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with(status), "{}", response);
+    }
+}
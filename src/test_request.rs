@@ -1,37 +1,1052 @@
-use std::io::Write;
-use std::net::TcpStream;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use http::Request;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
 
-#[test]
-fn write_to_stream() -> Result<(), std::io::Error> {
-    // request: &Request<Vec<u8>>, stream: &mut TcpStream
+use crate::request::{add_forwarded_scheme, is_idempotent, parse_client_request, read_client_request, real_client_ip, request_controller, supply_upstream_host, ClientIp, CloseAfterResponse, DotSegments, Error, ForwardedHeaderFormat, RealIpHeader, RequestConfig, UriMode};
 
+#[tokio::test]
+async fn write_to_stream() {
     let request = Request::builder()
         .method("GET")
-        .uri("http://localhost:8080/")
+        .uri("/")
         .header("User-Agent", "curl/7.68.0")
         .body(Vec::new())
         .unwrap();
 
-    let mut stream = TcpStream::connect("171.67.215.200:80")?;
+    // local upstream recording everything it receives
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap();
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        received
+    });
+
+    let mut stream = TcpStream::connect(upstream_address).await.unwrap();
+    let bytes_written = crate::request::write_to_stream(&request, &mut stream).await.unwrap();
+    drop(stream);
+
+    let received = upstream.await.unwrap();
+    assert_eq!(received, b"GET / HTTP/1.1\r\nuser-agent: curl/7.68.0\r\n\r\n".to_vec());
+    assert_eq!(bytes_written, received.len());
+}
+
+
+#[test]
+fn parse_asterisk_form_options() {
+    let request = parse_client_request(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n", &RequestConfig::default()).unwrap();
+
+    assert_eq!(request.uri(), "*");
+    assert_eq!(crate::request::format_request_line(&request).unwrap(), "OPTIONS * HTTP/1.1");
+}
+
+
+#[test]
+fn parse_asterisk_form_rejected_for_other_methods() {
+    let result = parse_client_request(b"GET * HTTP/1.1\r\nHost: localhost\r\n\r\n", &RequestConfig::default());
+
+    assert!(matches!(result, Err(crate::request::Error::MalformedRequest)));
+}
+
+
+#[test]
+fn parse_absolute_form_extracts_path() {
+    let request = parse_client_request(b"GET http://example.com/path?query=1 HTTP/1.1\r\nHost: example.com\r\n\r\n", &RequestConfig::default()).unwrap();
+
+    assert_eq!(request.uri(), "/path?query=1");
+    assert_eq!(crate::request::format_request_line(&request).unwrap(), "GET /path?query=1 HTTP/1.1");
+
+    // an absolute-form target without a path is forwarded as the root path
+    let request = parse_client_request(b"GET http://example.com HTTP/1.1\r\nHost: example.com\r\n\r\n", &RequestConfig::default()).unwrap();
+
+    assert_eq!(request.uri(), "/");
+}
+
+
+#[test]
+fn parse_absolute_form_sets_host() {
+    // the authority of the target wins over the Host header sent along
+    let request = parse_client_request(b"GET http://user@example.com:8080/path HTTP/1.1\r\nHost: other.example\r\n\r\n", &RequestConfig::default()).unwrap();
+
+    assert_eq!(request.uri(), "/path");
+    assert_eq!(request.headers().get_all("host").iter().collect::<Vec<_>>(), ["example.com:8080"]);
+
+    // a request without Host header gets one from the target
+    let request = parse_client_request(b"GET http://example.com/ HTTP/1.1\r\n\r\n", &RequestConfig::default()).unwrap();
+
+    assert_eq!(request.headers()["host"], "example.com");
+}
+
+
+#[test]
+fn parse_authority_form_rejected_for_get() {
+    let result = parse_client_request(b"GET example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n", &RequestConfig::default());
+
+    assert!(matches!(result, Err(crate::request::Error::MalformedRequest)));
+}
+
+
+#[test]
+fn parse_origin_form_unchanged() {
+    let request = parse_client_request(b"GET /index.html?lang=en HTTP/1.1\r\nHost: localhost\r\n\r\n", &RequestConfig::default()).unwrap();
+
+    assert_eq!(request.uri(), "/index.html?lang=en");
+}
+
+
+/// Returns the default settings with the given URI and dot segments modes.
+fn uri_config(uri_mode: UriMode, dot_segments: DotSegments) -> RequestConfig {
+    RequestConfig { uri_mode, dot_segments, ..RequestConfig::default() }
+}
+
+
+#[test]
+fn strict_mode_rejects_invalid_targets() {
+    let config = uri_config(UriMode::Strict, DotSegments::Keep);
+
+    for request in [
+        "GET /my file.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /caf\u{e9} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /100% HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /a%zz HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    ] {
+        let result = parse_client_request(request.as_bytes(), &config);
+        assert!(matches!(result, Err(Error::MalformedRequest)), "{:?} was accepted", request);
+    }
+}
+
 
+#[test]
+fn lax_mode_encodes_invalid_targets() {
+    let config = uri_config(UriMode::Lax, DotSegments::Keep);
 
-    stream.write(&crate::request::format_request_line(&request).into_bytes())?;
-    stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes())?;
-        stream.write(header_value.as_bytes())?;
-        stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
+    for (request, path) in [
+        ("GET /my file.txt?q=a b HTTP/1.1\r\nHost: localhost\r\n\r\n", "/my%20file.txt?q=a%20b"),
+        ("GET /caf\u{e9} HTTP/1.1\r\nHost: localhost\r\n\r\n", "/caf%C3%A9"),
+        ("GET /100% HTTP/1.1\r\nHost: localhost\r\n\r\n", "/100%25"),
+        ("GET /a%zz%41 HTTP/1.1\r\nHost: localhost\r\n\r\n", "/a%25zz%41"),
+    ] {
+        let request = parse_client_request(request.as_bytes(), &config).unwrap();
+        assert_eq!(request.uri().to_string(), path);
+        assert_eq!(request.headers()["host"], "localhost");
     }
-    stream.write(&['\r' as u8, '\n' as u8])?;
-    if request.body().len() > 0 {
-        stream.write(request.body())?;
+}
+
+
+#[tokio::test]
+async fn lax_mode_reads_target_with_spaces() {
+    let mut stream = Cursor::new(b"GET /my file.txt HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+    let mut buffer = vec![0; 1024];
+
+    let request = read_client_request(&mut stream, &mut buffer, &uri_config(UriMode::Lax, DotSegments::Keep)).await.unwrap();
+
+    assert_eq!(request.uri(), "/my%20file.txt");
+}
+
+
+#[test]
+fn dot_segments_are_normalized() {
+    let config = uri_config(UriMode::Strict, DotSegments::Normalize);
+
+    for (target, path) in [
+        ("/a/b/../c", "/a/c"),
+        ("/a/./b/.", "/a/b/"),
+        ("/../../etc/passwd", "/etc/passwd"),
+        ("/a/%2e%2E/b?x=/../y", "/b?x=/../y"),
+        ("/a/.%2e", "/"),
+        // an overlong encoding of a dot isn't a dot, it is forwarded untouched
+        ("/a/%c0%ae%c0%ae/b", "/a/%c0%ae%c0%ae/b"),
+        ("/a..b/.c", "/a..b/.c"),
+    ] {
+        let request = parse_client_request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).as_bytes(), &config).unwrap();
+        assert_eq!(request.uri(), path, "{:?}", target);
     }
+}
+
+
+#[test]
+fn dot_segments_are_rejected_or_kept() {
+    let request = b"GET /static/%2e%2e/secret HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    let result = parse_client_request(request, &uri_config(UriMode::Strict, DotSegments::Reject));
+    assert!(matches!(result, Err(Error::MalformedRequest)));
+
+    let request = parse_client_request(request, &uri_config(UriMode::Strict, DotSegments::Keep)).unwrap();
+    assert_eq!(request.uri(), "/static/%2e%2e/secret");
+}
+
+
+/// Mock stream accepting at most `max_write` bytes per write call, like a socket under backpressure.
+struct ShortWriteStream {
+    written: Vec<u8>,
+    max_write: usize,
+}
+
+impl tokio::io::AsyncWrite for ShortWriteStream {
+    fn poll_write(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<Result<usize, std::io::Error>> {
+        let accepted = buf.len().min(self.max_write);
+        self.written.extend_from_slice(&buf[..accepted]);
+        std::task::Poll::Ready(Ok(accepted))
+    }
+
+    fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), std::io::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), std::io::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+
+#[tokio::test]
+async fn write_to_stream_survives_partial_writes() {
+    let request = Request::builder()
+        .method("POST")
+        .uri("/upload")
+        .header("Host", "localhost")
+        .header("Content-Length", "26")
+        .body(b"abcdefghijklmnopqrstuvwxyz".to_vec())
+        .unwrap();
+
+    let mut stream = ShortWriteStream { written: Vec::new(), max_write: 3 };
+
+    let bytes_written = crate::request::write_to_stream(&request, &mut stream).await.unwrap();
+
+    let expected = b"POST /upload HTTP/1.1\r\nhost: localhost\r\ncontent-length: 26\r\n\r\nabcdefghijklmnopqrstuvwxyz";
+    assert_eq!(stream.written, expected.to_vec());
+    assert_eq!(bytes_written, expected.len());
+}
+
+
+/// Mock stream counting its write calls.
+#[derive(Default)]
+struct CountingStream {
+    written: Vec<u8>,
+    writes: usize,
+}
+
+impl tokio::io::AsyncWrite for CountingStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        self.writes += 1;
+        self.written.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+#[tokio::test]
+async fn write_to_stream_writes_request_at_once() {
+    let request = Request::builder()
+        .method("POST")
+        .uri("/upload?id=1")
+        .header("Host", "localhost")
+        .header("X-Forwarded-For", "192.168.1.10")
+        .header("Content-Length", "5")
+        .body(b"hello".to_vec())
+        .unwrap();
+
+    let mut stream = CountingStream::default();
+    let bytes_written = crate::request::write_to_stream(&request, &mut stream).await.unwrap();
+
+    let expected = b"POST /upload?id=1 HTTP/1.1\r\nhost: localhost\r\nx-forwarded-for: 192.168.1.10\r\ncontent-length: 5\r\n\r\nhello";
+    assert_eq!(stream.written, expected.to_vec());
+    assert_eq!(crate::request::serialize_request(&request).unwrap(), expected.to_vec());
+    assert_eq!(bytes_written, expected.len());
+    assert_eq!(stream.writes, 1);
+}
+
+
+/// Serializes a request, parses the bytes back with httparse and returns the request line, the headers and the body.
+fn round_trip(request: &Request<Vec<u8>>) -> (String, Vec<(String, String)>, Vec<u8>) {
+    let bytes = crate::request::serialize_request(request).unwrap();
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let head_length = match parsed.parse(&bytes).unwrap() {
+        httparse::Status::Complete(head_length) => head_length,
+        httparse::Status::Partial => panic!("incomplete request head"),
+    };
+
+    let request_line = format!("{} {} HTTP/1.{}", parsed.method.unwrap(), parsed.path.unwrap(), parsed.version.unwrap());
+    let headers = parsed.headers.iter().map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).into_owned())).collect();
+    (request_line, headers, bytes[head_length..].to_vec())
+}
+
+
+#[test]
+fn serialized_request_targets_are_origin_form() {
+    for (uri, target) in [
+        ("/", "/"),
+        ("/search?q=load+balancer&page=2", "/search?q=load+balancer&page=2"),
+        ("/path?", "/path?"),
+        ("http://example.com/api/v1?id=7", "/api/v1?id=7"),
+        ("http://example.com", "/"),
+        ("http://example.com?id=7", "/?id=7"),
+    ] {
+        let request = Request::builder().uri(uri).header("Host", "example.com").body(Vec::new()).unwrap();
+
+        let (request_line, headers, body) = round_trip(&request);
+
+        assert_eq!(request_line, format!("GET {} HTTP/1.1", target), "{}", uri);
+        assert_eq!(headers, vec![("host".to_string(), "example.com".to_string())]);
+        assert!(body.is_empty());
+    }
+
+    let connect = Request::builder().method("CONNECT").uri("example.com:443").body(Vec::new()).unwrap();
+    assert_eq!(crate::request::format_request_line(&connect).unwrap(), "CONNECT example.com:443 HTTP/1.1");
+}
+
+
+#[test]
+fn serialized_request_versions_are_wire_tokens() {
+    let request = Request::builder().uri("/").version(http::Version::HTTP_10).body(Vec::new()).unwrap();
+    assert_eq!(crate::request::format_request_line(&request).unwrap(), "GET / HTTP/1.0");
+
+    for version in [http::Version::HTTP_09, http::Version::HTTP_2, http::Version::HTTP_3] {
+        let request = Request::builder().uri("/").version(version).body(Vec::new()).unwrap();
+        let result = crate::request::serialize_request(&request);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+
+#[test]
+fn serialized_request_bodies_declare_their_length() {
+    for size in [0, 1, 17, 4096, 100_000] {
+        let body: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let content_length = ("content-length".to_string(), size.to_string());
+
+        // without a Content-Length header, one is added after the other headers when there is a body
+        let request = Request::builder().method("POST").uri("/upload").header("Host", "localhost").body(body.clone()).unwrap();
+        let (_, headers, sent) = round_trip(&request);
+        assert_eq!(headers.contains(&content_length), size > 0, "{}", size);
+        assert_eq!(headers.last() == Some(&content_length), size > 0, "{}", size);
+        assert_eq!(sent, body);
+
+        // a stale or repeated Content-Length header is replaced in place with the actual length
+        let request = Request::builder().method("POST").uri("/upload")
+            .header("Content-Length", "3")
+            .header("Host", "localhost")
+            .header("Content-Length", "3")
+            .body(body.clone())
+            .unwrap();
+        let (_, headers, sent) = round_trip(&request);
+        assert_eq!(headers, vec![content_length, ("host".to_string(), "localhost".to_string())]);
+        assert_eq!(sent, body);
+    }
+}
+
+
+#[test]
+fn serialized_chunked_request_bodies_are_a_single_chunk() {
+    let request = Request::builder().method("POST").uri("/upload")
+        .header("Transfer-Encoding", "gzip, chunked")
+        .header("Content-Length", "5")
+        .body(b"hello world".to_vec())
+        .unwrap();
+
+    let (_, headers, sent) = round_trip(&request);
+
+    assert_eq!(headers, vec![("transfer-encoding".to_string(), "gzip, chunked".to_string())]);
+    assert_eq!(sent, b"b\r\nhello world\r\n0\r\n\r\n".to_vec());
+
+    let empty = Request::builder().method("POST").uri("/upload").header("Transfer-Encoding", "chunked").body(Vec::new()).unwrap();
+    assert_eq!(round_trip(&empty).2, b"0\r\n\r\n".to_vec());
+}
+
+
+fn request_with_forwarding_headers() -> Request<Vec<u8>> {
+    Request::builder()
+        .uri("/")
+        .header("Host", "localhost")
+        .header("X-Forwarded-For", "203.0.113.7")
+        .header("X-Real-IP", "203.0.113.7")
+        .header("Forwarded", "for=203.0.113.7")
+        .body(Vec::new())
+        .unwrap()
+}
+
+
+#[test]
+fn client_ip_added_to_forwarded_request() {
+    let request = crate::request::client_request_builder(Some("192.168.1.1"), ForwardedHeaderFormat::XForwardedFor, &request_with_forwarding_headers(), 1, &[]).unwrap();
+
+    let forwarded_for: Vec<_> = request.headers().get_all("X-Forwarded-For").iter().collect();
+    assert_eq!(forwarded_for, vec!["203.0.113.7", "192.168.1.1"]);
+    assert!(request.headers().contains_key("X-Real-IP"));
+}
+
+
+#[test]
+fn client_ip_headers_stripped_without_client_ip() {
+    let request = crate::request::client_request_builder(None, ForwardedHeaderFormat::Forwarded, &request_with_forwarding_headers(), 1, &[]).unwrap();
+
+    for name in crate::request::CLIENT_IP_HEADERS {
+        assert!(!request.headers().contains_key(name), "{} was forwarded", name);
+    }
+    assert_eq!(request.headers()["Host"], "localhost");
+}
+
+
+#[test]
+fn client_ip_added_in_forwarded_format() {
+    let request = Request::builder().uri("/").header("Host", "localhost").body(Vec::new()).unwrap();
+
+    let forwarded = crate::request::client_request_builder(Some("192.0.2.1"), ForwardedHeaderFormat::Forwarded, &request, 1, &[]).unwrap();
+    assert_eq!(forwarded.headers()["Forwarded"], "for=192.0.2.1;proto=http");
+    assert!(!forwarded.headers().contains_key("X-Forwarded-For"));
+
+    // ports and IPv6 addresses are quoted
+    let forwarded = crate::request::client_request_builder(Some("192.0.2.1:54321"), ForwardedHeaderFormat::Forwarded, &request, 1, &[]).unwrap();
+    assert_eq!(forwarded.headers()["Forwarded"], "for=\"192.0.2.1:54321\";proto=http");
+    let forwarded = crate::request::client_request_builder(Some("2001:db8::1"), ForwardedHeaderFormat::Forwarded, &request, 1, &[]).unwrap();
+    assert_eq!(forwarded.headers()["Forwarded"], "for=\"[2001:db8::1]\";proto=http");
+    let forwarded = crate::request::client_request_builder(Some("[2001:db8::1]:54321"), ForwardedHeaderFormat::Forwarded, &request, 1, &[]).unwrap();
+    assert_eq!(forwarded.headers()["Forwarded"], "for=\"[2001:db8::1]:54321\";proto=http");
+}
+
+
+#[test]
+fn client_ip_appended_to_existing_forwarded_header() {
+    let request = Request::builder()
+        .uri("/")
+        .header("Host", "localhost")
+        .header("Forwarded", "for=203.0.113.7;proto=https")
+        .header("Forwarded", "for=198.51.100.2")
+        .header("X-Forwarded-For", "203.0.113.7")
+        .body(Vec::new())
+        .unwrap();
+
+    let forwarded = crate::request::client_request_builder(Some("192.0.2.1"), ForwardedHeaderFormat::Forwarded, &request, 1, &[]).unwrap();
+
+    let values: Vec<_> = forwarded.headers().get_all("Forwarded").iter().collect();
+    assert_eq!(values, vec!["for=203.0.113.7;proto=https, for=198.51.100.2, for=192.0.2.1;proto=http"]);
+    // the other forwarding headers are left as they were sent
+    let forwarded_for: Vec<_> = forwarded.headers().get_all("X-Forwarded-For").iter().collect();
+    assert_eq!(forwarded_for, vec!["203.0.113.7"]);
+}
+
+
+#[test]
+fn forwarded_header_format_parsing() {
+    assert_eq!("X-Forwarded-For".parse(), Ok(ForwardedHeaderFormat::XForwardedFor));
+    assert_eq!("forwarded".parse(), Ok(ForwardedHeaderFormat::Forwarded));
+    assert!("x-real-ip".parse::<ForwardedHeaderFormat>().is_err());
+}
+
+
+const POST_REQUEST: &[u8] = b"POST /submit?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+
 
-    Ok(())
+/// Reads `input` from an in-memory stream with a buffer of `buffer_size` bytes.
+async fn read_from_memory(input: &[u8], buffer_size: usize) -> Result<Request<Vec<u8>>, Error> {
+    let mut stream = Cursor::new(input.to_vec());
+    let mut buffer = vec![0; buffer_size];
+    read_client_request(&mut stream, &mut buffer, &RequestConfig::default()).await
 }
 
-pub fn format_request_line(request: &Request<Vec<u8>>) -> String {
-    format!("{} {} {:?}", request.method(), request.uri(), request.version())
+
+/// Mock stream returning at most one byte per read call, like a client sending its request byte by byte.
+struct OneByteStream {
+    input: Vec<u8>,
+    position: usize,
+}
+
+impl AsyncRead for OneByteStream {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), std::io::Error>> {
+        if self.position < self.input.len() {
+            buf.put_slice(&self.input[self.position..self.position + 1]);
+            self.position += 1;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+fn assert_post_request(request: &Request<Vec<u8>>) {
+    assert_eq!(request.method(), "POST");
+    assert_eq!(request.uri(), "/submit?x=1");
+    assert_eq!(request.headers()["Host"], "localhost");
+    assert_eq!(request.body(), b"hello");
+}
+
+
+#[tokio::test]
+async fn read_request_split_at_every_byte_boundary() {
+    for split in 1..POST_REQUEST.len() {
+        let (first, second) = POST_REQUEST.split_at(split);
+        let mut stream = first.chain(second);
+        let mut buffer = vec![0; 1024];
+
+        let request = read_client_request(&mut stream, &mut buffer, &RequestConfig::default()).await.unwrap();
+
+        assert_post_request(&request);
+    }
+}
+
+
+#[tokio::test]
+async fn read_request_one_byte_at_a_time() {
+    let mut stream = OneByteStream { input: POST_REQUEST.to_vec(), position: 0 };
+    let mut buffer = vec![0; 1024];
+
+    let request = read_client_request(&mut stream, &mut buffer, &RequestConfig::default()).await.unwrap();
+
+    assert_post_request(&request);
+}
+
+
+#[tokio::test]
+async fn read_request_missing_final_crlf() {
+    // the client closes the connection before the end of the headers
+    let result = read_from_memory(b"GET / HTTP/1.1\r\nHost: localhost\r\n", 1024).await;
+
+    assert!(matches!(result, Err(Error::ClientClosedConnection)));
+}
+
+
+/// Returns the default settings with a header read timeout.
+fn header_timeout_config(header_read_timeout: Duration) -> RequestConfig {
+    RequestConfig { header_read_timeout: Some(header_read_timeout), ..RequestConfig::default() }
+}
+
+
+#[tokio::test]
+async fn read_request_dribbled_headers_time_out() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+
+    // the client sends a header byte every 20 ms, the headers would take more than a second
+    tokio::spawn(async move {
+        for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: aaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n" {
+            if client.write_all(&[*byte]).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    let mut buffer = vec![0; 1024];
+    let started_at = Instant::now();
+    let result = read_client_request(&mut server, &mut buffer, &header_timeout_config(Duration::from_millis(200))).await;
+
+    assert!(matches!(result, Err(Error::RequestTimeout)));
+    assert!(started_at.elapsed() < Duration::from_secs(1));
+}
+
+
+#[tokio::test]
+async fn read_request_body_is_not_bound_by_header_timeout() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+
+    // the headers arrive at once, the body after the header read timeout
+    tokio::spawn(async move {
+        client.write_all(b"POST /submit?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.write_all(b"hello").await.unwrap();
+    });
+
+    let mut buffer = vec![0; 1024];
+    let request = read_client_request(&mut server, &mut buffer, &header_timeout_config(Duration::from_millis(100))).await.unwrap();
+
+    assert_post_request(&request);
+}
+
+
+#[tokio::test]
+async fn read_request_truncated_body() {
+    let result = read_from_memory(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nhello", 1024).await;
+
+    assert!(matches!(result, Err(Error::ConnectionError)));
+}
+
+
+#[tokio::test]
+async fn read_request_header_casing() {
+    let request = read_from_memory(b"GET / HTTP/1.1\r\nhOsT: localhost\r\nX-CUSTOM-header: Mixed Case\r\n\r\n", 1024).await.unwrap();
+
+    // header names are case-insensitive, values are kept as they were sent
+    assert_eq!(request.headers()["host"], "localhost");
+    assert_eq!(request.headers()["x-custom-header"], "Mixed Case");
+}
+
+
+#[tokio::test]
+async fn read_request_lf_only_line_endings() {
+    let request = read_from_memory(b"GET /lf HTTP/1.1\nHost: localhost\n\n", 1024).await.unwrap();
+
+    assert_eq!(request.uri(), "/lf");
+    assert_eq!(request.headers()["Host"], "localhost");
+
+    // the request is forwarded with CRLF line endings
+    let mut forwarded = Vec::new();
+    crate::request::write_to_stream(&request, &mut forwarded).await.unwrap();
+    assert_eq!(forwarded, b"GET /lf HTTP/1.1\r\nhost: localhost\r\n\r\n".to_vec());
+}
+
+
+#[tokio::test]
+async fn read_request_oversized_inputs() {
+    // headers larger than the buffer
+    let large_header = format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Large: {}\r\n\r\n", "a".repeat(2000));
+    assert!(matches!(read_from_memory(large_header.as_bytes(), 1024).await, Err(Error::MalformedRequest)));
+
+    // more headers than the parser accepts
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", (0..32).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect::<String>());
+    assert!(matches!(read_from_memory(many_headers.as_bytes(), 4096).await, Err(Error::MalformedRequest)));
+
+    // a body larger than the buffer is read in several rounds
+    let body = "b".repeat(10_000);
+    let large_body = format!("POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let request = read_from_memory(large_body.as_bytes(), 1024).await.unwrap();
+    assert_eq!(request.body(), body.as_bytes());
+}
+
+
+/// Reads a POST request carrying `headers` with `config`.
+async fn read_coded_request(headers: &str, config: &RequestConfig) -> Result<Request<Vec<u8>>, Error> {
+    let raw = format!("POST /upload HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: 5\r\n\r\nhello", headers);
+    read_client_request(&mut Cursor::new(raw.into_bytes()), &mut [0; 1024], config).await
+}
+
+
+#[tokio::test]
+async fn read_request_transfer_coding_matrix() {
+    for (headers, expected) in [
+        ("Transfer-Encoding: gzip, chunked\r\n", Some("gzip")),
+        ("Transfer-Encoding: chunked\r\nTransfer-Encoding: Deflate\r\n", Some("deflate")),
+        ("Transfer-Encoding: x-custom;q=1\r\n", Some("x-custom")),
+        ("Transfer-Encoding: chunked\r\n", None),
+        ("Transfer-Encoding: \r\n", None),
+    ] {
+        let result = read_coded_request(headers, &RequestConfig::default()).await;
+        match expected {
+            // the transfer codings that can't be framed are answered with 501 Not Implemented
+            Some(expected) => assert!(matches!(&result, Err(Error::UnsupportedTransferCoding { coding }) if coding == expected), "{} {:?}", headers, result),
+            // a chunked body with a Content-Length header too is how requests get smuggled
+            None => assert!(matches!(result, Err(Error::MalformedRequest)), "{} {:?}", headers, result),
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn read_request_chunked_body() {
+    let chunked = b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n6 \r\n world\r\nb\r\n, chunked!\n\r\n0\r\nX-Checksum: 42\r\n\r\n";
+
+    // decoded whatever the reads it arrives in, the extensions and trailer fields dropped
+    let request = read_client_request(&mut OneByteStream { input: chunked.to_vec(), position: 0 }, &mut [0; 1024], &RequestConfig::default()).await.unwrap();
+    assert_eq!(request.body(), b"hello world, chunked!\n");
+    assert_eq!(request.headers()["Transfer-Encoding"], "chunked");
+    assert!(!request.headers().contains_key("X-Checksum"));
+
+    // forwarded as a single chunk
+    let mut forwarded = Vec::new();
+    crate::request::write_to_stream(&request, &mut forwarded).await.unwrap();
+    assert!(forwarded.ends_with(b"\r\n\r\n16\r\nhello world, chunked!\n\r\n0\r\n\r\n"), "{:?}", String::from_utf8_lossy(&forwarded));
+
+    let empty = read_from_memory(b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n", 1024).await.unwrap();
+    assert!(empty.body().is_empty());
+}
+
+
+#[tokio::test]
+async fn read_request_invalid_chunked_bodies() {
+    let long_line = format!("5;{}\r\nhello\r\n0\r\n\r\n", "a".repeat(5000));
+    let long_trailers = format!("0\r\n{}\r\n\r\n", "X-Trailer: a\r\n".repeat(500));
+    for (headers, body) in [
+        ("", "5\r\nhello0\r\n\r\n"),
+        ("", "-5\r\nhello\r\n0\r\n\r\n"),
+        ("", "0x5\r\nhello\r\n0\r\n\r\n"),
+        ("", "\r\nhello\r\n0\r\n\r\n"),
+        ("", "10000000000000000\r\n"),
+        ("", "5\nhello\n0\n\n"),
+        ("", &long_line),
+        ("", &long_trailers),
+        ("Transfer-Encoding: chunked\r\n", "0\r\n\r\n"),
+        ("Content-Length: 5\r\n", "0\r\n\r\n"),
+    ] {
+        let raw = format!("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n{}\r\n{}", headers, body);
+        let result = read_from_memory(raw.as_bytes(), 1024).await;
+        assert!(matches!(result, Err(Error::MalformedRequest)), "{:?} {:?}", raw, result);
+    }
+
+    // a body ending before its last chunk
+    let truncated = read_from_memory(b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", 1024).await;
+    assert!(matches!(truncated, Err(Error::ConnectionError)));
+    let unterminated = read_from_memory(b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n", 1024).await;
+    assert!(matches!(unterminated, Err(Error::ConnectionError)));
+}
+
+
+#[tokio::test]
+async fn read_request_content_coding_matrix() {
+    let rejecting = RequestConfig { reject_unknown_content_coding: true, ..RequestConfig::default() };
+
+    for (headers, known) in [
+        ("", true),
+        ("Content-Encoding: gzip\r\n", true),
+        ("Content-Encoding: BR, zstd\r\n", true),
+        ("Content-Encoding: x-gzip\r\nContent-Encoding: identity\r\n", true),
+        ("Content-Encoding: lzma\r\n", false),
+        ("Content-Encoding: gzip, x-custom\r\n", false),
+    ] {
+        // passed through by default, whatever the coding
+        let request = read_coded_request(headers, &RequestConfig::default()).await.unwrap();
+        assert_eq!(request.body(), b"hello");
+
+        match known {
+            true => assert!(read_coded_request(headers, &rejecting).await.is_ok(), "{}", headers),
+            false => assert!(matches!(read_coded_request(headers, &rejecting).await, Err(Error::UnsupportedContentCoding { .. })), "{}", headers),
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn read_request_passes_content_codings_through_untouched() {
+    let request = read_coded_request("Content-Encoding: GZip, X-Custom;level=9\r\n", &RequestConfig::default()).await.unwrap();
+
+    let mut forwarded = Vec::new();
+    crate::request::write_to_stream(&request, &mut forwarded).await.unwrap();
+    let forwarded = String::from_utf8(forwarded).unwrap();
+    assert!(forwarded.contains("\r\ncontent-encoding: GZip, X-Custom;level=9\r\n"), "{}", forwarded);
+    assert!(forwarded.ends_with("\r\n\r\nhello"));
+}
+
+
+#[tokio::test]
+async fn request_controller_over_in_memory_stream() {
+    let config = RequestConfig {
+        max_hops: 5,
+        trusted_hops_from: Vec::new(),
+        forward_client_ip: true,
+        forwarded_header_format: ForwardedHeaderFormat::XForwardedFor,
+        default_host: None,
+        real_ip_from: Vec::new(),
+        real_ip_header: RealIpHeader::XForwardedFor,
+        header_read_timeout: None,
+        uri_mode: UriMode::Strict,
+        dot_segments: DotSegments::Keep,
+        acl_rules: Vec::new(),
+        upstream_keepalive_timeout: None,
+        static_routes: Vec::new(),
+        cors: None,
+        forward_scheme: false,
+        deadline_header: None,
+        debug_routing: None,
+        body_spool: None,
+        upstream_host_rules: Vec::new(),
+        reject_unknown_content_coding: false,
+    };
+    let mut stream = Cursor::new(POST_REQUEST.to_vec());
+    let mut buffer = vec![0; 1024];
+
+    let request = request_controller(&mut stream, "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+
+    assert_post_request(&request);
+    assert_eq!(request.headers()["X-Forwarded-For"], "10.0.0.1");
+    assert_eq!(request.headers()[crate::request::HOPS_HEADER], "1");
+}
+
+
+async fn controller_with_default_host(request: &[u8], default_host: Option<&str>) -> Result<Request<Vec<u8>>, Error> {
+    let config = RequestConfig {
+        max_hops: 5,
+        trusted_hops_from: Vec::new(),
+        forward_client_ip: true,
+        forwarded_header_format: ForwardedHeaderFormat::XForwardedFor,
+        default_host: default_host.map(|host| host.parse().unwrap()),
+        real_ip_from: Vec::new(),
+        real_ip_header: RealIpHeader::XForwardedFor,
+        header_read_timeout: None,
+        uri_mode: UriMode::Strict,
+        dot_segments: DotSegments::Keep,
+        acl_rules: Vec::new(),
+        upstream_keepalive_timeout: None,
+        static_routes: Vec::new(),
+        cors: None,
+        forward_scheme: false,
+        deadline_header: None,
+        debug_routing: None,
+        body_spool: None,
+        upstream_host_rules: Vec::new(),
+        reject_unknown_content_coding: false,
+    };
+    let mut stream = Cursor::new(request.to_vec());
+    let mut buffer = vec![0; 1024];
+
+    request_controller(&mut stream, "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await
+}
+
+
+#[tokio::test]
+async fn request_without_host_is_rejected_by_default() {
+    let result = controller_with_default_host(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n", None).await;
+    assert!(matches!(result, Err(Error::InvalidHost)));
+
+    // HTTP/1.0 requests may omit it
+    let request = controller_with_default_host(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n", None).await.unwrap();
+    assert!(request.headers().get("Host").is_none());
+
+    // several differing Host headers are rejected whatever the configuration
+    let duplicated = b"GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n";
+    assert!(matches!(controller_with_default_host(duplicated, None).await, Err(Error::InvalidHost)));
+    assert!(matches!(controller_with_default_host(duplicated, Some("default.example")).await, Err(Error::InvalidHost)));
+}
+
+
+#[tokio::test]
+async fn host_arity_and_version_matrix() {
+    for (version, hosts, expected) in [
+        ("1.1", &[][..], None),
+        ("1.1", &["a.example"][..], Some("a.example")),
+        ("1.1", &["a.example", "a.example"][..], Some("a.example")),
+        ("1.1", &["a.example", "A.Example", "a.example"][..], Some("a.example")),
+        ("1.1", &["a.example", "b.example"][..], None),
+        ("1.1", &["a.example", "a.example", "b.example"][..], None),
+        ("1.0", &[][..], Some("")),
+        ("1.0", &["a.example"][..], Some("a.example")),
+        ("1.0", &["a.example", "a.example"][..], Some("a.example")),
+        ("1.0", &["a.example", "b.example"][..], None),
+    ] {
+        let headers: String = hosts.iter().map(|host| format!("Host: {}\r\n", host)).collect();
+        let raw = format!("GET / HTTP/{}\r\n{}\r\n", version, headers);
+        match (controller_with_default_host(raw.as_bytes(), None).await, expected) {
+            (Ok(request), Some("")) => assert!(request.headers().get("Host").is_none(), "{:?}", raw),
+            (Ok(request), Some(expected)) => {
+                let forwarded: Vec<_> = request.headers().get_all("Host").iter().collect();
+                assert_eq!(forwarded, vec![expected], "{:?}", raw);
+            }
+            (result, None) => assert!(matches!(result, Err(Error::InvalidHost)), "{:?} {:?}", raw, result),
+            (result, expected) => panic!("{:?} {:?}, expected {:?}", raw, result, expected),
+        }
+    }
+}
+
+
+#[test]
+fn upstream_host_is_only_supplied_without_host() {
+    let mut request = Request::builder().version(http::Version::HTTP_10).uri("/").body(Vec::new()).unwrap();
+    supply_upstream_host(&mut request, "10.0.0.1:8080");
+    assert_eq!(request.headers()["Host"], "10.0.0.1:8080");
+
+    // the host of the client, or supplied before, is kept on the next upstream server
+    supply_upstream_host(&mut request, "backend.internal:80");
+    let hosts: Vec<_> = request.headers().get_all("Host").iter().collect();
+    assert_eq!(hosts, vec!["10.0.0.1:8080"]);
+}
+
+
+#[tokio::test]
+async fn request_without_host_gets_default_host() {
+    let request = controller_with_default_host(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n", Some("default.example")).await.unwrap();
+    assert_eq!(request.headers()["Host"], "default.example");
+
+    // the Host sent by the client is kept
+    let request = controller_with_default_host(b"GET / HTTP/1.1\r\nHost: client.example\r\n\r\n", Some("default.example")).await.unwrap();
+    let hosts: Vec<_> = request.headers().get_all("Host").iter().collect();
+    assert_eq!(hosts, vec!["client.example"]);
+}
+
+
+fn real_ip_config(real_ip_header: RealIpHeader) -> RequestConfig {
+    RequestConfig {
+        max_hops: 5,
+        trusted_hops_from: Vec::new(),
+        forward_client_ip: true,
+        forwarded_header_format: ForwardedHeaderFormat::XForwardedFor,
+        default_host: None,
+        real_ip_from: vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+        real_ip_header,
+        header_read_timeout: None,
+        uri_mode: UriMode::Strict,
+        dot_segments: DotSegments::Keep,
+        acl_rules: Vec::new(),
+        upstream_keepalive_timeout: None,
+        static_routes: Vec::new(),
+        cors: None,
+        forward_scheme: false,
+        deadline_header: None,
+        debug_routing: None,
+        body_spool: None,
+        upstream_host_rules: Vec::new(),
+        reject_unknown_content_coding: false,
+    }
+}
+
+
+fn request_with_headers(headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+    let mut request = Request::builder().uri("/").header("Host", "localhost");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Vec::new()).unwrap()
+}
+
+
+#[test]
+fn real_ip_is_rightmost_untrusted_forwarded_for_entry() {
+    let config = real_ip_config(RealIpHeader::XForwardedFor);
+    let proxy = "10.0.0.1".parse().unwrap();
+
+    // the client forged the first entry, the trusted proxies appended the others
+    let request = request_with_headers(&[("X-Forwarded-For", "1.2.3.4, 203.0.113.7, 10.0.0.2")]);
+    assert_eq!(real_client_ip(&request, proxy, &config), Some("203.0.113.7".parse().unwrap()));
+
+    // the chain spans several header lines, entries may carry a port
+    let request = request_with_headers(&[("X-Forwarded-For", "1.2.3.4"), ("X-Forwarded-For", "198.51.100.9:4321, [2001:db8::5]:80")]);
+    assert_eq!(real_client_ip(&request, proxy, &config), Some("198.51.100.9".parse().unwrap()));
+
+    // only trusted proxies in the chain, the leftmost one is the client
+    let request = request_with_headers(&[("X-Forwarded-For", "10.1.1.1, 10.0.0.2")]);
+    assert_eq!(real_client_ip(&request, proxy, &config), Some("10.1.1.1".parse().unwrap()));
+}
+
+
+#[test]
+fn real_ip_ignored_from_untrusted_peer_or_invalid_header() {
+    let config = real_ip_config(RealIpHeader::XForwardedFor);
+    let request = request_with_headers(&[("X-Forwarded-For", "203.0.113.7")]);
+
+    assert_eq!(real_client_ip(&request, "192.168.1.1".parse().unwrap(), &config), None);
+    assert_eq!(real_client_ip(&request_with_headers(&[]), "10.0.0.1".parse().unwrap(), &config), None);
+
+    // an unparsable entry before the client is found makes the whole chain unreliable
+    let request = request_with_headers(&[("X-Forwarded-For", "203.0.113.7, unknown, 10.0.0.2")]);
+    assert_eq!(real_client_ip(&request, "10.0.0.1".parse().unwrap(), &config), None);
+}
+
+
+#[test]
+fn real_ip_from_single_address_headers() {
+    let proxy = "10.0.0.1".parse().unwrap();
+
+    let config = real_ip_config(RealIpHeader::CfConnectingIp);
+    let request = request_with_headers(&[("CF-Connecting-IP", "203.0.113.7"), ("X-Forwarded-For", "1.2.3.4")]);
+    assert_eq!(real_client_ip(&request, proxy, &config), Some("203.0.113.7".parse().unwrap()));
+
+    let config = real_ip_config(RealIpHeader::XRealIp);
+    let request = request_with_headers(&[("X-Real-IP", "2001:db8::7")]);
+    assert_eq!(real_client_ip(&request, proxy, &config), Some("2001:db8::7".parse().unwrap()));
+
+    // a repeated single address header is ambiguous
+    let request = request_with_headers(&[("X-Real-IP", "203.0.113.7"), ("X-Real-IP", "1.2.3.4")]);
+    assert_eq!(real_client_ip(&request, proxy, &config), None);
+
+    assert_eq!("x-real-ip".parse::<RealIpHeader>(), Ok(RealIpHeader::XRealIp));
+    assert!("Forwarded".parse::<RealIpHeader>().is_err());
+}
+
+
+#[tokio::test]
+async fn request_controller_forwards_real_client_ip() {
+    let config = real_ip_config(RealIpHeader::XForwardedFor);
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n";
+    let mut buffer = vec![0; 1024];
+
+    let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+    let forwarded_for: Vec<_> = forwarded.headers().get_all("X-Forwarded-For").iter().collect();
+    assert_eq!(forwarded_for, vec!["203.0.113.7", "10.0.0.1"]);
+
+    let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "192.168.1.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+    let forwarded_for: Vec<_> = forwarded.headers().get_all("X-Forwarded-For").iter().collect();
+    assert_eq!(forwarded_for, vec!["203.0.113.7", "192.168.1.1"]);
+}
+
+
+#[tokio::test]
+async fn request_controller_returns_the_resolved_client_ip() {
+    let config = real_ip_config(RealIpHeader::XForwardedFor);
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n";
+    let mut buffer = vec![0; 1024];
+
+    // the client reported by a trusted proxy, the peer itself otherwise
+    let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+    assert_eq!(forwarded.extensions().get::<ClientIp>(), Some(&ClientIp("203.0.113.7".parse().unwrap())));
+
+    let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "192.168.1.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+    assert_eq!(forwarded.extensions().get::<ClientIp>(), Some(&ClientIp("192.168.1.1".parse().unwrap())));
+}
+
+
+#[tokio::test]
+async fn request_controller_marks_the_last_request_of_a_connection() {
+    // the hint kept for the upstream server doesn't change what the client asked for
+    let config = RequestConfig { upstream_keepalive_timeout: Some(Duration::from_secs(30)), ..RequestConfig::default() };
+    let mut buffer = vec![0; 1024];
+
+    for (request, closes) in [
+        (&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..], false),
+        (b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", true),
+        (b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: TE, Close\r\n\r\n", true),
+        (b"GET / HTTP/1.0\r\n\r\n", true),
+        (b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", false),
+    ] {
+        let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+        assert_eq!(forwarded.extensions().get::<CloseAfterResponse>().is_some(), closes, "{}", String::from_utf8_lossy(request));
+    }
+}
+
+
+#[test]
+fn idempotent_methods_are_the_rfc_ones() {
+    for (method, idempotent) in [("GET", true), ("HEAD", true), ("OPTIONS", true), ("TRACE", true), ("PUT", true), ("DELETE", true), ("POST", false), ("PATCH", false), ("CONNECT", false)] {
+        let request = Request::builder().method(method).uri("/").body(Vec::new()).unwrap();
+        assert_eq!(is_idempotent(&request), idempotent, "{}", method);
+    }
+}
+
+
+#[tokio::test]
+async fn request_controller_adds_keep_alive_hint() {
+    let config = RequestConfig { upstream_keepalive_timeout: Some(Duration::from_secs(30)), ..RequestConfig::default() };
+    let mut buffer = vec![0; 1024];
+
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nKeep-Alive: timeout=600\r\n\r\n";
+    let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+    assert_eq!(forwarded.headers()["Connection"], "keep-alive");
+    let keep_alive: Vec<_> = forwarded.headers().get_all("Keep-Alive").iter().collect();
+    assert_eq!(keep_alive, vec!["timeout=30"]);
+
+    // a request closing its connection isn't told to keep it
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, close\r\n\r\n";
+    let forwarded = request_controller(&mut Cursor::new(request.to_vec()), "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+    assert_eq!(forwarded.headers()["Connection"], "Upgrade, close");
+    assert!(!forwarded.headers().contains_key("Keep-Alive"));
+}
+
+
+#[test]
+fn forwarded_scheme_headers_are_replaced_unless_sent_by_a_trusted_proxy() {
+    let config = real_ip_config(RealIpHeader::XForwardedFor);
+    let spoofed = [("X-Forwarded-Proto", "https"), ("X-Forwarded-Port", "443"), ("X-Forwarded-Host", "shop.example")];
+
+    // a direct client reached the plaintext listener, whatever it claims
+    let mut request = request_with_headers(&spoofed);
+    add_forwarded_scheme(&mut request, "http", 8080, "192.168.1.1".parse().unwrap(), &config);
+    assert_eq!(request.headers()["X-Forwarded-Proto"], "http");
+    assert_eq!(request.headers()["X-Forwarded-Port"], "8080");
+    assert_eq!(request.headers()["X-Forwarded-Host"], "localhost");
+    assert_eq!(request.headers().get_all("X-Forwarded-Proto").iter().count(), 1);
+
+    // a trusted proxy terminated TLS, its headers are kept and the missing ones added
+    let mut request = request_with_headers(&spoofed[..2]);
+    add_forwarded_scheme(&mut request, "http", 8080, "10.0.0.1".parse().unwrap(), &config);
+    assert_eq!(request.headers()["X-Forwarded-Proto"], "https");
+    assert_eq!(request.headers()["X-Forwarded-Port"], "443");
+    assert_eq!(request.headers()["X-Forwarded-Host"], "localhost");
 }
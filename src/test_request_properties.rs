@@ -0,0 +1,144 @@
+use std::io::Cursor;
+
+use http::Request;
+use proptest::prelude::*;
+use tokio::io::AsyncReadExt;
+
+use crate::request::{read_client_request, write_to_stream, Error, RequestConfig};
+
+
+/// Requests the generated inputs are derived from: valid requests, request smuggling payloads and odd framings.
+///
+/// The seed corpus of the `read_client_request` fuzz target (`fuzz/corpus/read_client_request`) holds the same requests.
+const SEED_CORPUS: &[&[u8]] = &[
+    b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    b"GET /index.html?lang=en HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\nUser-Agent: curl/8.0\r\n\r\n",
+    b"POST /submit HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nhello world",
+    b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    b"GET http://example.com/path?query=1 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+    b"GET /lf HTTP/1.0\nHost: localhost\n\n",
+    // CL.TE and TE.CL smuggling
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 13\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nSMUGGLED",
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n8\r\nSMUGGLED\r\n0\r\n\r\n",
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding : chunked\r\n\r\n0\r\n\r\n",
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: xchunked\r\n\r\n0\r\n\r\n",
+    // conflicting and malformed lengths
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nhello",
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: +5\r\n\r\nhello",
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5, 5\r\n\r\nhello",
+    b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+    // obsolete line folding and stray bytes
+    b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Folded: a\r\n b\r\n\r\n",
+    b"GET / HTTP/1.1\r\nHost: local\x00host\r\n\r\n",
+    b"\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    // chunked bodies, valid with an extension and a trailer, and with an invalid chunk size
+    b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n",
+    b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5 5\r\nhello\r\n0\r\n\r\n",
+];
+
+
+/// Reads a request from `input`, delivered in reads split at the given positions.
+fn read_request(input: &[u8], splits: &[usize], buffer_size: usize) -> Result<Request<Vec<u8>>, Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let mut positions: Vec<usize> = splits.iter().map(|split| split % (input.len() + 1)).collect();
+        positions.push(0);
+        positions.push(input.len());
+        positions.sort_unstable();
+
+        // chain the parts, so every part is returned by a different read
+        let mut stream: Box<dyn tokio::io::AsyncRead + Unpin> = Box::new(Cursor::new(Vec::new()));
+        for part in positions.windows(2) {
+            stream = Box::new(stream.chain(Cursor::new(input[part[0]..part[1]].to_vec())));
+        }
+
+        let mut buffer = vec![0; buffer_size];
+        read_client_request(&mut stream, &mut buffer, &RequestConfig::default()).await
+    })
+}
+
+
+/// Serializes a request the way it is forwarded to the upstream servers.
+fn serialize(request: &Request<Vec<u8>>) -> Vec<u8> {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut bytes = Vec::new();
+    runtime.block_on(write_to_stream(request, &mut bytes)).unwrap();
+    bytes
+}
+
+
+/// Inputs derived from the seed corpus: truncated, with flipped or inserted bytes, or followed by random bytes.
+fn corpus_input() -> impl Strategy<Value = Vec<u8>> {
+    (0..SEED_CORPUS.len(), any::<usize>(), prop::collection::vec((any::<usize>(), any::<u8>()), 0..4), prop::collection::vec(any::<u8>(), 0..16))
+        .prop_map(|(seed, truncate_at, mutations, suffix)| {
+            let mut input = SEED_CORPUS[seed].to_vec();
+            for (position, byte) in mutations {
+                let position = position % (input.len() + 1);
+                if byte % 2 == 0 && position < input.len() {
+                    input[position] = byte;
+                } else {
+                    input.insert(position, byte);
+                }
+            }
+            input.truncate(truncate_at % (input.len() + 1) + 1);
+            input.extend(suffix);
+            input
+        })
+}
+
+
+/// Arbitrary bytes, and inputs derived from the seed corpus.
+fn request_input() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..512),
+        corpus_input(),
+        (0..SEED_CORPUS.len()).prop_map(|seed| SEED_CORPUS[seed].to_vec()),
+    ]
+}
+
+
+/// Number of cases of every property, 2000 unless overridden with `PROPTEST_CASES`.
+fn cases() -> u32 {
+    std::env::var("PROPTEST_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(2000)
+}
+
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(cases()))]
+
+    #[test]
+    fn reading_never_panics(input in request_input(), splits in prop::collection::vec(any::<usize>(), 0..4), buffer_size in 64usize..1024) {
+        // any outcome but a panic is fine, the typed error is answered by the proxy
+        let _ = read_request(&input, &splits, buffer_size);
+    }
+
+
+    #[test]
+    fn parsed_requests_round_trip(input in request_input(), splits in prop::collection::vec(any::<usize>(), 0..4)) {
+        if let Ok(request) = read_request(&input, &splits, 1024) {
+            let forwarded = serialize(&request);
+
+            let reparsed = match read_request(&forwarded, &[], forwarded.len().max(1024)) {
+                Ok(reparsed) => reparsed,
+                Err(e) => return Err(TestCaseError::fail(format!("{:?} doesn't re-parse: {:?}", String::from_utf8_lossy(&forwarded), e))),
+            };
+            prop_assert_eq!(reparsed.method(), request.method());
+            prop_assert_eq!(reparsed.uri(), request.uri());
+            prop_assert_eq!(reparsed.headers(), request.headers());
+            prop_assert_eq!(reparsed.body(), request.body());
+        }
+    }
+}
+
+
+#[test]
+fn seed_corpus_outcomes() {
+    let parsed: Vec<bool> = SEED_CORPUS.iter().map(|input| read_request(input, &[], 1024).is_ok()).collect();
+
+    // the valid requests are read, the smuggling payloads and conflicting lengths are rejected
+    assert_eq!(&parsed[..7], &[true; 7]);
+    assert_eq!(&parsed[7..15], &[false; 8]);
+    assert_eq!(&parsed[18..], &[true, false]);
+}
@@ -0,0 +1,114 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+
+use crate::connect::Connector;
+use crate::resolver::{CachingResolver, ResolveFuture, Resolved, Resolver, DEFAULT_TTL};
+
+
+/// A resolver answering every address with the same socket address and TTL, counting its lookups.
+#[derive(Debug)]
+struct StubResolver {
+    address: SocketAddr,
+    ttl: Option<Duration>,
+    lookups: AtomicUsize,
+}
+
+impl StubResolver {
+    fn new(address: &str, ttl: Option<Duration>) -> Arc<StubResolver> {
+        Arc::new(StubResolver { address: address.parse().unwrap(), ttl, lookups: AtomicUsize::new(0) })
+    }
+
+    fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::SeqCst)
+    }
+}
+
+impl Resolver for StubResolver {
+    fn resolve<'a>(&'a self, upstream_address: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            if upstream_address.starts_with("unknown") {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "unknown host"));
+            }
+            Ok(Resolved { addresses: vec![self.address], ttl: self.ttl })
+        })
+    }
+}
+
+
+#[tokio::test]
+async fn test_answers_are_cached_within_ttl() {
+    let stub = StubResolver::new("10.0.0.1:80", Some(Duration::from_secs(60)));
+    let resolver = CachingResolver::new(stub.clone(), None);
+
+    let first = resolver.resolve("api.internal:80").await.unwrap();
+    let second = resolver.resolve("api.internal:80").await.unwrap();
+
+    assert_eq!(first.addresses, vec!["10.0.0.1:80".parse().unwrap()]);
+    assert_eq!(second.addresses, first.addresses);
+    assert!(second.ttl.unwrap() <= Duration::from_secs(60));
+    assert_eq!(stub.lookups(), 1);
+
+    // every upstream address has its own answer
+    resolver.resolve("web.internal:80").await.unwrap();
+    assert_eq!(stub.lookups(), 2);
+    assert_eq!(resolver.cached(), 2);
+}
+
+
+#[tokio::test]
+async fn test_answers_are_refreshed_once_ttl_expires() {
+    let stub = StubResolver::new("10.0.0.1:80", Some(Duration::from_millis(50)));
+    let resolver = CachingResolver::new(stub.clone(), None);
+
+    resolver.resolve("api.internal:80").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    resolver.resolve("api.internal:80").await.unwrap();
+
+    assert_eq!(stub.lookups(), 2);
+}
+
+
+#[tokio::test]
+async fn test_ttl_override_and_default() {
+    // the override replaces the short TTL of the records
+    let stub = StubResolver::new("10.0.0.1:80", Some(Duration::from_millis(10)));
+    let resolver = CachingResolver::new(stub.clone(), Some(Duration::from_secs(60)));
+    resolver.resolve("api.internal:80").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    resolver.resolve("api.internal:80").await.unwrap();
+    assert_eq!(stub.lookups(), 1);
+
+    // without a TTL from the resolver, the default applies
+    let resolver = CachingResolver::new(StubResolver::new("10.0.0.1:80", None), None);
+    assert_eq!(resolver.resolve("api.internal:80").await.unwrap().ttl, Some(DEFAULT_TTL));
+}
+
+
+#[tokio::test]
+async fn test_failed_resolutions_are_not_cached() {
+    let stub = StubResolver::new("10.0.0.1:80", Some(Duration::from_secs(60)));
+    let resolver = CachingResolver::new(stub.clone(), None);
+
+    assert!(resolver.resolve("unknown.internal:80").await.is_err());
+    assert!(resolver.resolve("unknown.internal:80").await.is_err());
+    assert_eq!(stub.lookups(), 2);
+    assert_eq!(resolver.cached(), 0);
+}
+
+
+#[tokio::test]
+async fn test_connector_resolves_with_its_resolver() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stub = StubResolver::new(&listener.local_addr().unwrap().to_string(), Some(Duration::from_secs(60)));
+    let connector = Connector::new(0, Duration::from_millis(10), None)
+        .with_resolver(Arc::new(CachingResolver::new(stub.clone(), None)));
+
+    assert!(connector.connect("upstream.internal:80", None).await.is_ok());
+    assert!(connector.connect("upstream.internal:80", None).await.is_ok());
+    assert_eq!(stub.lookups(), 1);
+}
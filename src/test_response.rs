@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::header::HeaderValue;
+use http::Method;
+use tokio::io::AsyncWriteExt;
+
+use crate::response::{is_status_line_prefix, relay_response, with_connection_close, AddedHeaders, Error, ResponseConfig, ResponseLimits, UpstreamResponseLimit};
+
+
+/// Builds a response whose body is larger than most of the tested buffer sizes.
+fn large_response() -> Vec<u8> {
+    let body: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+    response.extend_from_slice(&body);
+    response
+}
+
+
+/// Relays `response` through a duplex upstream that stays open after sending it, and returns what the client received.
+async fn relay_from_open_upstream(response: &[u8], buffer_size: usize, request_method: &Method) -> Result<Vec<u8>, Error> {
+    let (mut upstream_writer, mut upstream_stream) = tokio::io::duplex(4096);
+    let upstream_response = response.to_vec();
+    let upstream = tokio::spawn(async move {
+        upstream_writer.write_all(&upstream_response).await.unwrap();
+        // keep the connection open, the relay must stop on the framing alone
+        upstream_writer
+    });
+
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; buffer_size];
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, request_method, &AddedHeaders::default(), &ResponseLimits::default(), false).await;
+    drop(upstream.await.unwrap());
+
+    result.map(|relayed| {
+        assert_eq!(relayed.bytes_relayed, client_stream.len());
+        assert!(!relayed.close_delimited);
+        client_stream
+    })
+}
+
+
+#[tokio::test]
+async fn test_relay_identical_across_buffer_sizes() {
+    let response = large_response();
+
+    for buffer_size in [1024, 4 * 1024, 8 * 1024, 64 * 1024, 256 * 1024] {
+        let client_stream = relay_from_open_upstream(&response, buffer_size, &Method::GET).await.unwrap();
+
+        assert_eq!(client_stream, response, "buffer size {}", buffer_size);
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_chunked_response_forwards_trailers() {
+    let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/grpc-web\r\nTransfer-Encoding: chunked\r\nTrailer: Grpc-Status, Grpc-Message\r\n\r\n\
+5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nGrpc-Status: 0\r\nGrpc-Message: OK\r\n\r\n";
+
+    for buffer_size in [1024, 8 * 1024] {
+        let client_stream = relay_from_open_upstream(response, buffer_size, &Method::POST).await.unwrap();
+
+        assert_eq!(client_stream, response.to_vec());
+        let received = String::from_utf8(client_stream).unwrap();
+        assert!(received.contains("Trailer: Grpc-Status, Grpc-Message\r\n"));
+        assert!(received.ends_with("0\r\nGrpc-Status: 0\r\nGrpc-Message: OK\r\n\r\n"));
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_chunks_larger_than_buffer() {
+    let chunk: Vec<u8> = (0..5000).map(|i| (i % 26) as u8 + b'a').collect();
+    let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+    response.extend_from_slice(&chunk);
+    response.extend_from_slice(b"\r\n0\r\n\r\n");
+
+    let client_stream = relay_from_open_upstream(&response, 1024, &Method::GET).await.unwrap();
+
+    assert_eq!(client_stream, response);
+}
+
+
+#[tokio::test]
+async fn test_relay_head_response_has_no_body() {
+    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n";
+
+    let client_stream = relay_from_open_upstream(response, 1024, &Method::HEAD).await.unwrap();
+
+    assert_eq!(client_stream, response.to_vec());
+}
+
+
+#[tokio::test]
+async fn test_relay_until_close_without_framing() {
+    let response = b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nread until the connection closes";
+    let mut upstream_stream: &[u8] = response;
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await.unwrap();
+
+    assert_eq!(relayed.bytes_relayed, response.len());
+    assert!(relayed.close_delimited);
+    assert_eq!(client_stream, response.to_vec());
+}
+
+
+#[tokio::test]
+async fn test_relay_upstream_closed_without_response() {
+    let mut upstream_stream: &[u8] = &[];
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await;
+
+    assert!(matches!(result, Err(Error::UpstreamReadFailed { bytes_relayed: 0, kind: None, response_started: false })));
+    assert!(client_stream.is_empty());
+}
+
+
+#[tokio::test]
+async fn test_relay_reports_upstream_reset() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut upstream_stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+    // closing with a zero linger sends a RST instead of a FIN
+    let (accepted, _) = listener.accept().await.unwrap();
+    accepted.set_zero_linger().unwrap();
+    drop(accepted);
+
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await;
+
+    assert!(matches!(result, Err(Error::UpstreamReadFailed { bytes_relayed: 0, kind: Some(std::io::ErrorKind::ConnectionReset), response_started: false })));
+}
+
+
+#[tokio::test]
+async fn test_relay_truncated_chunked_body() {
+    let mut upstream_stream: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n10\r\nonly a part";
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await;
+
+    assert!(matches!(result, Err(Error::UpstreamReadFailed { bytes_relayed, .. }) if bytes_relayed > 0));
+}
+
+
+#[tokio::test]
+async fn test_relay_reports_client_write_failure() {
+    let (mut upstream_writer, mut upstream_stream) = tokio::io::duplex(1024);
+    upstream_writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+
+    // the client side of the duplex is closed, so writing to it fails
+    let (mut client_stream, client_reader) = tokio::io::duplex(1024);
+    drop(client_reader);
+    let mut buffer = vec![0; 1024];
+
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await;
+
+    assert!(matches!(result, Err(Error::ClientWriteFailed(_))));
+}
+
+
+#[tokio::test]
+async fn test_relay_adds_server_timing_header() {
+    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+    let forwarded_at = Instant::now() - Duration::from_millis(20);
+    let mut upstream_stream: &[u8] = response;
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders { server_timing: Some(forwarded_at), ..AddedHeaders::default() }, &ResponseLimits::default(), false).await.unwrap();
+
+    assert_eq!(relayed.bytes_relayed, client_stream.len());
+    let received = String::from_utf8(client_stream).unwrap();
+    assert!(received.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nServer-Timing: upstream;dur="));
+    assert!(received.ends_with("\r\n\r\nhello"));
+
+    let duration: f64 = received.split("dur=").nth(1).unwrap().split("\r\n").next().unwrap().parse().unwrap();
+    assert!((20.0..10_000.0).contains(&duration), "duration {}", duration);
+}
+
+
+#[tokio::test]
+async fn test_relay_replaces_the_allowed_origin() {
+    let response = b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: 5\r\n\r\nhello";
+    let added_headers = AddedHeaders { allow_origin: Some(HeaderValue::from_static("https://app.example.com")), ..AddedHeaders::default() };
+    let mut upstream_stream: &[u8] = response;
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &added_headers, &ResponseLimits::default(), false).await.unwrap();
+
+    assert_eq!(relayed.bytes_relayed, client_stream.len());
+    assert_eq!(String::from_utf8(client_stream).unwrap(), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nAccess-Control-Allow-Origin: https://app.example.com\r\nVary: Origin\r\n\r\nhello");
+}
+
+
+#[tokio::test]
+async fn test_relay_forwards_interim_responses_before_the_final_one() {
+    let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+
+    for buffer_size in [64, 1024] {
+        let client_stream = relay_from_open_upstream(response, buffer_size, &Method::POST).await.unwrap();
+
+        assert_eq!(client_stream, response.to_vec(), "buffer size {}", buffer_size);
+    }
+
+    // the final response is the one reported, and the only one timed
+    let mut upstream_stream: &[u8] = response;
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::POST, &AddedHeaders { server_timing: Some(Instant::now()), ..AddedHeaders::default() }, &ResponseLimits::default(), false).await.unwrap();
+
+    assert_eq!(relayed.status, 200);
+    let received = String::from_utf8(client_stream).unwrap();
+    assert!(received.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\n"));
+    assert_eq!(received.matches("Server-Timing").count(), 1);
+    assert!(received.contains("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nServer-Timing: upstream;dur="));
+    assert!(received.ends_with("\r\n\r\nhello"));
+}
+
+
+#[tokio::test]
+async fn test_relay_stops_after_switching_protocols() {
+    let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+
+    let client_stream = relay_from_open_upstream(response, 1024, &Method::GET).await.unwrap();
+
+    assert_eq!(client_stream, response.to_vec());
+}
+
+
+#[tokio::test]
+async fn test_relay_rejects_declared_length_over_limit() {
+    let mut upstream_stream: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world";
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits { max_body_size: Some(10), ..ResponseLimits::default() }, false).await;
+
+    // nothing was sent, the client can still be answered with an error
+    assert!(matches!(result, Err(Error::ResponseTooLarge { bytes_relayed: 0 })));
+    assert!(client_stream.is_empty());
+}
+
+
+#[tokio::test]
+async fn test_relay_cuts_streamed_body_over_limit() {
+    for response in [
+        &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"[..],
+        &b"HTTP/1.0 200 OK\r\n\r\nhello world"[..],
+    ] {
+        let mut upstream_stream: &[u8] = response;
+        let mut client_stream = Vec::new();
+        let mut buffer = vec![0; 1024];
+
+        let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits { max_body_size: Some(10), ..ResponseLimits::default() }, false).await;
+
+        // the head was already sent, the client must see an incomplete response
+        assert!(matches!(result, Err(Error::ResponseTooLarge { bytes_relayed }) if bytes_relayed > 0 && bytes_relayed == client_stream.len()));
+        assert!(!client_stream.ends_with(b"world"));
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_body_at_limit_counts_body_only() {
+    for response in [
+        &b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world"[..],
+        &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"[..],
+        &b"HTTP/1.0 200 OK\r\n\r\nhello world"[..],
+    ] {
+        let mut upstream_stream: &[u8] = response;
+        let mut client_stream = Vec::new();
+        let mut buffer = vec![0; 1024];
+
+        let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits { max_body_size: Some(11), ..ResponseLimits::default() }, false).await.unwrap();
+
+        assert_eq!(relayed.bytes_relayed, response.len());
+        assert_eq!(client_stream, response.to_vec());
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_head_larger_than_buffer() {
+    let mut response = b"HTTP/1.1 200 OK\r\n".to_vec();
+    for index in 0..20 {
+        response.extend_from_slice(format!("Set-Cookie: session{}={}\r\n", index, "x".repeat(200)).as_bytes());
+    }
+    response.extend_from_slice(b"Content-Length: 11\r\n\r\nhello world");
+
+    // the head spills out of the buffer, but stays under the limit
+    for buffer_size in [64, 1024, 4096] {
+        let mut upstream_stream: &[u8] = &response;
+        let mut client_stream = Vec::new();
+        let mut buffer = vec![0; buffer_size];
+
+        let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await.unwrap();
+
+        assert_eq!(relayed.bytes_relayed, response.len(), "buffer size {}", buffer_size);
+        assert_eq!(client_stream, response, "buffer size {}", buffer_size);
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_rejects_head_over_limit() {
+    let mut response = b"HTTP/1.1 200 OK\r\n".to_vec();
+    response.extend_from_slice(format!("Set-Cookie: session={}\r\n", "x".repeat(4096)).as_bytes());
+    response.extend_from_slice(b"Content-Length: 11\r\n\r\nhello world");
+
+    for buffer_size in [1024, 8192] {
+        let mut upstream_stream: &[u8] = &response;
+        let mut client_stream = Vec::new();
+        let mut buffer = vec![0; buffer_size];
+
+        let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits { max_head_size: 2048, ..ResponseLimits::default() }, false).await;
+
+        // nothing was sent, the client can still be answered with an error
+        assert!(matches!(result, Err(Error::HeadTooLarge { bytes_relayed: 0 })), "buffer size {}", buffer_size);
+        assert!(client_stream.is_empty());
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_times_out_on_trickled_head() {
+    let (mut upstream_writer, mut upstream_stream) = tokio::io::duplex(4096);
+    let upstream = tokio::spawn(async move {
+        upstream_writer.write_all(b"HTTP/1.1 200 OK\r\nServer: slow\r\n").await.unwrap();
+        // the end of the head never comes in time
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        upstream_writer
+    });
+
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+    let limits = ResponseLimits { head_read_timeout: Some(Duration::from_millis(100)), ..ResponseLimits::default() };
+    let started_at = Instant::now();
+
+    let result = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &limits, false).await;
+
+    assert!(matches!(result, Err(Error::HeadTimedOut { bytes_relayed: 0 })));
+    assert!(started_at.elapsed() < Duration::from_secs(2));
+    assert!(client_stream.is_empty());
+    upstream.abort();
+}
+
+
+#[test]
+fn test_status_line_prefix() {
+    for valid in [&b""[..], b"H", b"HTTP/1.", b"HTTP/1.1 2", b"HTTP/1.1 200", b"HTTP/1.1 200\r", b"HTTP/1.0 204\r\n", b"HTTP/1.1 404 Not Found\r\nServer: x"] {
+        assert!(is_status_line_prefix(valid), "{:?}", String::from_utf8_lossy(valid));
+    }
+    for invalid in [&b"SSH-2.0-OpenSSH_9.6\r\n"[..], b"\x16\x03\x01", b"http/1.1 200 OK\r\n", b"HTTP/2 200\r\n", b"HTTP/1.1 20\r\n", b"HTTP/1.1 2000 OK\r\n", b"HTTP/1.1 OK\r\n"] {
+        assert!(!is_status_line_prefix(invalid), "{:?}", String::from_utf8_lossy(invalid));
+    }
+}
+
+
+#[tokio::test]
+async fn test_relay_rejects_non_http_upstream_on_its_first_bytes() {
+    // the upstream server stays open without ever sending an empty line, the relay must not wait for one
+    let result = relay_from_open_upstream(b"SSH-2.0-OpenSSH_9.6\r\n", 1024, &Method::GET).await;
+
+    assert!(matches!(result, Err(Error::MalformedResponse { bytes_relayed: 0 })));
+}
+
+
+#[test]
+fn test_connection_close_replaces_the_connection_headers() {
+    let response = b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\nkeep-alive: timeout=5\r\n\r\nok";
+
+    assert_eq!(with_connection_close(response), b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_vec());
+    // an incomplete head is left as it is
+    assert_eq!(with_connection_close(b"HTTP/1.1 200 OK\r\nConn"), b"HTTP/1.1 200 OK\r\nConn".to_vec());
+}
+
+
+#[tokio::test]
+async fn test_relay_closing_the_client_connection_marks_the_final_response_only() {
+    let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok";
+    let mut upstream_stream: &[u8] = response;
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::POST, &AddedHeaders { server_timing: Some(Instant::now()), ..AddedHeaders::default() }, &ResponseLimits::default(), true).await.unwrap();
+
+    let received = String::from_utf8(client_stream).unwrap();
+    assert!(received.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\nServer-Timing: upstream;dur="), "{}", received);
+    assert!(received.ends_with("\r\n\r\nok"));
+    assert_eq!(relayed.bytes_relayed, received.len());
+}
+
+
+#[test]
+fn test_response_limit_of_an_upstream_overrides_the_global_one() {
+    let limit: UpstreamResponseLimit = "127.0.0.1:8081=1024".parse().unwrap();
+    let unlimited: UpstreamResponseLimit = "[::1]:8082=0".parse().unwrap();
+    assert!("127.0.0.1:8081".parse::<UpstreamResponseLimit>().is_err());
+    assert!("127.0.0.1:8081=1k".parse::<UpstreamResponseLimit>().is_err());
+    assert!("=1024".parse::<UpstreamResponseLimit>().is_err());
+
+    let config = ResponseConfig {
+        max_body_size: Some(64),
+        upstream_max_body_sizes: Arc::new(HashMap::from([
+            (limit.address, limit.max_body_size),
+            (unlimited.address, unlimited.max_body_size),
+        ])),
+        ..ResponseConfig::default()
+    };
+    assert_eq!(config.max_body_size_of("127.0.0.1:8081"), Some(1024));
+    assert_eq!(config.max_body_size_of("[::1]:8082"), None);
+    assert_eq!(config.max_body_size_of("127.0.0.1:8083"), Some(64));
+}
@@ -0,0 +1,154 @@
+use http::Request;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::routing::{failover_upstreams, HeaderMatch, Pool, TieredUpstream, UpstreamPools};
+
+
+fn pools() -> UpstreamPools {
+    UpstreamPools {
+        default: vec!["127.0.0.1:8081".to_string()],
+        canary: vec!["127.0.0.1:9091".to_string()],
+        canary_header: Some("X-Canary=true".parse().unwrap()),
+        canary_percent: 0,
+        configured: vec!["127.0.0.1:8081".to_string(), "127.0.0.1:9091".to_string()],
+        healthy: vec!["127.0.0.1:8081".to_string(), "127.0.0.1:9091".to_string()],
+    }
+}
+
+
+fn rng() -> StdRng {
+    StdRng::seed_from_u64(42)
+}
+
+
+fn request_with_header(name: &str, value: &str) -> Request<Vec<u8>> {
+    Request::builder().uri("/").header("Host", "localhost").header(name, value).body(Vec::new()).unwrap()
+}
+
+
+#[test]
+fn test_header_match_routes_to_canary_pool() {
+    let pools = pools();
+
+    let pool = pools.route(&request_with_header("x-canary", "true"), &mut rng());
+
+    assert_eq!(pool, Pool::Canary);
+    assert_eq!(pools.upstreams(pool), ["127.0.0.1:9091".to_string()]);
+}
+
+
+#[test]
+fn test_non_match_routes_to_default_pool() {
+    let pools = pools();
+
+    assert_eq!(pools.route(&request_with_header("X-Canary", "false"), &mut rng()), Pool::Default);
+    assert_eq!(pools.route(&request_with_header("X-Other", "true"), &mut rng()), Pool::Default);
+    assert_eq!(pools.upstreams(Pool::Default), ["127.0.0.1:8081".to_string()]);
+
+    // without a rule every request goes to the default pool
+    let pools = UpstreamPools { canary_header: None, ..pools };
+    assert_eq!(pools.route(&request_with_header("X-Canary", "true"), &mut rng()), Pool::Default);
+}
+
+
+#[test]
+fn test_canary_request_falls_back_without_active_canary() {
+    let pools = UpstreamPools { canary: Vec::new(), ..pools() };
+
+    assert_eq!(pools.route(&request_with_header("X-Canary", "true"), &mut rng()), Pool::Default);
+}
+
+
+#[test]
+fn test_header_match_parsing() {
+    let rule: HeaderMatch = "X-Canary = true".parse().unwrap();
+    assert_eq!(rule.name, "x-canary");
+    assert_eq!(rule.value, "true");
+
+    assert!("X-Canary".parse::<HeaderMatch>().is_err());
+    assert!("Bad Header=true".parse::<HeaderMatch>().is_err());
+}
+
+
+/// Routes `requests` requests without the canary header and returns how many went to the canary pool.
+fn canary_share(pools: &UpstreamPools, requests: usize) -> usize {
+    let mut rng = rng();
+    let request = request_with_header("X-Other", "true");
+
+    (0..requests).filter(|_| pools.route(&request, &mut rng) == Pool::Canary).count()
+}
+
+
+#[test]
+fn test_canary_percent_routes_configured_share() {
+    let pools = UpstreamPools { canary_header: None, canary_percent: 5, ..pools() };
+
+    let canary = canary_share(&pools, 10_000);
+
+    // 5% of 10000 requests, with a margin for the randomness
+    assert!((400..=600).contains(&canary), "{} requests routed to the canary pool", canary);
+
+    assert_eq!(canary_share(&UpstreamPools { canary_percent: 0, ..pools.clone() }, 1000), 0);
+    assert_eq!(canary_share(&UpstreamPools { canary_percent: 100, ..pools }, 1000), 1000);
+}
+
+
+#[test]
+fn test_canary_percent_respects_canary_health() {
+    let pools = UpstreamPools { canary: Vec::new(), canary_percent: 50, ..pools() };
+
+    assert_eq!(canary_share(&pools, 1000), 0);
+}
+
+
+#[test]
+fn test_canary_header_takes_precedence_over_percent() {
+    let pools = UpstreamPools { canary_percent: 0, ..pools() };
+
+    assert_eq!(pools.route(&request_with_header("X-Canary", "true"), &mut rng()), Pool::Canary);
+}
+
+
+#[test]
+fn test_forced_upstream_belongs_to_its_pool() {
+    let pools = pools();
+
+    assert_eq!(pools.pool_of("127.0.0.1:9091"), Pool::Canary);
+    assert_eq!(pools.pool_of("127.0.0.1:8081"), Pool::Default);
+}
+
+
+fn tiered(tier: u8, address: &str) -> TieredUpstream {
+    TieredUpstream { tier, address: address.to_string() }
+}
+
+
+#[test]
+fn test_failover_moves_down_and_back_up_the_tiers() {
+    let primary = vec!["10.0.0.1:80".to_string()];
+    let secondary = tiered(1, "10.0.1.1:80");
+    let tertiary = [tiered(2, "10.0.2.1:80"), tiered(2, "10.0.2.2:80")];
+    let all_tiers = [tertiary[0].clone(), secondary.clone(), tertiary[1].clone()];
+
+    assert_eq!(failover_upstreams(&primary, &all_tiers), vec!["10.0.0.1:80"]);
+
+    // the primary tier fails, then the secondary one
+    assert_eq!(failover_upstreams(&[], &all_tiers), vec!["10.0.1.1:80"]);
+    assert_eq!(failover_upstreams(&[], &tertiary), vec!["10.0.2.1:80", "10.0.2.2:80"]);
+    assert!(failover_upstreams(&[], &[]).is_empty());
+
+    // the secondary tier recovers, then the primary one
+    assert_eq!(failover_upstreams(&[], &all_tiers), vec!["10.0.1.1:80"]);
+    assert_eq!(failover_upstreams(&primary, &all_tiers), vec!["10.0.0.1:80"]);
+}
+
+
+#[test]
+fn test_tiered_upstream_parsing() {
+    assert_eq!("2 = 10.0.2.1:80".parse(), Ok(tiered(2, "10.0.2.1:80")));
+
+    assert!("10.0.2.1:80".parse::<TieredUpstream>().is_err());
+    assert!("0=10.0.2.1:80".parse::<TieredUpstream>().is_err());
+    assert!("backup=10.0.2.1:80".parse::<TieredUpstream>().is_err());
+}
@@ -0,0 +1,35 @@
+use std::collections::HashSet;
+
+use crate::selection::select_upstream;
+
+
+#[test]
+fn test_select_upstream_honors_exclusion_set() {
+    let upstream_addresses = vec!["127.0.0.1:8081".to_string(), "127.0.0.1:8082".to_string(), "127.0.0.1:8083".to_string()];
+    let excluded = HashSet::from(["127.0.0.1:8081".to_string(), "127.0.0.1:8083".to_string()]);
+
+    // only one candidate is left, it must be picked every time
+    for _ in 0..50 {
+        assert_eq!(select_upstream(&upstream_addresses, &excluded), Some("127.0.0.1:8082".to_string()));
+    }
+}
+
+
+#[test]
+fn test_select_upstream_without_exclusion() {
+    let upstream_addresses = vec!["127.0.0.1:8081".to_string(), "127.0.0.1:8082".to_string()];
+
+    let selected = select_upstream(&upstream_addresses, &HashSet::new()).unwrap();
+
+    assert!(upstream_addresses.contains(&selected));
+}
+
+
+#[test]
+fn test_select_upstream_only_remaining_upstream_excluded() {
+    let upstream_addresses = vec!["127.0.0.1:8081".to_string()];
+    let excluded = HashSet::from(["127.0.0.1:8081".to_string()]);
+
+    assert_eq!(select_upstream(&upstream_addresses, &excluded), None);
+    assert_eq!(select_upstream(&[], &HashSet::new()), None);
+}
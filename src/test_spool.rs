@@ -0,0 +1,138 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::request::{read_client_request, write_to_stream, Error, RequestConfig};
+use crate::spool::{BodySpool, SpooledBody};
+
+
+/// Creates a spool directory unique to this test run.
+fn spool_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let dir = std::env::temp_dir().join(format!("lb-spool-{}-{}-{}", name, std::process::id(), nanos));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+
+fn spool_config(dir: &Path, memory_limit: usize) -> (RequestConfig, Arc<BodySpool>) {
+    let body_spool = Arc::new(BodySpool::new(memory_limit, dir.to_path_buf()));
+    (RequestConfig { body_spool: Some(body_spool.clone()), ..RequestConfig::default() }, body_spool)
+}
+
+
+fn spool_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+
+#[tokio::test]
+async fn test_small_bodies_stay_in_memory() {
+    let dir = spool_dir("memory");
+    let (config, body_spool) = spool_config(&dir, 16);
+    let mut stream = Cursor::new(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello".to_vec());
+    let mut buffer = [0; 1024];
+
+    let request = read_client_request(&mut stream, &mut buffer, &config).await.unwrap();
+
+    assert_eq!(request.body(), b"hello");
+    assert!(request.extensions().get::<SpooledBody>().is_none());
+    assert_eq!(spool_files(&dir), 0);
+    assert_eq!(body_spool.spilled(), 0);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+
+#[tokio::test]
+async fn test_large_bodies_are_spilled_and_sent_again_from_disk() {
+    let dir = spool_dir("spilled");
+    let (config, body_spool) = spool_config(&dir, 16);
+    let body = "0123456789".repeat(100);
+    let raw = format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let mut stream = Cursor::new(raw.clone().into_bytes());
+    // a small buffer, so the body arrives in several reads
+    let mut buffer = [0; 128];
+
+    let request = read_client_request(&mut stream, &mut buffer, &config).await.unwrap();
+
+    assert!(request.body().is_empty());
+    let spooled = request.extensions().get::<SpooledBody>().unwrap().clone();
+    assert_eq!(spooled.len(), 1000);
+    assert_eq!(std::fs::read(spooled.path()).unwrap(), body.as_bytes());
+    assert_eq!((body_spool.spilled(), body_spool.spilled_bytes()), (1, 1000));
+
+    // the body is streamed after the head, and again in full for a retry
+    for _ in 0..2 {
+        let mut forwarded = Vec::new();
+        let written = write_to_stream(&request, &mut forwarded).await.unwrap();
+        assert_eq!(written, forwarded.len());
+        assert!(String::from_utf8(forwarded).unwrap().ends_with(&format!("\r\n\r\n{}", body)));
+    }
+
+    // the file is deleted with the last handle of the request
+    drop(request);
+    assert!(spooled.path().exists());
+    drop(spooled);
+    assert_eq!(spool_files(&dir), 0);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+
+#[tokio::test]
+async fn test_large_chunked_bodies_are_spilled_once_decoded() {
+    let dir = spool_dir("chunked");
+    let (config, body_spool) = spool_config(&dir, 16);
+    let chunks: String = (0..10).map(|_| format!("64\r\n{}\r\n", "0123456789".repeat(10))).collect();
+    let raw = format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n", chunks);
+    let mut buffer = [0; 128];
+
+    let request = read_client_request(&mut Cursor::new(raw.into_bytes()), &mut buffer, &config).await.unwrap();
+
+    let spooled = request.extensions().get::<SpooledBody>().unwrap();
+    assert_eq!(std::fs::read(spooled.path()).unwrap(), "0123456789".repeat(100).as_bytes());
+    assert_eq!((body_spool.spilled(), body_spool.spilled_bytes()), (1, 1000));
+
+    // streamed from disk as a single chunk
+    let mut forwarded = Vec::new();
+    write_to_stream(&request, &mut forwarded).await.unwrap();
+    assert!(String::from_utf8(forwarded).unwrap().ends_with(&format!("\r\n\r\n3e8\r\n{}\r\n0\r\n\r\n", "0123456789".repeat(100))));
+
+    drop(request);
+    assert_eq!(spool_files(&dir), 0);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+
+#[tokio::test]
+async fn test_spool_file_is_deleted_when_the_client_aborts() {
+    let dir = spool_dir("abort");
+    let (config, body_spool) = spool_config(&dir, 16);
+    let (mut client, mut server) = tokio::io::duplex(4096);
+    client.write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\n\r\npartial body").await.unwrap();
+    drop(client);
+    let mut buffer = [0; 1024];
+
+    let result = read_client_request(&mut server, &mut buffer, &config).await;
+
+    assert!(matches!(result, Err(Error::ConnectionError)));
+    assert_eq!(spool_files(&dir), 0);
+    assert_eq!(body_spool.spilled(), 0);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+
+#[tokio::test]
+async fn test_spool_failures_are_reported() {
+    let dir = spool_dir("missing");
+    std::fs::remove_dir(&dir).unwrap();
+    let (config, _) = spool_config(&dir, 4);
+    let mut stream = Cursor::new(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n0123456789".to_vec());
+    let mut buffer = [0; 1024];
+
+    let result = read_client_request(&mut stream, &mut buffer, &config).await;
+
+    assert!(matches!(result, Err(Error::SpoolFailed { out_of_space: false })));
+}
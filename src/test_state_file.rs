@@ -0,0 +1,61 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::health_metrics::HealthMetrics;
+use crate::state_file::{load_state, parse_state, save_state};
+
+
+#[test]
+fn test_saved_state_is_loaded() {
+    let mut metrics = HealthMetrics::default();
+    metrics.record_probe("127.0.0.1:8081", Duration::from_millis(5), true);
+    metrics.record_probe("127.0.0.1:8081", Duration::from_millis(5), false);
+    metrics.record_probe("127.0.0.1:8082", Duration::from_millis(5), true);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let path = std::env::temp_dir().join(format!("lb-state-{}-{}", std::process::id(), nanos));
+    save_state(&path, &metrics).unwrap();
+    let records = load_state(&path, Duration::from_secs(60)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let down = &records["127.0.0.1:8081"];
+    assert!(!down.healthy);
+    assert!(down.last_success.is_some());
+    assert_eq!((down.probes, down.failures), (2, 1));
+    assert!(records["127.0.0.1:8082"].healthy);
+
+    // the counters carry on from the restored values
+    let mut restarted = HealthMetrics::default();
+    for (address, record) in records {
+        restarted.restore_probe(address, record);
+    }
+    restarted.record_probe("127.0.0.1:8081", Duration::from_millis(5), false);
+    let record = restarted.probe_record("127.0.0.1:8081").unwrap();
+    assert_eq!((record.probes, record.failures), (3, 2));
+}
+
+
+#[test]
+fn test_stale_state_is_ignored() {
+    let state = br#"{"saved_at": 1000, "upstreams": {"127.0.0.1:8081": {"healthy": false, "last_success": null, "probes": 1, "failures": 1}}}"#;
+
+    let saved_at = UNIX_EPOCH + Duration::from_secs(1000);
+    assert_eq!(parse_state(state, saved_at + Duration::from_secs(60), Duration::from_secs(300)).unwrap().len(), 1);
+    assert!(parse_state(state, saved_at + Duration::from_secs(600), Duration::from_secs(300)).is_err());
+}
+
+
+#[test]
+fn test_corrupt_state_is_rejected() {
+    let now = SystemTime::now();
+    let max_age = Duration::from_secs(300);
+
+    assert!(parse_state(b"", now, max_age).is_err());
+    assert!(parse_state(b"{\"saved_at\": 1000, \"upstreams\": {\"127.0.0.1:8081\": {\"heal", now, max_age).is_err());
+    assert!(parse_state(br#"{"upstreams": {}}"#, now, max_age).is_err());
+
+    let saved_at = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let state = format!(r#"{{"saved_at": {}, "upstreams": {{"127.0.0.1:8081": {{"healthy": "yes"}}}}}}"#, saved_at);
+    assert!(parse_state(state.as_bytes(), now, max_age).is_err());
+
+    assert!(load_state(std::path::Path::new("/nonexistent/lb-state"), max_age).is_err());
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+
+use rust_loadbalancer::health_metrics::HealthMetrics;
+use rust_loadbalancer::state_file::save_state;
+use crate::{CmdOptions, ProxyState};
+
+
+/// Returns a path for a state file unique to this test run.
+fn state_file_path() -> std::path::PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    std::env::temp_dir().join(format!("lb-state-restore-{}-{}", std::process::id(), nanos))
+}
+
+
+#[test]
+fn test_known_down_upstream_stays_out_of_rotation_after_restart() {
+    let path = state_file_path();
+
+    // the previous run found the first upstream server down
+    let mut metrics = HealthMetrics::default();
+    metrics.record_probe("127.0.0.1:8081", Duration::from_millis(5), false);
+    metrics.record_probe("127.0.0.1:8082", Duration::from_millis(5), true);
+    save_state(&path, &metrics).unwrap();
+
+    let args = CmdOptions::parse_from([
+        "rust_loadbalancer",
+        "--upstream", "127.0.0.1:8081",
+        "--upstream", "127.0.0.1:8082",
+        "--upstream", "127.0.0.1:8083",
+        "--state-file", path.to_str().unwrap(),
+    ]);
+    let state = ProxyState::new(args);
+    std::fs::remove_file(&path).unwrap();
+
+    // before any health check, only the upstream server known to be healthy is active
+    assert_eq!(state.active_upstream_addresses, vec!["127.0.0.1:8082"]);
+    assert_eq!(state.health_metrics.probe_record("127.0.0.1:8081").unwrap().failures, 1);
+}
+
+
+#[test]
+fn test_unreadable_state_file_is_ignored() {
+    let path = state_file_path();
+    std::fs::write(&path, "not a state file").unwrap();
+
+    let args = CmdOptions::parse_from(["rust_loadbalancer", "--upstream", "127.0.0.1:8081", "--state-file", path.to_str().unwrap()]);
+    let state = ProxyState::new(args);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(state.active_upstream_addresses.is_empty());
+    assert!(state.health_metrics.probe_record("127.0.0.1:8081").is_none());
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use http::{Request, StatusCode};
+
+use crate::static_route::{find, StaticRoute, MAX_STATIC_BODY_SIZE};
+
+
+fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+    let mut request = Request::builder().method(method).uri(path).header("Host", "localhost");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Vec::new()).unwrap()
+}
+
+
+/// Returns a file path unique to this test run.
+fn file_path(name: &str) -> std::path::PathBuf {
+    let nanos = std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    std::env::temp_dir().join(format!("lb-static-{}-{}-{}", name, std::process::id(), nanos))
+}
+
+
+#[test]
+fn test_file_route_is_served() {
+    let path = file_path("robots");
+    std::fs::write(&path, "User-agent: *\nDisallow: /\n").unwrap();
+    let route: StaticRoute = format!("/robots.txt file={} content_type=text/plain", path.display()).parse().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let (status, response) = route.respond(&request("GET", "/robots.txt", &[]));
+    let response = String::from_utf8(response).unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nLast-Modified: "), "{}", response);
+    assert!(response.ends_with(" GMT\r\nContent-Length: 26\r\n\r\nUser-agent: *\nDisallow: /\n"), "{}", response);
+
+    // a HEAD request gets the head only
+    let (_, response) = route.respond(&request("HEAD", "/robots.txt", &[]));
+    assert!(String::from_utf8(response).unwrap().ends_with(" GMT\r\nContent-Length: 26\r\n\r\n"));
+}
+
+
+#[test]
+fn test_conditional_request_is_answered_304() {
+    let mut route: StaticRoute = r#"/version body={"version":"1.4.2"} content_type=application/json"#.parse().unwrap();
+    route.last_modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let (status, response) = route.respond(&request("GET", "/version", &[("If-Modified-Since", "Tue, 14 Nov 2023 22:13:20 GMT")]));
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    assert_eq!(response, b"HTTP/1.1 304 Not Modified\r\nLast-Modified: Tue, 14 Nov 2023 22:13:20 GMT\r\n\r\n");
+
+    // an older date, or one that can't be parsed, gets the body
+    for date in ["Tue, 14 Nov 2023 22:13:19 GMT", "yesterday"] {
+        let (status, response) = route.respond(&request("GET", "/version", &[("If-Modified-Since", date)]));
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.ends_with(br#"{"version":"1.4.2"}"#));
+    }
+
+    let (status, response) = route.respond(&request("POST", "/version", &[]));
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert!(String::from_utf8(response).unwrap().contains("Allow: GET, HEAD\r\n"));
+}
+
+
+#[test]
+fn test_route_is_found_by_exact_path() {
+    let routes: Vec<StaticRoute> = ["/robots.txt body=none", "/version body=1"].iter().map(|route| route.parse().unwrap()).collect();
+
+    assert_eq!(find(&routes, &request("GET", "/version?format=json", &[])).unwrap().path, "/version");
+    assert!(find(&routes, &request("GET", "/version/", &[])).is_none());
+    assert!(find(&routes, &request("GET", "/", &[])).is_none());
+}
+
+
+#[test]
+fn test_invalid_routes_are_rejected() {
+    let large = file_path("large");
+    std::fs::write(&large, vec![b'x'; MAX_STATIC_BODY_SIZE + 1]).unwrap();
+    let missing = file_path("missing");
+
+    for route in [
+        "robots.txt body=none".to_string(),
+        "/robots.txt".to_string(),
+        "/robots.txt content_type=text/plain".to_string(),
+        "/robots.txt status=200 body=none".to_string(),
+        format!("/robots.txt file={}", missing.display()),
+        format!("/robots.txt file={}", large.display()),
+    ] {
+        assert!(route.parse::<StaticRoute>().is_err(), "{:?} was accepted", route);
+    }
+    std::fs::remove_file(&large).unwrap();
+}
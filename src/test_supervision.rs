@@ -0,0 +1,56 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::sync::Mutex;
+
+use rust_loadbalancer::supervisor::supervise;
+use crate::{health_check_loop, CmdOptions, ProxyState, INJECT_HEALTH_CHECK_PANIC};
+
+
+/// Starts an upstream server answering every health check with 200 OK, and returns its address.
+fn healthy_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+    });
+    address
+}
+
+
+#[tokio::test]
+async fn test_health_checks_recover_from_a_panic() {
+    let upstream = healthy_upstream();
+    let args = CmdOptions::parse_from(["rust_loadbalancer", "--upstream", &upstream, "--interval", "1"]);
+    let shared_state = Arc::new(Mutex::new(ProxyState::new(args)));
+    let task_restarts = shared_state.lock().await.task_restarts.clone();
+
+    INJECT_HEALTH_CHECK_PANIC.store(true, Ordering::SeqCst);
+    let thread_state = shared_state.clone();
+    let supervisor_restarts = task_restarts.clone();
+    let supervisor = tokio::spawn(async move {
+        supervise("health_checks", &supervisor_restarts, || health_check_loop(thread_state.clone())).await
+    });
+
+    // the first cycle panics, the restarted loop checks the upstream server
+    let mut recovered = false;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if shared_state.lock().await.active_upstream_addresses == [upstream.clone()] {
+            recovered = true;
+            break;
+        }
+    }
+    supervisor.abort();
+
+    assert!(recovered, "the health checks were not restarted");
+    assert_eq!(task_restarts.restarts("health_checks"), 1);
+    assert!(shared_state.lock().await.render_metrics().contains("lb_task_restarts_total{task=\"health_checks\"} 1\n"));
+}
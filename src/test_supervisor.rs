@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::supervisor::{supervise, TaskRestarts};
+
+
+#[tokio::test]
+async fn test_panicking_task_is_restarted_until_it_completes() {
+    let restarts = TaskRestarts::default();
+    let runs = Arc::new(AtomicU32::new(0));
+
+    supervise("flaky", &restarts, || {
+        let runs = runs.clone();
+        async move {
+            if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                panic!("flaky task failed");
+            }
+        }
+    }).await;
+
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert_eq!(restarts.restarts("flaky"), 2);
+    assert_eq!(restarts.restarts("steady"), 0);
+}
+
+
+#[test]
+fn test_restarts_and_connection_panics_are_rendered() {
+    let restarts = TaskRestarts::default();
+    restarts.record_restart("health_checks");
+    restarts.record_restart("health_checks");
+    restarts.record_restart("accept");
+    restarts.record_connection_panic();
+
+    assert_eq!(restarts.connection_panics(), 1);
+    assert_eq!(restarts.render_prometheus(), "# TYPE lb_task_restarts_total counter\n\
+        lb_task_restarts_total{task=\"accept\"} 1\n\
+        lb_task_restarts_total{task=\"health_checks\"} 2\n\
+        # TYPE lb_connection_panics_total counter\n\
+        lb_connection_panics_total 1\n");
+}
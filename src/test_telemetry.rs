@@ -0,0 +1,90 @@
+use std::time::Instant;
+
+use http::Request;
+
+use crate::telemetry::RequestSpan;
+
+
+fn request(method: &str, path: &str) -> Request<Vec<u8>> {
+    Request::builder().method(method).uri(path).header("Host", "localhost").body(Vec::new()).unwrap()
+}
+
+
+#[test]
+fn test_spans_are_recorded_without_a_subscriber() {
+    let span = RequestSpan::start(&request("GET", "/"), "192.0.2.1:54321".parse().unwrap(), Instant::now());
+
+    // without --otel-endpoint nothing listens, recording is a no-op
+    span.record_upstream("10.0.0.1:80");
+    span.record_status(200, Instant::now());
+}
+
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use opentelemetry::trace::SpanKind;
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData, SpanExporter};
+
+    use super::request;
+    use crate::telemetry::{subscriber, RequestSpan};
+
+    /// Exporter keeping the spans it is given, in memory.
+    #[derive(Debug, Clone, Default)]
+    struct TestExporter {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanExporter for TestExporter {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.spans.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+
+    #[test]
+    fn test_request_span_is_exported_with_its_attributes() {
+        let exporter = TestExporter::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+
+        tracing::subscriber::with_default(subscriber(&provider), || {
+            let started = Instant::now();
+            let span = RequestSpan::start(&request("POST", "/orders?id=1"), "192.0.2.1:54321".parse().unwrap(), started);
+            span.record_upstream("10.0.0.1:80");
+            span.record_status(201, started + Duration::from_millis(42));
+        });
+
+        let spans = exporter.spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "request");
+        assert_eq!(spans[0].span_kind, SpanKind::Server);
+        let attributes: HashMap<String, String> = spans[0].attributes.iter().map(|attribute| (attribute.key.to_string(), attribute.value.to_string())).collect();
+        assert_eq!(attributes["http.request.method"], "POST");
+        assert_eq!(attributes["url.path"], "/orders");
+        assert_eq!(attributes["client.address"], "192.0.2.1");
+        assert_eq!(attributes["upstream.address"], "10.0.0.1:80");
+        assert_eq!(attributes["http.response.status_code"], "201");
+        assert_eq!(attributes["duration_ms"], "42");
+    }
+
+
+    #[test]
+    fn test_error_answered_before_selection_has_no_upstream() {
+        let exporter = TestExporter::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+
+        tracing::subscriber::with_default(subscriber(&provider), || {
+            let span = RequestSpan::start(&request("GET", "/"), "192.0.2.1:54321".parse().unwrap(), Instant::now());
+            span.record_status(503, Instant::now());
+        });
+
+        let spans = exporter.spans.lock().unwrap();
+        assert!(spans[0].attributes.iter().all(|attribute| attribute.key.as_str() != "upstream.address"));
+        assert!(spans[0].attributes.iter().any(|attribute| attribute.key.as_str() == "http.response.status_code" && attribute.value.to_string() == "503"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+use http::Method;
+use tokio::io::AsyncWriteExt;
+
+use crate::response::{relay_response, AddedHeaders, ResponseLimits};
+use crate::timing::{PhaseDurations, Timings, PHASES};
+
+
+#[test]
+fn test_breakdown_of_every_phase() {
+    let started = Instant::now();
+    let timings = Timings {
+        started,
+        request_read: started + Duration::from_millis(2),
+        queued: Duration::from_millis(5),
+        connected: started + Duration::from_millis(10),
+        first_byte: started + Duration::from_millis(30),
+        relayed: started + Duration::from_millis(45),
+        client_write: Duration::from_millis(4),
+    };
+
+    let breakdown = timings.to_json();
+
+    assert_eq!(breakdown["header_read_ms"], 2.0);
+    assert_eq!(breakdown["queue_ms"], 5.0);
+    assert_eq!(breakdown["connect_ms"], 3.0);
+    assert_eq!(breakdown["upstream_ttfb_ms"], 20.0);
+    assert_eq!(breakdown["upstream_body_ms"], 11.0);
+    assert_eq!(breakdown["client_write_ms"], 4.0);
+    assert_eq!(breakdown["total_ms"], 45.0);
+}
+
+
+#[test]
+fn test_phase_histograms_observe_every_phase() {
+    let started = Instant::now();
+    let timings = Timings {
+        started,
+        request_read: started + Duration::from_millis(2),
+        queued: Duration::ZERO,
+        connected: started + Duration::from_millis(2),
+        first_byte: started + Duration::from_millis(302),
+        relayed: started + Duration::from_millis(310),
+        client_write: Duration::from_millis(1),
+    };
+    let phase_durations = PhaseDurations::default();
+
+    phase_durations.record(&timings);
+    phase_durations.record(&Timings::new(started));
+
+    let ttfb = phase_durations.histogram("upstream_ttfb").unwrap();
+    assert_eq!((ttfb.count, ttfb.buckets[0], ttfb.buckets[6]), (2, 1, 1));
+    assert!((ttfb.sum - 0.3).abs() < 1e-9, "{}", ttfb.sum);
+    assert!(phase_durations.histogram("dns").is_none());
+
+    let rendered = phase_durations.render_prometheus();
+    for phase in PHASES {
+        assert!(rendered.contains(&format!("lb_request_phase_duration_seconds_count{{phase=\"{}\"}} 2\n", phase)), "{}", rendered);
+    }
+    assert!(rendered.contains("lb_request_phase_duration_seconds_bucket{phase=\"upstream_ttfb\",le=\"0.25\"} 1\n"));
+    assert!(rendered.contains("lb_request_phase_duration_seconds_bucket{phase=\"upstream_ttfb\",le=\"0.5\"} 2\n"));
+}
+
+
+#[test]
+fn test_phases_not_gone_through_are_zero() {
+    let breakdown = Timings::new(Instant::now()).to_json();
+
+    for field in ["header_read_ms", "queue_ms", "connect_ms", "upstream_ttfb_ms", "upstream_body_ms", "client_write_ms", "total_ms"] {
+        assert_eq!(breakdown[field], 0.0, "{}", field);
+    }
+}
+
+
+#[tokio::test]
+async fn test_timings_of_a_delayed_response() {
+    let (mut upstream_writer, mut upstream_stream) = tokio::io::duplex(4096);
+    let upstream = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        upstream_writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await.unwrap();
+        upstream_writer
+    });
+
+    // fill the timings the way the proxy does around a relayed response
+    let mut timings = Timings::new(Instant::now());
+    timings.request_read = Instant::now();
+    timings.connected = Instant::now();
+    let mut client_stream = Vec::new();
+    let mut buffer = vec![0; 1024];
+    let relayed = relay_response(&mut upstream_stream, &mut client_stream, &mut buffer, &Method::GET, &AddedHeaders::default(), &ResponseLimits::default(), false).await.unwrap();
+    timings.first_byte = relayed.first_byte_at;
+    timings.relayed = Instant::now();
+    timings.client_write = relayed.client_write_time;
+    drop(upstream.await.unwrap());
+
+    assert!(timings.checkpoints().windows(2).all(|pair| pair[0] <= pair[1]));
+    let breakdown = timings.to_json();
+    let ttfb = breakdown["upstream_ttfb_ms"].as_f64().unwrap();
+    assert!((100.0..1000.0).contains(&ttfb), "{}", ttfb);
+    assert!(breakdown["total_ms"].as_f64().unwrap() >= ttfb);
+    assert_eq!(relayed.status, 200);
+}
@@ -0,0 +1,91 @@
+use std::io::Cursor;
+
+use http::Request;
+
+use crate::request::{add_forwarded_scheme, client_request_builder, request_controller, ForwardedHeaderFormat, RequestConfig};
+use crate::upstream_host::{upstream_host, ClientHost, HostRule};
+
+
+fn request(host: &str, path: &str) -> Request<Vec<u8>> {
+    Request::builder().uri(path).header("Host", host).body(Vec::new()).unwrap()
+}
+
+
+fn rules(rules: &[&str]) -> Vec<HostRule> {
+    rules.iter().map(|rule| rule.parse().unwrap()).collect()
+}
+
+
+/// Returns the `Host` header the request is forwarded with.
+fn forwarded_host(rules: &[HostRule], request: &Request<Vec<u8>>) -> String {
+    let forwarded = client_request_builder(None, ForwardedHeaderFormat::XForwardedFor, request, 1, rules).unwrap();
+    let hosts: Vec<_> = forwarded.headers().get_all("Host").iter().map(|host| host.to_str().unwrap().to_string()).collect();
+    assert_eq!(hosts.len(), 1, "{:?}", hosts);
+    hosts[0].clone()
+}
+
+
+#[test]
+fn test_client_host_is_preserved_by_default() {
+    assert_eq!(forwarded_host(&[], &request("shop.example.com", "/")), "shop.example.com");
+
+    // a preserve rule stops the rules after it
+    let rules = rules(&["preserve path=^/legacy/", "fixed=backend.internal"]);
+    assert_eq!(forwarded_host(&rules, &request("shop.example.com", "/legacy/cart")), "shop.example.com");
+    assert_eq!(forwarded_host(&rules, &request("shop.example.com", "/cart")), "backend.internal");
+}
+
+
+#[test]
+fn test_fixed_host_replaces_the_client_host() {
+    let rules = rules(&["fixed=api.internal:8080 path=^/api/ host=^shop\\."]);
+
+    assert_eq!(forwarded_host(&rules, &request("shop.example.com", "/api/orders")), "api.internal:8080");
+    // both conditions must hold
+    assert_eq!(forwarded_host(&rules, &request("blog.example.com", "/api/orders")), "blog.example.com");
+    assert_eq!(forwarded_host(&rules, &request("shop.example.com", "/orders")), "shop.example.com");
+
+    // the client's host is kept for X-Forwarded-Host
+    let mut forwarded = client_request_builder(None, ForwardedHeaderFormat::XForwardedFor, &request("shop.example.com", "/api/orders"), 1, &rules).unwrap();
+    assert_eq!(forwarded.extensions().get::<ClientHost>().unwrap().0, "shop.example.com");
+    add_forwarded_scheme(&mut forwarded, "http", 8080, "192.0.2.1".parse().unwrap(), &RequestConfig::default());
+    assert_eq!(forwarded.headers()["X-Forwarded-Host"], "shop.example.com");
+    assert_eq!(forwarded.headers()["Host"], "api.internal:8080");
+}
+
+
+#[test]
+fn test_host_is_derived_from_the_matched_vhost() {
+    let rules = rules(&[
+        r"vhost=${app}-${env}.internal host=^(?P<app>[a-z]+)\.(?P<env>staging|prod)\.example\.com(:\d+)?$",
+        r"vhost=$1.internal host=^([a-z]+)\.example\.com$",
+    ]);
+
+    assert_eq!(forwarded_host(&rules, &request("shop.staging.example.com:8080", "/")), "shop-staging.internal");
+    assert_eq!(forwarded_host(&rules, &request("blog.example.com", "/")), "blog.internal");
+    // a host no vhost rule matches is preserved
+    assert_eq!(forwarded_host(&rules, &request("example.org", "/")), "example.org");
+}
+
+
+#[tokio::test]
+async fn test_request_controller_applies_the_rules() {
+    let config = RequestConfig { upstream_host_rules: rules(&["fixed=backend.internal"]), ..RequestConfig::default() };
+    let mut buffer = vec![0; 1024];
+
+    let forwarded = request_controller(&mut Cursor::new(b"GET / HTTP/1.1\r\nHost: shop.example.com\r\n\r\n".to_vec()), "10.0.0.1:1234".parse().unwrap(), &mut buffer, &config).await.unwrap();
+
+    assert_eq!(forwarded.headers()["Host"], "backend.internal");
+}
+
+
+#[test]
+fn test_invalid_rules_are_rejected() {
+    for rule in ["rewrite=backend.internal", "fixed=", "preserve path=(", "vhost=$1.internal path=^/", "fixed=a method=GET", "fixed=a path"] {
+        assert!(rule.parse::<HostRule>().is_err(), "{}", rule);
+    }
+
+    // a vhost template expanding to nothing keeps the client's host
+    let rules = rules(&[r"vhost=$2 host=^(.+)$"]);
+    assert_eq!(upstream_host(&rules, &request("shop.example.com", "/")), None);
+}
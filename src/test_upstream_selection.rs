@@ -0,0 +1,64 @@
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use rust_loadbalancer::connect::{self, Connector, FailureKind};
+use rust_loadbalancer::context::ProxyContext;
+use rust_loadbalancer::request::RequestConfig;
+use rust_loadbalancer::response::ResponseConfig;
+use crate::connect_to_upstream_server;
+
+
+fn context(connector: Connector) -> ProxyContext {
+    ProxyContext::new(RequestConfig::default(), ResponseConfig::default(), connector)
+}
+
+
+#[tokio::test]
+async fn test_connect_excludes_failed_upstream() {
+    // reserve a port and release it so nothing is listening on it
+    let closed_address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open_address = listener.local_addr().unwrap().to_string();
+
+    let upstream_addresses = vec![closed_address.clone(), open_address.clone()];
+    let context = context(Connector::new(0, Duration::from_millis(10), None));
+
+    for _ in 0..10 {
+        let mut excluded = HashSet::new();
+        let (slot, stream, _) = connect_to_upstream_server(&upstream_addresses, &mut excluded, &context, None).await.unwrap();
+
+        assert_eq!(slot.upstream_address(), open_address);
+        assert_eq!(stream.peer_addr().unwrap().to_string(), open_address);
+        assert!(!excluded.contains(&open_address));
+    }
+}
+
+
+#[tokio::test]
+async fn test_connect_fails_when_every_upstream_fails() {
+    let closed_address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let upstream_addresses = vec![closed_address.clone()];
+    let mut excluded = HashSet::new();
+    let context = context(Connector::new(1, Duration::from_millis(10), None));
+
+    let connected = connect_to_upstream_server(&upstream_addresses, &mut excluded, &context, None).await;
+    assert!(matches!(connected, Err(connect::Error::NoUpstream(Some(FailureKind::Refused)))));
+    assert!(excluded.contains(&closed_address));
+}
+
+
+#[tokio::test]
+async fn test_connect_budget_covers_every_upstream() {
+    let upstream_addresses: Vec<_> = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string())
+        .collect();
+    let mut excluded = HashSet::new();
+
+    // each upstream server alone fits in the budget, but not all three
+    let context = context(Connector::new(2, Duration::from_millis(40), Some(Duration::from_millis(150))));
+
+    let connected = connect_to_upstream_server(&upstream_addresses, &mut excluded, &context, context.connector.deadline()).await;
+    assert!(matches!(connected, Err(connect::Error::BudgetExhausted)));
+    assert!(excluded.len() < upstream_addresses.len());
+}
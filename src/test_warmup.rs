@@ -0,0 +1,63 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::http_health_checks::HealthCheckRequest;
+use crate::warmup::WarmUp;
+
+
+/// Starts a local upstream on port 0 failing its first `failures` requests with 500, and answering the next ones
+/// with 200 OK. Returns its address and the number of requests it received.
+fn recovering_upstream(failures: usize) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let received = Arc::new(AtomicUsize::new(0));
+
+    let counter = received.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).unwrap();
+            let response = match counter.fetch_add(1, Ordering::SeqCst) < failures {
+                true => "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+                false => "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    (address, received)
+}
+
+
+fn warm_up(requests: u32) -> WarmUp {
+    WarmUp { requests, interval: Duration::from_millis(10), request: HealthCheckRequest::get("/warmup".to_string()) }
+}
+
+
+#[tokio::test]
+async fn test_warm_up_requires_every_request_to_succeed() {
+    let (address, received) = recovering_upstream(2);
+    let warm_up = warm_up(3);
+
+    // the warm-up stops at the first failure, the upstream server isn't admitted
+    assert!(!warm_up.run(&address).await);
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+    assert!(!warm_up.run(&address).await);
+    assert_eq!(received.load(Ordering::SeqCst), 2);
+
+    // admitted once all the warm-up requests succeeded
+    assert!(warm_up.run(&address).await);
+    assert_eq!(received.load(Ordering::SeqCst), 5);
+}
+
+
+#[tokio::test]
+async fn test_warm_up_of_unreachable_upstream_fails() {
+    let closed_address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+    assert!(!warm_up(1).run(&closed_address).await);
+}
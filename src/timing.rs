@@ -0,0 +1,152 @@
+//! # Timing Module
+//!
+//! This module breaks the time spent on a request down into the phases it went through, for the access log.
+//!
+//! A slow response alone doesn't tell whether the client sent its headers slowly, the request waited for an upstream
+//! server at capacity, the connection took long to establish, the upstream server took long to answer or to send its
+//! body, or the client was slow to receive it. Every request carries a `Timings` record, filled with an
+//! `Instant::now()` at every phase boundary, and its breakdown is rendered as fields of the JSON access log line:
+//!
+//! - `header_read_ms`: Reading the request line and headers from the client. For the following requests of a
+//!   kept-alive connection, this includes the time the connection was idle.
+//! - `queue_ms`: Waiting for a slot of an upstream server at capacity.
+//! - `connect_ms`: Connecting to the upstream server, zero when the connection of the previous request is reused.
+//! - `upstream_ttfb_ms`: Forwarding the request and waiting for the first byte of the response.
+//! - `upstream_body_ms`: Receiving the rest of the response from the upstream server.
+//! - `client_write_ms`: Writing the response to the client.
+//! - `total_ms`: The whole request, from the start of its read to the end of its response.
+//!
+//! A phase a request didn't go through is zero, never absent, so every line has the same fields.
+//!
+//! The durations of the phases of every relayed response are also observed in a histogram per phase, rendered in the
+//! Prometheus text format as `lb_request_phase_duration_seconds{phase}`, the phase named as its access log field
+//! without the `_ms` suffix.
+//!
+//! ## Structures
+//!
+//! - `Timings`: The instants at the phase boundaries of a request.
+//! - `PhaseDurations`: The histograms of the durations of every phase of the relayed responses.
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
+use crate::health_metrics::{DurationHistogram, DURATION_BUCKETS};
+
+/// Names of the phases of a request, in the order of `Timings::phases`.
+pub const PHASES: [&str; 7] = ["header_read", "queue", "connect", "upstream_ttfb", "upstream_body", "client_write", "total"];
+
+/// The instants at the phase boundaries of a request, and the durations of the phases measured on their own.
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    /// The instant the proxy started reading the request.
+    pub started: Instant,
+
+    /// The instant the request line and headers were read.
+    pub request_read: Instant,
+
+    /// Time the request waited for a slot of an upstream server.
+    pub queued: Duration,
+
+    /// The instant the connection to the upstream server was ready.
+    pub connected: Instant,
+
+    /// The instant the first byte of the response was received.
+    pub first_byte: Instant,
+
+    /// The instant the response was relayed to the client.
+    pub relayed: Instant,
+
+    /// Time spent writing the response to the client.
+    pub client_write: Duration,
+}
+
+impl Timings {
+    /// Starts the timings of a request whose read starts at `started`, every phase boundary set to it.
+    pub fn new(started: Instant) -> Timings {
+        Timings {
+            started,
+            request_read: started,
+            queued: Duration::ZERO,
+            connected: started,
+            first_byte: started,
+            relayed: started,
+            client_write: Duration::ZERO,
+        }
+    }
+
+    /// Returns the instants at the phase boundaries, in the order the request goes through them.
+    pub fn checkpoints(&self) -> [Instant; 5] {
+        [self.started, self.request_read, self.connected, self.first_byte, self.relayed]
+    }
+
+    /// Returns the duration of every phase, in the order of `PHASES`.
+    pub fn phases(&self) -> [Duration; PHASES.len()] {
+        let connecting = self.connected.saturating_duration_since(self.request_read);
+        let receiving = self.relayed.saturating_duration_since(self.first_byte);
+
+        [
+            self.request_read.saturating_duration_since(self.started),
+            self.queued,
+            connecting.saturating_sub(self.queued),
+            self.first_byte.saturating_duration_since(self.connected),
+            receiving.saturating_sub(self.client_write),
+            self.client_write,
+            self.relayed.saturating_duration_since(self.started),
+        ]
+    }
+
+    /// Renders the duration of every phase as the fields of an access log line, in milliseconds.
+    pub fn to_json(&self) -> Value {
+        let fields: Map<String, Value> = PHASES.iter()
+            .zip(self.phases())
+            .map(|(phase, duration)| (format!("{}_ms", phase), milliseconds(duration).into()))
+            .collect();
+        Value::Object(fields)
+    }
+}
+
+/// The histograms of the durations of every phase of the relayed responses, in the order of `PHASES`.
+#[derive(Debug, Default)]
+pub struct PhaseDurations {
+    histograms: Mutex<[DurationHistogram; PHASES.len()]>,
+}
+
+impl PhaseDurations {
+    /// Observes the duration of every phase of a relayed response.
+    pub fn record(&self, timings: &Timings) {
+        let mut histograms = self.histograms.lock().unwrap();
+        for (histogram, duration) in histograms.iter_mut().zip(timings.phases()) {
+            histogram.observe(duration);
+        }
+    }
+
+    /// Returns the histogram of the durations of a phase, if it is one of `PHASES`.
+    pub fn histogram(&self, phase: &str) -> Option<DurationHistogram> {
+        let position = PHASES.iter().position(|name| *name == phase)?;
+        Some(self.histograms.lock().unwrap()[position].clone())
+    }
+
+    /// Renders the histograms in the Prometheus text format, as the `lb_request_phase_duration_seconds` histogram.
+    pub fn render_prometheus(&self) -> String {
+        let mut rendered = String::from("# TYPE lb_request_phase_duration_seconds histogram\n");
+        for (phase, histogram) in PHASES.iter().zip(self.histograms.lock().unwrap().iter()) {
+            let mut cumulated = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulated += count;
+                let _ = writeln!(rendered, "lb_request_phase_duration_seconds_bucket{{phase=\"{}\",le=\"{}\"}} {}", phase, bound, cumulated);
+            }
+            let _ = writeln!(rendered, "lb_request_phase_duration_seconds_bucket{{phase=\"{}\",le=\"+Inf\"}} {}", phase, histogram.count);
+            let _ = writeln!(rendered, "lb_request_phase_duration_seconds_sum{{phase=\"{}\"}} {}", phase, histogram.sum);
+            let _ = writeln!(rendered, "lb_request_phase_duration_seconds_count{{phase=\"{}\"}} {}", phase, histogram.count);
+        }
+        rendered
+    }
+}
+
+/// Returns a duration in milliseconds, with microsecond precision.
+fn milliseconds(duration: Duration) -> f64 {
+    (duration.as_micros() as f64) / 1000.0
+}
@@ -0,0 +1,143 @@
+//! # Upstream Host Module
+//!
+//! This module decides the `Host` header the requests are forwarded with.
+//!
+//! Several virtual hosts often share the same upstream servers, which tell them apart by the `Host` header, so the
+//! client's `Host` header is forwarded as it is by default. Some upstream servers expect a name of their own instead.
+//! The rules are given in order with `--upstream-host`, each as a mode followed by `key=value` conditions:
+//!
+//! - `preserve`: The client's `Host` header is forwarded, which also stops the rules after it.
+//! - `fixed=api.internal:8080`: The requests are forwarded with this `Host` header.
+//! - `vhost=${app}.internal`: The requests are forwarded with a `Host` header derived from the virtual host matched by
+//!   the `host` condition, whose capture groups are expanded in the template (`$1`, `${1}` or `${name}`).
+//!
+//! The conditions are `path=^/api/`, a regular expression searched in the request path, and
+//! `host=^(.+)\.example\.com$`, a regular expression searched in the client's `Host` header, port included. A `vhost`
+//! rule needs a `host` condition. A rule matches the requests meeting all of its conditions, and the first matching one
+//! applies. The requests matching no rule keep the client's `Host` header.
+//!
+//! ## Structures
+//!
+//! - `HostRule`: A rule choosing the `Host` header of the requests it matches.
+//! - `ClientHost`: The client's `Host` header of a request forwarded with another one, kept for `X-Forwarded-Host`.
+//!
+//! ## Functions
+//!
+//! ### `upstream_host`
+//!
+//! This function returns the `Host` header a request must be forwarded with, when it isn't the client's.
+//!
+//! ## Enums
+//!
+//! - `HostMode`: Where the `Host` header of the forwarded requests comes from.
+
+use std::str::FromStr;
+
+use http::header::HeaderValue;
+use http::Request;
+use regex::Regex;
+
+/// Where the `Host` header of the requests matching a rule comes from.
+#[derive(Debug, Clone)]
+pub enum HostMode {
+    /// The client's `Host` header is forwarded.
+    Preserve,
+    /// The requests are forwarded with this `Host` header.
+    Fixed(HeaderValue),
+    /// The `Host` header is this template, expanded with the capture groups of the `host` condition.
+    Vhost(String),
+}
+
+/// A rule choosing the `Host` header of the requests matching its path and virtual host.
+#[derive(Debug, Clone)]
+pub struct HostRule {
+    /// Where the `Host` header of the matching requests comes from.
+    pub mode: HostMode,
+
+    /// Expression searched in the path of the matching requests, any path if `None`.
+    pub path: Option<Regex>,
+
+    /// Expression searched in the client's `Host` header of the matching requests, any host if `None`.
+    pub host: Option<Regex>,
+}
+
+impl HostRule {
+    /// Tells whether the rule applies to a request.
+    pub fn matches(&self, request: &Request<Vec<u8>>) -> bool {
+        let client_host = request.headers().get(http::header::HOST).and_then(|host| host.to_str().ok());
+        self.path.as_ref().is_none_or(|path| path.is_match(request.uri().path()))
+            && self.host.as_ref().is_none_or(|host| client_host.is_some_and(|client_host| host.is_match(client_host)))
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = String;
+
+    /// Parses a rule such as `fixed=api.internal path=^/api/` or `vhost=$1.internal host=^(.+)\.example\.com$`.
+    fn from_str(rule: &str) -> Result<HostRule, String> {
+        let mut words = rule.split_whitespace();
+        let mode = match words.next().map(|mode| mode.split_once('=').unwrap_or((mode, ""))) {
+            Some(("preserve", "")) => HostMode::Preserve,
+            Some(("fixed", host)) if !host.is_empty() => {
+                HostMode::Fixed(HeaderValue::from_str(host).map_err(|e| format!("invalid host {:?}: {}", host, e))?)
+            }
+            Some(("vhost", template)) if !template.is_empty() => HostMode::Vhost(template.to_string()),
+            _ => return Err(format!("expected a rule starting with preserve, fixed=HOST or vhost=TEMPLATE, got {:?}", rule)),
+        };
+
+        let mut host_rule = HostRule { mode, path: None, host: None };
+        for condition in words {
+            let (key, value) = condition.split_once('=').ok_or(format!("expected key=value, got {:?}", condition))?;
+            let expression = Regex::new(value).map_err(|e| format!("invalid {} expression {:?}: {}", key, value, e))?;
+            match key {
+                "path" => host_rule.path = Some(expression),
+                "host" => host_rule.host = Some(expression),
+                _ => return Err(format!("unknown condition {:?}, expected path or host", key)),
+            }
+        }
+
+        if matches!(host_rule.mode, HostMode::Vhost(_)) && host_rule.host.is_none() {
+            return Err(format!("expected a host condition to derive the host from in {:?}", rule));
+        }
+        Ok(host_rule)
+    }
+}
+
+/// The client's `Host` header of a request forwarded with another one, carried in the extensions of the request so
+/// the `X-Forwarded-Host` header still tells the upstream server the host the client asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHost(pub HeaderValue);
+
+/// Returns the `Host` header a request must be forwarded with, when the first matching rule replaces the client's.
+///
+/// # Arguments
+///
+/// * `rules` - The rules, in the order they were given.
+/// * `request` - The client request, with its `Host` header.
+///
+/// # Returns
+///
+/// * `Some(HeaderValue)` - The `Host` header replacing the client's.
+/// * `None` - If the client's `Host` header must be forwarded: no rule matches, the matching rule preserves it, or the
+///   host derived from the virtual host isn't a valid header value.
+pub fn upstream_host(rules: &[HostRule], request: &Request<Vec<u8>>) -> Option<HeaderValue> {
+    let rule = rules.iter().find(|rule| rule.matches(request))?;
+    match &rule.mode {
+        HostMode::Preserve => None,
+        HostMode::Fixed(host) => Some(host.clone()),
+        HostMode::Vhost(template) => {
+            // a vhost rule only matches requests whose host matched its expression
+            let client_host = request.headers().get(http::header::HOST)?.to_str().ok()?;
+            let captures = rule.host.as_ref()?.captures(client_host)?;
+            let mut derived = String::new();
+            captures.expand(template, &mut derived);
+            match HeaderValue::from_str(&derived) {
+                Ok(host) if !derived.is_empty() => Some(host),
+                _ => {
+                    log::error!("Host {:?} derived from {:?} is invalid, forwarding the client's host", derived, client_host);
+                    None
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! # Warm-up Module
+//!
+//! This module warms up the upstream servers that just became healthy before they receive real traffic.
+//!
+//! A single passing health probe admits an upstream server with cold caches, JIT compilers and connection pools, and
+//! the first clients it serves pay for it. With a warm-up, an upstream server that passed its health probe is sent a
+//! configured number of warm-up requests, a short interval apart, and is only admitted once all of them succeeded.
+//! A failed warm-up counts as a failed probe: the upstream server stays out of rotation until the next health cycle.
+//!
+//! ## Structures
+//!
+//! - `WarmUp`: The warm-up requests sent to an upstream server before it is admitted.
+
+use std::time::Duration;
+
+use crate::http_health_checks::{http_health_check, HealthCheckRequest};
+
+/// The warm-up requests sent to an upstream server before it is admitted.
+#[derive(Debug, Clone)]
+pub struct WarmUp {
+    /// Number of warm-up requests that must all succeed.
+    pub requests: u32,
+
+    /// Delay between two warm-up requests.
+    pub interval: Duration,
+
+    /// The request sent, which succeeds on a 200 OK response like a health check.
+    pub request: HealthCheckRequest,
+}
+
+impl WarmUp {
+    /// Sends the warm-up requests to an upstream server, stopping at the first failure.
+    ///
+    /// This takes up to `requests` times `interval`, plus the time taken by the requests, each of them bounded by the
+    /// timeout of the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream_address` - The address of the upstream server to warm up.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether every warm-up request succeeded, and the upstream server can be admitted.
+    pub async fn run(&self, upstream_address: &str) -> bool {
+        for sent in 0..self.requests {
+            if sent > 0 {
+                tokio::time::sleep(self.interval).await;
+            }
+
+            if let Err(e) = http_health_check(upstream_address.to_string(), &self.request).await {
+                log::warn!("Warm-up request {} of {} to {} failed: {}", sent + 1, self.requests, upstream_address, e);
+                return false;
+            }
+        }
+
+        log::info!("{} passed {} warm-up requests", upstream_address, self.requests);
+        true
+    }
+}
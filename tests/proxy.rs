@@ -0,0 +1,1556 @@
+mod support;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use support::{eventually, read_response, request_path, send_request, MockResponse, MockUpstream, Proxy};
+
+
+const GET: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+
+/// Builds a `200 OK` response with `body`.
+fn ok(body: &str) -> Vec<u8> {
+    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+
+#[test]
+fn test_get_round_trip_through_proxy() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let response = send_request(&proxy.address, b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+
+    let forwarded = String::from_utf8(upstream.requests().last().unwrap().clone()).unwrap();
+    assert!(forwarded.starts_with("GET /index.html HTTP/1.1\r\n"));
+    assert!(forwarded.contains("x-forwarded-for: 127.0.0.1"));
+}
+
+
+#[test]
+fn test_post_body_reaches_upstream() {
+    // the upstream echoes the body of the request
+    let upstream = MockUpstream::start_with(|request| {
+        let request = String::from_utf8_lossy(request);
+        ok(request.split("\r\n\r\n").nth(1).unwrap_or(""))
+    });
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let body = "name=load&value=balancer";
+    let request = format!("POST /form HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let response = send_request(&proxy.address, request.as_bytes()).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with(body));
+    assert_eq!(upstream.received("/form"), 1);
+}
+
+
+#[test]
+fn test_chunked_upload_reaches_upstream_decoded() {
+    // the upstream echoes the body of the request
+    let upstream = MockUpstream::start_with(|request| {
+        let request = String::from_utf8_lossy(request);
+        ok(request.split_once("\r\n\r\n").map_or("", |(_, body)| body))
+    });
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    client.write_all(b"7;last\r\n, world\r\n0\r\nX-Checksum: 1\r\n\r\n").unwrap();
+    let response = read_response(&mut client).unwrap();
+
+    // the chunks are joined into one, the extension and trailer dropped
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("c\r\nhello, world\r\n0\r\n\r\n"), "{}", response);
+    let forwarded = String::from_utf8(upstream.requests().into_iter().find(|request| request_path(request) == "/upload").unwrap()).unwrap();
+    assert!(forwarded.contains("transfer-encoding: chunked\r\n"), "{}", forwarded);
+    assert!(!forwarded.contains("x-checksum"), "{}", forwarded);
+
+    // a chunked body with a Content-Length too never reaches the upstream
+    let response = send_request(&proxy.address, b"POST /smuggled HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+    assert_eq!(upstream.received("/smuggled"), 0);
+}
+
+
+#[test]
+fn test_chunked_response_loses_its_stale_content_length() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    // the client reads the chunked body in full, rather than the 3 bytes a Content-Length would tell it
+    let response = send_request(&proxy.address, b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n"), "{}", response);
+    assert!(!response.to_ascii_lowercase().contains("content-length"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"), "{}", response);
+}
+
+
+#[test]
+fn test_unhealthy_upstream_answers_503() {
+    let upstream = MockUpstream::start_response(MockResponse::status(500));
+    let proxy = Proxy::spawn(&[&upstream.address], &[]);
+
+    let response = send_request(&proxy.address, GET).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+}
+
+
+#[test]
+fn test_upstream_closing_without_response_answers_502() {
+    // healthy, but every other request is dropped
+    let upstream = MockUpstream::start_routes(&[("/health", MockResponse::status(200))], MockResponse::status(200).drop_after(0));
+    let proxy = Proxy::start(&[&upstream.address], &["--path", "/health"]);
+
+    let response = send_request(&proxy.address, b"GET /data HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    // the request is idempotent, it was sent again once before giving up
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+    assert_eq!(upstream.received("/data"), 2);
+}
+
+
+#[test]
+fn test_error_detail_tells_why_upstream_failed() {
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/health" => ok(""),
+        _ => Vec::new(),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--path", "/health", "--expose-error-detail"]);
+
+    let response = send_request(&proxy.address, b"GET /data HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+    assert!(response.ends_with("\r\n\r\nupstream read failed: empty\n"));
+}
+
+
+#[test]
+fn test_non_http_upstream_response_is_answered_with_bad_gateway() {
+    // a misconfigured backend answering another protocol, and keeping the connection open
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/health" => ok(""),
+        _ => b"SSH-2.0-OpenSSH_9.6\r\n".to_vec(),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--path", "/health", "--expose-error-detail"]);
+
+    let started_at = Instant::now();
+    let response = send_request(&proxy.address, b"GET /data HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nmalformed upstream response\n"), "{}", response);
+    assert!(!response.contains("SSH-2.0"));
+    assert!(started_at.elapsed() < Duration::from_secs(2));
+}
+
+
+#[test]
+fn test_health_check_with_configured_method_and_body() {
+    // JSON-RPC upstream only healthy for a POST of the status call
+    let upstream = MockUpstream::start_with(|request| {
+        let request = String::from_utf8_lossy(request);
+        match request.split_once("\r\n\r\n") {
+            Some((head, r#"{"method":"status"}"#)) if head.starts_with("POST /rpc ") && head.contains("Content-Type: application/json") => ok("ready"),
+            Some((head, _)) if head.starts_with("GET /data ") => ok("data"),
+            _ => b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        }
+    });
+    let proxy = Proxy::start(&[&upstream.address], &[
+        "--path", "/rpc",
+        "--health-method", "POST",
+        "--health-body", r#"{"method":"status"}"#,
+        "--health-content-type", "application/json",
+    ]);
+
+    let response = send_request(&proxy.address, b"GET /data HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(upstream.received("/rpc") >= 1);
+}
+
+
+#[test]
+fn test_traffic_shifts_when_upstream_turns_unhealthy() {
+    let healthy = Arc::new(AtomicBool::new(true));
+    let flapping_health = healthy.clone();
+    let flapping = MockUpstream::start_with(move |request| match request_path(request).as_str() {
+        "/health" if !flapping_health.load(Ordering::SeqCst) => b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        _ => ok("flapping"),
+    });
+    let stable = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nstable");
+    let proxy = Proxy::start(&[&flapping.address, &stable.address], &["--path", "/health", "--interval", "1"]);
+
+    // both upstreams serve traffic while healthy
+    eventually(Duration::from_secs(10), || send_request(&proxy.address, GET).unwrap().ends_with("flapping"));
+    eventually(Duration::from_secs(10), || send_request(&proxy.address, GET).unwrap().ends_with("stable"));
+
+    // once the next health check has run, only the stable upstream is used
+    healthy.store(false, Ordering::SeqCst);
+    eventually(Duration::from_secs(10), || {
+        let served = flapping.received("/");
+        (0..20).all(|_| send_request(&proxy.address, GET).unwrap().ends_with("stable")) && flapping.received("/") == served
+    });
+}
+
+
+/// Starts an upstream answering `name`, whose health checks fail while `healthy` is false.
+fn upstream_with_health(name: &'static str, healthy: Arc<AtomicBool>) -> MockUpstream {
+    MockUpstream::start_with(move |request| match request_path(request).as_str() {
+        "/health" if !healthy.load(Ordering::SeqCst) => b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        _ => ok(name),
+    })
+}
+
+
+#[test]
+fn test_upstream_is_admitted_after_warm_up() {
+    // the first two requests, health probes, fail
+    let received = AtomicUsize::new(0);
+    let upstream = MockUpstream::start_with(move |_| match received.fetch_add(1, Ordering::SeqCst) {
+        0 | 1 => b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        _ => ok("warm"),
+    });
+    let proxy = Proxy::spawn(&[&upstream.address], &["--interval", "1", "--warmup-requests", "3", "--warmup-path", "/warmup"]);
+
+    eventually(Duration::from_secs(10), || send_request(&proxy.address, GET).is_ok_and(|response| response.ends_with("warm")));
+
+    // admitted only once its health probe and every warm-up request passed
+    assert_eq!(upstream.received("/warmup"), 3);
+}
+
+
+#[test]
+fn test_traffic_moves_across_failover_tiers() {
+    let health: Vec<_> = (0..3).map(|_| Arc::new(AtomicBool::new(true))).collect();
+    let primary = upstream_with_health("primary", health[0].clone());
+    let secondary = upstream_with_health("secondary", health[1].clone());
+    let tertiary = upstream_with_health("tertiary", health[2].clone());
+    let proxy = Proxy::start(&[&primary.address], &[
+        "--path", "/health",
+        "--interval", "1",
+        "--tier-upstream", &format!("1={}", secondary.address),
+        "--tier-upstream", &format!("2={}", tertiary.address),
+    ]);
+    let served_by = |name: &str| (0..5).all(|_| send_request(&proxy.address, GET).unwrap().ends_with(name));
+
+    assert!(served_by("primary"));
+
+    // traffic moves down a tier every time the tier in use fails
+    health[0].store(false, Ordering::SeqCst);
+    eventually(Duration::from_secs(10), || served_by("secondary"));
+    health[1].store(false, Ordering::SeqCst);
+    eventually(Duration::from_secs(10), || served_by("tertiary"));
+
+    // and back up as soon as a higher tier recovers
+    health[1].store(true, Ordering::SeqCst);
+    eventually(Duration::from_secs(10), || served_by("secondary"));
+    health[0].store(true, Ordering::SeqCst);
+    eventually(Duration::from_secs(10), || served_by("primary"));
+}
+
+
+#[test]
+fn test_least_bytes_skews_toward_the_upstream_with_small_transfers() {
+    let large = MockUpstream::start_response(MockResponse::status(200).body(&"x".repeat(10 * 1024 * 1024)));
+    let small = MockUpstream::start_response(MockResponse::status(200).body(&"x".repeat(1024)));
+    let proxy = Proxy::start(&[&large.address, &small.address], &["--least-bytes", "--least-bytes-window", "60"]);
+
+    // every download on a new connection, so every one of them is balanced
+    for _ in 0..20 {
+        let response = send_request(&proxy.address, b"GET /download HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    // a single large transfer outweighs every small one, the upstream serving it idles once it served one
+    assert!(large.received("/download") <= 2, "{} large downloads", large.received("/download"));
+    assert!(small.received("/download") >= 18, "{} small downloads", small.received("/download"));
+}
+
+
+#[test]
+fn test_concurrent_clients() {
+    let upstream = MockUpstream::start_delayed("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", Duration::from_millis(50));
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+    let served = upstream.received("/");
+
+    let clients: Vec<_> = (0..16)
+        .map(|i| {
+            let address = proxy.address.clone();
+            thread::spawn(move || send_request(&address, format!("GET /client/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i).as_bytes()))
+        })
+        .collect();
+
+    for client in clients {
+        assert_eq!(client.join().unwrap().unwrap(), "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    }
+    for i in 0..16 {
+        assert_eq!(upstream.received(&format!("/client/{}", i)), 1);
+    }
+    assert_eq!(upstream.received("/"), served);
+}
+
+
+/// Opens a connection to the proxy and checks it is served, keeping it open.
+fn served_connection(address: &str) -> Option<TcpStream> {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(GET).ok()?;
+
+    read_response(&mut stream).ok().filter(|response| response.starts_with("HTTP/1.1 200 OK")).map(|_| stream)
+}
+
+
+#[test]
+fn test_connections_over_per_ip_limit_are_refused() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &["--max-connections-per-ip", "2"]);
+
+    // the connection checking the proxy is up may take a moment to be released
+    let mut held = Vec::new();
+    eventually(Duration::from_secs(5), || served_connection(&proxy.address).map(|stream| held.push(stream)).is_some());
+    held.push(served_connection(&proxy.address).unwrap());
+
+    // a third connection from the same IP address is closed right away
+    assert!(served_connection(&proxy.address).is_none());
+
+    // closing a connection frees its slot
+    drop(held.pop());
+    eventually(Duration::from_secs(5), || served_connection(&proxy.address).is_some());
+}
+
+
+#[test]
+fn test_dribbled_headers_answer_408() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &["--header-read-timeout", "1"]);
+    let forwarded = upstream.requests().len();
+
+    let mut stream = TcpStream::connect(&proxy.address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // the response is read while the headers are being sent
+    let mut reader = stream.try_clone().unwrap();
+    let response = thread::spawn(move || read_response(&mut reader));
+
+    // a header byte every 100 ms, until the proxy gives up on the request
+    let started_at = std::time::Instant::now();
+    for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: aaaaaaaaaaaaaaaaaaaa" {
+        if response.is_finished() || stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let response = response.join().unwrap().unwrap();
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+    assert!(started_at.elapsed() >= Duration::from_secs(1));
+    assert_eq!(upstream.requests().len(), forwarded);
+}
+
+
+#[test]
+fn test_malformed_request_answers_400() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+    let forwarded = upstream.requests().len();
+
+    let response = send_request(&proxy.address, b"NOT AN\0HTTP REQUEST\r\n\r\n").unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert_eq!(upstream.requests().len(), forwarded);
+}
+
+
+#[test]
+fn test_unsupported_codings_are_rejected_before_the_upstream() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &["--reject-unknown-content-coding"]);
+    let forwarded = upstream.requests().len();
+
+    let response = send_request(&proxy.address, b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 501 Not Implemented"), "{}", response);
+
+    let response = send_request(&proxy.address, b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: lzma\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+    assert!(response.starts_with("HTTP/1.1 415 Unsupported Media Type"), "{}", response);
+    assert_eq!(upstream.requests().len(), forwarded);
+
+    // the registered content codings still go through
+    let response = send_request(&proxy.address, b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: gzip\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(upstream.received("/upload"), 1);
+}
+
+
+#[test]
+fn test_no_forwarded_for_hides_client_ip() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &["--no-forwarded-for"]);
+
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 203.0.113.7\r\nX-Real-IP: 203.0.113.7\r\n\r\n";
+    send_request(&proxy.address, request).unwrap();
+
+    for forwarded in upstream.requests() {
+        let forwarded = String::from_utf8(forwarded).unwrap();
+        assert!(!forwarded.contains("x-forwarded-for"));
+        assert!(!forwarded.contains("x-real-ip"));
+    }
+}
+
+
+#[test]
+fn test_request_without_host_uses_default_host() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &["--default-host", "default.example"]);
+
+    let response = send_request(&proxy.address, b"GET /no-host HTTP/1.1\r\n\r\n").unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let forwarded = upstream.requests().into_iter().find(|request| request_path(request) == "/no-host").unwrap();
+    assert!(String::from_utf8(forwarded).unwrap().contains("host: default.example\r\n"));
+}
+
+
+#[test]
+fn test_host_headers_are_forwarded_once() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+    let forwarded_hosts = |path: &str| {
+        let forwarded = String::from_utf8(upstream.requests().into_iter().find(|request| request_path(request) == path).unwrap()).unwrap();
+        forwarded.lines().filter_map(|line| line.strip_prefix("host: ")).map(str::to_string).collect::<Vec<_>>()
+    };
+
+    // an HTTP/1.0 request without Host is forwarded with the address of its upstream server
+    let response = send_request(&proxy.address, b"GET /http10 HTTP/1.0\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(forwarded_hosts("/http10"), std::slice::from_ref(&upstream.address));
+
+    // repeated identical hosts are collapsed, differing ones rejected
+    let response = send_request(&proxy.address, b"GET /repeated HTTP/1.1\r\nHost: shop.example\r\nHost: SHOP.example\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert_eq!(forwarded_hosts("/repeated"), ["shop.example"]);
+    let response = send_request(&proxy.address, b"GET /differing HTTP/1.1\r\nHost: shop.example\r\nHost: admin.example\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+    assert_eq!(upstream.received("/differing"), 0);
+}
+
+
+#[test]
+fn test_server_timing_reports_upstream_latency() {
+    let upstream = MockUpstream::start_delayed("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", Duration::from_millis(50));
+    let timed_proxy = Proxy::start(&[&upstream.address], &["--server-timing"]);
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let response = send_request(&timed_proxy.address, GET).unwrap();
+    let duration: f64 = response.split("Server-Timing: upstream;dur=").nth(1).unwrap().split("\r\n").next().unwrap().parse().unwrap();
+    assert!((50.0..10_000.0).contains(&duration), "duration {}", duration);
+
+    let response = send_request(&proxy.address, GET).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(!response.contains("Server-Timing"));
+}
+
+
+#[test]
+fn test_responses_over_max_size_are_not_relayed() {
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/declared" => format!("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{}", "a".repeat(100)).into_bytes(),
+        "/streamed" => format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3c\r\n{0}\r\n3c\r\n{0}\r\n0\r\n\r\n", "b".repeat(60)).into_bytes(),
+        _ => ok("small"),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--max-response-size", "64", "--expose-error-detail"]);
+
+    // the declared length is over the limit, nothing was sent yet
+    let response = send_request(&proxy.address, b"GET /declared HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nupstream response too large: oversized_body\n"), "{}", response);
+
+    // the streamed body goes over the limit after the head was sent, the connection is closed mid-response
+    let response = send_request(&proxy.address, b"GET /streamed HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(!response.ends_with("0\r\n\r\n"), "{}", response);
+
+    let response = send_request(&proxy.address, GET).unwrap();
+    assert!(response.ends_with("small"));
+}
+
+
+#[test]
+fn test_response_size_limit_of_an_upstream_overrides_the_global_one() {
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/declared" => format!("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{}", "a".repeat(100)).into_bytes(),
+        "/streamed" => format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3c\r\n{0}\r\n3c\r\n{0}\r\n0\r\n\r\n", "b".repeat(60)).into_bytes(),
+        "/until-close" => format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}", "c".repeat(100)).into_bytes(),
+        _ => ok("small"),
+    });
+    let limit = format!("{}=64", upstream.address);
+    let limited = Proxy::start(&[&upstream.address], &["--upstream-max-response-size", &limit]);
+
+    // the declared length is over the limit of the upstream, the client gets a 502 rather than part of the body
+    let response = send_request(&limited.address, b"GET /declared HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+
+    // chunked and close-delimited bodies are cut once the bytes relayed go over it
+    for path in ["/streamed", "/until-close"] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        let response = send_request(&limited.address, request.as_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert!(body.len() < 100 && !body.ends_with("0\r\n\r\n"), "{}", response);
+    }
+
+    // a limit of 0 exempts the upstream from --max-response-size
+    let unlimited = format!("{}=0", upstream.address);
+    let exempted = Proxy::start(&[&upstream.address], &["--max-response-size", "64", "--upstream-max-response-size", &unlimited]);
+    let response = send_request(&exempted.address, b"GET /declared HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with(&"a".repeat(100)), "{}", response);
+}
+
+
+#[test]
+fn test_oversized_and_slow_response_heads_are_answered_with_502() {
+    let cookies = (0..40).fold(MockResponse::status(200), |response, index| response.header("Set-Cookie", &format!("session{}={}", index, "x".repeat(2048))));
+    let upstream = MockUpstream::start_routes(&[
+        ("/huge-cookies", cookies.body("never sent")),
+        ("/slow-head", MockResponse::status(200).body("too late").stall_after(20, Duration::from_secs(3))),
+    ], MockResponse::status(200).body("ok"));
+    let proxy = Proxy::start(&[&upstream.address], &["--expose-error-detail", "--upstream-header-read-timeout", "1"]);
+
+    // the head is over the default limit of 64KB, nothing of it reaches the client
+    let response = send_request(&proxy.address, b"GET /huge-cookies HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nupstream response head too large: oversized_head\n"), "{}", response);
+    assert!(!response.contains("Set-Cookie"));
+
+    // the status line arrives, the rest of the head doesn't within the timeout
+    let started_at = Instant::now();
+    let response = send_request(&proxy.address, b"GET /slow-head HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nupstream response head too slow: slow_head\n"), "{}", response);
+    assert!(!response.contains("200 OK"));
+    assert!(started_at.elapsed() < Duration::from_secs(3));
+
+    // a smaller limit refuses heads the default one lets through
+    let strict = Proxy::start(&[&upstream.address], &["--max-response-header-size", "1024"]);
+    let response = send_request(&strict.address, b"GET /huge-cookies HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    let response = send_request(&strict.address, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+}
+
+
+#[test]
+fn test_requests_queue_while_upstreams_are_at_capacity() {
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/slow" => {
+            thread::sleep(Duration::from_millis(500));
+            ok("slow")
+        }
+        _ => ok(""),
+    });
+    let patient_proxy = Proxy::start(&[&upstream.address], &["--max-upstream-concurrency", "1", "--queue-timeout-ms", "3000"]);
+    let impatient_proxy = Proxy::start(&[&upstream.address], &["--max-upstream-concurrency", "1", "--queue-timeout-ms", "100"]);
+    let slow_request = b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    for (proxy, answered) in [(&patient_proxy, "HTTP/1.1 200 OK"), (&impatient_proxy, "HTTP/1.1 503 Service Unavailable")] {
+        // the first request takes the only slot of the upstream server for 500ms
+        let address = proxy.address.clone();
+        let saturating = thread::spawn(move || send_request(&address, slow_request).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let response = send_request(&proxy.address, slow_request).unwrap();
+
+        assert!(response.starts_with(answered), "{}", response);
+        assert!(saturating.join().unwrap().ends_with("slow"));
+    }
+}
+
+
+#[test]
+fn test_interim_responses_are_forwarded_before_the_final_one() {
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/upload" => [&b"HTTP/1.1 100 Continue\r\n\r\n"[..], &ok("uploaded")].concat(),
+        _ => ok("next"),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\ndata").unwrap();
+    let mut received = Vec::new();
+    while !received.ends_with(b"uploaded") {
+        let mut buffer = [0; 1024];
+        let read = client.read(&mut buffer).unwrap();
+        assert!(read > 0, "{}", String::from_utf8_lossy(&received));
+        received.extend_from_slice(&buffer[..read]);
+    }
+    assert_eq!(received, [&b"HTTP/1.1 100 Continue\r\n\r\n"[..], &ok("uploaded")].concat());
+
+    // the next request on the connection gets its own response, not a leftover of the previous one
+    client.write_all(b"GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("next"));
+}
+
+#[test]
+fn test_identical_requests_in_flight_are_coalesced() {
+    let upstream = MockUpstream::start_with(|request| match request_path(request).as_str() {
+        "/hot" => {
+            thread::sleep(Duration::from_millis(500));
+            ok("hot")
+        }
+        _ => ok(""),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--coalesce"]);
+    let hot_request = b"GET /hot HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    // every request arrives while the first one waits for the upstream server
+    let clients: Vec<_> = (0..8)
+        .map(|_| {
+            let address = proxy.address.clone();
+            thread::spawn(move || send_request(&address, hot_request).unwrap())
+        })
+        .collect();
+    for client in clients {
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("hot"), "{}", response);
+    }
+    assert_eq!(upstream.received("/hot"), 1);
+
+    // once the flight is over, the next request goes to the upstream server
+    assert!(send_request(&proxy.address, hot_request).unwrap().ends_with("hot"));
+    assert_eq!(upstream.received("/hot"), 2);
+}
+
+
+#[test]
+fn test_access_log_breaks_down_request_time() {
+    let slow = MockResponse::status(200).body("slow").delay(Duration::from_millis(200));
+    let upstream = MockUpstream::start_routes(&[("/slow", slow)], MockResponse::status(200));
+    let proxy = Proxy::start(&[&upstream.address], &["--access-log"]);
+
+    let response = send_request(&proxy.address, b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("slow"));
+
+    let mut line = None;
+    eventually(Duration::from_secs(5), || {
+        line = proxy.output().into_iter().find(|line| line.contains("\"target\":\"/slow\""));
+        line.is_some()
+    });
+    let line: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+
+    assert_eq!(line["status"], 200);
+    assert_eq!(line["upstream"], upstream.address.as_str());
+    let ttfb = line["upstream_ttfb_ms"].as_f64().unwrap();
+    assert!((200.0..2000.0).contains(&ttfb), "{}", line);
+    assert_eq!(line["queue_ms"], 0.0);
+    let phases: f64 = ["header_read_ms", "connect_ms", "upstream_ttfb_ms", "upstream_body_ms", "client_write_ms"].iter()
+        .map(|field| line[field].as_f64().unwrap())
+        .sum();
+    assert!(phases <= line["total_ms"].as_f64().unwrap() + 0.01, "{}", line);
+}
+
+
+#[test]
+fn test_acl_rules_deny_requests_before_routing() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &[
+        "--access-log",
+        "--acl-rule", "deny method=TRACE",
+        "--acl-rule", r"deny path=^/\.git/ status=404",
+    ]);
+
+    let response = send_request(&proxy.address, b"TRACE / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+    let response = send_request(&proxy.address, b"GET /.git/config HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    let response = send_request(&proxy.address, b"GET /.gitignore HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("ok"));
+
+    // the denied requests never reach the upstream server, and are tagged with their rule in the access log
+    assert_eq!((upstream.received("/.gitignore"), upstream.received("/.git/config")), (1, 0));
+    assert!(!upstream.requests().iter().any(|request| request.starts_with(b"TRACE")));
+    let mut line = None;
+    eventually(Duration::from_secs(5), || {
+        line = proxy.output().into_iter().find(|line| line.contains("\"target\":\"/.git/config\""));
+        line.is_some()
+    });
+    let line: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+    assert_eq!((line["status"].as_u64(), line["acl_rule"].as_u64()), (Some(404), Some(1)));
+}
+
+
+#[test]
+fn test_access_log_covers_error_responses_with_the_resolved_client() {
+    let upstream = MockUpstream::start_routes(&[("/ok", MockResponse::status(200).body("ok")), ("/dropped", MockResponse::status(200).drop_after(0))], MockResponse::status(200));
+    let proxy = Proxy::start(&[&upstream.address], &["--access-log", "--real-ip-from", "127.0.0.0/8", "--acl-rule", "deny path=^/admin", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    for target in ["/ok", "/dropped", "/admin"] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n", target);
+        send_request(&proxy.address, request.as_bytes()).unwrap();
+    }
+    send_request(&proxy.address, b"NOT AN\0HTTP REQUEST\r\n\r\n").unwrap();
+
+    // the requests are logged with the client reported by the trusted proxy, the unreadable one with the peer
+    let mut lines = Vec::new();
+    eventually(Duration::from_secs(5), || {
+        lines = proxy.output().into_iter().filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok()).collect();
+        lines.iter().any(|line| line["status"] == 400)
+    });
+    let line_of = |target: &str| lines.iter().find(|line| line["target"] == target).unwrap_or_else(|| panic!("no {} in {:?}", target, lines));
+    for (target, status) in [("/ok", 200), ("/dropped", 502), ("/admin", 403)] {
+        assert_eq!((line_of(target)["client"].as_str(), line_of(target)["status"].as_u64()), (Some("203.0.113.7"), Some(status)));
+    }
+    assert_eq!(line_of("/dropped")["method"], "GET");
+    assert!(line_of("/dropped")["total_ms"].is_f64());
+    let unreadable = lines.iter().find(|line| line["status"] == 400).unwrap();
+    assert_eq!(unreadable["client"], "127.0.0.1");
+    assert!(unreadable["target"].is_null());
+
+    // only the relayed responses are observed in the phase histograms, the one of the startup probe included
+    let relayed = lines.iter().filter(|line| line["upstream"].is_string()).count();
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains("# TYPE lb_request_phase_duration_seconds histogram\n"));
+    for phase in ["header_read", "queue", "connect", "upstream_ttfb", "upstream_body", "client_write", "total"] {
+        assert!(metrics.contains(&format!("lb_request_phase_duration_seconds_count{{phase=\"{}\"}} {}\n", phase, relayed)), "{}", metrics);
+    }
+}
+
+
+#[test]
+fn test_plaintext_requests_are_redirected_to_https() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &["--redirect-http-to-https", "127.0.0.1:0"]);
+    let redirect_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Redirecting to HTTPS on ")).unwrap();
+
+    let response = send_request(redirect_address, b"GET /docs/page?lang=en&v=2 HTTP/1.1\r\nHost: example.com:80\r\n\r\n").unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+    assert!(response.contains("\r\nLocation: https://example.com/docs/page?lang=en&v=2\r\n"));
+    assert_eq!(upstream.received("/docs/page?lang=en&v=2"), 0);
+}
+
+
+#[test]
+fn test_dashboard_shows_the_state_of_every_upstream() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let down_address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let proxy = Proxy::start(&[&upstream.address, &down_address], &["--interval", "1", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    let mut page = String::new();
+    eventually(Duration::from_secs(10), || {
+        page = send_request(metrics_address, b"GET /dashboard HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        page.lines().any(|line| line.contains(&down_address) && line.contains(">unhealthy</span>"))
+    });
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains("Content-Type: text/html; charset=utf-8\r\n"));
+
+    // the requests relayed show in the error rate of the upstream server
+    send_request(&proxy.address, GET).unwrap();
+    let page = send_request(metrics_address, b"GET /dashboard HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let row = page.lines().find(|line| line.contains(&upstream.address)).unwrap();
+    assert!(row.contains(">healthy</span>") && row.contains("<td class=\"number\">0.0%</td>"), "{}", row);
+}
+
+
+#[test]
+fn test_metrics_report_health_checks() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let down_address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let proxy = Proxy::start(&[&upstream.address, &down_address], &["--interval", "1", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    // wait for a few health cycles
+    let count_line = format!("health_check_duration_seconds_count{{upstream=\"{}\"}} ", upstream.address);
+    let mut metrics = String::new();
+    eventually(Duration::from_secs(10), || {
+        metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        metrics.lines().any(|line| line.strip_prefix(&count_line).is_some_and(|count| count.parse::<u64>().unwrap() >= 3))
+    });
+
+    assert!(metrics.starts_with("HTTP/1.1 200 OK"));
+    assert!(metrics.contains("# TYPE health_check_duration_seconds histogram\n"));
+    assert!(metrics.contains("# TYPE health_check_failures_total counter\n"));
+    assert!(metrics.contains(&format!("health_check_failures_total{{upstream=\"{}\"}} 0\n", upstream.address)));
+    let down_failures = format!("health_check_failures_total{{upstream=\"{}\"}} ", down_address);
+    let failures: u64 = metrics.lines().find_map(|line| line.strip_prefix(&down_failures)).unwrap().parse().unwrap();
+    assert!(failures >= 3);
+
+    // local probes are fast, all of them fall in the 1 second bucket
+    let fast_bucket = format!("health_check_duration_seconds_bucket{{upstream=\"{}\",le=\"1\"}} ", upstream.address);
+    let fast: u64 = metrics.lines().find_map(|line| line.strip_prefix(&fast_bucket)).unwrap().parse().unwrap();
+    assert!(fast >= 3);
+}
+
+
+#[test]
+fn test_real_ip_from_trusted_proxy_is_forwarded() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    let proxy = Proxy::start(&[&upstream.address], &["--real-ip-from", "127.0.0.0/8", "--real-ip-header", "CF-Connecting-IP"]);
+
+    send_request(&proxy.address, b"GET /real-ip HTTP/1.1\r\nHost: localhost\r\nCF-Connecting-IP: 203.0.113.7\r\n\r\n").unwrap();
+
+    let forwarded = upstream.requests().into_iter().find(|request| request_path(request) == "/real-ip").unwrap();
+    let forwarded = String::from_utf8(forwarded).unwrap();
+    assert!(forwarded.contains("cf-connecting-ip: 203.0.113.7\r\n"));
+    assert!(forwarded.contains("x-forwarded-for: 127.0.0.1\r\n"));
+}
+
+
+#[test]
+fn test_watched_config_edit_moves_traffic() {
+    let old = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nold");
+    let new = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nnew");
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let config = std::env::temp_dir().join(format!("lb-watch-config-{}-{}", std::process::id(), nanos));
+    std::fs::write(&config, format!("{}\n", old.address)).unwrap();
+
+    let proxy = Proxy::start(&[], &["--watch-config", config.to_str().unwrap(), "--interval", "1"]);
+    assert!(send_request(&proxy.address, GET).unwrap().ends_with("old"));
+
+    // an invalid edit is ignored
+    std::fs::write(&config, "not an upstream\n").unwrap();
+    thread::sleep(Duration::from_secs(2));
+    assert!(send_request(&proxy.address, GET).unwrap().ends_with("old"));
+
+    std::fs::write(&config, format!("# moved\n{}\n", new.address)).unwrap();
+    eventually(Duration::from_secs(10), || send_request(&proxy.address, GET).unwrap().ends_with("new"));
+    assert!((0..10).all(|_| send_request(&proxy.address, GET).unwrap().ends_with("new")));
+
+    std::fs::remove_file(&config).unwrap();
+}
+
+
+#[test]
+fn test_canary_header_routes_to_canary_pool() {
+    let stable = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nstable");
+    let canary = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ncanary");
+    let proxy = Proxy::start(&[&stable.address], &["--canary-upstream", &canary.address, "--canary-header", "X-Canary=true"]);
+
+    let response = send_request(&proxy.address, b"GET /new HTTP/1.1\r\nHost: localhost\r\nX-Canary: true\r\n\r\n").unwrap();
+    assert!(response.ends_with("canary"));
+
+    let response = send_request(&proxy.address, b"GET /old HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("stable"));
+
+    assert_eq!((canary.received("/new"), canary.received("/old")), (1, 0));
+    assert_eq!((stable.received("/new"), stable.received("/old")), (0, 1));
+}
+
+
+#[test]
+fn test_explanation_matches_the_routing_of_the_real_request() {
+    let stable = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nstable");
+    let canary = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ncanary");
+    let proxy = Proxy::start(&[&stable.address], &["--canary-upstream", &canary.address, "--canary-header", "X-Canary=true", "--acl-rule", "deny path=^/admin", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+    let explain = |description: &str| {
+        let request = format!("POST /explain HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", description.len(), description);
+        let response = send_request(metrics_address, request.as_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        serde_json::from_str::<serde_json::Value>(response.split_once("\r\n\r\n").unwrap().1).unwrap()
+    };
+
+    let explanation = explain(r#"{"path": "/new", "host": "localhost", "headers": {"X-Canary": "true"}}"#);
+    assert_eq!(explanation["pick"], canary.address.as_str());
+    let response = send_request(&proxy.address, b"GET /new HTTP/1.1\r\nHost: localhost\r\nX-Canary: true\r\n\r\n").unwrap();
+    assert!(response.ends_with("canary"));
+
+    let explanation = explain(r#"{"path": "/old", "host": "localhost"}"#);
+    assert_eq!(explanation["pick"], stable.address.as_str());
+    let response = send_request(&proxy.address, b"GET /old HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("stable"));
+
+    let explanation = explain(r#"{"path": "/admin/users", "host": "localhost"}"#);
+    assert_eq!((explanation["decision"].as_str(), explanation["status"].as_u64()), (Some("denied"), Some(403)));
+    let response = send_request(&proxy.address, b"GET /admin/users HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+
+    // explaining sends nothing upstream
+    assert_eq!((canary.received("/new"), stable.received("/old"), stable.received("/admin/users")), (1, 1, 0));
+}
+
+
+#[test]
+fn test_injected_faults_delay_and_abort_until_cleared() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &["--metrics-bind", "127.0.0.1:0", "--enable-fault-injection", "--access-log"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+    let set_faults = |faults: Option<&str>| {
+        let request = match faults {
+            Some(faults) => format!("POST /faults HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", faults.len(), faults),
+            None => "DELETE /faults HTTP/1.1\r\nHost: localhost\r\n\r\n".to_string(),
+        };
+        let response = send_request(metrics_address, request.as_bytes()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    };
+    let timed_request = |path: &str| {
+        let started_at = Instant::now();
+        let response = send_request(&proxy.address, format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).unwrap();
+        (response, started_at.elapsed())
+    };
+
+    // the matching requests are delayed, the other ones aren't
+    set_faults(Some(r#"{"latency_ms": 400, "match_path": "^/api/"}"#));
+    let (response, elapsed) = timed_request("/api/slow");
+    assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+    let (_, elapsed) = timed_request("/fast");
+    assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+
+    // about half of the requests are aborted, and never reach the upstream server
+    set_faults(Some(r#"{"abort_pct": 50, "abort_status": 503}"#));
+    let requests = 100;
+    let aborted = (0..requests).filter(|_| timed_request("/flaky").0.starts_with("HTTP/1.1 503 Service Unavailable\r\n")).count();
+    assert!((25..=75).contains(&aborted), "{} aborted out of {}", aborted, requests);
+    assert_eq!(upstream.received("/flaky"), requests - aborted);
+
+    // once cleared, the requests are proxied normally again
+    set_faults(None);
+    assert!((0..20).all(|_| timed_request("/flaky").0.starts_with("HTTP/1.1 200 OK\r\n")));
+    assert_eq!(upstream.received("/flaky"), requests - aborted + 20);
+
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains(&format!("lb_faults_injected_total{{kind=\"abort\"}} {}\n", aborted)), "{}", metrics);
+    assert!(metrics.contains("lb_faults_injected_total{kind=\"latency\"} 1\n"), "{}", metrics);
+    eventually(Duration::from_secs(5), || {
+        proxy.output().iter().any(|line| line.contains("\"upstream\":\"fault\"") && line.contains("\"fault\":{\"abort_status\":503"))
+    });
+}
+
+
+#[test]
+fn test_admin_token_is_required_and_rotated_on_sighup() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let token_file = std::env::temp_dir().join(format!("lb-proxy-admin-token-{}-{}", std::process::id(), nanos));
+    std::fs::write(&token_file, "old-token\n").unwrap();
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &[
+        "--metrics-bind", "127.0.0.1:0", "--enable-fault-injection",
+        "--admin-token-file", token_file.to_str().unwrap(), "--admin-token-exempt-metrics",
+    ]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+    let clear_faults = |token: Option<&str>| {
+        let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+        send_request(metrics_address, format!("DELETE /faults HTTP/1.1\r\nHost: localhost\r\n{}\r\n", authorization).as_bytes()).unwrap()
+    };
+
+    assert!(clear_faults(Some("old-token")).starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(clear_faults(None).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(clear_faults(Some("wrong")).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    let response = send_request(metrics_address, b"GET /debug/config HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+    // the scrapes are exempt
+    let response = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+    // every change of the faults is audited, refused or not
+    eventually(Duration::from_secs(5), || {
+        let audited: Vec<String> = proxy.output().into_iter().filter(|line| line.contains("\"audit\":\"admin\"")).collect();
+        audited.len() == 3 && audited.iter().all(|line| line.contains("\"client\":\"127.0.0.1:") && line.contains("\"method\":\"DELETE\"") && line.contains("\"target\":\"/faults\""))
+            && audited[0].contains("\"status\":200") && audited[1].contains("\"status\":401")
+    });
+
+    // the old token is refused once the new one is read, on the same listener
+    std::fs::write(&token_file, "new-token\n").unwrap();
+    proxy.send_signal("HUP");
+    eventually(Duration::from_secs(5), || proxy.output().iter().any(|line| line == "Admin token reloaded"));
+    assert!(clear_faults(Some("old-token")).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(clear_faults(Some("new-token")).starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(send_request(&proxy.address, GET).unwrap().ends_with("ok"));
+
+    std::fs::remove_file(&token_file).unwrap();
+}
+
+
+#[test]
+fn test_drain_file_stops_and_resumes_listening() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let drain_file = std::env::temp_dir().join(format!("lb-proxy-drain-{}-{}", std::process::id(), nanos));
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &["--drain-file", drain_file.to_str().unwrap()]);
+
+    // a keep-alive connection opened before the drain
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(GET).unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("ok"));
+
+    // the proxy stops listening once the file exists
+    std::fs::write(&drain_file, b"").unwrap();
+    eventually(Duration::from_secs(10), || TcpStream::connect(&proxy.address).is_err());
+
+    // the open connection gets its in-flight request answered, then it is closed
+    client.write_all(GET).unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("ok"));
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+
+    // and listens again once the file is removed
+    std::fs::remove_file(&drain_file).unwrap();
+    eventually(Duration::from_secs(10), || send_request(&proxy.address, GET).is_ok_and(|response| response.ends_with("ok")));
+}
+
+
+#[test]
+fn test_drain_closes_idle_upstream_connections() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let drain_file = std::env::temp_dir().join(format!("lb-proxy-idle-drain-{}-{}", std::process::id(), nanos));
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &["--drain-file", drain_file.to_str().unwrap()]);
+
+    // a keep-alive connection left idle, holding its upstream connection
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(GET).unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("ok"));
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 1);
+
+    // the upstream connection is closed once draining starts, without another request
+    std::fs::write(&drain_file, b"").unwrap();
+    eventually(Duration::from_secs(10), || upstream.open_connections() == 0);
+
+    // a request still sent by the client is answered on a new upstream connection, then both are closed
+    client.write_all(GET).unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("ok"));
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 0);
+
+    std::fs::remove_file(&drain_file).unwrap();
+}
+
+
+#[test]
+fn test_idle_upstream_connections_are_closed_after_the_keepalive_timeout() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &["--upstream-keepalive-timeout", "1"]);
+
+    // a keep-alive connection left idle, holding its upstream connection
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET /keepalive HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("ok"));
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 1);
+
+    // the upstream server is told how long the connection is kept
+    let forwarded = upstream.requests().into_iter().find(|request| request_path(request) == "/keepalive").unwrap();
+    let forwarded = String::from_utf8_lossy(&forwarded);
+    assert!(forwarded.contains("keep-alive: timeout=1\r\n"), "{}", forwarded);
+
+    // the idle upstream connection is closed once the timeout expires, the client connection stays open
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 0);
+    client.write_all(b"GET /keepalive HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).unwrap().ends_with("ok"));
+    assert_eq!(upstream.received("/keepalive"), 2);
+}
+
+
+#[test]
+fn test_connection_close_request_is_the_last_one_served() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET /last HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+
+    // the response tells the client the connection ends, whatever the upstream server said, and the proxy closes it
+    let response = read_response(&mut client).unwrap();
+    assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+    assert!(!response.contains("keep-alive"), "{}", response);
+    let mut rest = Vec::new();
+    assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+
+    // the upstream connection is closed along with it
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 0);
+    assert_eq!(upstream.received("/last"), 1);
+}
+
+
+#[test]
+fn test_keep_alive_connection_serves_several_requests() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    for _ in 0..3 {
+        client.write_all(b"GET /again HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut client).unwrap();
+        assert!(!response.contains("Connection: close"), "{}", response);
+        assert!(response.ends_with("ok"));
+    }
+    assert_eq!(upstream.received("/again"), 3);
+}
+
+
+#[test]
+fn test_idle_upstream_connections_are_capped_per_upstream() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::start(&[&upstream.address], &["--max-idle-per-upstream", "2", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    // four keep-alive clients left idle, one after the other, each holding its upstream connection
+    let mut clients: Vec<TcpStream> = (0..4).map(|_| {
+        let mut client = TcpStream::connect(&proxy.address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"GET /keepalive HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut client).unwrap().ends_with("ok"));
+        client
+    }).collect();
+
+    // the two oldest idle connections are evicted, and only the cap stays open
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 2);
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains(&format!("lb_idle_upstream_connections{{upstream=\"{}\"}} 2\n", upstream.address)), "{}", metrics);
+    assert!(metrics.contains("lb_idle_upstream_evictions_total 2\n"), "{}", metrics);
+
+    // the clients of the evicted connections are still answered, on new upstream connections
+    for client in &mut clients {
+        client.write_all(b"GET /keepalive HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(client).unwrap().ends_with("ok"));
+    }
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 2);
+}
+
+
+#[test]
+fn test_first_request_reuses_a_prewarmed_connection() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let proxy = Proxy::spawn(&[&upstream.address], &["--prewarm", "2", "--interval", "60", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    // the health check connection is closed, the pre-warmed ones stay open
+    eventually(Duration::from_secs(5), || upstream.open_connections() == 2);
+    let accepted = upstream.accepted_connections();
+
+    let response = send_request(&proxy.address, b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("ok"), "{}", response);
+    assert_eq!(upstream.received("/first"), 1);
+    assert_eq!(upstream.accepted_connections(), accepted);
+
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains(&format!("lb_prewarmed_connections{{upstream=\"{}\"}} 1\n", upstream.address)), "{}", metrics);
+    assert!(metrics.contains("lb_prewarmed_connections_reused_total 1\n"), "{}", metrics);
+}
+
+
+#[test]
+fn test_upstream_closing_mid_response_closes_the_client_connection() {
+    // the head and half of the body are sent before the upstream server goes away
+    let truncated = MockResponse::status(200).body("0123456789").drop_after(43);
+    let upstream = MockUpstream::start_routes(&[("/truncated", truncated)], MockResponse::status(200));
+    let proxy = Proxy::start(&[&upstream.address], &[]);
+
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET /truncated HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    // the client can tell the response is incomplete: the connection closes before the announced length
+    let mut received = Vec::new();
+    let _ = client.read_to_end(&mut received);
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n"), "{}", received);
+    assert!(!received.ends_with("0123456789"), "{}", received);
+}
+
+
+#[test]
+fn test_static_route_is_answered_without_upstream() {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let robots = std::env::temp_dir().join(format!("lb-proxy-robots-{}-{}", std::process::id(), nanos));
+    std::fs::write(&robots, "User-agent: *\nDisallow: /\n").unwrap();
+    let upstream = MockUpstream::start_response(MockResponse::status(200));
+    let route = format!("/robots.txt file={} content_type=text/plain", robots.display());
+    let proxy = Proxy::start(&[&upstream.address], &["--static-route", &route, "--access-log"]);
+    std::fs::remove_file(&robots).unwrap();
+
+    let response = send_request(&proxy.address, b"GET /robots.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n"), "{}", response);
+    assert!(response.ends_with("\r\nContent-Length: 26\r\n\r\nUser-agent: *\nDisallow: /\n"), "{}", response);
+
+    // the client revalidating its copy gets a 304
+    let last_modified = response.lines().find_map(|line| line.strip_prefix("Last-Modified: ")).unwrap();
+    let revalidation = format!("GET /robots.txt HTTP/1.1\r\nHost: localhost\r\nIf-Modified-Since: {}\r\n\r\n", last_modified);
+    let response = send_request(&proxy.address, revalidation.as_bytes()).unwrap();
+    assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{}", response);
+
+    assert_eq!(upstream.received("/robots.txt"), 0);
+    eventually(Duration::from_secs(5), || {
+        proxy.output().iter().any(|line| line.contains("\"target\":\"/robots.txt\"") && line.contains("\"upstream\":\"static\""))
+    });
+}
+
+
+#[test]
+fn test_duplicate_upstream_stops_startup_unless_deduplicated() {
+    let upstream = MockUpstream::start("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_rust_loadbalancer"))
+        .args(["--upstream", &upstream.address, "--upstream", &upstream.address])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    let proxy = Proxy::start(&[&upstream.address, &upstream.address], &["--dedupe-upstreams"]);
+    let response = send_request(&proxy.address, b"GET /once HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    // the state printed at startup lists the upstream server once
+    let listed_once = format!("upstream_addresses: [{:?}]", upstream.address);
+    eventually(Duration::from_secs(5), || proxy.output().iter().any(|line| line.starts_with("ProxyState") && line.contains(&listed_once)));
+}
+
+
+#[test]
+fn test_cors_preflight_is_answered_and_actual_request_forwarded() {
+    let upstream = MockUpstream::start_response(MockResponse::status(200).header("Access-Control-Allow-Origin", "*").body("orders"));
+    let proxy = Proxy::start(&[&upstream.address], &["--cors-origin", "https://app.example.com", "--cors-methods", "GET,PUT", "--cors-headers", "Authorization", "--access-log"]);
+
+    let preflight = b"OPTIONS /api/orders HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\n\r\n";
+    let response = send_request(&proxy.address, preflight).unwrap();
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: https://app.example.com\r\n\
+Access-Control-Allow-Methods: GET, PUT\r\nAccess-Control-Allow-Headers: authorization\r\nVary: Origin\r\n"), "{}", response);
+    assert_eq!(upstream.received("/api/orders"), 0);
+
+    // the actual request reaches the upstream server, and its response allows the origin in place of the upstream one
+    let actual = b"PUT /api/orders HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example.com\r\nContent-Length: 2\r\n\r\n{}";
+    let response = send_request(&proxy.address, actual).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert_eq!(response.matches("Access-Control-Allow-Origin").count(), 1, "{}", response);
+    assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example.com\r\nVary: Origin\r\n\r\norders"), "{}", response);
+    assert_eq!(upstream.received("/api/orders"), 1);
+
+    // a request without an Origin keeps the response of the upstream server
+    let response = send_request(&proxy.address, b"GET /api/orders HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.contains("\r\nAccess-Control-Allow-Origin: *\r\n"), "{}", response);
+    eventually(Duration::from_secs(5), || {
+        proxy.output().iter().any(|line| line.contains("\"method\":\"OPTIONS\"") && line.contains("\"upstream\":\"preflight\""))
+    });
+}
+
+
+#[test]
+fn test_static_route_with_missing_file_stops_startup() {
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_rust_loadbalancer"))
+        .args(["--upstream", "127.0.0.1:1", "--static-route", "/robots.txt file=/nonexistent/robots.txt"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap();
+
+    assert!(!status.success());
+}
+
+
+#[test]
+fn test_idempotent_requests_are_sent_again_when_the_upstream_closes_without_answering() {
+    // the upstream server drops the first /restarting request and every /gone one, while passing its health checks
+    let restarting = Arc::new(AtomicUsize::new(0));
+    let received = restarting.clone();
+    let upstream = MockUpstream::start_with(move |request| match request_path(request).as_str() {
+        "/health" => ok(""),
+        "/restarting" if received.fetch_add(1, Ordering::SeqCst) == 0 => Vec::new(),
+        "/restarting" => ok("back"),
+        "/gone" => Vec::new(),
+        _ => b"HTTP/1.1 204 No Content\r\n\r\n".to_vec(),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--path", "/health", "--eject-on-5xx", "3", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    // a response without a body is a response
+    let response = send_request(&proxy.address, b"GET /no-content HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 204 No Content"), "{}", response);
+    assert_eq!(upstream.received("/no-content"), 1);
+
+    let response = send_request(&proxy.address, b"GET /restarting HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("back"), "{}", response);
+    assert_eq!(upstream.received("/restarting"), 2);
+
+    // a POST is never sent twice, a GET is answered with 502 once its retry was dropped too
+    let response = send_request(&proxy.address, b"POST /gone HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert_eq!(upstream.received("/gone"), 1);
+    let response = send_request(&proxy.address, b"GET /gone HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+    assert_eq!(upstream.received("/gone"), 3);
+
+    // every unanswered request counts, the three in a row ejected the upstream server
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains(&format!("lb_upstream_errors_total{{upstream=\"{}\",kind=\"empty\"}} 4\n", upstream.address)), "{}", metrics);
+    assert!(metrics.contains(&format!("lb_upstream_ejections_total{{upstream=\"{}\"}} 1\n", upstream.address)), "{}", metrics);
+}
+
+
+#[test]
+fn test_request_is_sent_again_when_a_reused_connection_was_closed() {
+    // every other payment reaches a connection the upstream server is closing, and is dropped unanswered
+    let payments = Arc::new(AtomicUsize::new(0));
+    let received = payments.clone();
+    let upstream = MockUpstream::start_with(move |request| match request_path(request).as_str() {
+        "/pay" if received.fetch_add(1, Ordering::SeqCst) % 2 == 1 => Vec::new(),
+        _ => ok("paid"),
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    for _ in 0..3 {
+        client.write_all(b"POST /pay HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+        let response = read_response(&mut client).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("paid"), "{}", response);
+    }
+
+    // the first request went on a new connection, the two others were sent again once
+    assert_eq!(upstream.received("/pay"), 5);
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains("lb_pooled_connection_retries_total 2\n"), "{}", metrics);
+}
+
+
+#[test]
+fn test_upstream_answering_server_errors_is_ejected() {
+    // the health checks on / pass on every upstream server, but two of them fail the /api requests
+    let failing = MockUpstream::start_routes(&[("/api", MockResponse::status(500))], MockResponse::status(200));
+    let missing = MockUpstream::start_routes(&[("/api", MockResponse::status(404))], MockResponse::status(200));
+    let healthy = MockUpstream::start_routes(&[("/api", MockResponse::status(200).body("ok"))], MockResponse::status(200));
+    let proxy = Proxy::start(&[&failing.address, &missing.address, &healthy.address], &["--eject-on-5xx", "3", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    for _ in 0..60 {
+        send_request(&proxy.address, b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    }
+
+    // the upstream server answering 500 is out of rotation after three of them, the one answering 404 never is
+    assert_eq!(failing.received("/api"), 3);
+    assert!(missing.received("/api") > 3, "{}", missing.received("/api"));
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains(&format!("lb_upstream_ejections_total{{upstream=\"{}\"}} 1\n", failing.address)), "{}", metrics);
+    assert!(!metrics.contains(&format!("lb_upstream_ejections_total{{upstream=\"{}\"}}", missing.address)), "{}", metrics);
+}
+
+
+#[test]
+fn test_debug_routing_header_forces_the_upstream() {
+    // the health checks on / fail on the second upstream server
+    let healthy = MockUpstream::start_routes(&[("/debug", MockResponse::status(200).body("healthy"))], MockResponse::status(200));
+    let unhealthy = MockUpstream::start_routes(&[("/debug", MockResponse::status(200).body("unhealthy"))], MockResponse::status(500));
+    let proxy = Proxy::start(&[&healthy.address, &unhealthy.address], &["--debug-routing-header", "X-LB-Upstream", "--access-log"]);
+    let debug = |upstream: &str| {
+        let request = format!("GET /debug HTTP/1.1\r\nHost: localhost\r\nX-LB-Upstream: {}\r\n\r\n", upstream);
+        send_request(&proxy.address, request.as_bytes()).unwrap()
+    };
+
+    assert!(debug(&healthy.address).ends_with("healthy"));
+    let refused = debug(&unhealthy.address);
+    assert!(refused.starts_with("HTTP/1.1 503 Service Unavailable") && refused.contains("append !"), "{}", refused);
+    assert!(debug(&format!("{}!", unhealthy.address)).ends_with("unhealthy"));
+
+    let unknown = debug("10.0.0.7:8080");
+    assert!(unknown.starts_with("HTTP/1.1 400 Bad Request"), "{}", unknown);
+    assert!(unknown.contains(&healthy.address) && unknown.contains(&unhealthy.address), "{}", unknown);
+
+    // the upstream servers never see the header, and the access log tells which requests were forced
+    let requests = healthy.requests().into_iter().chain(unhealthy.requests()).map(|request| String::from_utf8_lossy(&request).to_lowercase());
+    assert!(requests.into_iter().all(|request| !request.contains("x-lb-upstream")));
+    let override_field = format!("\"upstream_override\":\"{}!\"", unhealthy.address);
+    eventually(Duration::from_secs(5), || proxy.output().iter().any(|line| line.contains(&override_field)));
+}
+
+
+#[test]
+fn test_debug_routing_header_of_untrusted_client_is_ignored() {
+    let first = MockUpstream::start_routes(&[("/debug", MockResponse::status(200).body("first"))], MockResponse::status(200));
+    let second = MockUpstream::start_routes(&[("/debug", MockResponse::status(200).body("second"))], MockResponse::status(200));
+    let proxy = Proxy::start(&[&first.address, &second.address], &["--debug-routing-header", "X-LB-Upstream", "--debug-routing-from", "10.0.0.0/8"]);
+
+    let request = format!("GET /debug HTTP/1.1\r\nHost: localhost\r\nX-LB-Upstream: {}\r\n\r\n", first.address);
+    for _ in 0..20 {
+        assert!(send_request(&proxy.address, request.as_bytes()).unwrap().starts_with("HTTP/1.1 200 OK"));
+    }
+
+    // the requests were spread over both upstream servers, without the header
+    assert!(second.received("/debug") > 0);
+    let requests = first.requests().into_iter().chain(second.requests()).map(|request| String::from_utf8_lossy(&request).to_lowercase());
+    assert!(requests.into_iter().all(|request| !request.contains("x-lb-upstream")));
+}
+
+
+#[test]
+fn test_forwarded_scheme_headers_describe_the_plaintext_listener() {
+    let upstream = MockUpstream::start_response(MockResponse::status(200));
+    let proxy = Proxy::start(&[&upstream.address], &["--forward-scheme"]);
+    let port = proxy.address.rsplit(':').next().unwrap().to_string();
+
+    send_request(&proxy.address, b"GET /links HTTP/1.1\r\nHost: shop.example\r\nX-Forwarded-Proto: https\r\n\r\n").unwrap();
+
+    let forwarded = upstream.requests().into_iter().map(|request| String::from_utf8_lossy(&request).to_lowercase())
+        .find(|request| request.starts_with("get /links")).unwrap();
+    assert!(forwarded.contains("x-forwarded-proto: http\r\n"), "{}", forwarded);
+    assert!(forwarded.contains(&format!("x-forwarded-port: {}\r\n", port)), "{}", forwarded);
+    assert!(forwarded.contains("x-forwarded-host: shop.example\r\n"), "{}", forwarded);
+}
+
+
+#[test]
+fn test_request_deadline_is_enforced_and_forwarded() {
+    let slow = MockResponse::status(200).body("slow").delay(Duration::from_secs(2));
+    let upstream = MockUpstream::start_routes(&[("/slow", slow)], MockResponse::status(200).body("fast"));
+    let proxy = Proxy::start(&[&upstream.address], &["--deadline-header", "X-Request-Deadline"]);
+
+    // a spent budget is answered right away, without contacting the upstream server
+    let started = std::time::Instant::now();
+    let response = send_request(&proxy.address, b"GET /spent HTTP/1.1\r\nHost: localhost\r\nX-Request-Deadline: 0ms\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout"), "{}", response);
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(upstream.received("/spent"), 0);
+
+    // an upstream server too slow for the budget is given up on
+    let started = std::time::Instant::now();
+    let response = send_request(&proxy.address, b"GET /slow HTTP/1.1\r\nHost: localhost\r\nX-Request-Deadline: 200ms\r\n\r\n").unwrap();
+    assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout"), "{}", response);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // the upstream server is told the budget left
+    let response = send_request(&proxy.address, b"GET /fast HTTP/1.1\r\nHost: localhost\r\nX-Request-Deadline: 5S\r\n\r\n").unwrap();
+    assert!(response.ends_with("fast"), "{}", response);
+    let forwarded = upstream.requests().into_iter().map(|request| String::from_utf8_lossy(&request).to_lowercase())
+        .find(|request| request.starts_with("get /fast")).unwrap();
+    let budget: u64 = forwarded.split("x-request-deadline: ").nth(1).and_then(|rest| rest.split("m\r\n").next()).unwrap().parse().unwrap();
+    assert!((4000..5000).contains(&budget), "{}", forwarded);
+}
+
+
+#[test]
+fn test_requests_are_shed_while_the_upstream_is_slow() {
+    let slow = Arc::new(AtomicBool::new(true));
+    let upstream_slow = slow.clone();
+    let upstream = MockUpstream::start_with(move |request| {
+        if request_path(request) == "/api" && upstream_slow.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(200));
+        }
+        ok("ok")
+    });
+    let proxy = Proxy::start(&[&upstream.address], &["--shed-latency-ms", "100", "--shed-window-ms", "1000", "--metrics-bind", "127.0.0.1:0"]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+    let api = b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    // the slow responses push the latency over its mark, and requests start being shed
+    let mut shed = None;
+    let mut stream = TcpStream::connect(&proxy.address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    eventually(Duration::from_secs(10), || {
+        stream.write_all(api).unwrap();
+        shed = read_response(&mut stream).ok().filter(|response| response.starts_with("HTTP/1.1 503"));
+        shed.is_some()
+    });
+    let shed = shed.unwrap();
+    assert!(shed.contains("Retry-After: 1\r\n"));
+
+    // the shed request was read in full, the client can retry on the same connection
+    assert!(!shed.contains("Connection: close"), "{}", shed);
+    stream.write_all(api).unwrap();
+    assert!(read_response(&mut stream).unwrap().starts_with("HTTP/1.1 "));
+
+    // once the upstream server is fast again, the shedding backs off to nothing
+    slow.store(false, Ordering::SeqCst);
+    eventually(Duration::from_secs(15), || {
+        send_request(&proxy.address, api).unwrap();
+        send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap().contains("lb_shed_rate 0\n")
+    });
+    for _ in 0..20 {
+        assert!(send_request(&proxy.address, api).unwrap().starts_with("HTTP/1.1 200 OK"));
+    }
+}
+
+
+#[test]
+fn test_frontends_balance_isolated_pools() {
+    let upstream_a = MockUpstream::start_with(|_| ok("a"));
+    let upstream_b = MockUpstream::start_with(|_| ok("b"));
+    let down_address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let proxy = Proxy::start(&[&upstream_a.address], &[
+        "--interval", "1",
+        "--frontend", "b=127.0.0.1:0",
+        "--pool-upstream", &format!("b={}", upstream_b.address),
+        "--pool-upstream", &format!("b={}", down_address),
+        "--metrics-bind", "127.0.0.1:0",
+    ]);
+    let frontend_b = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Listening for requests of pool b on ")).unwrap();
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+    let api = b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    eventually(Duration::from_secs(10), || send_request(frontend_b, api).is_ok_and(|response| response.starts_with("HTTP/1.1 200")));
+
+    // every listener only sends its requests to its own pool
+    for _ in 0..10 {
+        assert!(send_request(&proxy.address, api).unwrap().ends_with("\r\n\r\na"));
+        assert!(send_request(frontend_b, api).unwrap().ends_with("\r\n\r\nb"));
+    }
+    assert_eq!(upstream_a.received("/api"), 10);
+    assert_eq!(upstream_b.received("/api"), 11);
+
+    // every pool only checks the health of its own upstream servers, and its metrics are labeled with its name
+    let mut metrics = String::new();
+    eventually(Duration::from_secs(10), || {
+        metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        metrics.contains(&format!("health_check_failures_total{{pool=\"b\",upstream=\"{}\"}} ", down_address))
+    });
+    assert!(metrics.contains(&format!("health_check_failures_total{{pool=\"default\",upstream=\"{}\"}} 0\n", upstream_a.address)));
+    assert!(metrics.contains(&format!("health_check_failures_total{{pool=\"b\",upstream=\"{}\"}} 0\n", upstream_b.address)));
+    assert!(!metrics.contains(&format!("pool=\"default\",upstream=\"{}\"", down_address)));
+    assert!(!metrics.contains(&format!("pool=\"b\",upstream=\"{}\"", upstream_a.address)));
+    assert_eq!(metrics.matches("# TYPE health_check_failures_total counter\n").count(), 1);
+}
+
+
+#[test]
+fn test_large_request_bodies_are_spilled_to_disk_and_sent_again() {
+    // every other upload reaches a connection the upstream server is closing, and is dropped unanswered
+    let uploads = Arc::new(AtomicUsize::new(0));
+    let received = uploads.clone();
+    let upstream = MockUpstream::start_with(move |request| match request_path(request).as_str() {
+        "/upload" if received.fetch_add(1, Ordering::SeqCst) % 2 == 1 => Vec::new(),
+        _ => ok("stored"),
+    });
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let spool_dir = std::env::temp_dir().join(format!("lb-spool-e2e-{}-{}", std::process::id(), nanos));
+    std::fs::create_dir_all(&spool_dir).unwrap();
+    let proxy = Proxy::start(&[&upstream.address], &[
+        "--body-memory-limit", "1024",
+        "--spool-dir", spool_dir.to_str().unwrap(),
+        "--metrics-bind", "127.0.0.1:0",
+    ]);
+    let metrics_address = proxy.startup_output.iter().find_map(|line| line.strip_prefix("Serving metrics on ")).unwrap();
+
+    let body = "0123456789abcdef".repeat(256);
+    let mut client = TcpStream::connect(&proxy.address).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    for _ in 0..3 {
+        client.write_all(format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+        let response = read_response(&mut client).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("stored"), "{}", response);
+    }
+
+    // every upload reached the upstream server in full, the two sent again read from their spool file
+    let forwarded: Vec<Vec<u8>> = upstream.requests().into_iter().filter(|request| request_path(request) == "/upload").collect();
+    assert_eq!(forwarded.len(), 5);
+    assert!(forwarded.iter().all(|request| request.ends_with(body.as_bytes())));
+    let metrics = send_request(metrics_address, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(metrics.contains("lb_spilled_requests_total 3\n"), "{}", metrics);
+    assert!(metrics.contains("lb_spilled_bytes_total 12288\n"), "{}", metrics);
+
+    // the spool files are deleted once the requests are answered
+    eventually(Duration::from_secs(5), || std::fs::read_dir(&spool_dir).unwrap().count() == 0);
+    std::fs::remove_dir(&spool_dir).unwrap();
+}
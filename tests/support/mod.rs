@@ -0,0 +1,474 @@
+//! # Integration Test Support
+//!
+//! Helpers shared by the integration tests, so that no test depends on a host outside of the machine running it.
+//!
+//! ## Structures
+//!
+//! - `MockUpstream`: A local upstream server on port 0 answering every request with a scripted response.
+//! - `MockResponse`: A response of a mock upstream built from its status, headers, body and misbehaviors.
+//! - `Proxy`: The proxy server binary running in a child process on port 0, killed when dropped.
+//!
+//! ## Functions
+//!
+//! - `send_request`: Sends a raw request on a new connection and returns the response.
+//! - `read_response`: Reads one response from a stream, using its `Content-Length` to find its end.
+//! - `request_path`: Returns the request target of a raw request.
+//! - `eventually`: Waits until a condition holds, for assertions on state that changes over time.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Scripts the response of a mock upstream from the raw request it received.
+type Handler = dyn Fn(&[u8]) -> Reply + Send + Sync;
+
+/// What a mock upstream writes back for a request.
+struct Reply {
+    /// The raw response, written as-is.
+    bytes: Vec<u8>,
+
+    /// Close the connection once the response is written.
+    close: bool,
+
+    /// Wait for this long after writing the first bytes of the response, before writing the rest.
+    stall: Option<(usize, Duration)>,
+}
+
+/// A response of a mock upstream, built from a status with the methods below.
+///
+/// The body is sent with a `Content-Length` header, or in chunks with `chunked`. The misbehaviors (`delay`,
+/// `drop_after`, `stall_after`) script the failures of an upstream server.
+///
+/// # Example
+///
+/// ```ignore
+/// let upstream = MockUpstream::start_response(MockResponse::status(200).header("Cache-Control", "no-store").body("ok"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    chunked: bool,
+    delay: Duration,
+    drop_after: Option<usize>,
+    stall_after: Option<(usize, Duration)>,
+}
+
+impl MockResponse {
+    /// Starts a response with `status` and an empty body.
+    pub fn status(status: u16) -> MockResponse {
+        MockResponse { status, headers: Vec::new(), body: Vec::new(), chunked: false, delay: Duration::ZERO, drop_after: None, stall_after: None }
+    }
+
+    /// Adds a header to the response.
+    pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the body of the response.
+    pub fn body(mut self, body: &str) -> MockResponse {
+        self.body = body.as_bytes().to_vec();
+        self
+    }
+
+    /// Sends the body with `Transfer-Encoding: chunked`, one chunk per line of the body.
+    pub fn chunked(mut self) -> MockResponse {
+        self.chunked = true;
+        self
+    }
+
+    /// Waits for `delay` before answering.
+    pub fn delay(mut self, delay: Duration) -> MockResponse {
+        self.delay = delay;
+        self
+    }
+
+    /// Closes the connection after writing the first `bytes` bytes of the response. Zero closes the connection
+    /// without answering.
+    pub fn drop_after(mut self, bytes: usize) -> MockResponse {
+        self.drop_after = Some(bytes);
+        self
+    }
+
+    /// Waits for `stall` after writing the first `bytes` bytes of the response, before writing the rest.
+    pub fn stall_after(mut self, bytes: usize, stall: Duration) -> MockResponse {
+        self.stall_after = Some((bytes, stall));
+        self
+    }
+
+    /// Returns the raw response, head and body.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            100 => "Continue",
+            200 => "OK",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            _ => "Mock",
+        };
+        let mut bytes = format!("HTTP/1.1 {} {}\r\n", self.status, reason).into_bytes();
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if self.chunked {
+            bytes.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+            for chunk in self.body.split_inclusive(|byte| *byte == b'\n') {
+                bytes.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                bytes.extend_from_slice(chunk);
+                bytes.extend_from_slice(b"\r\n");
+            }
+            bytes.extend_from_slice(b"0\r\n\r\n");
+        } else {
+            bytes.extend_from_slice(format!("Content-Length: {}\r\n\r\n", self.body.len()).as_bytes());
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+
+    /// Waits for the delay and returns what to write back.
+    fn reply(&self) -> Reply {
+        thread::sleep(self.delay);
+        let mut bytes = self.to_bytes();
+        if let Some(length) = self.drop_after {
+            bytes.truncate(length);
+        }
+        Reply { bytes, close: self.drop_after.is_some(), stall: self.stall_after }
+    }
+}
+
+/// A local upstream server answering every request with a scripted response.
+///
+/// The upstream listens on `127.0.0.1:0`, serves each connection in its own thread and keeps the connections open
+/// between requests. Every received request is recorded and can be inspected with `requests` and `received`, which
+/// tells which upstream served a request.
+pub struct MockUpstream {
+    /// Address the upstream listens on.
+    pub address: String,
+
+    /// Raw requests received so far, in order.
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+
+    /// Number of connections the peer hasn't closed yet.
+    open_connections: Arc<AtomicUsize>,
+
+    /// Number of connections accepted so far.
+    accepted_connections: Arc<AtomicUsize>,
+}
+
+impl MockUpstream {
+    /// Starts an upstream answering every request with `response`.
+    pub fn start(response: &str) -> MockUpstream {
+        let response = response.as_bytes().to_vec();
+        MockUpstream::start_with(move |_| response.clone())
+    }
+
+    /// Starts an upstream answering every request with `response`, after waiting for `delay`.
+    pub fn start_delayed(response: &str, delay: Duration) -> MockUpstream {
+        let response = response.as_bytes().to_vec();
+        MockUpstream::start_with(move |_| {
+            thread::sleep(delay);
+            response.clone()
+        })
+    }
+
+    /// Starts an upstream answering every request with `response`.
+    pub fn start_response(response: MockResponse) -> MockUpstream {
+        MockUpstream::start_routes(&[], response)
+    }
+
+    /// Starts an upstream answering the requests for the paths of `routes` with their response, and every other
+    /// request with `fallback`.
+    pub fn start_routes(routes: &[(&str, MockResponse)], fallback: MockResponse) -> MockUpstream {
+        let routes: Vec<(String, MockResponse)> = routes.iter().map(|(path, response)| (path.to_string(), response.clone())).collect();
+        MockUpstream::serve(move |request| {
+            let path = request_path(request);
+            routes.iter().find(|(route, _)| *route == path).map_or(&fallback, |(_, response)| response).reply()
+        })
+    }
+
+    /// Starts an upstream answering every request with the response returned by `handler`.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - Builds the raw response from the raw request (head and body). An empty response closes the
+    ///   connection without answering.
+    pub fn start_with<F>(handler: F) -> MockUpstream
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        MockUpstream::serve(move |request| {
+            let bytes = handler(request);
+            let close = bytes.is_empty();
+            Reply { bytes, close, stall: None }
+        })
+    }
+
+    /// Starts an upstream writing back what `handler` replies to every request.
+    fn serve<F>(handler: F) -> MockUpstream
+    where
+        F: Fn(&[u8]) -> Reply + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let open_connections = Arc::new(AtomicUsize::new(0));
+        let accepted_connections = Arc::new(AtomicUsize::new(0));
+
+        let recorded = requests.clone();
+        let open = open_connections.clone();
+        let accepted = accepted_connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let recorded = recorded.clone();
+                let handler = handler.clone();
+                let open = open.clone();
+                open.fetch_add(1, Ordering::SeqCst);
+                accepted.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    serve_connection(stream, &recorded, handler.as_ref());
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        MockUpstream { address, requests, open_connections, accepted_connections }
+    }
+
+    /// Returns the raw requests received so far.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Returns the number of connections to the upstream that are still open.
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of connections to the upstream accepted so far, closed ones included.
+    pub fn accepted_connections(&self) -> usize {
+        self.accepted_connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests received so far for `path`.
+    pub fn received(&self, path: &str) -> usize {
+        self.requests.lock().unwrap().iter().filter(|request| request_path(request) == path).count()
+    }
+}
+
+/// Answers the requests of one connection until the peer closes it.
+fn serve_connection(mut stream: TcpStream, requests: &Mutex<Vec<Vec<u8>>>, handler: &Handler) {
+    while let Some(request) = read_message(&mut stream) {
+        requests.lock().unwrap().push(request.clone());
+        let reply = handler(&request);
+        let (first, rest) = match reply.stall {
+            Some((bytes, stall)) if bytes < reply.bytes.len() => {
+                if stream.write_all(&reply.bytes[..bytes]).is_err() {
+                    return;
+                }
+                thread::sleep(stall);
+                (&[][..], &reply.bytes[bytes..])
+            }
+            _ => (&reply.bytes[..], &[][..]),
+        };
+        if stream.write_all(first).is_err() || stream.write_all(rest).is_err() || reply.close {
+            return;
+        }
+    }
+}
+
+/// The proxy server binary running in a child process.
+///
+/// The proxy server binds to `127.0.0.1:0`, its address is read from its output. The process is killed when the
+/// `Proxy` is dropped.
+pub struct Proxy {
+    /// Address the proxy server listens on.
+    pub address: String,
+
+    /// Lines printed by the proxy server before it listened for requests.
+    pub startup_output: Vec<String>,
+
+    /// Lines printed by the proxy server since it listened for requests.
+    output: Arc<Mutex<Vec<String>>>,
+
+    child: Child,
+}
+
+impl Proxy {
+    /// Starts the proxy server in front of `upstreams` and waits until it forwards requests.
+    ///
+    /// The proxy only forwards requests once the first health check has marked an upstream as active, so this waits
+    /// until a `GET /` is answered with something other than 503. That request reaches one of the upstreams.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstreams` - The addresses of the upstream servers.
+    /// * `args` - Additional command line options.
+    pub fn start(upstreams: &[&str], args: &[&str]) -> Proxy {
+        let proxy = Proxy::spawn(upstreams, args);
+
+        eventually(Duration::from_secs(10), || {
+            send_request(&proxy.address, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .is_ok_and(|response| !response.starts_with("HTTP/1.1 503"))
+        });
+
+        proxy
+    }
+
+    /// Starts the proxy server in front of `upstreams` and only waits until it listens for connections.
+    pub fn spawn(upstreams: &[&str], args: &[&str]) -> Proxy {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rust_loadbalancer"));
+        command.args(["--bind", "127.0.0.1:0"]);
+        for upstream in upstreams {
+            command.args(["--upstream", upstream]);
+        }
+        let mut child = command.args(args).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+
+        // the proxy server prints the address it is bound to, with the port chosen by the system
+        let mut output = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        let mut startup_output = Vec::new();
+        let address = loop {
+            line.clear();
+            assert!(output.read_line(&mut line).unwrap() > 0, "the proxy server exited before listening");
+            if let Some(address) = line.trim().strip_prefix("Listening for requests on ") {
+                break address.to_string();
+            }
+            startup_output.push(line.trim().to_string());
+        };
+
+        // keep reading the output so the proxy server never blocks on a full pipe
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let recorded = lines.clone();
+        thread::spawn(move || {
+            for line in output.lines().map_while(Result::ok) {
+                recorded.lock().unwrap().push(line);
+            }
+        });
+
+        Proxy { address, startup_output, output: lines, child }
+    }
+
+    /// Returns the lines printed by the proxy server since it listened for requests.
+    pub fn output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Sends a signal, such as `HUP`, to the proxy server.
+    pub fn send_signal(&self, signal: &str) {
+        let status = Command::new("kill").arg(format!("-{}", signal)).arg(self.child.id().to_string()).status().unwrap();
+        assert!(status.success(), "could not send SIG{} to the proxy server", signal);
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Sends a raw request on a new connection and returns the response.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The response, status line and headers included.
+/// * `Err(std::io::Error)` - If the connection failed or was closed before a complete response.
+pub fn send_request(address: &str, request: &[u8]) -> Result<String, std::io::Error> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(request)?;
+    read_response(&mut stream)
+}
+
+/// Returns the request target of a raw request, or an empty string if it has no request line.
+pub fn request_path(request: &[u8]) -> String {
+    let request = String::from_utf8_lossy(request);
+    request.split(' ').nth(1).unwrap_or("").to_string()
+}
+
+/// Waits until `condition` holds, checking it every 50 milliseconds.
+///
+/// Panics if it still doesn't hold after `timeout`.
+pub fn eventually<F: FnMut() -> bool>(timeout: Duration, mut condition: F) {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met within {:?}", timeout);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Reads one response from `stream`.
+///
+/// The end of the response is found with its `Content-Length` header, or by reading until the connection closes.
+pub fn read_response(stream: &mut TcpStream) -> Result<String, std::io::Error> {
+    match read_message(stream) {
+        Some(response) => Ok(String::from_utf8_lossy(&response).into_owned()),
+        None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// Reads one HTTP message (head and `Content-Length` body) from `stream`.
+///
+/// A chunked request ends with its last chunk, without trailer fields. Another message without `Content-Length` ends
+/// with the head if it is a request, and when the connection closes if it is a response. Returns `None` if the
+/// connection closes before a complete head.
+fn read_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut message = Vec::new();
+    let mut buffer = [0; 4096];
+
+    let head_length = loop {
+        if let Some(position) = message.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => return None,
+            Ok(bytes_read) => message.extend_from_slice(&buffer[..bytes_read]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&message[..head_length]).to_ascii_lowercase();
+    let content_length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|value| value.trim().parse::<usize>().ok());
+
+    match content_length {
+        Some(length) => {
+            while message.len() < head_length + length {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => return None,
+                    Ok(bytes_read) => message.extend_from_slice(&buffer[..bytes_read]),
+                }
+            }
+        }
+        None if !head.starts_with("http/") && head.contains("\r\ntransfer-encoding: chunked\r\n") => {
+            while !message[head_length..].ends_with(b"0\r\n\r\n") {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => return None,
+                    Ok(bytes_read) => message.extend_from_slice(&buffer[..bytes_read]),
+                }
+            }
+        }
+        None if head.starts_with("http/") => {
+            let _ = stream.read_to_end(&mut message);
+        }
+        None => (),
+    }
+
+    Some(message)
+}